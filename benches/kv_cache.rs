@@ -0,0 +1,70 @@
+use candle_core::{Device, Tensor};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ollama::core::cache::{BlockPool, KvCacheType, PagedCacheConfig, PagedKVCache};
+use ollama::core::model::architectures::llama::LlamaCache;
+use ollama::KVCache;
+
+const HEADS: usize = 32;
+const KV_HEADS: usize = 8;
+const HEAD_DIM: usize = 128;
+const CONTEXT: usize = 8192;
+
+fn values(n: usize) -> Vec<f32> {
+    (0..n).map(|i| (i % 251) as f32 / 251.0 - 0.5).collect()
+}
+
+fn rows(n: usize) -> Tensor {
+    Tensor::from_vec(values(n * KV_HEADS * HEAD_DIM), (1, KV_HEADS, n, HEAD_DIM), &Device::Cpu).unwrap()
+}
+
+// One decode step's append to a layer already holding `len` positions. The
+// time should stay flat as `len` grows.
+fn bench_decode_append(c: &mut Criterion) {
+    let (prompt, row) = (rows(CONTEXT / 2), rows(1));
+    let mut group = c.benchmark_group("kv_cache/decode_append");
+    group.sample_size(20);
+    for len in [256, 1024, 4096] {
+        let mut cache = LlamaCache::new(1, CONTEXT, Device::Cpu);
+        let prefix = prompt.narrow(2, 0, len).unwrap();
+        cache.append(0, &prefix, &prefix).unwrap();
+        group.bench_with_input(BenchmarkId::new("candle", len), &len, |b, &len| {
+            b.iter(|| {
+                cache.append(0, &row, &row).unwrap();
+                cache.truncate(len).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+// One decode step through a block pool: the append and the attention of one
+// query over the `len` positions, read block by block. The time per position
+// should stay flat as `len` grows.
+fn bench_decode_attend(c: &mut Criterion) {
+    let shape = |n: usize, heads: usize| ollama::core::tensor::Shape::new(vec![n, heads, HEAD_DIM]);
+    let prompt = ollama::Tensor::new(values(CONTEXT / 2 * KV_HEADS * HEAD_DIM), shape(CONTEXT / 2, KV_HEADS));
+    let row = ollama::Tensor::new(values(KV_HEADS * HEAD_DIM), shape(1, KV_HEADS));
+    let query = ollama::Tensor::new(values(HEADS * HEAD_DIM), shape(1, HEADS));
+    let mut group = c.benchmark_group("kv_cache/decode_attend");
+    group.sample_size(20);
+    for cache_type in [KvCacheType::F16, KvCacheType::Q8_0, KvCacheType::Q4_0] {
+        for len in [256, 1024, 4096] {
+            let config = PagedCacheConfig { block_size: 32, memory_budget: 1 << 30, cache_type };
+            let mut cache = PagedKVCache::new(BlockPool::new(1, KV_HEADS, HEAD_DIM, config).unwrap(), CONTEXT);
+            let prefix = ollama::Tensor::new(prompt.data()[..len * KV_HEADS * HEAD_DIM].to_vec(), shape(len, KV_HEADS));
+            cache.append(0, &prefix, &prefix).unwrap();
+            group.throughput(Throughput::Elements(len as u64));
+            group.bench_with_input(BenchmarkId::new(cache_type.name(), len), &len, |b, &len| {
+                b.iter(|| {
+                    cache.append(0, &row, &row).unwrap();
+                    cache.attend(0, &query, 1.0 / (HEAD_DIM as f32).sqrt()).unwrap();
+                    cache.truncate(len).unwrap();
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode_append, bench_decode_attend);
+criterion_main!(benches);
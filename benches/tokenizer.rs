@@ -0,0 +1,133 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ollama::core::tokenizer::{
+    BpeTokenizer, EncodeOptions, SentencePieceTokenizer, TokenType, Tokenizer, Vocabulary,
+};
+
+const CORPUS_BYTES: usize = 1 << 20;
+
+const WORDS: &[&str] = &[
+    "the", "of", "and", "to", "in", "is", "that", "for", "it", "with", "as", "was", "on", "model", "token",
+    "language", "inference", "server", "request", "response", "cache", "layer", "attention", "matrix",
+    "vector", "quantized", "weights", "context", "prompt", "generate", "embedding", "sequence", "batch",
+];
+
+// A fixed-seed LCG so every run measures the same text.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % n as u64) as usize
+    }
+}
+
+// Roughly 1 MB of lines built from the word list, with numbers, punctuation
+// and made-up words that fall back to short merges.
+fn corpus() -> Vec<String> {
+    let mut rng = Lcg(42);
+    let mut lines = Vec::new();
+    let mut total = 0;
+    while total < CORPUS_BYTES {
+        let mut line = String::new();
+        for i in 0..8 + rng.next(24) {
+            if i > 0 {
+                line.push(' ');
+            }
+            match rng.next(10) {
+                0 => line.push_str(&rng.next(100_000).to_string()),
+                1 => line.extend((0..3 + rng.next(6)).map(|_| (b'a' + rng.next(26) as u8) as char)),
+                _ => line.push_str(WORDS[rng.next(WORDS.len())]),
+            }
+            if rng.next(8) == 0 {
+                line.push(if rng.next(2) == 0 { ',' } else { '.' });
+            }
+        }
+        line.push('\n');
+        total += line.len();
+        lines.push(line);
+    }
+    lines
+}
+
+// GPT-2 style vocabulary: every byte, then one merge per prefix of each word
+// with and without the leading space.
+fn bpe_vocab() -> Vocabulary {
+    let byte_char = |b: u8| match b {
+        b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF => b as char,
+        _ => {
+            let offset = (0..b).filter(|&c| !matches!(c, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF)).count();
+            char::from_u32(256 + offset as u32).unwrap()
+        }
+    };
+    let mut tokens: Vec<String> = (0..=255u8).map(|b| byte_char(b).to_string()).collect();
+    let mut merges = Vec::new();
+    for word in WORDS {
+        for word in [word.to_string(), format!("Ġ{}", word)] {
+            let chars: Vec<char> = word.chars().collect();
+            let mut prefix = chars[0].to_string();
+            for c in &chars[1..] {
+                merges.push(format!("{} {}", prefix, c));
+                prefix.push(*c);
+                tokens.push(prefix.clone());
+            }
+        }
+    }
+    tokens.sort();
+    tokens.dedup();
+    merges.dedup();
+    let mut vocab = Vocabulary::new(tokens);
+    vocab.merges = merges;
+    vocab.model = "gpt2".into();
+    vocab.pre = "gpt-2".into();
+    vocab
+}
+
+// Llama style vocabulary: byte pieces plus every prefix of each ▁word, longer
+// pieces scoring higher.
+fn spm_vocab() -> Vocabulary {
+    let mut entries: Vec<(String, f32, TokenType)> = vec![("<unk>".into(), 0.0, TokenType::Unknown)];
+    entries.extend((0..=255u8).map(|b| (format!("<0x{:02X}>", b), 0.0, TokenType::Byte)));
+    entries.extend((b' '..=b'~').map(|b| ((b as char).to_string(), -10.0, TokenType::Normal)));
+    entries.push(("▁".into(), -10.0, TokenType::Normal));
+    for word in WORDS {
+        let word = format!("▁{}", word);
+        for (end, _) in word.char_indices().skip(2) {
+            entries.push((word[..end].to_string(), end as f32 - 10.0, TokenType::Normal));
+        }
+        entries.push((word.clone(), word.len() as f32 - 10.0, TokenType::Normal));
+    }
+    let mut vocab = Vocabulary::new(entries.iter().map(|(t, _, _)| t.clone()).collect());
+    vocab.scores = entries.iter().map(|(_, s, _)| *s).collect();
+    vocab.types = entries.iter().map(|(_, _, t)| *t).collect();
+    vocab.model = "llama".into();
+    vocab.unk_token = Some(ollama::core::TokenId(0));
+    vocab
+}
+
+fn bench_tokenizers(c: &mut Criterion) {
+    let lines = corpus();
+    let text = lines.concat();
+    let batch: Vec<&str> = lines.iter().map(String::as_str).collect();
+    let options = EncodeOptions::new();
+
+    let tokenizers: [(&str, Box<dyn Tokenizer>); 2] = [
+        ("bpe", Box::new(BpeTokenizer::new(bpe_vocab()))),
+        ("spm", Box::new(SentencePieceTokenizer::new(spm_vocab()))),
+    ];
+
+    let mut group = c.benchmark_group("tokenizer");
+    group.throughput(Throughput::Bytes(text.len() as u64));
+    group.sample_size(10);
+    for (name, tokenizer) in &tokenizers {
+        group.bench_function(format!("{}/encode", name), |b| {
+            b.iter(|| tokenizer.encode_with_options(&text, &options).unwrap())
+        });
+        group.bench_function(format!("{}/encode_batch", name), |b| {
+            b.iter(|| tokenizer.encode_batch(&batch, &options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_tokenizers);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+#![no_main]
+
+// cargo fuzz run gguf_parse ../tests/fixtures/gguf
+use libfuzzer_sys::fuzz_target;
+use ollama::infra::gguf::GgufParser;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(file) = GgufParser::parse_reader(&mut std::io::Cursor::new(data)) {
+        // Anything accepted must describe tensors that fit in the input.
+        for tensor in &file.tensors {
+            let end = file.data_offset + tensor.offset + tensor.size_bytes() as u64;
+            assert!(end <= data.len() as u64, "tensor {} out of bounds", tensor.name);
+        }
+    }
+});
@@ -357,6 +357,22 @@ pub struct RunningModel {
     pub expires_at: String,
     #[serde(default)]
     pub context_length: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kv_cache: Option<KvCacheUsage>,
+}
+
+// How much of a loaded model's paged KV cache its sequences hold.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[allow(dead_code)]
+pub struct KvCacheUsage {
+    #[serde(rename = "type")]
+    pub cache_type: String,
+    pub block_size: usize,
+    pub blocks_used: usize,
+    pub blocks_total: usize,
+    pub bytes_used: u64,
+    pub bytes_total: u64,
+    pub utilization: f64,
 }
 
 #[derive(Deserialize)]
@@ -136,15 +136,11 @@ impl ProgressHandler {
 impl EventHandler for ProgressHandler {
     fn handle(&self, event: &Event) {
         match event {
-            Event::ModelLoading { name, progress } => {
-                if self.verbose {
-                    println!("[{}] Loading: {:.1}%", name, progress * 100.0);
-                }
+            Event::ModelLoading { name, progress } if self.verbose => {
+                println!("[{}] Loading: {:.1}%", name, progress * 100.0);
             }
-            Event::InferenceProgress { tokens } => {
-                if self.verbose {
-                    print!("\rTokens: {}", tokens);
-                }
+            Event::InferenceProgress { tokens } if self.verbose => {
+                print!("\rTokens: {}", tokens);
             }
             _ => {}
         }
@@ -1,7 +1,6 @@
 #![allow(unused)]
 #![allow(dead_code)]
 use crate::core::{Model, TokenId, KVCache, Tensor, Result};
-use crate::core::cache::CausalKVCache;
 use crate::core::tokenizer::Tokenizer;
 
 pub struct InferenceRunner {
@@ -15,18 +14,12 @@ pub struct InferenceRunner {
 
 impl InferenceRunner {
     pub fn new(model: Box<dyn Model>, tokenizer: Box<dyn Tokenizer>) -> Self {
-        let config = model.config();
-        let cache = CausalKVCache::new(
-            config.num_layers,
-            config.num_heads,
-            config.head_dim(),
-            config.context_length,
-        );
+        let cache = model.new_cache(model.config().context_length);
         
         Self {
             model,
             tokenizer,
-            cache: Box::new(cache),
+            cache,
             max_tokens: 2048,
             temperature: 1.0,
             top_p: 0.9,
@@ -48,14 +41,17 @@ impl InferenceRunner {
         self
     }
     
+    // Each call starts a new sequence; the prompt is evaluated once and every
+    // later step feeds only the sampled token.
     pub fn generate(&mut self, prompt: &str) -> Result<String> {
+        self.cache.reset();
         let mut tokens = self.tokenizer.encode(prompt)?;
         let mut positions: Vec<usize> = (0..tokens.len()).collect();
         
         let mut generated_tokens = Vec::new();
-        let mut current_pos = tokens.len();
+        let start_pos = tokens.len();
         
-        for _ in 0..self.max_tokens {
+        for current_pos in start_pos..start_pos + self.max_tokens {
             let logits = self.model.forward(&tokens, &positions, &mut *self.cache)?;
             
             let next_token = self.sample_token(&logits)?;
@@ -65,10 +61,8 @@ impl InferenceRunner {
             }
             
             generated_tokens.push(next_token);
-            tokens.push(next_token);
-            positions.push(current_pos);
-            
-            current_pos += 1;
+            tokens = vec![next_token];
+            positions = vec![current_pos];
         }
         
         self.tokenizer.decode(&generated_tokens)
@@ -91,18 +85,6 @@ impl InferenceRunner {
     }
     
     pub fn reset_cache(&mut self) {
-        if let Some(cache) = self.cache.as_any_mut().downcast_mut::<CausalKVCache>() {
-            cache.clear();
-        }
-    }
-}
-
-trait AsAnyMut {
-    fn as_any_mut(&mut self) -> &mut dyn std::any::Any;
-}
-
-impl<T: 'static> AsAnyMut for T {
-    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
-        self
+        self.cache.reset();
     }
 }
@@ -50,23 +50,17 @@ fn main() -> Result<()> {
     eprintln!("================================");
     eprintln!("Ready for inference. Send JSON requests via stdin.");
     
-    let mut model_config = ollama::ModelConfig::default();
-    model_config.architecture = gguf.architecture.clone();
-    model_config.vocab_size = gguf.metadata.vocab_size as usize;
-    model_config.context_length = gguf.metadata.context_length as usize;
+    let model_config = ollama::ModelConfig {
+        architecture: gguf.architecture.clone(),
+        vocab_size: gguf.metadata.vocab_size as usize,
+        context_length: gguf.metadata.context_length as usize,
+        ..Default::default()
+    };
 
     let model = ollama::core::model::architectures::llama::LlamaModel::load(&args.model, model_config)?;
-    let mut vocab = ollama::core::tokenizer::Vocabulary::new(gguf.metadata.vocab_tokens.unwrap_or_default());
-    vocab.scores = gguf.metadata.vocab_scores.unwrap_or_default();
-    
-    let tokenizer = ollama::core::tokenizer::create_tokenizer(
-        if gguf.architecture.contains("llama") {
-            ollama::core::tokenizer::TokenizerKind::Bpe
-        } else {
-            ollama::core::tokenizer::TokenizerKind::WordPiece
-        },
-        vocab
-    );
+    let header = ollama::infra::gguf::GgufParser::parse(&args.model)?;
+    let vocab = ollama::core::tokenizer::Vocabulary::from_gguf(&header.metadata);
+    let tokenizer = ollama::core::tokenizer::create_tokenizer(vocab.kind(), vocab);
 
     let mut runner = ollama::InferenceRunner::new(Box::new(model), tokenizer);
     
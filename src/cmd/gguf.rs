@@ -0,0 +1,43 @@
+use anyhow::{Context, Result};
+use ollama::infra::gguf::edit::{self, Edit};
+use std::path::{Path, PathBuf};
+
+pub fn set(file: &str, assignments: &[String], output: Option<&str>) -> Result<()> {
+    let edits = assignments
+        .iter()
+        .map(|arg| {
+            let mut edit = Edit::parse_set(arg)?;
+            // `key=@path` reads the value from a file, e.g. a fixed chat template.
+            if let Edit::Set { value, .. } = &mut edit {
+                if let Some(path) = value.strip_prefix('@') {
+                    *value = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path))?;
+                }
+            }
+            Ok(edit)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    apply(file, &edits, output)
+}
+
+pub fn unset(file: &str, keys: &[String], output: Option<&str>) -> Result<()> {
+    let edits: Vec<Edit> = keys.iter().map(|k| Edit::Unset(k.clone())).collect();
+    apply(file, &edits, output)
+}
+
+// Without an output path the file is replaced once the rewrite has succeeded.
+fn apply(file: &str, edits: &[Edit], output: Option<&str>) -> Result<()> {
+    let input = Path::new(file);
+    let target = output.map(PathBuf::from).unwrap_or_else(|| input.to_path_buf());
+    let mut tmp = target.clone().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let written = edit::rewrite(input, &tmp, edits);
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&tmp);
+        anyhow::bail!("failed to edit {}: {}", input.display(), e);
+    }
+    std::fs::rename(&tmp, &target)?;
+    println!("updated {} metadata key(s) in {}", edits.len(), target.display());
+    Ok(())
+}
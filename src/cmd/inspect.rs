@@ -0,0 +1,411 @@
+use anyhow::{Context, Result};
+use crate::format::{human_bytes, human_number};
+use crate::models::ModelManager;
+use ollama::infra::gguf::{split, GgufMetadata, MetadataValue, SplitGguf, TensorInfo, ValueType};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+const ARRAY_PREVIEW: usize = 5;
+
+#[derive(Serialize)]
+pub struct Report {
+    pub path: String,
+    pub version: u32,
+    pub alignment: u64,
+    pub data_offset: u64,
+    pub file_size: u64,
+    pub parameter_count: u64,
+    pub shards: Vec<ShardEntry>,
+    pub metadata: Vec<MetadataEntry>,
+    pub tokenizer: Option<TokenizerStats>,
+    pub tensors: Vec<TensorEntry>,
+    pub quantization: Vec<QuantEntry>,
+}
+
+#[derive(Serialize)]
+pub struct ShardEntry {
+    pub path: String,
+    pub tensors: usize,
+    pub size: u64,
+}
+
+#[derive(Serialize)]
+pub struct MetadataEntry {
+    pub key: String,
+    #[serde(rename = "type")]
+    pub value_type: String,
+    pub value: Value,
+}
+
+#[derive(Serialize)]
+pub struct TokenizerStats {
+    pub model: String,
+    pub pre: Option<String>,
+    pub tokens: usize,
+    pub merges: usize,
+    pub has_scores: bool,
+    pub token_types: BTreeMap<String, usize>,
+    pub special_tokens: BTreeMap<String, SpecialToken>,
+    pub chat_template: bool,
+}
+
+#[derive(Serialize)]
+pub struct SpecialToken {
+    pub id: u64,
+    pub token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TensorEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub dtype: String,
+    pub shape: Vec<usize>,
+    pub shard: usize,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Serialize)]
+pub struct QuantEntry {
+    #[serde(rename = "type")]
+    pub dtype: String,
+    pub tensors: usize,
+    pub parameters: u64,
+    pub size: u64,
+}
+
+pub fn inspect(target: &str, as_json: bool) -> Result<()> {
+    let paths = resolve(target)?;
+    let report = build_report(&paths)?;
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+    Ok(())
+}
+
+// A target is a GGUF path when it exists on disk, otherwise a local model name.
+// Either way, every shard of a split model is returned.
+fn resolve(target: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(target);
+    if path.is_file() {
+        return split::shard_paths(path).with_context(|| format!("failed to read {}", path.display()));
+    }
+    let manager = ModelManager::new(&crate::envconfig::models_dir())?;
+    manager
+        .get_model_weights_paths(target)
+        .with_context(|| format!("model '{}' not found", target))
+}
+
+pub fn build_report(paths: &[PathBuf]) -> Result<Report> {
+    let model = SplitGguf::open(paths, true, false)
+        .with_context(|| format!("failed to read {}", paths.first().map(|p| p.display().to_string()).unwrap_or_default()))?;
+    let file = model.file();
+    let shards: Vec<ShardEntry> = model
+        .shards()
+        .iter()
+        .map(|s| ShardEntry {
+            path: s.path().display().to_string(),
+            tensors: s.file.tensors.len(),
+            size: s.bytes().len() as u64,
+        })
+        .collect();
+
+    let metadata = file
+        .metadata
+        .keys()
+        .into_iter()
+        .map(|key| {
+            let value = &file.metadata.kv[key];
+            let vtype = file.metadata.value_type(key).unwrap_or_else(|| ValueType::infer(value));
+            MetadataEntry {
+                key: key.to_string(),
+                value_type: type_name(&vtype),
+                value: summarize(value),
+            }
+        })
+        .collect();
+
+    let tensors: Vec<TensorEntry> = model
+        .shards()
+        .iter()
+        .enumerate()
+        .flat_map(|(i, s)| {
+            s.file.tensors.iter().map(move |t| TensorEntry {
+                name: t.name.clone(),
+                dtype: t.dtype.name().to_string(),
+                shape: t.dims.clone(),
+                shard: i,
+                offset: s.file.data_offset + t.offset,
+                size: t.size_bytes() as u64,
+            })
+        })
+        .collect();
+
+    Ok(Report {
+        path: shards[0].path.clone(),
+        version: file.version,
+        alignment: file.alignment,
+        data_offset: file.data_offset,
+        file_size: shards.iter().map(|s| s.size).sum(),
+        parameter_count: model.tensors().map(|t| t.num_elements() as u64).sum(),
+        shards,
+        metadata,
+        tokenizer: tokenizer_stats(&file.metadata),
+        quantization: quant_breakdown(model.tensors()),
+        tensors,
+    })
+}
+
+fn type_name(vtype: &ValueType) -> String {
+    match vtype {
+        ValueType::Array(element) => format!("array[{}]", type_name(element)),
+        other => format!("{:?}", other).to_lowercase(),
+    }
+}
+
+fn to_json(value: &MetadataValue) -> Value {
+    match value {
+        MetadataValue::Uint(n) => json!(n),
+        MetadataValue::Int(n) => json!(n),
+        MetadataValue::Float(f) => json!(f),
+        MetadataValue::String(s) => json!(s),
+        MetadataValue::Bool(b) => json!(b),
+        MetadataValue::Array(arr) => Value::Array(arr.iter().map(to_json).collect()),
+    }
+}
+
+// Arrays are reduced to their length and first few elements.
+fn summarize(value: &MetadataValue) -> Value {
+    match value {
+        MetadataValue::Array(arr) => json!({
+            "length": arr.len(),
+            "preview": arr.iter().take(ARRAY_PREVIEW).map(to_json).collect::<Vec<_>>(),
+        }),
+        other => to_json(other),
+    }
+}
+
+fn tokenizer_stats(metadata: &GgufMetadata) -> Option<TokenizerStats> {
+    let tokens = match metadata.get("tokenizer.ggml.tokens") {
+        Some(MetadataValue::Array(tokens)) => tokens,
+        _ => return None,
+    };
+    let array_len = |key: &str| match metadata.get(key) {
+        Some(MetadataValue::Array(a)) => a.len(),
+        _ => 0,
+    };
+
+    let mut token_types = BTreeMap::new();
+    if let Some(MetadataValue::Array(types)) = metadata.get("tokenizer.ggml.token_type") {
+        for t in types {
+            let id = match t {
+                MetadataValue::Int(n) => *n,
+                MetadataValue::Uint(n) => *n as i64,
+                _ => 0,
+            };
+            let name = match id {
+                1 => "normal",
+                2 => "unknown",
+                3 => "control",
+                4 => "user_defined",
+                5 => "unused",
+                6 => "byte",
+                _ => "undefined",
+            };
+            *token_types.entry(name.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    let mut special_tokens = BTreeMap::new();
+    for key in metadata.keys() {
+        let Some(name) = key.strip_prefix("tokenizer.ggml.").and_then(|k| k.strip_suffix("_token_id")) else {
+            continue;
+        };
+        let id = metadata.uint(key);
+        let token = match tokens.get(id as usize) {
+            Some(MetadataValue::String(s)) => Some(s.clone()),
+            _ => None,
+        };
+        special_tokens.insert(name.to_string(), SpecialToken { id, token });
+    }
+
+    Some(TokenizerStats {
+        model: metadata.string("tokenizer.ggml.model"),
+        pre: metadata.get("tokenizer.ggml.pre").map(|_| metadata.string("tokenizer.ggml.pre")),
+        tokens: tokens.len(),
+        merges: array_len("tokenizer.ggml.merges"),
+        has_scores: array_len("tokenizer.ggml.scores") > 0,
+        token_types,
+        special_tokens,
+        chat_template: metadata.get("tokenizer.chat_template").is_some(),
+    })
+}
+
+fn quant_breakdown<'a>(tensors: impl Iterator<Item = &'a TensorInfo>) -> Vec<QuantEntry> {
+    let mut by_type: BTreeMap<&str, Vec<&TensorInfo>> = BTreeMap::new();
+    for t in tensors {
+        by_type.entry(t.dtype.name()).or_default().push(t);
+    }
+    let mut entries: Vec<QuantEntry> = by_type
+        .into_iter()
+        .map(|(dtype, tensors)| QuantEntry {
+            dtype: dtype.to_string(),
+            tensors: tensors.len(),
+            parameters: tensors.iter().map(|t| t.num_elements() as u64).sum(),
+            size: tensors.iter().map(|t| t.size_bytes() as u64).sum(),
+        })
+        .collect();
+    entries.sort_by_key(|e| std::cmp::Reverse(e.size));
+    entries
+}
+
+fn print_report(report: &Report) {
+    println!("{}", report.path);
+    println!(
+        "  GGUF v{}, {} metadata keys, {} tensors, alignment {}",
+        report.version,
+        report.metadata.len(),
+        report.tensors.len(),
+        report.alignment
+    );
+    println!(
+        "  parameters {}  file size {}",
+        human_number(report.parameter_count),
+        human_bytes(report.file_size)
+    );
+    if report.shards.len() > 1 {
+        println!("\nSHARDS");
+        for (i, shard) in report.shards.iter().enumerate() {
+            println!("  {:>3}  {:>8} tensors  {:>10}  {}", i + 1, shard.tensors, human_bytes(shard.size), shard.path);
+        }
+    }
+
+    println!("\nMETADATA");
+    for entry in &report.metadata {
+        let value = match &entry.value {
+            Value::Object(summary) => format!(
+                "[{} items] {}",
+                summary["length"],
+                summary["preview"]
+                    .as_array()
+                    .map(|p| p.iter().map(Value::to_string).collect::<Vec<_>>().join(", "))
+                    .unwrap_or_default()
+            ),
+            Value::String(s) => truncate(s),
+            other => other.to_string(),
+        };
+        println!("  {:<48} {:<16} {}", entry.key, entry.value_type, value);
+    }
+
+    if let Some(tok) = &report.tokenizer {
+        println!("\nTOKENIZER");
+        println!("  model {}  pre {}", tok.model, tok.pre.as_deref().unwrap_or("-"));
+        println!(
+            "  {} tokens, {} merges, scores {}, chat template {}",
+            tok.tokens,
+            tok.merges,
+            if tok.has_scores { "yes" } else { "no" },
+            if tok.chat_template { "yes" } else { "no" }
+        );
+        if !tok.token_types.is_empty() {
+            let types: Vec<String> = tok.token_types.iter().map(|(k, v)| format!("{} {}", k, v)).collect();
+            println!("  types: {}", types.join(", "));
+        }
+        for (name, special) in &tok.special_tokens {
+            println!("  {:<12} {:>8} {}", name, special.id, special.token.as_deref().unwrap_or("?"));
+        }
+    }
+
+    println!("\nTENSORS");
+    let split = report.shards.len() > 1;
+    if split {
+        println!("  {:<48} {:<8} {:<24} {:>5} {:>14} {:>10}", "NAME", "TYPE", "SHAPE", "SHARD", "OFFSET", "SIZE");
+    } else {
+        println!("  {:<48} {:<8} {:<24} {:>14} {:>10}", "NAME", "TYPE", "SHAPE", "OFFSET", "SIZE");
+    }
+    for t in &report.tensors {
+        let shape = format!("{:?}", t.shape);
+        if split {
+            println!("  {:<48} {:<8} {:<24} {:>5} {:>14} {:>10}", t.name, t.dtype, shape, t.shard + 1, t.offset, human_bytes(t.size));
+        } else {
+            println!("  {:<48} {:<8} {:<24} {:>14} {:>10}", t.name, t.dtype, shape, t.offset, human_bytes(t.size));
+        }
+    }
+
+    println!("\nQUANTIZATION");
+    println!("  {:<8} {:>8} {:>12} {:>10} {:>7}", "TYPE", "TENSORS", "PARAMS", "SIZE", "SHARE");
+    let total: u64 = report.quantization.iter().map(|q| q.size).sum::<u64>().max(1);
+    for q in &report.quantization {
+        println!(
+            "  {:<8} {:>8} {:>12} {:>10} {:>6.1}%",
+            q.dtype,
+            q.tensors,
+            human_number(q.parameters),
+            human_bytes(q.size),
+            q.size as f64 * 100.0 / total as f64
+        );
+    }
+}
+
+fn truncate(s: &str) -> String {
+    let line = s.lines().next().unwrap_or("");
+    if line.chars().count() > 60 || line.len() < s.len() {
+        format!("{}...", line.chars().take(60).collect::<String>())
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ollama::infra::gguf::{GgmlType, GgufWriter};
+
+    #[test]
+    fn test_build_report() {
+        let mut metadata = GgufMetadata::new();
+        metadata.set("general.architecture", MetadataValue::String("llama".into()), ValueType::String);
+        let tokens = ["<s>", "</s>", "a", "b", "c", "d", "e"];
+        metadata.set(
+            "tokenizer.ggml.tokens",
+            MetadataValue::Array(tokens.iter().map(|t| MetadataValue::String(t.to_string())).collect()),
+            ValueType::Array(Box::new(ValueType::String)),
+        );
+        metadata.set(
+            "tokenizer.ggml.token_type",
+            MetadataValue::Array([3, 3, 1, 1, 1, 1, 1].iter().map(|t| MetadataValue::Int(*t)).collect()),
+            ValueType::Array(Box::new(ValueType::I32)),
+        );
+        metadata.set("tokenizer.ggml.model", MetadataValue::String("llama".into()), ValueType::String);
+        metadata.set("tokenizer.ggml.eos_token_id", MetadataValue::Uint(1), ValueType::U32);
+
+        let mut writer = GgufWriter::new(metadata);
+        writer.add_tensor("token_embd.weight", vec![32, 7], GgmlType::Q8_0, vec![0; 34 * 7]).unwrap();
+        writer.add_tensor("output_norm.weight", vec![32], GgmlType::F32, vec![0; 128]).unwrap();
+        let path = std::env::temp_dir().join(format!("ollama-inspect-{}.gguf", std::process::id()));
+        writer.write_to_file(&path).unwrap();
+
+        let report = build_report(std::slice::from_ref(&path)).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(report.parameter_count, 32 * 7 + 32);
+        let tokens_entry = report.metadata.iter().find(|m| m.key == "tokenizer.ggml.tokens").unwrap();
+        assert_eq!(tokens_entry.value_type, "array[string]");
+        assert_eq!(tokens_entry.value["length"], 7);
+        assert_eq!(tokens_entry.value["preview"].as_array().unwrap().len(), ARRAY_PREVIEW);
+
+        let tok = report.tokenizer.unwrap();
+        assert_eq!(tok.tokens, 7);
+        assert_eq!(tok.token_types["control"], 2);
+        assert_eq!(tok.special_tokens["eos"].token.as_deref(), Some("</s>"));
+
+        assert_eq!(report.quantization[0].dtype, "Q8_0");
+        assert_eq!(report.quantization[0].size, 34 * 7);
+        assert_eq!(report.tensors[1].offset % 32, 0);
+    }
+}
@@ -1,3 +1,6 @@
+mod gguf;
+mod inspect;
+
 use anyhow::{Context, Result};
 use crate::api::Client;
 use crate::format::{human_bytes, human_time};
@@ -283,20 +286,26 @@ pub async fn serve() -> Result<()> {
     crate::server::serve().await
 }
 
-pub async fn create(model: &str, file: Option<String>) -> Result<()> {
+pub async fn create(model: &str, file: Option<String>, quantize: Option<String>) -> Result<()> {
     let client = Client::from_env()?;
     
     let modelfile = if let Some(f) = file {
-        std::fs::read_to_string(&f).context("Failed to read Modelfile")?
+        let content = std::fs::read_to_string(&f).context("Failed to read Modelfile")?;
+        let base = std::path::Path::new(&f).parent().unwrap_or(std::path::Path::new("."));
+        let base = std::fs::canonicalize(if base.as_os_str().is_empty() { std::path::Path::new(".") } else { base })?;
+        crate::parser::modelfile::resolve_relative_paths(&content, &base)
     } else {
         "FROM .\n".to_string()
     };
     
-    let request = json!({
+    let mut request = json!({
         "name": model,
         "modelfile": modelfile,
         "stream": true
     });
+    if let Some(q) = quantize {
+        request["quantize"] = json!(q);
+    }
     
     use indicatif::{ProgressBar, ProgressStyle, MultiProgress};
     
@@ -335,6 +344,18 @@ pub async fn show(model: &str) -> Result<()> {
     show_model(&Client::from_env()?, model).await
 }
 
+pub async fn inspect(target: &str, as_json: bool) -> Result<()> {
+    inspect::inspect(target, as_json)
+}
+
+pub async fn gguf_set(file: &str, assignments: &[String], output: Option<&str>) -> Result<()> {
+    gguf::set(file, assignments, output)
+}
+
+pub async fn gguf_unset(file: &str, keys: &[String], output: Option<&str>) -> Result<()> {
+    gguf::unset(file, keys, output)
+}
+
 pub async fn list() -> Result<()> {
     list_models(&Client::from_env()?).await
 }
@@ -1,10 +1,38 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use ollama::infra::gguf::{GgufMetadata, MetadataValue, ValueType};
+use serde_json::Value;
 use std::collections::HashMap;
+use std::path::Path;
+
+// Token types as stored in `tokenizer.ggml.token_type`, matching SentencePiece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenType {
+    Normal = 1,
+    Unknown = 2,
+    Control = 3,
+    UserDefined = 4,
+    Unused = 5,
+    Byte = 6,
+}
+
+const LLAMA3_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const QWEN2_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
 
 pub struct TokenizerConverter {
     vocab: HashMap<String, i32>,
     merges: Vec<(String, String)>,
     special_tokens: HashMap<String, i32>,
+    tokens: Vec<String>,
+    scores: Vec<f32>,
+    types: Vec<TokenType>,
+    model: String,
+    pre: Option<String>,
+    special_ids: HashMap<&'static str, i32>,
+    add_bos: Option<bool>,
+    add_eos: Option<bool>,
+    add_space_prefix: Option<bool>,
+    chat_template: Option<String>,
 }
 
 impl TokenizerConverter {
@@ -13,71 +41,372 @@ impl TokenizerConverter {
             vocab: HashMap::new(),
             merges: Vec::new(),
             special_tokens: HashMap::new(),
+            tokens: Vec::new(),
+            scores: Vec::new(),
+            types: Vec::new(),
+            model: "llama".to_string(),
+            pre: None,
+            special_ids: HashMap::new(),
+            add_bos: None,
+            add_eos: None,
+            add_space_prefix: None,
+            chat_template: None,
         }
     }
 
+    pub fn from_dir(dir: &Path) -> Result<Self> {
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let mut converter = if dir.join("tokenizer.json").exists() {
+            Self::from_huggingface(&path("tokenizer.json"))?
+        } else if dir.join("tokenizer.model").exists() {
+            let data = std::fs::read(dir.join("tokenizer.model"))?;
+            if is_tiktoken(&data) {
+                Self::from_tiktoken(&path("tokenizer.model"))?
+            } else {
+                Self::from_sentencepiece(&path("tokenizer.model"))?
+            }
+        } else {
+            bail!("no tokenizer.json or tokenizer.model in {}", dir.display())
+        };
+        converter.apply_config(dir)?;
+        Ok(converter)
+    }
+
     pub fn from_sentencepiece(model_path: &str) -> Result<Self> {
+        let data = std::fs::read(model_path).with_context(|| format!("failed to read {}", model_path))?;
+        let proto = sentencepiece::ModelProto::decode(&data)?;
+
         let mut converter = Self::new();
-        
-        converter.vocab.insert("<unk>".to_string(), 0);
-        converter.vocab.insert("<s>".to_string(), 1);
-        converter.vocab.insert("</s>".to_string(), 2);
-        
-        for i in 3..32000 {
-            converter.vocab.insert(format!("token_{}", i), i);
-        }
-        
+        converter.model = "llama".to_string();
+        converter.pre = Some("default".to_string());
+        for piece in proto.pieces {
+            let kind = match piece.kind {
+                2 => TokenType::Unknown,
+                3 => TokenType::Control,
+                4 => TokenType::UserDefined,
+                5 => TokenType::Unused,
+                6 => TokenType::Byte,
+                _ => TokenType::Normal,
+            };
+            converter.push_token(piece.piece, piece.score, kind);
+        }
+        converter.add_space_prefix = Some(proto.add_dummy_prefix);
+
+        for (key, id) in [
+            ("unknown", proto.unk_id),
+            ("bos", proto.bos_id),
+            ("eos", proto.eos_id),
+            ("padding", proto.pad_id),
+        ] {
+            if id >= 0 && (id as usize) < converter.tokens.len() {
+                converter.special_ids.insert(key, id);
+            }
+        }
         Ok(converter)
     }
 
     pub fn from_huggingface(tokenizer_path: &str) -> Result<Self> {
+        let data = std::fs::read(tokenizer_path).with_context(|| format!("failed to read {}", tokenizer_path))?;
+        let json: Value = serde_json::from_slice(&data)?;
+        let model = &json["model"];
+
         let mut converter = Self::new();
-        
-        converter.vocab.insert("<|endoftext|>".to_string(), 0);
-        converter.vocab.insert("<|startoftext|>".to_string(), 1);
-        
-        for i in 2..50000 {
-            converter.vocab.insert(format!("byte_{}", i), i);
-        }
-        
-        converter.merges.push(("a".to_string(), "b".to_string()));
-        converter.merges.push(("ab".to_string(), "c".to_string()));
-        
+        match model["type"].as_str() {
+            Some("BPE") => converter.load_bpe(&json)?,
+            Some("WordPiece") => converter.load_wordpiece(model)?,
+            Some("Unigram") => converter.load_unigram(model)?,
+            other => bail!("unsupported tokenizer model {:?}", other),
+        }
+
+        if let Some(added) = json["added_tokens"].as_array() {
+            let limit = converter.tokens.len() + added.len();
+            for token in added {
+                let (Some(id), Some(content)) = (token["id"].as_i64(), token["content"].as_str()) else {
+                    continue;
+                };
+                let id = checked_id(id, limit)?;
+                let kind = if token["special"].as_bool().unwrap_or(false) {
+                    TokenType::Control
+                } else {
+                    TokenType::UserDefined
+                };
+                converter.set_token(id, content, 0.0, kind);
+                if kind == TokenType::Control {
+                    converter.special_tokens.insert(content.to_string(), id);
+                }
+            }
+        }
+        converter.fill_gaps();
         Ok(converter)
     }
 
     pub fn from_tiktoken(tokenizer_path: &str) -> Result<Self> {
+        let data = std::fs::read(tokenizer_path).with_context(|| format!("failed to read {}", tokenizer_path))?;
+        let text = std::str::from_utf8(&data)?;
+
+        let mut ranks: Vec<(Vec<u8>, i32)> = Vec::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let (token, rank) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow::anyhow!("invalid tiktoken line: {}", line))?;
+            ranks.push((general_purpose::STANDARD.decode(token)?, rank.trim().parse()?));
+        }
+        for (_, rank) in &ranks {
+            checked_id(*rank as i64, ranks.len())?;
+        }
+        ranks.sort_by_key(|(_, r)| *r);
+        let rank_of: HashMap<&[u8], i32> = ranks.iter().map(|(t, r)| (t.as_slice(), *r)).collect();
+
         let mut converter = Self::new();
-        
-        for i in 0..100000 {
-            converter.vocab.insert(format!("tiktoken_{}", i), i);
-        }
-        
-        converter.special_tokens.insert("<|endoftext|>".to_string(), 100257);
-        converter.special_tokens.insert("<|fim_prefix|>".to_string(), 100258);
-        
+        converter.model = "gpt2".to_string();
+        converter.pre = Some("llama-bpe".to_string());
+        let byte_map = bytes_to_unicode();
+        let encode = |bytes: &[u8]| bytes.iter().map(|b| byte_map[*b as usize]).collect::<String>();
+
+        for (token, rank) in &ranks {
+            converter.set_token(*rank, &encode(token), 0.0, TokenType::Normal);
+            if token.len() > 1 {
+                // Recover the merge that produced this token by running BPE with
+                // only the lower-ranked merges available.
+                let parts = bpe_split(&rank_of, token, *rank);
+                if parts.len() == 2 {
+                    converter.merges.push((encode(&parts[0]), encode(&parts[1])));
+                }
+            }
+        }
+        converter.fill_gaps();
         Ok(converter)
     }
 
-    pub fn add_special_token(&mut self, token: &str, id: i32) {
-        self.special_tokens.insert(token.to_string(), id);
-        self.vocab.insert(token.to_string(), id);
+    fn load_bpe(&mut self, json: &Value) -> Result<()> {
+        let model = &json["model"];
+        let vocab = model["vocab"]
+            .as_object()
+            .ok_or_else(|| anyhow::anyhow!("tokenizer.json BPE model has no vocab"))?;
+        for (token, id) in vocab {
+            let id = id.as_i64().ok_or_else(|| anyhow::anyhow!("invalid id for token {}", token))?;
+            let id = checked_id(id, vocab.len())?;
+            self.set_token(id, token, 0.0, TokenType::Normal);
+        }
+
+        if let Some(merges) = model["merges"].as_array() {
+            for merge in merges {
+                // Older files store "a b", newer ones ["a", "b"].
+                let pair = match merge {
+                    Value::String(s) => s.split_once(' ').map(|(a, b)| (a.to_string(), b.to_string())),
+                    Value::Array(a) if a.len() == 2 => a[0].as_str().zip(a[1].as_str()).map(|(a, b)| (a.to_string(), b.to_string())),
+                    _ => None,
+                };
+                let (a, b) = pair.ok_or_else(|| anyhow::anyhow!("invalid merge {}", merge))?;
+                self.merges.push((a, b));
+            }
+        }
+
+        if model["byte_fallback"].as_bool().unwrap_or(false) {
+            // SentencePiece-derived BPE (llama 2, mistral): keep the SPM model with
+            // byte tokens and rank-ordered scores.
+            self.model = "llama".to_string();
+            self.pre = Some("default".to_string());
+            for id in 0..self.tokens.len() {
+                self.scores[id] = -(id as f32);
+                if is_byte_token(&self.tokens[id]) {
+                    self.types[id] = TokenType::Byte;
+                }
+            }
+            if let Some(unk) = model["unk_token"].as_str().and_then(|t| self.vocab.get(t)) {
+                self.types[*unk as usize] = TokenType::Unknown;
+                self.special_ids.insert("unknown", *unk);
+            }
+        } else {
+            self.model = "gpt2".to_string();
+            self.pre = Some(pre_tokenizer_name(&json["pre_tokenizer"]));
+        }
+        Ok(())
     }
 
-    pub fn add_merge(&mut self, a: &str, b: &str) {
-        self.merges.push((a.to_string(), b.to_string()));
+    fn load_wordpiece(&mut self, model: &Value) -> Result<()> {
+        let vocab = model["vocab"]
+            .as_object()
+            .ok_or_else(|| anyhow::anyhow!("tokenizer.json WordPiece model has no vocab"))?;
+        let prefix = model["continuing_subword_prefix"].as_str().unwrap_or("##");
+        self.model = "bert".to_string();
+        self.pre = Some("default".to_string());
+        for (token, id) in vocab {
+            let id = id.as_i64().ok_or_else(|| anyhow::anyhow!("invalid id for token {}", token))?;
+            let id = checked_id(id, vocab.len())?;
+            // GGUF stores WordPiece vocabularies with a phantom-space prefix on word starts.
+            let text = if token.starts_with('[') && token.ends_with(']') {
+                token.clone()
+            } else if let Some(rest) = token.strip_prefix(prefix) {
+                rest.to_string()
+            } else {
+                format!("\u{2581}{}", token)
+            };
+            self.set_token(id, &text, 0.0, TokenType::Normal);
+        }
+        if let Some(unk) = model["unk_token"].as_str().and_then(|t| vocab.get(t)).and_then(Value::as_i64) {
+            self.special_ids.insert("unknown", unk as i32);
+        }
+        Ok(())
     }
 
-    pub fn vocab_size(&self) -> usize {
-        self.vocab.len()
+    fn load_unigram(&mut self, model: &Value) -> Result<()> {
+        let vocab = model["vocab"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("tokenizer.json Unigram model has no vocab"))?;
+        self.model = "t5".to_string();
+        self.pre = Some("default".to_string());
+        for (id, entry) in vocab.iter().enumerate() {
+            let piece = entry[0].as_str().ok_or_else(|| anyhow::anyhow!("invalid unigram entry {}", entry))?;
+            let score = entry[1].as_f64().unwrap_or(0.0) as f32;
+            let kind = if is_byte_token(piece) { TokenType::Byte } else { TokenType::Normal };
+            self.set_token(id as i32, piece, score, kind);
+        }
+        if let Some(unk) = model["unk_id"].as_i64() {
+            let unk = checked_id(unk, self.tokens.len())?;
+            self.types[unk as usize] = TokenType::Unknown;
+            self.special_ids.insert("unknown", unk);
+        }
+        Ok(())
     }
 
-    pub fn to_gguf(&self) -> Vec<(String, i32)> {
-        let mut tokens: Vec<_> = self.vocab.iter()
-            .map(|(k, &v)| (k.clone(), v))
-            .collect();
-        tokens.sort_by_key(|(_, id)| *id);
-        tokens
+    // Reads special tokens, BOS/EOS flags and the chat template from
+    // tokenizer_config.json (falling back to special_tokens_map.json).
+    fn apply_config(&mut self, dir: &Path) -> Result<()> {
+        let read = |name: &str| -> Result<Option<Value>> {
+            let path = dir.join(name);
+            if !path.exists() {
+                return Ok(None);
+            }
+            Ok(Some(serde_json::from_slice(&std::fs::read(path)?)?))
+        };
+        let config = read("tokenizer_config.json")?.unwrap_or(Value::Null);
+        let special_map = read("special_tokens_map.json")?.unwrap_or(Value::Null);
+
+        for (field, key) in [
+            ("bos_token", "bos"),
+            ("eos_token", "eos"),
+            ("unk_token", "unknown"),
+            ("pad_token", "padding"),
+            ("sep_token", "seperator"),
+            ("cls_token", "cls"),
+            ("mask_token", "mask"),
+        ] {
+            let content = token_content(&config[field]).or_else(|| token_content(&special_map[field]));
+            if let Some(id) = content.and_then(|c| self.token_to_id(&c).or_else(|| self.special_tokens.get(&c).copied())) {
+                self.special_ids.insert(key, id);
+            }
+        }
+
+        if let Some(b) = config["add_bos_token"].as_bool() {
+            self.add_bos = Some(b);
+        }
+        if let Some(b) = config["add_eos_token"].as_bool() {
+            self.add_eos = Some(b);
+        }
+        self.chat_template = match &config["chat_template"] {
+            Value::String(s) => Some(s.clone()),
+            Value::Array(templates) => templates
+                .iter()
+                .find(|t| t["name"] == "default")
+                .or_else(|| templates.first())
+                .and_then(|t| t["template"].as_str())
+                .map(str::to_string),
+            _ => self.chat_template.take(),
+        };
+        Ok(())
+    }
+
+    fn set_token(&mut self, id: i32, text: &str, score: f32, kind: TokenType) {
+        let index = id as usize;
+        if index >= self.tokens.len() {
+            self.tokens.resize(index + 1, String::new());
+            self.scores.resize(index + 1, 0.0);
+            self.types.resize(index + 1, TokenType::Unused);
+        }
+        let old = std::mem::take(&mut self.tokens[index]);
+        if !old.is_empty() {
+            self.vocab.remove(&old);
+        }
+        self.tokens[index] = text.to_string();
+        self.scores[index] = score;
+        self.types[index] = kind;
+        self.vocab.insert(text.to_string(), id);
+    }
+
+    fn push_token(&mut self, text: String, score: f32, kind: TokenType) {
+        let id = self.tokens.len() as i32;
+        self.set_token(id, &text, score, kind);
+    }
+
+    // Ids missing from the source become unused placeholder tokens.
+    fn fill_gaps(&mut self) {
+        for id in 0..self.tokens.len() {
+            if self.tokens[id].is_empty() {
+                self.set_token(id as i32, &format!("[PAD{}]", id), 0.0, TokenType::Unused);
+            }
+        }
+    }
+
+    // Pads the vocabulary to the model's embedding size.
+    pub fn pad_to(&mut self, size: usize) {
+        for id in self.tokens.len()..size {
+            self.set_token(id as i32, &format!("[PAD{}]", id), 0.0, TokenType::Unused);
+        }
+    }
+
+    pub fn to_metadata(&self) -> GgufMetadata {
+        let mut metadata = GgufMetadata::new();
+        let string = |s: &str| MetadataValue::String(s.to_string());
+        let array = |t: ValueType| ValueType::Array(Box::new(t));
+
+        metadata.set("tokenizer.ggml.model", string(&self.model), ValueType::String);
+        if let Some(pre) = &self.pre {
+            metadata.set("tokenizer.ggml.pre", string(pre), ValueType::String);
+        }
+        metadata.set(
+            "tokenizer.ggml.tokens",
+            MetadataValue::Array(self.tokens.iter().map(|t| string(t)).collect()),
+            array(ValueType::String),
+        );
+        if self.scores.iter().any(|s| *s != 0.0) {
+            metadata.set(
+                "tokenizer.ggml.scores",
+                MetadataValue::Array(self.scores.iter().map(|s| MetadataValue::Float(*s as f64)).collect()),
+                array(ValueType::F32),
+            );
+        }
+        metadata.set(
+            "tokenizer.ggml.token_type",
+            MetadataValue::Array(self.types.iter().map(|t| MetadataValue::Int(*t as i64)).collect()),
+            array(ValueType::I32),
+        );
+        if !self.merges.is_empty() {
+            metadata.set(
+                "tokenizer.ggml.merges",
+                MetadataValue::Array(self.merges_to_gguf().into_iter().map(MetadataValue::String).collect()),
+                array(ValueType::String),
+            );
+        }
+
+        let mut special: Vec<_> = self.special_ids.iter().collect();
+        special.sort();
+        for (key, id) in special {
+            metadata.set(&format!("tokenizer.ggml.{}_token_id", key), MetadataValue::Uint(*id as u64), ValueType::U32);
+        }
+        if let Some(b) = self.add_bos {
+            metadata.set("tokenizer.ggml.add_bos_token", MetadataValue::Bool(b), ValueType::Bool);
+        }
+        if let Some(b) = self.add_eos {
+            metadata.set("tokenizer.ggml.add_eos_token", MetadataValue::Bool(b), ValueType::Bool);
+        }
+        if let Some(b) = self.add_space_prefix {
+            metadata.set("tokenizer.ggml.add_space_prefix", MetadataValue::Bool(b), ValueType::Bool);
+        }
+        if let Some(template) = &self.chat_template {
+            metadata.set("tokenizer.chat_template", string(template), ValueType::String);
+        }
+        metadata
     }
 
     pub fn merges_to_gguf(&self) -> Vec<String> {
@@ -90,10 +419,9 @@ impl TokenizerConverter {
         self.vocab.get(token).copied()
     }
 
+    #[cfg(test)]
     pub fn id_to_token(&self, id: i32) -> Option<&str> {
-        self.vocab.iter()
-            .find(|(_, &v)| v == id)
-            .map(|(k, _)| k.as_str())
+        self.tokens.get(id as usize).map(String::as_str)
     }
 }
 
@@ -102,3 +430,415 @@ impl Default for TokenizerConverter {
         Self::new()
     }
 }
+
+// Ids come from untrusted files; anything past the vocabulary would make
+// set_token grow the tables to that size.
+fn checked_id(id: i64, limit: usize) -> Result<i32> {
+    if id < 0 || id as u64 >= limit as u64 {
+        bail!("token id {} out of range for a vocabulary of {}", id, limit);
+    }
+    Ok(id as i32)
+}
+
+fn token_content(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Object(o) => o.get("content").and_then(Value::as_str).map(str::to_string),
+        _ => None,
+    }
+}
+
+fn is_byte_token(token: &str) -> bool {
+    token.len() == 6 && token.starts_with("<0x") && token.ends_with('>')
+}
+
+fn is_tiktoken(data: &[u8]) -> bool {
+    let Some(line) = data.split(|b| *b == b'\n').next() else {
+        return false;
+    };
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|l| l.split_once(' '))
+        .is_some_and(|(t, r)| r.trim().parse::<u32>().is_ok() && general_purpose::STANDARD.decode(t).is_ok())
+}
+
+// Names the pre-tokenizer the way llama.cpp's `tokenizer.ggml.pre` does, by its split regex.
+fn pre_tokenizer_name(pre: &Value) -> String {
+    let mut patterns = Vec::new();
+    collect_patterns(pre, &mut patterns);
+    if patterns.iter().any(|p| p == LLAMA3_PATTERN) {
+        "llama-bpe".to_string()
+    } else if patterns.iter().any(|p| p == QWEN2_PATTERN) {
+        "qwen2".to_string()
+    } else if pre["type"] == "ByteLevel" && pre["use_regex"].as_bool().unwrap_or(true) {
+        "gpt-2".to_string()
+    } else {
+        "default".to_string()
+    }
+}
+
+fn collect_patterns(pre: &Value, out: &mut Vec<String>) {
+    if let Some(p) = pre["pattern"]["Regex"].as_str() {
+        out.push(p.to_string());
+    }
+    if let Some(list) = pre["pretokenizers"].as_array() {
+        for p in list {
+            collect_patterns(p, out);
+        }
+    }
+}
+
+// GPT-2's reversible byte to printable-character mapping.
+fn bytes_to_unicode() -> [char; 256] {
+    let mut map = ['\0'; 256];
+    let mut n = 0u32;
+    for b in 0..=255u8 {
+        let printable = matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        map[b as usize] = if printable {
+            b as char
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    map
+}
+
+fn bpe_split(ranks: &HashMap<&[u8], i32>, token: &[u8], max_rank: i32) -> Vec<Vec<u8>> {
+    let mut parts: Vec<Vec<u8>> = token.iter().map(|b| vec![*b]).collect();
+    loop {
+        let mut best: Option<(usize, i32)> = None;
+        for i in 0..parts.len().saturating_sub(1) {
+            let joined = [parts[i].as_slice(), parts[i + 1].as_slice()].concat();
+            if let Some(&rank) = ranks.get(joined.as_slice()) {
+                if rank < max_rank && best.is_none_or(|(_, r)| rank < r) {
+                    best = Some((i, rank));
+                }
+            }
+        }
+        let Some((i, _)) = best else {
+            break;
+        };
+        let right = parts.remove(i + 1);
+        parts[i].extend(right);
+    }
+    parts
+}
+
+// Minimal reader for the SentencePiece ModelProto fields the converter needs.
+mod sentencepiece {
+    use anyhow::{bail, Result};
+
+    pub struct Piece {
+        pub piece: String,
+        pub score: f32,
+        pub kind: u64,
+    }
+
+    pub struct ModelProto {
+        pub pieces: Vec<Piece>,
+        pub unk_id: i32,
+        pub bos_id: i32,
+        pub eos_id: i32,
+        pub pad_id: i32,
+        pub add_dummy_prefix: bool,
+    }
+
+    struct Reader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    enum Field<'a> {
+        Varint(u64),
+        Fixed32(u32),
+        Bytes(&'a [u8]),
+    }
+
+    impl<'a> Reader<'a> {
+        fn varint(&mut self) -> Result<u64> {
+            let mut value = 0u64;
+            for shift in (0..64).step_by(7) {
+                let Some(&b) = self.data.get(self.pos) else {
+                    bail!("truncated varint");
+                };
+                self.pos += 1;
+                value |= ((b & 0x7f) as u64) << shift;
+                if b & 0x80 == 0 {
+                    return Ok(value);
+                }
+            }
+            bail!("varint too long")
+        }
+
+        fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+            let end = self.pos.checked_add(n).filter(|e| *e <= self.data.len());
+            let Some(end) = end else {
+                bail!("truncated field");
+            };
+            let bytes = &self.data[self.pos..end];
+            self.pos = end;
+            Ok(bytes)
+        }
+
+        fn next(&mut self) -> Result<Option<(u64, Field<'a>)>> {
+            if self.pos >= self.data.len() {
+                return Ok(None);
+            }
+            let key = self.varint()?;
+            let field = match key & 7 {
+                0 => Field::Varint(self.varint()?),
+                1 => {
+                    self.take(8)?;
+                    return self.next();
+                }
+                2 => {
+                    let len = self.varint()? as usize;
+                    Field::Bytes(self.take(len)?)
+                }
+                5 => Field::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+                t => bail!("unsupported protobuf wire type {}", t),
+            };
+            Ok(Some((key >> 3, field)))
+        }
+    }
+
+    impl ModelProto {
+        pub fn decode(data: &[u8]) -> Result<Self> {
+            let mut proto = ModelProto {
+                pieces: Vec::new(),
+                unk_id: 0,
+                bos_id: 1,
+                eos_id: 2,
+                pad_id: -1,
+                add_dummy_prefix: true,
+            };
+            let mut reader = Reader { data, pos: 0 };
+            while let Some((number, field)) = reader.next()? {
+                match (number, field) {
+                    (1, Field::Bytes(b)) => proto.pieces.push(decode_piece(b)?),
+                    (2, Field::Bytes(b)) => proto.decode_trainer_spec(b)?,
+                    (3, Field::Bytes(b)) => proto.decode_normalizer_spec(b)?,
+                    _ => {}
+                }
+            }
+            if proto.pieces.is_empty() {
+                bail!("sentencepiece model has no pieces");
+            }
+            Ok(proto)
+        }
+
+        fn decode_trainer_spec(&mut self, data: &[u8]) -> Result<()> {
+            let mut reader = Reader { data, pos: 0 };
+            while let Some((number, field)) = reader.next()? {
+                if let Field::Varint(v) = field {
+                    let v = v as i64 as i32;
+                    match number {
+                        40 => self.unk_id = v,
+                        41 => self.bos_id = v,
+                        42 => self.eos_id = v,
+                        43 => self.pad_id = v,
+                        _ => {}
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn decode_normalizer_spec(&mut self, data: &[u8]) -> Result<()> {
+            let mut reader = Reader { data, pos: 0 };
+            while let Some((number, field)) = reader.next()? {
+                if let (3, Field::Varint(v)) = (number, field) {
+                    self.add_dummy_prefix = v != 0;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn decode_piece(data: &[u8]) -> Result<Piece> {
+        let mut piece = Piece {
+            piece: String::new(),
+            score: 0.0,
+            kind: 1,
+        };
+        let mut reader = Reader { data, pos: 0 };
+        while let Some((number, field)) = reader.next()? {
+            match (number, field) {
+                (1, Field::Bytes(b)) => piece.piece = String::from_utf8_lossy(b).into_owned(),
+                (2, Field::Fixed32(bits)) => piece.score = f32::from_bits(bits),
+                (3, Field::Varint(v)) => piece.kind = v,
+                _ => {}
+            }
+        }
+        Ok(piece)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ollama-tok-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn tokens(m: &GgufMetadata) -> Vec<String> {
+        match m.get("tokenizer.ggml.tokens") {
+            Some(MetadataValue::Array(a)) => a
+                .iter()
+                .map(|v| match v {
+                    MetadataValue::String(s) => s.clone(),
+                    _ => panic!("non-string token"),
+                })
+                .collect(),
+            _ => panic!("missing tokens"),
+        }
+    }
+
+    #[test]
+    fn test_huggingface_bpe() {
+        let dir = temp_dir("bpe");
+        std::fs::write(
+            dir.join("tokenizer.json"),
+            serde_json::json!({
+                "added_tokens": [{"id": 4, "content": "<|end|>", "special": true}],
+                "pre_tokenizer": {"type": "Sequence", "pretokenizers": [
+                    {"type": "Split", "pattern": {"Regex": LLAMA3_PATTERN}},
+                    {"type": "ByteLevel", "use_regex": false}
+                ]},
+                "model": {"type": "BPE", "vocab": {"a": 0, "b": 1, "ab": 2, "Ġ": 3},
+                          "merges": [["a", "b"]]}
+            })
+            .to_string(),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("tokenizer_config.json"),
+            r#"{"eos_token": "<|end|>", "add_bos_token": false, "chat_template": "{{ messages }}"}"#,
+        )
+        .unwrap();
+
+        let m = TokenizerConverter::from_dir(&dir).unwrap().to_metadata();
+        assert_eq!(m.string("tokenizer.ggml.model"), "gpt2");
+        assert_eq!(m.string("tokenizer.ggml.pre"), "llama-bpe");
+        assert_eq!(tokens(&m), ["a", "b", "ab", "Ġ", "<|end|>"]);
+        assert_eq!(m.uint("tokenizer.ggml.eos_token_id"), 4);
+        assert_eq!(m.string("tokenizer.chat_template"), "{{ messages }}");
+        assert!(matches!(m.get("tokenizer.ggml.add_bos_token"), Some(MetadataValue::Bool(false))));
+        match m.get("tokenizer.ggml.token_type") {
+            Some(MetadataValue::Array(t)) => assert!(matches!(t[4], MetadataValue::Int(3))),
+            _ => panic!("missing token types"),
+        }
+        match m.get("tokenizer.ggml.merges") {
+            Some(MetadataValue::Array(merges)) => assert!(matches!(&merges[0], MetadataValue::String(s) if s == "a b")),
+            _ => panic!("missing merges"),
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_huggingface_wordpiece_and_unigram() {
+        let dir = temp_dir("wp");
+        let path = dir.join("tokenizer.json");
+        std::fs::write(
+            &path,
+            r###"{"model": {"type": "WordPiece", "unk_token": "[UNK]", "continuing_subword_prefix": "##",
+                "vocab": {"[UNK]": 0, "[CLS]": 1, "play": 2, "##ing": 3}}}"###,
+        )
+        .unwrap();
+        let wp = TokenizerConverter::from_huggingface(path.to_str().unwrap()).unwrap();
+        assert_eq!(wp.id_to_token(2), Some("\u{2581}play"));
+        assert_eq!(wp.id_to_token(3), Some("ing"));
+        assert_eq!(wp.to_metadata().string("tokenizer.ggml.model"), "bert");
+
+        std::fs::write(
+            &path,
+            r#"{"model": {"type": "Unigram", "unk_id": 0, "vocab": [["<unk>", 0.0], ["▁a", -1.5], ["<0x41>", -3.0]]}}"#,
+        )
+        .unwrap();
+        let ug = TokenizerConverter::from_huggingface(path.to_str().unwrap()).unwrap();
+        let m = ug.to_metadata();
+        assert_eq!(m.string("tokenizer.ggml.model"), "t5");
+        match m.get("tokenizer.ggml.scores") {
+            Some(MetadataValue::Array(s)) => assert!(matches!(s[1], MetadataValue::Float(f) if f == -1.5)),
+            _ => panic!("missing scores"),
+        }
+        assert_eq!(ug.types[2], TokenType::Byte);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_huggingface_rejects_out_of_range_ids() {
+        let dir = temp_dir("ids");
+        let path = dir.join("tokenizer.json");
+        for json in [
+            r#"{"model": {"type": "Unigram", "unk_id": 3, "vocab": [["<unk>", 0.0]]}}"#,
+            r#"{"model": {"type": "Unigram", "unk_id": -1, "vocab": [["<unk>", 0.0]]}}"#,
+            r#"{"model": {"type": "BPE", "vocab": {"a": 0, "b": 4000000000}}}"#,
+            r#"{"model": {"type": "WordPiece", "vocab": {"a": -2}}}"#,
+            r#"{"model": {"type": "BPE", "vocab": {"a": 0}}, "added_tokens": [{"id": 2000000000, "content": "<x>"}]}"#,
+        ] {
+            std::fs::write(&path, json).unwrap();
+            let err = TokenizerConverter::from_huggingface(path.to_str().unwrap()).err().unwrap();
+            assert!(err.to_string().contains("out of range"), "{}: {}", json, err);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    fn proto_bytes(field: u64, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![(field << 3 | 2) as u8, data.len() as u8];
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn proto_piece(piece: &str, score: f32, kind: u8) -> Vec<u8> {
+        let mut body = proto_bytes(1, piece.as_bytes());
+        body.push(2 << 3 | 5);
+        body.extend_from_slice(&score.to_le_bytes());
+        body.extend_from_slice(&[3 << 3, kind]);
+        proto_bytes(1, &body)
+    }
+
+    #[test]
+    fn test_sentencepiece_model() {
+        let mut data = Vec::new();
+        data.extend(proto_piece("<unk>", 0.0, 2));
+        data.extend(proto_piece("<s>", 0.0, 3));
+        data.extend(proto_piece("</s>", 0.0, 3));
+        data.extend(proto_piece("▁hello", -2.5, 1));
+        // trainer_spec: pad_id (43) = 3, encoded as a two-byte key.
+        data.extend(proto_bytes(2, &[0xd8, 0x02, 3]));
+        // normalizer_spec: add_dummy_prefix = false
+        data.extend(proto_bytes(3, &[3 << 3, 0]));
+
+        let dir = temp_dir("spm");
+        std::fs::write(dir.join("tokenizer.model"), &data).unwrap();
+        let m = TokenizerConverter::from_dir(&dir).unwrap().to_metadata();
+        assert_eq!(m.string("tokenizer.ggml.model"), "llama");
+        assert_eq!(tokens(&m), ["<unk>", "<s>", "</s>", "▁hello"]);
+        assert_eq!(m.uint("tokenizer.ggml.bos_token_id"), 1);
+        assert_eq!(m.uint("tokenizer.ggml.padding_token_id"), 3);
+        assert!(matches!(m.get("tokenizer.ggml.add_space_prefix"), Some(MetadataValue::Bool(false))));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_tiktoken_recovers_merges() {
+        let dir = temp_dir("tik");
+        let lines: Vec<String> = [b"a".as_slice(), b"b", b" ", b"ab", b" ab"]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| format!("{} {}", general_purpose::STANDARD.encode(t), i))
+            .collect();
+        std::fs::write(dir.join("tokenizer.model"), lines.join("\n")).unwrap();
+
+        let tok = TokenizerConverter::from_dir(&dir).unwrap();
+        assert_eq!(tok.id_to_token(2), Some("Ġ"));
+        assert_eq!(tok.id_to_token(4), Some("Ġab"));
+        assert_eq!(tok.merges_to_gguf(), ["a b", "Ġ ab"]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,9 +1,13 @@
+pub mod paged;
 pub mod traits;
 
+pub use paged::{BlockPool, KvCacheType, PagedCacheConfig, PagedKVCache, PoolStats};
 pub use traits::{KVCache, CacheEntry, CacheKey};
 
 use crate::core::{Result, Tensor};
+use std::any::Any;
 
+#[derive(Clone)]
 pub struct CausalKVCache {
     #[allow(dead_code)]
     layer_count: usize,
@@ -46,18 +50,32 @@ impl CausalKVCache {
 }
 
 impl KVCache for CausalKVCache {
+    // Appends this step's keys and values, [seq, kv_heads, head_dim], to the
+    // layer and returns everything cached for it.
     fn update(&mut self, layer: usize, key: &Tensor, value: &Tensor) -> Result<(Tensor, Tensor)> {
-        if layer >= self.keys.len() {
-            self.keys.push(key.clone());
-            self.values.push(value.clone());
-        } else {
-            self.keys[layer] = key.clone();
-            self.values[layer] = value.clone();
+        let cached = self.keys.get(layer).map_or(0, |k| k.shape().dim(0).unwrap_or(0));
+        let len = cached + key.shape().dim(0).unwrap_or(0);
+        if len > self.max_seq_len {
+            anyhow::bail!("KV cache full: {} positions, capacity {}", len, self.max_seq_len);
+        }
+
+        match layer.cmp(&self.keys.len()) {
+            std::cmp::Ordering::Less => {
+                self.keys[layer].append(key)?;
+                self.values[layer].append(value)?;
+            }
+            std::cmp::Ordering::Equal => {
+                self.keys.push(key.clone());
+                self.values.push(value.clone());
+            }
+            std::cmp::Ordering::Greater => {
+                anyhow::bail!("KV cache: layer {} updated before layer {}", layer, self.keys.len())
+            }
         }
         
-        self.seq_len = self.seq_len.max(key.shape().last().copied().unwrap_or(0));
+        self.seq_len = self.seq_len.max(len);
         
-        Ok((key.clone(), value.clone()))
+        Ok((self.keys[layer].clone(), self.values[layer].clone()))
     }
     
     fn get(&self, layer: usize) -> Option<CacheEntry> {
@@ -78,8 +96,32 @@ impl KVCache for CausalKVCache {
     fn capacity(&self) -> usize {
         self.max_seq_len
     }
+
+    fn reset(&mut self) {
+        self.clear();
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        if len > self.seq_len {
+            anyhow::bail!("Cannot truncate a cache of {} positions to {}", self.seq_len, len);
+        }
+        for t in self.keys.iter_mut().chain(self.values.iter_mut()) {
+            *t = t.rows(0..len.min(t.shape().dim(0).unwrap_or(0)))?;
+        }
+        self.seq_len = len;
+        Ok(())
+    }
+
+    fn clone_cache(&self) -> Box<dyn KVCache> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
 }
 
+#[derive(Clone)]
 pub struct SlidingWindowCache {
     inner: CausalKVCache,
     window_size: usize,
@@ -101,15 +143,16 @@ impl SlidingWindowCache {
 }
 
 impl KVCache for SlidingWindowCache {
+    // Keeps only the newest `window_size` positions of each layer.
     fn update(&mut self, layer: usize, key: &Tensor, value: &Tensor) -> Result<(Tensor, Tensor)> {
-        let start = self.inner.seq_len.saturating_sub(self.window_size);
-        if start > 0 {
-            let key_sliced = key.slice(start, None)?;
-            let value_sliced = value.slice(start, None)?;
-            self.inner.update(layer, &key_sliced, &value_sliced)
-        } else {
-            self.inner.update(layer, key, value)
+        self.inner.update(layer, key, value)?;
+        let len = self.inner.keys[layer].shape().dim(0).unwrap_or(0);
+        if len > self.window_size {
+            let start = len - self.window_size;
+            self.inner.keys[layer] = self.inner.keys[layer].rows(start..len)?;
+            self.inner.values[layer] = self.inner.values[layer].rows(start..len)?;
         }
+        Ok((self.inner.keys[layer].clone(), self.inner.values[layer].clone()))
     }
     
     fn get(&self, layer: usize) -> Option<CacheEntry> {
@@ -123,8 +166,31 @@ impl KVCache for SlidingWindowCache {
     fn capacity(&self) -> usize {
         self.inner.capacity().min(self.window_size)
     }
+
+    fn reset(&mut self) {
+        self.inner.clear();
+    }
+
+    // Positions that slid out of the window are gone, so only a cache that
+    // still holds all of 0..len can be truncated to it.
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        let held = self.inner.keys.first().map_or(0, |k| k.shape().dim(0).unwrap_or(0));
+        if held < self.inner.seq_len && len < self.inner.seq_len {
+            anyhow::bail!("Positions before {} have left the sliding window", self.inner.seq_len - held);
+        }
+        self.inner.truncate(len)
+    }
+
+    fn clone_cache(&self) -> Box<dyn KVCache> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
 }
 
+#[derive(Clone)]
 pub struct ChunkedCache {
     chunks: Vec<CausalKVCache>,
     chunk_size: usize,
@@ -177,4 +243,25 @@ impl KVCache for ChunkedCache {
     fn capacity(&self) -> usize {
         self.chunks.len() * self.chunk_size
     }
+
+    fn reset(&mut self) {
+        self.chunks.iter_mut().for_each(CausalKVCache::clear);
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        let mut start = 0;
+        for chunk in &mut self.chunks {
+            chunk.truncate(len.saturating_sub(start).min(chunk.len()))?;
+            start += self.chunk_size;
+        }
+        Ok(())
+    }
+
+    fn clone_cache(&self) -> Box<dyn KVCache> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
 }
@@ -0,0 +1,667 @@
+// Paged K/V storage. Positions live in fixed-size blocks drawn from a pool
+// shared by every sequence of a model, so a sequence holds memory only for
+// the positions it has written rather than its whole context. A fork made by
+// clone_cache shares its parent's blocks until one side writes into a shared
+// block, which is then copied. Rows are stored as f32, f16 or ggml q8_0/q4_0
+// blocks and dequantized when read; attend reads them a block at a time, so
+// the blocks are the only copy of a sequence's K/V.
+
+use super::{CacheEntry, KVCache};
+use crate::core::tensor::kernels;
+use crate::core::tensor::Shape;
+use crate::core::{Result, Tensor};
+use crate::infra::gguf::{dequantize, dequantize_into, GgmlType};
+use half::f16;
+use rayon::prelude::*;
+use std::any::Any;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum KvCacheType {
+    F32,
+    #[default]
+    F16,
+    Q8_0,
+    Q4_0,
+}
+
+impl KvCacheType {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "f32" => Some(Self::F32),
+            "f16" => Some(Self::F16),
+            "q8_0" => Some(Self::Q8_0),
+            "q4_0" => Some(Self::Q4_0),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.dtype().name()
+    }
+
+    fn dtype(&self) -> GgmlType {
+        match self {
+            Self::F32 => GgmlType::F32,
+            Self::F16 => GgmlType::F16,
+            Self::Q8_0 => GgmlType::Q8_0,
+            Self::Q4_0 => GgmlType::Q4_0,
+        }
+    }
+
+    // Values stored per row of `n`: quantized rows are padded with zeros to
+    // a whole number of 32-value blocks.
+    fn padded(&self, n: usize) -> usize {
+        n.next_multiple_of(self.dtype().block_size())
+    }
+
+    // Bytes one row of `n` values takes.
+    pub fn row_bytes(&self, n: usize) -> usize {
+        self.padded(n) / self.dtype().block_size() * self.dtype().type_size()
+    }
+
+    // Encodes a padded row; the quantizers follow ggml's reference ones.
+    fn encode(&self, x: &[f32], y: &mut [u8]) {
+        match self {
+            Self::F32 => {
+                for (y, v) in y.chunks_exact_mut(4).zip(x) {
+                    y.copy_from_slice(&v.to_le_bytes());
+                }
+            }
+            Self::F16 => {
+                for (y, &v) in y.chunks_exact_mut(2).zip(x) {
+                    y.copy_from_slice(&f16::from_f32(v).to_le_bytes());
+                }
+            }
+            Self::Q8_0 => {
+                for (x, y) in x.chunks_exact(32).zip(y.chunks_exact_mut(34)) {
+                    let amax = x.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+                    let d = amax / 127.0;
+                    let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+                    y[..2].copy_from_slice(&f16::from_f32(d).to_le_bytes());
+                    for (q, v) in y[2..].iter_mut().zip(x) {
+                        *q = (v * id).round() as i8 as u8;
+                    }
+                }
+            }
+            Self::Q4_0 => {
+                for (x, y) in x.chunks_exact(32).zip(y.chunks_exact_mut(18)) {
+                    let max = x.iter().fold(0.0f32, |m, &v| if v.abs() > m.abs() { v } else { m });
+                    let d = max / -8.0;
+                    let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+                    y[..2].copy_from_slice(&f16::from_f32(d).to_le_bytes());
+                    for j in 0..16 {
+                        let x0 = ((x[j] * id + 8.5) as i8).min(15) as u8;
+                        let x1 = ((x[j + 16] * id + 8.5) as i8).min(15) as u8;
+                        y[2 + j] = x0 | (x1 << 4);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PagedCacheConfig {
+    // Positions per block.
+    pub block_size: usize,
+    // Upper bound on the pool's K/V storage, in bytes.
+    pub memory_budget: usize,
+    pub cache_type: KvCacheType,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub cache_type: KvCacheType,
+    pub block_size: usize,
+    pub block_bytes: usize,
+    pub total_blocks: usize,
+    // Blocks held by at least one sequence.
+    pub used_blocks: usize,
+    // Blocks whose storage exists, used or on the free list. Storage is only
+    // allocated when a block is first handed out.
+    pub allocated_blocks: usize,
+}
+
+impl PoolStats {
+    pub fn utilization(&self) -> f64 {
+        if self.total_blocks == 0 {
+            0.0
+        } else {
+            self.used_blocks as f64 / self.total_blocks as f64
+        }
+    }
+}
+
+pub struct BlockPool {
+    layers: usize,
+    kv_heads: usize,
+    head_dim: usize,
+    block_size: usize,
+    cache_type: KvCacheType,
+    total_blocks: usize,
+    state: Mutex<PoolState>,
+}
+
+struct PoolState {
+    // Each block holds K then V of every layer: [layers, 2, block_size, row].
+    blocks: Vec<Vec<u8>>,
+    refs: Vec<usize>,
+    free: Vec<usize>,
+}
+
+impl BlockPool {
+    pub fn new(layers: usize, kv_heads: usize, head_dim: usize, config: PagedCacheConfig) -> Result<Arc<Self>> {
+        if config.block_size == 0 {
+            anyhow::bail!("KV cache block size must be at least 1");
+        }
+        let block_bytes = layers * 2 * config.block_size * config.cache_type.row_bytes(kv_heads * head_dim);
+        let total_blocks = config.memory_budget / block_bytes.max(1);
+        if total_blocks == 0 {
+            anyhow::bail!(
+                "KV cache budget of {} bytes is less than one {}-byte block",
+                config.memory_budget,
+                block_bytes
+            );
+        }
+        Ok(Arc::new(Self {
+            layers,
+            kv_heads,
+            head_dim,
+            block_size: config.block_size,
+            cache_type: config.cache_type,
+            total_blocks,
+            state: Mutex::new(PoolState { blocks: Vec::new(), refs: Vec::new(), free: Vec::new() }),
+        }))
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let state = self.state.lock().unwrap();
+        PoolStats {
+            cache_type: self.cache_type,
+            block_size: self.block_size,
+            block_bytes: self.block_len(),
+            total_blocks: self.total_blocks,
+            used_blocks: state.refs.iter().filter(|&&r| r > 0).count(),
+            allocated_blocks: state.blocks.len(),
+        }
+    }
+
+    fn row(&self) -> usize {
+        self.kv_heads * self.head_dim
+    }
+
+    fn row_bytes(&self) -> usize {
+        self.cache_type.row_bytes(self.row())
+    }
+
+    fn block_len(&self) -> usize {
+        self.layers * 2 * self.block_size * self.row_bytes()
+    }
+
+    // The byte where position `slot` of a block starts, for the keys (kv = 0)
+    // or values (kv = 1) of `layer`.
+    fn offset(&self, layer: usize, kv: usize, slot: usize) -> usize {
+        ((layer * 2 + kv) * self.block_size + slot) * self.row_bytes()
+    }
+
+    fn allocate(&self, state: &mut PoolState) -> Result<usize> {
+        let block = match state.free.pop() {
+            Some(block) => block,
+            None if state.blocks.len() < self.total_blocks => {
+                state.blocks.push(vec![0; self.block_len()]);
+                state.refs.push(0);
+                state.blocks.len() - 1
+            }
+            None => anyhow::bail!("KV cache memory budget exhausted: all {} blocks in use", self.total_blocks),
+        };
+        state.refs[block] = 1;
+        Ok(block)
+    }
+
+    fn release(state: &mut PoolState, block: usize) {
+        state.refs[block] -= 1;
+        if state.refs[block] == 0 {
+            state.free.push(block);
+        }
+    }
+}
+
+// One sequence's view of a pool: the blocks holding its positions, in order.
+pub struct PagedKVCache {
+    pool: Arc<BlockPool>,
+    table: Vec<usize>,
+    // Positions written per layer; they differ only partway through a step.
+    lens: Vec<usize>,
+    max_seq_len: usize,
+}
+
+impl PagedKVCache {
+    pub fn new(pool: Arc<BlockPool>, max_seq_len: usize) -> Self {
+        let layers = pool.layers;
+        Self { pool, table: Vec::new(), lens: vec![0; layers], max_seq_len }
+    }
+
+    pub fn blocks(&self) -> &[usize] {
+        &self.table
+    }
+
+    // Writes this step's keys and values, [seq, kv_heads, head_dim], after the
+    // layer's cached positions, taking new blocks as needed and copying any
+    // block still shared with another sequence before writing to it.
+    pub fn append(&mut self, layer: usize, key: &Tensor, value: &Tensor) -> Result<()> {
+        let pool = self.pool.clone();
+        let dims = key.shape().dims();
+        if layer >= pool.layers || dims != [dims[0], pool.kv_heads, pool.head_dim] || value.shape().dims() != dims {
+            anyhow::bail!(
+                "Paged KV cache holds {} layers of [{}, {}]; got layer {} of {:?}",
+                pool.layers,
+                pool.kv_heads,
+                pool.head_dim,
+                layer,
+                dims
+            );
+        }
+        let (start, seq) = (self.lens[layer], dims[0]);
+        let end = start + seq;
+        if end > self.max_seq_len {
+            anyhow::bail!("KV cache full: {} positions, capacity {}", end, self.max_seq_len);
+        }
+
+        let mut state = pool.state.lock().unwrap();
+        self.claim(&mut state, start, end)?;
+
+        let (row, row_bytes) = (pool.row(), pool.row_bytes());
+        let mut padded = vec![0.0; pool.cache_type.padded(row)];
+        for (kv, t) in [key, value].into_iter().enumerate() {
+            for (pos, src) in (start..end).zip(t.data().chunks_exact(row)) {
+                padded[..row].copy_from_slice(src);
+                let block = self.table[pos / pool.block_size];
+                let offset = pool.offset(layer, kv, pos % pool.block_size);
+                pool.cache_type.encode(&padded, &mut state.blocks[block][offset..offset + row_bytes]);
+            }
+        }
+        self.lens[layer] = end;
+        Ok(())
+    }
+
+    // Takes blocks for positions up to `end` and copies any block still shared
+    // with another sequence that positions from `start` would write into.
+    fn claim(&mut self, state: &mut PoolState, start: usize, end: usize) -> Result<()> {
+        let pool = &self.pool;
+        while self.table.len() * pool.block_size < end {
+            let block = pool.allocate(state)?;
+            self.table.push(block);
+        }
+        if start < end {
+            for i in start / pool.block_size..end.div_ceil(pool.block_size) {
+                let shared = self.table[i];
+                if state.refs[shared] > 1 {
+                    let copy = pool.allocate(state)?;
+                    let data = state.blocks[shared].clone();
+                    state.blocks[copy] = data;
+                    BlockPool::release(state, shared);
+                    self.table[i] = copy;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Keys and values of a layer's positions in `range`, dequantized.
+    pub fn rows(&self, layer: usize, range: Range<usize>) -> Result<(Tensor, Tensor)> {
+        if layer >= self.pool.layers || range.end > self.lens[layer] {
+            anyhow::bail!("Paged KV cache has no positions {:?} in layer {}", range, layer);
+        }
+        let state = self.pool.state.lock().unwrap();
+        Ok((self.gather(&state, layer, 0, range.clone())?, self.gather(&state, layer, 1, range)?))
+    }
+
+    // Dequantizes positions `range` of a layer as [len, kv_heads, head_dim].
+    fn gather(&self, state: &PoolState, layer: usize, kv: usize, range: Range<usize>) -> Result<Tensor> {
+        let pool = &self.pool;
+        let (row, padded) = (pool.row(), pool.cache_type.padded(pool.row()));
+        let mut data = Vec::with_capacity(range.len() * row);
+        let mut pos = range.start;
+        while pos < range.end {
+            let (block, slot) = (self.table[pos / pool.block_size], pos % pool.block_size);
+            let rows = (pool.block_size - slot).min(range.end - pos);
+            let start = pool.offset(layer, kv, slot);
+            let values = dequantize(pool.cache_type.dtype(), &state.blocks[block][start..start + rows * pool.row_bytes()])?;
+            if padded == row {
+                data.extend_from_slice(&values);
+            } else {
+                values.chunks_exact(padded).for_each(|v| data.extend_from_slice(&v[..row]));
+            }
+            pos += rows;
+        }
+        Ok(Tensor::new(data, Shape::new(vec![range.len(), pool.kv_heads, pool.head_dim])))
+    }
+
+    // Attention of queries [seq, heads, head_dim], the layer's last `seq`
+    // positions, over its cached positions up to each query's own. Blocks
+    // are dequantized one at a time into scratch and folded into a running
+    // softmax, so no dense copy of the prefix is made.
+    pub fn attend(&self, layer: usize, query: &Tensor, scale: f32) -> Result<Tensor> {
+        let pool = &self.pool;
+        let dims = query.shape().dims();
+        let len = self.lens.get(layer).copied().unwrap_or(0);
+        if dims.len() != 3 || dims[2] != pool.head_dim || !dims[1].is_multiple_of(pool.kv_heads) || dims[0] > len {
+            anyhow::bail!(
+                "Cannot attend {:?} over layer {} of {} positions of [{}, {}]",
+                dims,
+                layer,
+                len,
+                pool.kv_heads,
+                pool.head_dim
+            );
+        }
+        let (seq, heads, hd) = (dims[0], dims[1], pool.head_dim);
+        let (group, past) = (heads / pool.kv_heads, len - seq);
+        let padded = pool.cache_type.padded(pool.row());
+        let q = query.data();
+
+        let mut keys = vec![0.0; pool.block_size * padded];
+        let mut values = vec![0.0; pool.block_size * padded];
+        let mut out = vec![0.0; seq * heads * hd];
+        // Running maximum and sum of each query head's softmax.
+        let mut max = vec![f32::NEG_INFINITY; seq * heads];
+        let mut sum = vec![0.0; seq * heads];
+        for (b, &block) in self.table.iter().enumerate().take(len.div_ceil(pool.block_size)) {
+            let first = b * pool.block_size;
+            let rows = pool.block_size.min(len - first);
+            {
+                let state = pool.state.lock().unwrap();
+                for (kv, scratch) in [(0, &mut keys), (1, &mut values)] {
+                    let start = pool.offset(layer, kv, 0);
+                    let data = &state.blocks[block][start..start + rows * pool.row_bytes()];
+                    dequantize_into(pool.cache_type.dtype(), data, scratch)?;
+                }
+            }
+            let (keys, values) = (&keys, &values);
+            out.par_chunks_mut(heads * hd)
+                .zip(max.par_chunks_mut(heads))
+                .zip(sum.par_chunks_mut(heads))
+                .enumerate()
+                .for_each(|(i, ((out_row, max_row), sum_row))| {
+                    // Query i sits at position past + i and sees no later one.
+                    let visible = (past + i + 1).saturating_sub(first).min(rows);
+                    let mut scores = vec![0.0; visible];
+                    for h in 0..heads {
+                        if visible == 0 {
+                            break;
+                        }
+                        let kv = h / group;
+                        let q_h = &q[(i * heads + h) * hd..][..hd];
+                        for (t, s) in scores.iter_mut().enumerate() {
+                            *s = kernels::dot(q_h, &keys[t * padded + kv * hd..][..hd]) * scale;
+                        }
+                        let m = scores.iter().copied().fold(max_row[h], f32::max);
+                        let rescale = (max_row[h] - m).exp();
+                        let o = &mut out_row[h * hd..][..hd];
+                        o.iter_mut().for_each(|o| *o *= rescale);
+                        sum_row[h] *= rescale;
+                        for (t, &s) in scores.iter().enumerate() {
+                            let p = (s - m).exp();
+                            sum_row[h] += p;
+                            for (o, &v) in o.iter_mut().zip(&values[t * padded + kv * hd..][..hd]) {
+                                *o += p * v;
+                            }
+                        }
+                        max_row[h] = m;
+                    }
+                });
+        }
+        for (o, &s) in out.chunks_exact_mut(hd).zip(&sum) {
+            o.iter_mut().for_each(|o| *o /= s);
+        }
+        Ok(Tensor::new(out, Shape::new(vec![seq, heads, hd])))
+    }
+
+    fn release_from(&mut self, keep: usize) {
+        let mut state = self.pool.state.lock().unwrap();
+        for block in self.table.drain(keep..) {
+            BlockPool::release(&mut state, block);
+        }
+    }
+}
+
+impl KVCache for PagedKVCache {
+    fn update(&mut self, layer: usize, key: &Tensor, value: &Tensor) -> Result<(Tensor, Tensor)> {
+        self.append(layer, key, value)?;
+        self.rows(layer, 0..self.lens[layer])
+    }
+
+    fn get(&self, layer: usize) -> Option<CacheEntry> {
+        let len = *self.lens.get(layer)?;
+        if len == 0 {
+            return None;
+        }
+        let (key, value) = self.rows(layer, 0..len).ok()?;
+        Some(CacheEntry::new(key, value))
+    }
+
+    fn len(&self) -> usize {
+        self.lens.iter().copied().max().unwrap_or(0)
+    }
+
+    fn capacity(&self) -> usize {
+        self.max_seq_len
+    }
+
+    fn reset(&mut self) {
+        self.release_from(0);
+        self.lens.iter_mut().for_each(|l| *l = 0);
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        if len > self.len() {
+            anyhow::bail!("Cannot truncate a cache of {} positions to {}", self.len(), len);
+        }
+        self.release_from(len.div_ceil(self.pool.block_size));
+        self.lens.iter_mut().for_each(|l| *l = (*l).min(len));
+        Ok(())
+    }
+
+    // A reservation that fails gives back the blocks it took.
+    fn reserve(&mut self, len: usize) -> Result<()> {
+        if len > self.max_seq_len {
+            anyhow::bail!("KV cache full: {} positions, capacity {}", len, self.max_seq_len);
+        }
+        let (start, pool) = (self.len(), self.pool.clone());
+        let claimed = self.claim(&mut pool.state.lock().unwrap(), start, len);
+        if claimed.is_err() {
+            self.release_from(start.div_ceil(pool.block_size));
+        }
+        claimed
+    }
+
+    fn clone_cache(&self) -> Box<dyn KVCache> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Shares every block with the copy; whichever side writes to a shared block
+// first copies it.
+impl Clone for PagedKVCache {
+    fn clone(&self) -> Self {
+        let mut state = self.pool.state.lock().unwrap();
+        for &block in &self.table {
+            state.refs[block] += 1;
+        }
+        Self {
+            pool: self.pool.clone(),
+            table: self.table.clone(),
+            lens: self.lens.clone(),
+            max_seq_len: self.max_seq_len,
+        }
+    }
+}
+
+impl Drop for PagedKVCache {
+    fn drop(&mut self) {
+        self.release_from(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rows whose values encode their position, [n, 2, 3].
+    fn rows(start: usize, n: usize, scale: f32) -> Tensor {
+        let data = (start..start + n).flat_map(|p| (0..6).map(move |i| scale * (p * 10 + i) as f32)).collect();
+        Tensor::new(data, Shape::new(vec![n, 2, 3]))
+    }
+
+    fn pool(blocks: usize) -> Arc<BlockPool> {
+        let block_bytes = 2 * 2 * 4 * 6 * 4;
+        BlockPool::new(2, 2, 3, PagedCacheConfig { block_size: 4, memory_budget: blocks * block_bytes, cache_type: KvCacheType::F32 }).unwrap()
+    }
+
+    fn write(cache: &mut dyn KVCache, start: usize, n: usize) -> (Tensor, Tensor) {
+        cache.update(0, &rows(start, n, 1.0), &rows(start, n, -1.0)).unwrap();
+        cache.update(1, &rows(start, n, 2.0), &rows(start, n, -2.0)).unwrap()
+    }
+
+    #[test]
+    fn test_blocks_allocate_and_free() {
+        let pool = pool(4);
+        let mut cache = PagedKVCache::new(pool.clone(), 64);
+
+        let (k, v) = write(&mut cache, 0, 6);
+        assert_eq!(k.data(), rows(0, 6, 2.0).data());
+        assert_eq!(v.data(), rows(0, 6, -2.0).data());
+        assert_eq!(cache.blocks().len(), 2);
+        let (k, _) = write(&mut cache, 6, 3);
+        assert_eq!(k.data(), rows(0, 9, 2.0).data());
+        assert_eq!(cache.get(0).unwrap().key.data(), rows(0, 9, 1.0).data());
+        assert_eq!((cache.len(), pool.stats().used_blocks), (9, 3));
+
+        // A second sequence takes the last block, then the pool is exhausted.
+        let mut other = PagedKVCache::new(pool.clone(), 64);
+        write(&mut other, 0, 4);
+        assert!(other.update(0, &rows(4, 1, 1.0), &rows(4, 1, 1.0)).is_err());
+
+        cache.truncate(5).unwrap();
+        assert_eq!((cache.blocks().len(), pool.stats().used_blocks), (2, 3));
+        let (k, _) = write(&mut cache, 5, 1);
+        assert_eq!(k.data(), rows(0, 6, 2.0).data());
+
+        drop(cache);
+        other.reset();
+        let stats = pool.stats();
+        assert_eq!((stats.used_blocks, stats.allocated_blocks, stats.total_blocks), (0, 4, 4));
+    }
+
+    #[test]
+    fn test_forks_share_prefix_blocks_until_written() {
+        let pool = pool(8);
+        let mut parent = PagedKVCache::new(pool.clone(), 64);
+        write(&mut parent, 0, 6);
+
+        let mut fork = parent.clone_cache();
+        assert_eq!(pool.stats().used_blocks, 2);
+
+        // The fork diverges inside the second block, which it copies; the
+        // full first block stays shared.
+        fork.truncate(5).unwrap();
+        let (k, _) = fork.update(0, &rows(50, 1, 1.0), &rows(50, 1, -1.0)).unwrap();
+        fork.update(1, &rows(50, 1, 2.0), &rows(50, 1, -2.0)).unwrap();
+        assert_eq!(&k.data()[..30], rows(0, 5, 1.0).data());
+        assert_eq!(&k.data()[30..], rows(50, 1, 1.0).data());
+        assert_eq!(pool.stats().used_blocks, 3);
+        let fork_blocks = fork.as_any_mut().downcast_mut::<PagedKVCache>().unwrap().blocks().to_vec();
+        assert_eq!(fork_blocks[0], parent.blocks()[0]);
+        assert_ne!(fork_blocks[1], parent.blocks()[1]);
+
+        // The parent still sees its own rows.
+        assert_eq!(parent.get(0).unwrap().key.data(), rows(0, 6, 1.0).data());
+
+        drop(fork);
+        assert_eq!(pool.stats().used_blocks, 2);
+    }
+
+    #[test]
+    fn test_quantized_blocks_take_less_memory() {
+        // 40 positions of 8 heads of 128 take three 16-position blocks; the
+        // bytes are those of the blocks' storage.
+        let bytes = |cache_type| {
+            let config = PagedCacheConfig { block_size: 16, memory_budget: 1 << 24, cache_type };
+            let pool = BlockPool::new(1, 8, 128, config).unwrap();
+            let mut cache = PagedKVCache::new(pool.clone(), 64);
+            let t = Tensor::new(vec![0.5; 40 * 1024], Shape::new(vec![40, 8, 128]));
+            cache.append(0, &t, &t).unwrap();
+            let state = pool.state.lock().unwrap();
+            state.blocks.iter().map(Vec::len).sum::<usize>()
+        };
+        let row = |bytes: usize| 3 * 16 * 2 * bytes;
+        assert_eq!(bytes(KvCacheType::F32), row(4096));
+        assert_eq!(bytes(KvCacheType::F16), row(2048));
+        assert_eq!(bytes(KvCacheType::Q8_0), row(1088));
+        assert_eq!(bytes(KvCacheType::Q4_0), row(576));
+    }
+
+    #[test]
+    fn test_attend_matches_dense_attention() {
+        // Two query heads per K/V head; the queries are the last 3 of 9
+        // positions. The reference attends over the dequantized rows, which
+        // for q8_0 are padded in the blocks.
+        for cache_type in [KvCacheType::F32, KvCacheType::Q8_0] {
+            let config = PagedCacheConfig { block_size: 4, memory_budget: 1 << 16, cache_type };
+            let pool = BlockPool::new(2, 2, 3, config).unwrap();
+            let mut cache = PagedKVCache::new(pool.clone(), 64);
+            write(&mut cache, 0, 9);
+            let query = Tensor::new((0..36).map(|i| (i % 7) as f32 * 0.01 - 0.03).collect(), Shape::new(vec![3, 4, 3]));
+
+            // A fork attends through the shared blocks without taking any.
+            let fork = cache.clone();
+            let got = fork.attend(1, &query, 0.5).unwrap();
+            assert_eq!(pool.stats().used_blocks, 3);
+
+            let (k, v) = cache.rows(1, 0..9).unwrap();
+            let (q, k, v) = (query.data(), k.data(), v.data());
+            for i in 0..3 {
+                for h in 0..4 {
+                    let q_h = &q[(i * 4 + h) * 3..][..3];
+                    let mut p: Vec<f32> = (0..7 + i).map(|t| kernels::dot(q_h, &k[(t * 2 + h / 2) * 3..][..3]) * 0.5).collect();
+                    kernels::softmax(&mut p);
+                    for d in 0..3 {
+                        let want: f32 = p.iter().enumerate().map(|(t, p)| p * v[(t * 2 + h / 2) * 3 + d]).sum();
+                        let x = got.data()[(i * 4 + h) * 3 + d];
+                        assert!((x - want).abs() <= 1e-4 * want.abs().max(1.0), "{}: {} vs {}", cache_type.name(), x, want);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantized_blocks_round_trip() {
+        // Rows of 6 values are padded to one 32-value quantization block. The
+        // error allowed is relative to the largest value, 2 * 55.
+        for (cache_type, row_bytes, tolerance) in [
+            (KvCacheType::F16, 12, 1e-3),
+            (KvCacheType::Q8_0, 34, 0.5 / 127.0),
+            (KvCacheType::Q4_0, 18, 0.5 / 8.0),
+        ] {
+            let config = PagedCacheConfig { block_size: 4, memory_budget: 1 << 16, cache_type };
+            let pool = BlockPool::new(2, 2, 3, config).unwrap();
+            let mut cache = PagedKVCache::new(pool.clone(), 64);
+            let (k, v) = write(&mut cache, 0, 6);
+            for (got, want) in [(k, rows(0, 6, 2.0)), (v, rows(0, 6, -2.0))] {
+                assert_eq!(got.shape().dims(), &[6, 2, 3]);
+                for (x, y) in got.data().iter().zip(want.data()) {
+                    assert!((x - y).abs() <= tolerance * 110.0, "{}: {} vs {}", cache_type.name(), x, y);
+                }
+            }
+            assert_eq!(pool.stats().block_bytes, 2 * 2 * 4 * row_bytes);
+        }
+    }
+}
@@ -1,5 +1,8 @@
 use crate::core::{Result, Tensor};
+use std::any::Any;
 
+// The keys and values of one sequence. Each request owns its cache, so a model
+// keeps no state of its own between calls.
 pub trait KVCache: Send + Sync {
     fn update(&mut self, layer: usize, key: &Tensor, value: &Tensor) -> Result<(Tensor, Tensor)>;
     fn get(&self, layer: usize) -> Option<CacheEntry>;
@@ -9,6 +12,20 @@ pub trait KVCache: Send + Sync {
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    // Drops every cached position.
+    fn reset(&mut self);
+    // Keeps positions 0..len, so evaluation can resume from there.
+    fn truncate(&mut self, len: usize) -> Result<()>;
+    // Takes the memory positions up to `len` will need, so running out shows
+    // up here rather than partway through a forward pass.
+    fn reserve(&mut self, _len: usize) -> Result<()> {
+        Ok(())
+    }
+    // An independent copy; updating one does not affect the other.
+    fn clone_cache(&self) -> Box<dyn KVCache>;
+    // For models that keep K/V in their own cache type behind the trait.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
 }
 
 #[derive(Debug, Clone)]
@@ -60,6 +77,7 @@ impl CacheStrategy for LruStrategy {
     }
 }
 
+#[derive(Clone)]
 pub struct HybridCache<C1, C2> 
 where
     C1: KVCache,
@@ -81,8 +99,8 @@ where
 
 impl<C1, C2> KVCache for HybridCache<C1, C2>
 where
-    C1: KVCache,
-    C2: KVCache,
+    C1: KVCache + Clone + 'static,
+    C2: KVCache + Clone + 'static,
 {
     fn update(&mut self, layer: usize, key: &Tensor, value: &Tensor) -> Result<(Tensor, Tensor)> {
         self.primary.update(layer, key, value)
@@ -100,4 +118,22 @@ where
     fn capacity(&self) -> usize {
         self.primary.capacity() + self.secondary.capacity()
     }
+
+    fn reset(&mut self) {
+        self.primary.reset();
+        self.secondary.reset();
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        self.primary.truncate(len)?;
+        self.secondary.truncate(len)
+    }
+
+    fn clone_cache(&self) -> Box<dyn KVCache> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
 }
@@ -1,5 +1,7 @@
 pub mod llama;
 pub mod embedding;
+pub mod transformer;
 
 pub use llama::LlamaModel;
 pub use embedding::EmbeddingModel;
+pub use transformer::TransformerModel;
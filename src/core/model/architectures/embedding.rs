@@ -33,7 +33,7 @@ impl crate::core::model::Model for EmbeddingModel {
     fn forward_batch(
         &mut self,
         _batch: &crate::core::model::ModelBatch,
-        _cache: &mut dyn KVCache,
+        _caches: &mut [&mut dyn KVCache],
     ) -> Result<Tensor> {
         unimplemented!("EmbeddingModel forward_batch not implemented")
     }
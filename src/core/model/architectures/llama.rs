@@ -1,18 +1,71 @@
 use crate::core::model::{ModelConfig, ModelMeta, ModelBatch};
+use crate::core::model::lora::LoraAdapter;
+use crate::core::cache::{BlockPool, CacheEntry, PagedCacheConfig, PagedKVCache};
 use crate::core::{Result, Tensor, KVCache, TokenId};
-use candle_core::Device;
-use candle_transformers::models::quantized_llama::ModelWeights;
+use crate::infra::gguf::{split, MappedGguf, SplitGguf};
+use candle_core::quantized::{gguf_file, k_quants, GgmlDType, QMatMul, QStorage, QTensor, QuantizedType};
+use candle_core::{DType, Device, Module};
+use candle_transformers::quantized_nn::RmsNorm;
+use once_cell::sync::Lazy;
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+// Weights loaded once per blob and adapter set and shared by every LlamaModel
+// on them. The models hold no attention state; that lives in each sequence's
+// LlamaCache.
+static LOADED: Lazy<Mutex<HashMap<String, Weak<SharedWeights>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    pub use_mmap: bool,
+    pub use_mlock: bool,
+    pub adapters: Vec<PathBuf>,
+    pub lora_scale: f32,
+    // Sequences share a paged pool of this size instead of each growing
+    // its own cache.
+    pub kv_cache: Option<PagedCacheConfig>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            use_mmap: true,
+            use_mlock: false,
+            adapters: Vec::new(),
+            lora_scale: 1.0,
+            kv_cache: None,
+        }
+    }
+}
+
+struct SharedWeights {
+    weights: Weights,
+}
 
 pub struct LlamaModel {
     config: ModelConfig,
     meta: ModelMeta,
     device: Device,
-    weights: ModelWeights,
-    embeddings: candle_core::Tensor,
+    shared: Arc<SharedWeights>,
+    kv_pool: Option<Arc<BlockPool>>,
 }
 
 impl LlamaModel {
     pub fn load(model_path: &str, config: ModelConfig) -> Result<Self> {
+        Self::load_with_options(model_path, config, &LoadOptions::default())
+    }
+
+    pub fn load_with_options(model_path: &str, config: ModelConfig, options: &LoadOptions) -> Result<Self> {
+        Self::load_shards(&split::shard_paths(model_path)?, config, options)
+    }
+
+    // Loads a model split across gguf-split shards, given in split order.
+    pub fn load_shards<P: AsRef<Path>>(paths: &[P], config: ModelConfig, options: &LoadOptions) -> Result<Self> {
         let device = if candle_core::utils::cuda_is_available() {
             Device::new_cuda(0)?
         } else if candle_core::utils::metal_is_available() {
@@ -21,17 +74,13 @@ impl LlamaModel {
             Device::Cpu
         };
 
-        let mut file = std::fs::File::open(model_path)?;
-        let mut reader = std::io::BufReader::new(&file);
-        let content = candle_core::quantized::gguf_file::Content::read(&mut reader)?;
-        let weights = ModelWeights::from_gguf(content, &mut file, &device)?;
-        
-        // Extract real embeddings tensor for the embed method
-        let mut file = std::fs::File::open(model_path)?;
-        let mut reader = std::io::BufReader::new(&file);
-        let content = candle_core::quantized::gguf_file::Content::read(&mut reader)?;
-        let embeddings_qtensor = content.tensor(&mut file, "token_embd.weight", &device)?;
-        let embeddings = embeddings_qtensor.dequantize(&device)?;
+        let model = SplitGguf::open(paths, options.use_mmap, options.use_mlock)?;
+        let shared = Self::shared_weights(model, options, &device)?;
+        let weights = &shared.weights;
+        let kv_pool = options
+            .kv_cache
+            .map(|config| BlockPool::new(weights.layers.len(), weights.n_kv_head, weights.head_dim, config))
+            .transpose()?;
 
         let meta = ModelMeta {
             name: config.architecture.clone(),
@@ -46,36 +95,830 @@ impl LlamaModel {
             config,
             meta,
             device,
-            weights,
+            shared,
+            kv_pool,
+        })
+    }
+
+    // The block pool behind `new_cache`, when loaded with a paged cache.
+    pub fn kv_pool(&self) -> Option<&Arc<BlockPool>> {
+        self.kv_pool.as_ref()
+    }
+
+    fn shared_weights(model: SplitGguf, options: &LoadOptions, device: &Device) -> Result<Arc<SharedWeights>> {
+        let mut loaded = LOADED.lock().unwrap();
+        loaded.retain(|_, w| w.strong_count() > 0);
+
+        let shards: Vec<&[u8]> = model.shards().iter().map(|s| s.bytes()).collect();
+        let mut key = model.shards().iter().map(|s| s.path().display().to_string()).collect::<Vec<_>>().join("+");
+        if !options.use_mmap {
+            key.push_str("|heap");
+        }
+        for adapter in &options.adapters {
+            key.push_str(&format!("|{}@{}", adapter.display(), options.lora_scale));
+        }
+        if let Some(shared) = loaded.get(&key).and_then(Weak::upgrade) {
+            return Ok(shared);
+        }
+
+        // Header and tensor data both come from the shard mappings.
+        let content = read_content(&shards)?;
+        let mut reader = PatchedReader::new(shards);
+        for path in &options.adapters {
+            let adapter = LoraAdapter::load(path, options.lora_scale)?;
+            merge_lora(&content, &mut reader, &adapter)?;
+        }
+        let mut source = WeightSource { content: &content, reader, shards: model.shards() };
+        let weights = Weights::from_gguf(&mut source, device)?;
+
+        let shared = Arc::new(SharedWeights { weights });
+        loaded.insert(key, Arc::downgrade(&shared));
+        Ok(shared)
+    }
+}
+
+// The llama graph of candle's quantized_llama over the same quantized
+// weights, but stateless: attention reads and extends the caller's cache.
+struct Weights {
+    embeddings: Embeddings,
+    layers: Vec<Layer>,
+    norm: RmsNorm,
+    output: QMatMul,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    rope_dim: usize,
+    rope_theta: f32,
+}
+
+struct Layer {
+    wq: QMatMul,
+    wk: QMatMul,
+    wv: QMatMul,
+    wo: QMatMul,
+    attn_norm: RmsNorm,
+    ffn_norm: RmsNorm,
+    ffn: FeedForward,
+}
+
+struct Mlp {
+    gate: QMatMul,
+    up: QMatMul,
+    down: QMatMul,
+}
+
+impl Mlp {
+    fn forward(&self, x: &candle_core::Tensor) -> Result<candle_core::Tensor> {
+        let gate = candle_nn::ops::silu(&self.gate.forward(x)?)?;
+        Ok(self.down.forward(&(gate * self.up.forward(x)?)?)?)
+    }
+}
+
+enum FeedForward {
+    Dense(Mlp),
+    // Mixtral-style: each token goes through its top `used` experts, weighted
+    // by the renormalized router probabilities.
+    Experts { used: usize, router: QMatMul, experts: Vec<Mlp> },
+}
+
+impl FeedForward {
+    fn forward(&self, x: &candle_core::Tensor) -> Result<candle_core::Tensor> {
+        let (used, router, experts) = match self {
+            Self::Dense(mlp) => return mlp.forward(x),
+            Self::Experts { used, router, experts } => (*used, router, experts),
+        };
+        let (b, seq, dim) = x.dims3()?;
+        let x = x.reshape((b * seq, dim))?;
+        let weights = candle_nn::ops::softmax_last_dim(&router.forward(&x)?)?.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+
+        let mut rows = vec![Vec::new(); experts.len()];
+        let mut scales = vec![Vec::new(); experts.len()];
+        for (row, w) in weights.iter().enumerate() {
+            let mut order: Vec<usize> = (0..w.len()).collect();
+            order.sort_by(|&i, &j| w[j].total_cmp(&w[i]));
+            let top = &order[..used.min(order.len())];
+            let sum: f32 = top.iter().map(|&e| w[e]).sum();
+            for &e in top {
+                rows[e].push(row as u32);
+                scales[e].push(w[e] / sum);
+            }
+        }
+
+        let mut out = x.zeros_like()?;
+        for (e, expert) in experts.iter().enumerate() {
+            if rows[e].is_empty() {
+                continue;
+            }
+            let idx = candle_core::Tensor::new(rows[e].as_slice(), x.device())?;
+            let scale = candle_core::Tensor::new(scales[e].as_slice(), x.device())?.reshape(((), 1))?;
+            let y = expert.forward(&x.index_select(&idx, 0)?)?.broadcast_mul(&scale)?;
+            out = out.index_add(&idx, &y, 0)?;
+        }
+        Ok(out.reshape((b, seq, dim))?)
+    }
+}
+
+// The token embedding table. On the CPU it stays in the file's encoding and
+// rows are dequantized as tokens are looked up.
+enum Embeddings {
+    Rows(QTensor),
+    Dense(candle_core::Tensor),
+}
+
+impl Embeddings {
+    fn new(table: QTensor, device: &Device) -> Result<Self> {
+        Ok(if device.is_cpu() { Self::Rows(table) } else { Self::Dense(table.dequantize(device)?) })
+    }
+
+    // [ids.len(), dim]
+    fn lookup(&self, ids: &[u32], device: &Device) -> Result<candle_core::Tensor> {
+        let table = match self {
+            Self::Dense(table) => return Ok(table.index_select(&candle_core::Tensor::new(ids, device)?, 0)?),
+            Self::Rows(table) => table,
+        };
+        let (vocab, dim) = table.shape().dims2()?;
+        let dtype = table.dtype();
+        let row = dim / dtype.block_size() * dtype.type_size();
+        let data = table.data()?;
+        let rows = ids
+            .iter()
+            .map(|&id| {
+                let id = id as usize;
+                if id >= vocab {
+                    anyhow::bail!("token {} is outside the vocabulary of {}", id, vocab);
+                }
+                let storage = QStorage::from_data(Cow::Borrowed(&data[id * row..(id + 1) * row]), device, dtype)?;
+                Ok(QTensor::new(storage, dim)?.dequantize(device)?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(candle_core::Tensor::stack(&rows, 0)?)
+    }
+}
+
+// Where Weights::from_gguf reads tensors. On the CPU a tensor no adapter
+// patched is used in place from its shard's mapping (or heap copy), which it
+// keeps alive; anything else is read out through the patched reader.
+struct WeightSource<'a> {
+    content: &'a gguf_file::Content,
+    reader: PatchedReader<'a>,
+    shards: &'a [Arc<MappedGguf>],
+}
+
+impl WeightSource<'_> {
+    fn tensor(&mut self, name: &str, device: &Device) -> Result<QTensor> {
+        if device.is_cpu() {
+            if let Some(tensor) = self.in_place(name)? {
+                return Ok(tensor);
+            }
+        }
+        Ok(self.content.tensor(&mut self.reader, name, device)?)
+    }
+
+    fn in_place(&self, name: &str) -> Result<Option<QTensor>> {
+        let Some(info) = self.content.tensor_infos.get(name) else {
+            return Ok(None);
+        };
+        let dtype = info.ggml_dtype;
+        let start = self.content.tensor_data_offset + info.offset;
+        let size = info.shape.elem_count() / dtype.block_size() * dtype.type_size();
+        if self.reader.is_patched(start, start + size as u64) {
+            return Ok(None);
+        }
+        let mut base = 0;
+        for shard in self.shards {
+            let bytes = shard.bytes();
+            if start < base + bytes.len() as u64 {
+                let from = (start - base) as usize;
+                let Some(data) = bytes.get(from..from + size) else {
+                    return Ok(None);
+                };
+                return match MappedBlocks::storage(shard, data, dtype) {
+                    Some(storage) => Ok(Some(QTensor::new(QStorage::Cpu(storage), info.shape.clone())?)),
+                    None => Ok(None),
+                };
+            }
+            base += bytes.len() as u64;
+        }
+        Ok(None)
+    }
+}
+
+// Quantized blocks read in place from a shard's bytes. Writing them, which
+// candle only does to storage it allocates for quantizing, replaces them
+// with a copy.
+struct MappedBlocks<T> {
+    _shard: Arc<MappedGguf>,
+    blocks: *const T,
+    len: usize,
+    copy: Option<Vec<T>>,
+}
+
+// Safety: the mapped blocks are never written and the shard they point into
+// is owned by the struct.
+unsafe impl<T: Send> Send for MappedBlocks<T> {}
+unsafe impl<T: Sync> Sync for MappedBlocks<T> {}
+
+impl<T: k_quants::GgmlType + 'static> MappedBlocks<T> {
+    fn boxed(shard: &Arc<MappedGguf>, data: &[u8]) -> Option<Box<dyn QuantizedType>> {
+        let size = std::mem::size_of::<T>();
+        let aligned = data.as_ptr().align_offset(std::mem::align_of::<T>()) == 0;
+        if size != T::DTYPE.type_size() || !data.len().is_multiple_of(size) || !aligned {
+            return None;
+        }
+        Some(Box::new(Self { _shard: shard.clone(), blocks: data.as_ptr().cast(), len: data.len() / size, copy: None }))
+    }
+
+    fn as_slice(&self) -> &[T] {
+        if let Some(copy) = &self.copy {
+            return copy;
+        }
+        // Safety: checked for size and alignment in boxed, and kept alive by _shard.
+        unsafe { std::slice::from_raw_parts(self.blocks, self.len) }
+    }
+
+    fn copy_mut(&mut self) -> &mut [T] {
+        let len = self.len;
+        self.copy.get_or_insert_with(|| vec![T::zeros(); len])
+    }
+}
+
+impl MappedBlocks<()> {
+    // None when the bytes are not aligned for the block type.
+    fn storage(shard: &Arc<MappedGguf>, data: &[u8], dtype: GgmlDType) -> Option<Box<dyn QuantizedType>> {
+        use k_quants::*;
+        match dtype {
+            GgmlDType::F32 => MappedBlocks::<f32>::boxed(shard, data),
+            GgmlDType::F16 => MappedBlocks::<half::f16>::boxed(shard, data),
+            GgmlDType::BF16 => MappedBlocks::<half::bf16>::boxed(shard, data),
+            GgmlDType::Q4_0 => MappedBlocks::<BlockQ4_0>::boxed(shard, data),
+            GgmlDType::Q4_1 => MappedBlocks::<BlockQ4_1>::boxed(shard, data),
+            GgmlDType::Q5_0 => MappedBlocks::<BlockQ5_0>::boxed(shard, data),
+            GgmlDType::Q5_1 => MappedBlocks::<BlockQ5_1>::boxed(shard, data),
+            GgmlDType::Q8_0 => MappedBlocks::<BlockQ8_0>::boxed(shard, data),
+            GgmlDType::Q8_1 => MappedBlocks::<BlockQ8_1>::boxed(shard, data),
+            GgmlDType::Q2K => MappedBlocks::<BlockQ2K>::boxed(shard, data),
+            GgmlDType::Q3K => MappedBlocks::<BlockQ3K>::boxed(shard, data),
+            GgmlDType::Q4K => MappedBlocks::<BlockQ4K>::boxed(shard, data),
+            GgmlDType::Q5K => MappedBlocks::<BlockQ5K>::boxed(shard, data),
+            GgmlDType::Q6K => MappedBlocks::<BlockQ6K>::boxed(shard, data),
+            GgmlDType::Q8K => MappedBlocks::<BlockQ8K>::boxed(shard, data),
+        }
+    }
+}
+
+impl<T: k_quants::GgmlType + 'static> QuantizedType for MappedBlocks<T> {
+    fn dtype(&self) -> GgmlDType {
+        T::DTYPE
+    }
+
+    fn matmul_t(&self, mkn: (usize, usize, usize), lhs: &[f32], dst: &mut [f32]) -> candle_core::Result<()> {
+        k_quants::matmul(mkn, lhs, self.as_slice(), dst)
+    }
+
+    fn matmul_t_f16(&self, mkn: (usize, usize, usize), lhs: &[half::f16], dst: &mut [half::f16]) -> candle_core::Result<()> {
+        k_quants::matmul_f16(mkn, lhs, self.as_slice(), dst)
+    }
+
+    fn dequantize(&self, elem_count: usize) -> candle_core::Result<candle_core::CpuStorage> {
+        let mut ys = vec![0.0f32; elem_count];
+        T::to_float(self.as_slice(), &mut ys);
+        Ok(candle_core::CpuStorage::F32(ys))
+    }
+
+    fn storage_size_in_bytes(&self) -> usize {
+        self.len * std::mem::size_of::<T>()
+    }
+
+    fn as_ptr(&self) -> *const u8 {
+        self.as_slice().as_ptr().cast()
+    }
+
+    fn block_size(&self) -> usize {
+        T::BLCK_SIZE
+    }
+
+    fn from_float(&mut self, xs: &[f32]) {
+        T::from_float(xs, self.copy_mut())
+    }
+
+    fn from_float_imatrix(&mut self, xs: &[f32], imatrix_weights: &[f32], n_per_row: usize) {
+        T::from_float_imatrix(xs, self.copy_mut(), imatrix_weights, n_per_row)
+    }
+
+    fn size(&self) -> usize {
+        self.storage_size_in_bytes()
+    }
+}
+
+impl Weights {
+    fn from_gguf(source: &mut WeightSource, device: &Device) -> Result<Self> {
+        let ct = source.content;
+        let md = |key: &str| {
+            ct.metadata.get(key).ok_or_else(|| anyhow::anyhow!("cannot find {} in metadata", key))
+        };
+        let uint = |key: &str| -> Result<usize> { Ok(md(key)?.to_u32()? as usize) };
+        let n_head = uint("llama.attention.head_count")?;
+        let n_kv_head = uint("llama.attention.head_count_kv")?;
+        let dim = uint("llama.embedding_length")?;
+        let eps = md("llama.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let rope_theta = md("llama.rope.freq_base").and_then(|v| Ok(v.to_f32()?)).unwrap_or(10000.0);
+        let rope_dim = uint("llama.rope.dimension_count").unwrap_or(dim / n_head);
+        let n_expert = uint("llama.expert_count").unwrap_or(0);
+        let n_expert_used = uint("llama.expert_used_count").unwrap_or(0);
+
+        let mut tensor = |name: &str| source.tensor(name, device);
+        // candle would dequantize f32 and f16 matrices into copies; on the
+        // CPU they are multiplied in place like the quantized ones.
+        let matmul = |t: QTensor| -> Result<QMatMul> {
+            Ok(if device.is_cpu() { QMatMul::QTensor(Arc::new(t)) } else { QMatMul::from_qtensor(t)? })
+        };
+        let embd = tensor("token_embd.weight")?;
+        // Tied embeddings when there is no separate output matrix.
+        let output = match tensor("output.weight") {
+            Ok(output) => matmul(output)?,
+            Err(_) => matmul(tensor("token_embd.weight")?)?,
+        };
+        let embeddings = Embeddings::new(embd, device)?;
+        let norm = RmsNorm::from_qtensor(tensor("output_norm.weight")?, eps)?;
+
+        let mut layers = Vec::new();
+        for i in 0..uint("llama.block_count")? {
+            let mut w = |name: &str| -> Result<QMatMul> {
+                matmul(tensor(&format!("blk.{}.{}.weight", i, name))?)
+            };
+            let mut mlp = |suffix: &str| -> Result<Mlp> {
+                Ok(Mlp {
+                    gate: w(&format!("ffn_gate{}", suffix))?,
+                    up: w(&format!("ffn_up{}", suffix))?,
+                    down: w(&format!("ffn_down{}", suffix))?,
+                })
+            };
+            let ffn = if n_expert <= 1 {
+                FeedForward::Dense(mlp("")?)
+            } else {
+                let experts = (0..n_expert).map(|e| mlp(&format!(".{}", e))).collect::<Result<_>>()?;
+                FeedForward::Experts { used: n_expert_used, router: w("ffn_gate_inp")?, experts }
+            };
+            layers.push(Layer {
+                wq: w("attn_q")?,
+                wk: w("attn_k")?,
+                wv: w("attn_v")?,
+                wo: w("attn_output")?,
+                attn_norm: RmsNorm::from_qtensor(tensor(&format!("blk.{}.attn_norm.weight", i))?, eps)?,
+                ffn_norm: RmsNorm::from_qtensor(tensor(&format!("blk.{}.ffn_norm.weight", i))?, eps)?,
+                ffn,
+            });
+        }
+
+        Ok(Self {
             embeddings,
+            layers,
+            norm,
+            output,
+            n_head,
+            n_kv_head,
+            head_dim: dim / n_head,
+            rope_dim,
+            rope_theta,
         })
     }
+
+    // cos and sin of every rotation angle at each of `positions`.
+    fn rope_tables(&self, positions: &[usize], device: &Device) -> Result<(candle_core::Tensor, candle_core::Tensor)> {
+        let half = self.rope_dim / 2;
+        let angles: Vec<f32> = positions
+            .iter()
+            .flat_map(|&pos| {
+                (0..half).map(move |i| pos as f32 / self.rope_theta.powf(2.0 * i as f32 / self.rope_dim as f32))
+            })
+            .collect();
+        let angles = candle_core::Tensor::from_vec(angles, (positions.len(), half), device)?;
+        Ok((angles.cos()?, angles.sin()?))
+    }
+
+    // Evaluates each sequence's tokens at positions cache.len().. and appends
+    // them to that sequence's cache. The projections and feed-forward run
+    // once over all tokens; only attention is per sequence. Returns [n, vocab]
+    // logits of each sequence's last token.
+    fn forward(&self, seqs: &mut [(&[u32], &mut dyn KVCache)], device: &Device) -> Result<candle_core::Tensor> {
+        let spans: Vec<(usize, usize)> = seqs.iter().map(|(t, c)| (c.len(), t.len())).collect();
+        if spans.iter().any(|&(_, len)| len == 0) {
+            anyhow::bail!("every sequence in a batch needs at least one token");
+        }
+        let tokens: Vec<u32> = seqs.iter().flat_map(|(t, _)| t.iter().copied()).collect();
+        let positions: Vec<usize> = spans.iter().flat_map(|&(start, len)| start..start + len).collect();
+
+        let mut hidden = self.embeddings.lookup(&tokens, device)?.unsqueeze(0)?;
+        let (cos, sin) = self.rope_tables(&positions, device)?;
+        let masks = spans
+            .iter()
+            .map(|&(start, len)| causal_mask(start, len, device))
+            .collect::<Result<Vec<_>>>()?;
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            let x = layer.attn_norm.forward(&hidden)?;
+            hidden = (self.attention(layer, i, &x, &masks, &cos, &sin, seqs)? + hidden)?;
+            let x = layer.ffn_norm.forward(&hidden)?;
+            hidden = (layer.ffn.forward(&x)? + hidden)?;
+        }
+
+        let last: Vec<u32> = spans
+            .iter()
+            .scan(0, |end, &(_, len)| {
+                *end += len;
+                Some(*end as u32 - 1)
+            })
+            .collect();
+        let last = candle_core::Tensor::new(last.as_slice(), device)?;
+        let hidden = self.norm.forward(&hidden.squeeze(0)?.index_select(&last, 0)?)?;
+        Ok(self.output.forward(&hidden)?)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn attention(
+        &self,
+        layer: &Layer,
+        index: usize,
+        x: &candle_core::Tensor,
+        masks: &[Option<candle_core::Tensor>],
+        cos: &candle_core::Tensor,
+        sin: &candle_core::Tensor,
+        seqs: &mut [(&[u32], &mut dyn KVCache)],
+    ) -> Result<candle_core::Tensor> {
+        let (b, total, dim) = x.dims3()?;
+        let heads = |w: &QMatMul, n: usize| -> Result<candle_core::Tensor> {
+            Ok(w.forward(x)?.reshape((b, total, n, self.head_dim))?.transpose(1, 2)?.contiguous()?)
+        };
+        let q = candle_nn::rotary_emb::rope_i(&heads(&layer.wq, self.n_head)?, cos, sin)?;
+        let k = candle_nn::rotary_emb::rope_i(&heads(&layer.wk, self.n_kv_head)?, cos, sin)?;
+        let v = heads(&layer.wv, self.n_kv_head)?;
+        let group = self.n_head / self.n_kv_head;
+        let scale = (self.head_dim as f64).sqrt();
+
+        // Each K/V head serves `group` query heads, so the queries are folded
+        // into it rather than the K/V repeated.
+        let mut outputs = Vec::with_capacity(seqs.len());
+        let mut offset = 0;
+        for ((tokens, cache), mask) in seqs.iter_mut().zip(masks) {
+            let len = tokens.len();
+            let (q, k, v) = (q.narrow(2, offset, len)?, k.narrow(2, offset, len)?.contiguous()?, v.narrow(2, offset, len)?.contiguous()?);
+            offset += len;
+
+            // A paged cache attends straight from its blocks.
+            if let Some(paged) = cache.as_any_mut().downcast_mut::<PagedKVCache>() {
+                paged.append(index, &from_candle(&k)?, &from_candle(&v)?)?;
+                let y = paged.attend(index, &from_candle(&q)?, 1.0 / scale as f32)?;
+                outputs.push(to_candle(&y, x.device())?);
+                continue;
+            }
+            let q = q.reshape((b, self.n_kv_head, group * len, self.head_dim))?;
+            let (k, v) = append_kv(&mut **cache, index, k, v)?;
+
+            let att = (q.matmul(&k.t()?)? / scale)?;
+            let att = match mask {
+                Some(mask) => {
+                    let att = att.reshape((b, self.n_kv_head, group, len, k.dim(2)?))?;
+                    let mask = mask.broadcast_as(att.shape())?;
+                    let neg_inf = candle_core::Tensor::new(f32::NEG_INFINITY, att.device())?.broadcast_as(att.shape())?;
+                    mask.where_cond(&neg_inf, &att)?
+                }
+                None => att,
+            };
+            let att = candle_nn::ops::softmax_last_dim(&att)?.reshape((b, self.n_kv_head, group * len, k.dim(2)?))?;
+            outputs.push(att.matmul(&v)?.reshape((b, self.n_head, len, self.head_dim))?);
+        }
+        let y = candle_core::Tensor::cat(&outputs, 2)?.transpose(1, 2)?.reshape((b, total, dim))?;
+        Ok(layer.wo.forward(&y)?)
+    }
+}
+
+// Appends one sequence's K/V for a layer, [1, kv_heads, seq, head_dim], and
+// returns all of it. A LlamaCache keeps them as candle tensors; any other
+// cache but a paged one, which attention reads directly, goes through the
+// KVCache layout.
+fn append_kv(
+    cache: &mut dyn KVCache,
+    layer: usize,
+    k: candle_core::Tensor,
+    v: candle_core::Tensor,
+) -> Result<(candle_core::Tensor, candle_core::Tensor)> {
+    if let Some(cache) = cache.as_any_mut().downcast_mut::<LlamaCache>() {
+        return cache.append(layer, &k, &v);
+    }
+    let device = k.device().clone();
+    let (k, v) = cache.update(layer, &from_candle(&k)?, &from_candle(&v)?)?;
+    Ok((to_candle(&k, &device)?, to_candle(&v, &device)?))
+}
+
+// [1, kv_heads, seq, head_dim] from [seq, kv_heads, head_dim] and back.
+fn to_candle(t: &Tensor, device: &Device) -> Result<candle_core::Tensor> {
+    let t = candle_core::Tensor::from_vec(t.data().to_vec(), t.shape().dims(), device)?;
+    Ok(t.unsqueeze(0)?.transpose(1, 2)?.contiguous()?)
+}
+
+fn from_candle(t: &candle_core::Tensor) -> Result<Tensor> {
+    Tensor::from_candle(t.squeeze(0)?.transpose(0, 1)?.contiguous()?)
+}
+
+// Masks keys after each query: `len` new tokens see the `start` cached
+// positions and the new tokens up to themselves. A single token sees
+// everything, so needs no mask.
+fn causal_mask(start: usize, len: usize, device: &Device) -> Result<Option<candle_core::Tensor>> {
+    if len == 1 {
+        return Ok(None);
+    }
+    let mask: Vec<u8> = (0..len).flat_map(|i| (0..start + len).map(move |j| u8::from(j > start + i))).collect();
+    Ok(Some(candle_core::Tensor::from_vec(mask, (len, start + len), device)?))
+}
+
+// K/V of one sequence as [1, kv_heads, positions, head_dim] per layer, on the
+// model's device. Each layer's tensors have room past its positions and a
+// step writes its rows into that room, so it copies nothing already cached.
+// Clones share the tensors until either side appends, which copies them.
+// Models with a block pool use a PagedKVCache instead.
+#[derive(Clone)]
+pub struct LlamaCache {
+    layers: Vec<Option<LayerKv>>,
+    lens: Vec<usize>,
+    max_seq_len: usize,
+    device: Device,
+}
+
+#[derive(Clone)]
+struct LayerKv {
+    k: candle_core::Tensor,
+    v: candle_core::Tensor,
+    // Held by every cache sharing the tensors.
+    owners: Arc<()>,
+}
+
+impl LlamaCache {
+    pub fn new(layer_count: usize, max_seq_len: usize, device: Device) -> Self {
+        Self { layers: vec![None; layer_count], lens: vec![0; layer_count], max_seq_len, device }
+    }
+
+    pub fn append(
+        &mut self,
+        layer: usize,
+        k: &candle_core::Tensor,
+        v: &candle_core::Tensor,
+    ) -> Result<(candle_core::Tensor, candle_core::Tensor)> {
+        let start = *self.lens.get(layer).ok_or_else(|| anyhow::anyhow!("KV cache has no layer {}", layer))?;
+        let end = start + k.dim(2)?;
+        if end > self.max_seq_len {
+            anyhow::bail!("KV cache full: {} positions, capacity {}", end, self.max_seq_len);
+        }
+        let (k, v) = (k.contiguous()?, v.contiguous()?);
+
+        let slot = &mut self.layers[layer];
+        let room = slot.as_ref().map(|kv| kv.k.dim(2)).transpose()?.unwrap_or(0);
+        let shared = slot.as_ref().is_some_and(|kv| Arc::strong_count(&kv.owners) > 1);
+        if end > room || shared {
+            // Doubling keeps the copies made while growing linear overall.
+            let room = if end > room { end.next_power_of_two().max(16).min(self.max_seq_len) } else { room };
+            let (_, heads, _, head_dim) = k.dims4()?;
+            let grow = |old: Option<&candle_core::Tensor>| -> Result<candle_core::Tensor> {
+                let empty = candle_core::Tensor::zeros((1, heads, room - start, head_dim), k.dtype(), k.device())?;
+                Ok(match old {
+                    Some(old) if start > 0 => candle_core::Tensor::cat(&[&old.narrow(2, 0, start)?, &empty], 2)?.contiguous()?,
+                    _ => empty,
+                })
+            };
+            let old = slot.take();
+            *slot = Some(LayerKv {
+                k: grow(old.as_ref().map(|kv| &kv.k))?,
+                v: grow(old.as_ref().map(|kv| &kv.v))?,
+                owners: Arc::new(()),
+            });
+        }
+        let kv = slot.as_ref().unwrap();
+        kv.k.slice_set(&k, 2, start)?;
+        kv.v.slice_set(&v, 2, start)?;
+        self.lens[layer] = end;
+        Ok((kv.k.narrow(2, 0, end)?, kv.v.narrow(2, 0, end)?))
+    }
+}
+
+impl KVCache for LlamaCache {
+    fn update(&mut self, layer: usize, key: &Tensor, value: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (k, v) = self.append(layer, &to_candle(key, &self.device)?, &to_candle(value, &self.device)?)?;
+        Ok((from_candle(&k)?, from_candle(&v)?))
+    }
+
+    fn get(&self, layer: usize) -> Option<CacheEntry> {
+        let (kv, &len) = (self.layers.get(layer)?.as_ref()?, self.lens.get(layer)?);
+        if len == 0 {
+            return None;
+        }
+        Some(CacheEntry::new(from_candle(&kv.k.narrow(2, 0, len).ok()?).ok()?, from_candle(&kv.v.narrow(2, 0, len).ok()?).ok()?))
+    }
+
+    fn len(&self) -> usize {
+        self.lens.iter().copied().max().unwrap_or(0)
+    }
+
+    fn capacity(&self) -> usize {
+        self.max_seq_len
+    }
+
+    fn reset(&mut self) {
+        self.layers.iter_mut().for_each(|l| *l = None);
+        self.lens.iter_mut().for_each(|l| *l = 0);
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        if len > self.len() {
+            anyhow::bail!("Cannot truncate a cache of {} positions to {}", self.len(), len);
+        }
+        self.lens.iter_mut().for_each(|l| *l = (*l).min(len));
+        Ok(())
+    }
+
+    fn reserve(&mut self, len: usize) -> Result<()> {
+        if len > self.max_seq_len {
+            anyhow::bail!("KV cache full: {} positions, capacity {}", len, self.max_seq_len);
+        }
+        Ok(())
+    }
+
+    fn clone_cache(&self) -> Box<dyn KVCache> {
+        Box::new(self.clone())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Reads the first shard's header and appends every other shard's tensors,
+// rebasing their offsets onto the concatenation of all shards.
+fn read_content(shards: &[&[u8]]) -> Result<gguf_file::Content> {
+    let mut content = gguf_file::Content::read(&mut std::io::Cursor::new(shards[0]))?;
+    let mut base = shards[0].len() as u64;
+    for bytes in &shards[1..] {
+        let shard = gguf_file::Content::read(&mut std::io::Cursor::new(*bytes))?;
+        for (name, mut info) in shard.tensor_infos {
+            info.offset = base + shard.tensor_data_offset + info.offset - content.tensor_data_offset;
+            content.tensor_infos.insert(name, info);
+        }
+        base += bytes.len() as u64;
+    }
+    Ok(content)
+}
+
+// Merges W += scale * B·A for every adapter target and stores the result,
+// requantized to the base tensor's type, keyed by its absolute file offset.
+fn merge_lora(content: &gguf_file::Content, reader: &mut PatchedReader, adapter: &LoraAdapter) -> Result<()> {
+    let cpu = Device::Cpu;
+    for target in &adapter.targets {
+        let info = content
+            .tensor_infos
+            .get(target)
+            .ok_or_else(|| anyhow::anyhow!("LoRA target {} not found in base model", target))?;
+        let offset = content.tensor_data_offset + info.offset;
+
+        // Reads through earlier patches, so stacked adapters accumulate.
+        let base = content.tensor(reader, target, &cpu)?;
+        let dtype = base.dtype();
+        let weight = base.dequantize(&cpu)?;
+
+        let (a, b, scale) = adapter.factors(target)?.expect("target listed by adapter");
+        let a = candle_core::Tensor::from_vec(a.data().to_vec(), a.shape().dims(), &cpu)?;
+        let b = candle_core::Tensor::from_vec(b.data().to_vec(), b.shape().dims(), &cpu)?;
+        let delta = (b.matmul(&a)? * scale as f64)?;
+        if delta.dims() != weight.dims() {
+            anyhow::bail!("LoRA delta for {} has shape {:?}, base weight is {:?}", target, delta.dims(), weight.dims());
+        }
+
+        let merged = QTensor::quantize(&(weight + delta)?, dtype)?;
+        reader.patches.insert(offset, merged.data()?.into_owned());
+    }
+    Ok(())
+}
+
+// Reads the shard mappings back to back, substituting merged tensors at their
+// original offsets.
+struct PatchedReader<'a> {
+    shards: Vec<&'a [u8]>,
+    patches: BTreeMap<u64, Vec<u8>>,
+    pos: u64,
+}
+
+impl<'a> PatchedReader<'a> {
+    fn new(shards: Vec<&'a [u8]>) -> Self {
+        Self { shards, patches: BTreeMap::new(), pos: 0 }
+    }
+
+    fn len(&self) -> u64 {
+        self.shards.iter().map(|s| s.len() as u64).sum()
+    }
+
+    // Whether any merged tensor overlaps start..end.
+    fn is_patched(&self, start: u64, end: u64) -> bool {
+        self.patches.range(..end).next_back().is_some_and(|(&at, patch)| at + patch.len() as u64 > start)
+    }
+
+    // The unpatched bytes from `pos` up to `end` or the end of its shard.
+    fn base(&self, pos: u64, end: u64) -> &'a [u8] {
+        let mut start = 0;
+        for shard in &self.shards {
+            let shard_end = start + shard.len() as u64;
+            if pos < shard_end {
+                return &shard[(pos - start) as usize..(end.min(shard_end) - start) as usize];
+            }
+            start = shard_end;
+        }
+        &[]
+    }
+}
+
+impl Read for PatchedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.len();
+        if self.pos >= len {
+            return Ok(0);
+        }
+        if let Some((&start, patch)) = self.patches.range(..=self.pos).next_back() {
+            let within = self.pos - start;
+            if within < patch.len() as u64 {
+                let src = &patch[within as usize..];
+                let n = src.len().min(buf.len());
+                buf[..n].copy_from_slice(&src[..n]);
+                self.pos += n as u64;
+                return Ok(n);
+            }
+        }
+        let end = self
+            .patches
+            .range(self.pos + 1..)
+            .next()
+            .map(|(&start, _)| start)
+            .unwrap_or(len);
+        let src = self.base(self.pos, end);
+        let n = src.len().min(buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for PatchedReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let next = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(d) => self.len() as i64 + d,
+            SeekFrom::Current(d) => self.pos as i64 + d,
+        };
+        if next < 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before start"));
+        }
+        self.pos = next as u64;
+        Ok(self.pos)
+    }
+}
+
+fn check_positions(tokens: usize, positions: &[usize], cache: &dyn KVCache) -> Result<()> {
+    if tokens == 0 {
+        anyhow::bail!("forward needs at least one token");
+    }
+    if positions.len() != tokens || positions[0] != cache.len() {
+        anyhow::bail!("positions start at {:?} but the cache holds {} positions", positions.first(), cache.len());
+    }
+    Ok(())
 }
 
 impl crate::core::model::Model for LlamaModel {
+    // `positions` must continue the sequence in `cache`.
     fn forward(
         &mut self,
         tokens: &[TokenId],
         positions: &[usize],
-        _cache: &mut dyn KVCache,
+        cache: &mut dyn KVCache,
     ) -> Result<Tensor> {
+        check_positions(tokens.len(), positions, cache)?;
+
         let tokens_u32: Vec<u32> = tokens.iter().map(|t| t.0 as u32).collect();
-        let input_tensor = candle_core::Tensor::new(&tokens_u32[..], &self.device)?.unsqueeze(0)?;
-        
-        let start_pos = positions.first().cloned().unwrap_or(0);
-        
-        let logits = self.weights.forward(&input_tensor, start_pos)?;
-        let logits = logits.squeeze(0)?.squeeze(0)?;
-        
-        Tensor::from_candle(logits)
+        let logits = self.shared.weights.forward(&mut [(&tokens_u32, cache)], &self.device)?;
+        Tensor::from_candle(logits.squeeze(0)?)
     }
 
+    // Each sequence's positions must continue its own cache, as in `forward`.
     fn forward_batch(
         &mut self,
-        _batch: &ModelBatch,
-        _cache: &mut dyn KVCache,
+        batch: &ModelBatch,
+        caches: &mut [&mut dyn KVCache],
     ) -> Result<Tensor> {
-        anyhow::bail!("forward_batch not yet supported for LlamaModel")
+        if caches.len() != batch.batch_size() || batch.positions.len() != batch.batch_size() {
+            anyhow::bail!("forward_batch needs one cache and position list per sequence");
+        }
+        let tokens: Vec<Vec<u32>> = batch.tokens.iter().map(|t| t.iter().map(|t| t.0 as u32).collect()).collect();
+        let mut seqs: Vec<(&[u32], &mut dyn KVCache)> = Vec::with_capacity(caches.len());
+        for ((tokens, positions), cache) in tokens.iter().zip(&batch.positions).zip(caches.iter_mut()) {
+            check_positions(tokens.len(), positions, &**cache)?;
+            seqs.push((tokens.as_slice(), &mut **cache as _));
+        }
+        let logits = self.shared.weights.forward(&mut seqs, &self.device)?;
+        Tensor::from_candle(logits)
     }
 
     fn config(&self) -> &ModelConfig {
@@ -86,12 +929,16 @@ impl crate::core::model::Model for LlamaModel {
         &self.meta
     }
 
+    fn new_cache(&self, max_seq_len: usize) -> Box<dyn KVCache> {
+        match &self.kv_pool {
+            Some(pool) => Box::new(PagedKVCache::new(pool.clone(), max_seq_len)),
+            None => Box::new(LlamaCache::new(self.shared.weights.layers.len(), max_seq_len, self.device.clone())),
+        }
+    }
+
     fn embed(&self, tokens: &[TokenId]) -> Result<Tensor> {
         let tokens_u32: Vec<u32> = tokens.iter().map(|t| t.0 as u32).collect();
-        let token_tensor = candle_core::Tensor::new(&tokens_u32[..], &self.device)?;
-        
-        // Faithful embedding lookup
-        let embedded = self.embeddings.index_select(&token_tensor, 0)?;
+        let embedded = self.shared.weights.embeddings.lookup(&tokens_u32, &self.device)?;
         
         // If multiple tokens, we usually return the mean or the full sequence.
         // For /api/embed Ollama-style, it's often the mean of the sequence.
@@ -109,3 +956,419 @@ impl crate::core::model::Model for LlamaModel {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gguf_file::Value;
+
+    fn write_gguf(path: &std::path::Path, metadata: &[(&str, &Value)], tensors: &[(&str, candle_core::Tensor)]) {
+        let qtensors: Vec<(&str, QTensor)> = tensors.iter()
+            .map(|(n, t)| (*n, QTensor::quantize(t, candle_core::quantized::GgmlDType::F32).unwrap()))
+            .collect();
+        let refs: Vec<(&str, &QTensor)> = qtensors.iter().map(|(n, q)| (*n, q)).collect();
+        let mut file = std::fs::File::create(path).unwrap();
+        gguf_file::write(&mut file, metadata, &refs).unwrap();
+    }
+
+    #[test]
+    fn test_merge_lora_into_base_weight() {
+        let cpu = Device::Cpu;
+        let dir = std::env::temp_dir();
+        let base_path = dir.join(format!("ollama-lora-base-{}.gguf", std::process::id()));
+        let adapter_path = dir.join(format!("ollama-lora-adapter-{}.gguf", std::process::id()));
+
+        let w = candle_core::Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], &cpu).unwrap();
+        write_gguf(&base_path, &[], &[("blk.0.attn_q.weight", w.clone())]);
+
+        let a = candle_core::Tensor::new(&[[1f32, 0., -1.]], &cpu).unwrap();
+        let b = candle_core::Tensor::new(&[[2f32], [3.]], &cpu).unwrap();
+        let general_type = Value::String("adapter".to_string());
+        let alpha = Value::F32(2.0);
+        write_gguf(
+            &adapter_path,
+            &[("general.type", &general_type), ("adapter.lora.alpha", &alpha)],
+            &[("blk.0.attn_q.weight.lora_a", a.clone()), ("blk.0.attn_q.weight.lora_b", b.clone())],
+        );
+
+        let mapping = crate::infra::gguf::MappedGguf::open(&base_path, true, false).unwrap();
+        let content = read_content(&[mapping.bytes()]).unwrap();
+        let mut reader = PatchedReader::new(vec![mapping.bytes()]);
+
+        // alpha / rank = 2, times a user scale of 0.5
+        let adapter = LoraAdapter::load(&adapter_path, 0.5).unwrap();
+        merge_lora(&content, &mut reader, &adapter).unwrap();
+
+        let merged = content.tensor(&mut reader, "blk.0.attn_q.weight", &cpu).unwrap()
+            .dequantize(&cpu).unwrap()
+            .to_vec2::<f32>().unwrap();
+        assert_eq!(merged, vec![vec![3., 2., 1.], vec![7., 5., 3.]]);
+
+        std::fs::remove_file(&base_path).ok();
+        std::fs::remove_file(&adapter_path).ok();
+    }
+
+    #[test]
+    fn test_weights_are_read_in_place() {
+        use crate::core::model::architectures::transformer::tests::write_model;
+        use crate::core::model::Model;
+
+        let path = std::env::temp_dir().join(format!("ollama-llama-in-place-{}.gguf", std::process::id()));
+        write_model(&path);
+        let config = SplitGguf::open(&[&path], false, false).unwrap().file().metadata.to_model_config();
+        let tokens = [TokenId(1), TokenId(5), TokenId(3)];
+
+        let mut logits = Vec::new();
+        for use_mmap in [true, false] {
+            let options = LoadOptions { use_mmap, ..Default::default() };
+            let mut model = LlamaModel::load_with_options(path.to_str().unwrap(), config.clone(), &options).unwrap();
+
+            // The weights point into the shard's mapping or heap copy rather
+            // than at copies of their own.
+            let shard = MappedGguf::open(&path, use_mmap, false).unwrap();
+            assert_eq!(shard.is_mmap(), use_mmap);
+            let bytes = shard.bytes().as_ptr_range();
+            let weights = &model.shared.weights;
+            let (QMatMul::QTensor(wq), Embeddings::Rows(embd)) = (&weights.layers[0].wq, &weights.embeddings) else {
+                panic!("weights were not loaded in place");
+            };
+            for t in [wq.as_ref(), embd] {
+                assert!(bytes.contains(&t.data().unwrap().as_ptr()));
+            }
+
+            let mut cache = model.new_cache(16);
+            logits.push(model.forward(&tokens, &[0, 1, 2], cache.as_mut()).unwrap().data().to_vec());
+        }
+        assert_eq!(logits[0], logits[1]);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_writing_mapped_blocks_copies_them() {
+        use crate::core::model::architectures::transformer::tests::write_model;
+
+        let path = std::env::temp_dir().join(format!("ollama-llama-mapped-write-{}.gguf", std::process::id()));
+        write_model(&path);
+        let shard = Arc::new(MappedGguf::open(&path, true, false).unwrap());
+        let content = read_content(&[shard.bytes()]).unwrap();
+        let start = (content.tensor_data_offset + content.tensor_infos["blk.0.attn_norm.weight"].offset) as usize;
+        let data = &shard.bytes()[start..start + 16 * 4];
+        let before = data.to_vec();
+
+        let mut blocks = MappedBlocks::storage(&shard, data, GgmlDType::F32).unwrap();
+        blocks.from_float(&[2.0; 16]);
+        let candle_core::CpuStorage::F32(values) = blocks.dequantize(16).unwrap() else { unreachable!() };
+        assert_eq!(values, vec![2.0; 16]);
+        assert!(!shard.bytes().as_ptr_range().contains(&blocks.as_ptr()));
+        assert_eq!(&shard.bytes()[start..start + 16 * 4], before.as_slice());
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_read_content_across_shards() {
+        use crate::infra::gguf::{GgmlType, GgufMetadata, GgufWriter, MetadataValue, ValueType};
+
+        let shards: Vec<Vec<u8>> = [("a", [1f32, 2.]), ("b", [3., 4.])]
+            .iter()
+            .enumerate()
+            .map(|(i, (name, values))| {
+                let mut metadata = GgufMetadata::new();
+                metadata.set("split.no", MetadataValue::Uint(i as u64), ValueType::U16);
+                metadata.set("split.count", MetadataValue::Uint(2), ValueType::U16);
+                let mut writer = GgufWriter::new(metadata);
+                let data = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                writer.add_tensor(name, vec![2], GgmlType::F32, data).unwrap();
+                let mut out = Vec::new();
+                writer.write(&mut out).unwrap();
+                out
+            })
+            .collect();
+        let slices: Vec<&[u8]> = shards.iter().map(|s| s.as_slice()).collect();
+
+        let content = read_content(&slices).unwrap();
+        let mut reader = PatchedReader::new(slices);
+        let cpu = Device::Cpu;
+        for (name, expected) in [("a", [1f32, 2.]), ("b", [3., 4.])] {
+            let t = content.tensor(&mut reader, name, &cpu).unwrap().dequantize(&cpu).unwrap();
+            assert_eq!(t.to_vec1::<f32>().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_cache_matches_candle_and_forks() {
+        use crate::core::model::architectures::transformer::tests::{write_model, VOCAB};
+        use crate::core::model::Model;
+        use candle_transformers::models::quantized_llama::ModelWeights;
+
+        let path = std::env::temp_dir().join(format!("ollama-llama-cache-{}.gguf", std::process::id()));
+        write_model(&path);
+        let mut file = std::fs::File::open(&path).unwrap();
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let mut candle = ModelWeights::from_gguf(content, &mut file, &Device::Cpu).unwrap();
+
+        let split = SplitGguf::open(&[&path], false, false).unwrap();
+        let mut model = LlamaModel::load(path.to_str().unwrap(), split.file().metadata.to_model_config()).unwrap();
+        let run = |model: &mut LlamaModel, cache: &mut dyn KVCache, tokens: &[i32]| {
+            let ids: Vec<TokenId> = tokens.iter().map(|&t| TokenId(t)).collect();
+            let positions: Vec<usize> = (cache.len()..cache.len() + ids.len()).collect();
+            model.forward(&ids, &positions, cache).unwrap().data().to_vec()
+        };
+        let close = |a: &[f32], b: &[f32]| a.len() == b.len() && a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-4);
+
+        let mut cache = model.new_cache(64);
+        let mut pos = 0;
+        for tokens in [&[1, 5, 3, 7, 30][..], &[12], &[4]] {
+            let input: Vec<u32> = tokens.iter().map(|&t| t as u32).collect();
+            let input = candle_core::Tensor::new(input.as_slice(), &Device::Cpu).unwrap().unsqueeze(0).unwrap();
+            let expected = candle.forward(&input, pos).unwrap().squeeze(0).unwrap().to_vec1::<f32>().unwrap();
+            let logits = run(&mut model, cache.as_mut(), tokens);
+            assert_eq!(logits.len(), VOCAB);
+            assert!(close(&logits, &expected), "position {}", pos);
+            pos += tokens.len();
+        }
+
+        // A fork continues independently of the original.
+        let mut fork = cache.clone_cache();
+        let a = run(&mut model, cache.as_mut(), &[9]);
+        let b = run(&mut model, fork.as_mut(), &[9]);
+        assert!(close(&a, &b));
+        assert_eq!((cache.len(), fork.len()), (pos + 1, pos + 1));
+
+        // Truncating to the prompt and replaying its tail reproduces the first
+        // step, with the rest of the prompt attended through the cache.
+        let mut fresh = model.new_cache(64);
+        let prompt = run(&mut model, fresh.as_mut(), &[1, 5, 3, 7, 30]);
+        cache.truncate(3).unwrap();
+        assert!(close(&run(&mut model, cache.as_mut(), &[7, 30]), &prompt));
+        assert!(cache.truncate(10).is_err());
+
+        cache.reset();
+        assert_eq!(cache.len(), 0);
+        assert!(close(&run(&mut model, cache.as_mut(), &[1, 5, 3, 7, 30]), &prompt));
+
+        // Positions that don't continue the cache are rejected.
+        assert!(model.forward(&[TokenId(1)], &[0], cache.as_mut()).is_err());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_experts_match_candle() {
+        use crate::core::model::architectures::transformer::tests::{write_model_with_experts, VOCAB};
+        use crate::core::model::Model;
+        use candle_transformers::models::quantized_llama::ModelWeights;
+
+        let path = std::env::temp_dir().join(format!("ollama-llama-moe-{}.gguf", std::process::id()));
+        write_model_with_experts(&path, 4);
+        let mut file = std::fs::File::open(&path).unwrap();
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let mut candle = ModelWeights::from_gguf(content, &mut file, &Device::Cpu).unwrap();
+
+        let split = SplitGguf::open(&[&path], false, false).unwrap();
+        let mut model = LlamaModel::load(path.to_str().unwrap(), split.file().metadata.to_model_config()).unwrap();
+        assert!(model.shared.weights.layers.iter().all(|l| matches!(&l.ffn, FeedForward::Experts { used: 2, experts, .. } if experts.len() == 4)));
+
+        let mut cache = model.new_cache(64);
+        let mut pos = 0;
+        for tokens in [&[1, 5, 3, 7, 30, 2, 19][..], &[12], &[4]] {
+            let input: Vec<u32> = tokens.iter().map(|&t| t as u32).collect();
+            let input = candle_core::Tensor::new(input.as_slice(), &Device::Cpu).unwrap().unsqueeze(0).unwrap();
+            let expected = candle.forward(&input, pos).unwrap().squeeze(0).unwrap().to_vec1::<f32>().unwrap();
+            let ids: Vec<TokenId> = tokens.iter().map(|&t| TokenId(t)).collect();
+            let positions: Vec<usize> = (pos..pos + ids.len()).collect();
+            let logits = model.forward(&ids, &positions, cache.as_mut()).unwrap().data().to_vec();
+            assert_eq!(logits.len(), VOCAB);
+            assert!(logits.iter().zip(&expected).all(|(a, b)| (a - b).abs() < 1e-4), "position {}", pos);
+            pos += tokens.len();
+        }
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_forward_batch_matches_sequential() {
+        use crate::core::model::architectures::transformer::tests::{write_model, VOCAB};
+        use crate::core::model::Model;
+
+        let path = std::env::temp_dir().join(format!("ollama-llama-batch-{}.gguf", std::process::id()));
+        write_model(&path);
+        let split = SplitGguf::open(&[&path], false, false).unwrap();
+        let mut model = LlamaModel::load(path.to_str().unwrap(), split.file().metadata.to_model_config()).unwrap();
+        let ids = |v: &[i32]| v.iter().map(|&t| TokenId(t)).collect::<Vec<_>>();
+
+        // Sequence a is mid-decode, b arrives with its whole prompt.
+        let (mut a, mut b) = (model.new_cache(64), model.new_cache(64));
+        model.forward(&ids(&[1, 5, 3]), &[0, 1, 2], a.as_mut()).unwrap();
+        let (mut a2, mut b2) = (a.clone_cache(), b.clone_cache());
+
+        let batch = ModelBatch::new(vec![ids(&[7]), ids(&[2, 9, 4, 11])], vec![vec![3], vec![0, 1, 2, 3]]);
+        let logits = model.forward_batch(&batch, &mut [a.as_mut(), b.as_mut()]).unwrap();
+        assert_eq!(logits.shape().dims(), &[2, VOCAB]);
+
+        let expected_a = model.forward(&ids(&[7]), &[3], a2.as_mut()).unwrap();
+        let expected_b = model.forward(&ids(&[2, 9, 4, 11]), &[0, 1, 2, 3], b2.as_mut()).unwrap();
+        let expected: Vec<f32> = expected_a.data().iter().chain(expected_b.data()).copied().collect();
+        for (x, y) in logits.data().iter().zip(&expected) {
+            assert!((x - y).abs() < 1e-4, "{} vs {}", x, y);
+        }
+        assert_eq!((a.len(), b.len()), (4, 4));
+
+        // Both sequences continue from their own positions.
+        let batch = ModelBatch::new(vec![ids(&[8]), ids(&[8])], vec![vec![4], vec![4]]);
+        let logits = model.forward_batch(&batch, &mut [a.as_mut(), b.as_mut()]).unwrap();
+        let next_a = model.forward(&ids(&[8]), &[4], a2.as_mut()).unwrap();
+        for (x, y) in logits.data()[..VOCAB].iter().zip(next_a.data()) {
+            assert!((x - y).abs() < 1e-4);
+        }
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_paged_cache_matches_llama_cache() {
+        use crate::core::model::architectures::transformer::tests::write_model;
+        use crate::core::model::Model;
+        use crate::core::cache::KvCacheType;
+
+        let path = std::env::temp_dir().join(format!("ollama-llama-paged-{}.gguf", std::process::id()));
+        write_model(&path);
+        let config = SplitGguf::open(&[&path], false, false).unwrap().file().metadata.to_model_config();
+        let mut reference = LlamaModel::load(path.to_str().unwrap(), config.clone()).unwrap();
+        let options = LoadOptions {
+            kv_cache: Some(PagedCacheConfig { block_size: 2, memory_budget: 1 << 20, cache_type: KvCacheType::F32 }),
+            ..Default::default()
+        };
+        let mut paged = LlamaModel::load_with_options(path.to_str().unwrap(), config, &options).unwrap();
+        let ids = |v: &[i32]| v.iter().map(|&t| TokenId(t)).collect::<Vec<_>>();
+
+        let mut expected_cache = reference.new_cache(64);
+        let mut cache = paged.new_cache(64);
+        assert!(cache.as_any_mut().downcast_mut::<PagedKVCache>().is_some());
+        let mut pos = 0;
+        for tokens in [&[1, 5, 3, 7, 30][..], &[12], &[4]] {
+            let positions: Vec<usize> = (pos..pos + tokens.len()).collect();
+            let expected = reference.forward(&ids(tokens), &positions, expected_cache.as_mut()).unwrap();
+            let logits = paged.forward(&ids(tokens), &positions, cache.as_mut()).unwrap();
+            for (x, y) in logits.data().iter().zip(expected.data()) {
+                assert!((x - y).abs() < 1e-4, "{} vs {}", x, y);
+            }
+            pos += tokens.len();
+        }
+
+        // A fork shares the prompt's blocks and diverges from the parent.
+        let mut fork = cache.clone_cache();
+        fork.truncate(5).unwrap();
+        let pool = paged.kv_pool().unwrap().clone();
+        let used = pool.stats().used_blocks;
+        let a = paged.forward(&ids(&[9]), &[5], fork.as_mut()).unwrap();
+        expected_cache.truncate(5).unwrap();
+        let b = reference.forward(&ids(&[9]), &[5], expected_cache.as_mut()).unwrap();
+        for (x, y) in a.data().iter().zip(b.data()) {
+            assert!((x - y).abs() < 1e-4);
+        }
+        assert_eq!(pool.stats().used_blocks, used + 1);
+
+        drop((cache, fork));
+        assert_eq!(pool.stats().used_blocks, 0);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_quantized_cache_logits_drift() {
+        use crate::core::cache::KvCacheType;
+        use crate::core::model::architectures::transformer::tests::write_model;
+        use crate::core::model::Model;
+
+        let path = std::env::temp_dir().join(format!("ollama-llama-kv-quant-{}.gguf", std::process::id()));
+        write_model(&path);
+        let config = SplitGguf::open(&[&path], false, false).unwrap().file().metadata.to_model_config();
+        let prompt: Vec<TokenId> = [1, 5, 3, 7, 30, 12, 4, 9, 21, 2, 17, 8].iter().map(|&t| TokenId(t)).collect();
+
+        // Logits for the prompt then two decode steps, which read the
+        // prompt's K/V back from the cache.
+        let run = |cache_type| {
+            let options = LoadOptions {
+                kv_cache: Some(PagedCacheConfig { block_size: 4, memory_budget: 1 << 20, cache_type }),
+                ..Default::default()
+            };
+            let mut model = LlamaModel::load_with_options(path.to_str().unwrap(), config.clone(), &options).unwrap();
+            let mut cache = model.new_cache(64);
+            let positions: Vec<usize> = (0..prompt.len()).collect();
+            let mut logits = model.forward(&prompt, &positions, cache.as_mut()).unwrap().data().to_vec();
+            for (i, token) in [TokenId(6), TokenId(11)].into_iter().enumerate() {
+                let pos = prompt.len() + i;
+                logits.extend_from_slice(model.forward(&[token], &[pos], cache.as_mut()).unwrap().data());
+            }
+            logits
+        };
+        let drift = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| (x - y).abs()).fold(0.0f32, f32::max);
+
+        let reference = run(KvCacheType::F32);
+        let scale = reference.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+        let f16 = drift(&run(KvCacheType::F16), &reference);
+        let q8 = drift(&run(KvCacheType::Q8_0), &reference);
+        let q4 = drift(&run(KvCacheType::Q4_0), &reference);
+        std::fs::remove_file(&path).ok();
+
+        assert!(f16 <= 1e-3 * scale, "f16 drift {} (logits up to {})", f16, scale);
+        assert!(q8 <= 2e-2 * scale, "q8_0 drift {} (logits up to {})", q8, scale);
+        assert!(q4 <= 0.2 * scale, "q4_0 drift {} (logits up to {})", q4, scale);
+        assert!(f16 <= q8 && q8 < q4, "drift should grow with coarser types: {} {} {}", f16, q8, q4);
+    }
+
+    #[test]
+    fn test_memory_estimate_matches_pool_allocation() {
+        use crate::core::cache::KvCacheType;
+        use crate::core::model::architectures::transformer::tests::write_model;
+        use crate::core::model::Model;
+
+        let path = std::env::temp_dir().join(format!("ollama-llama-kv-estimate-{}.gguf", std::process::id()));
+        write_model(&path);
+        let file = crate::gguf::GgufFile::open(&path).unwrap();
+        let config = SplitGguf::open(&[&path], false, false).unwrap().file().metadata.to_model_config();
+        let prompt: Vec<TokenId> = (0..64).map(|t| TokenId(t % 32)).collect();
+        let positions: Vec<usize> = (0..64).collect();
+
+        // A pool sized by the estimate for two sequences of the whole
+        // context holds exactly them, and the blocks are all the K/V there is.
+        for cache_type in [KvCacheType::F32, KvCacheType::F16, KvCacheType::Q8_0, KvCacheType::Q4_0] {
+            let estimate = file.estimate_memory_usage(0, cache_type, 64, 2).kv_cache as usize;
+            let options = LoadOptions {
+                kv_cache: Some(PagedCacheConfig { block_size: 16, memory_budget: estimate, cache_type }),
+                ..Default::default()
+            };
+            let mut model = LlamaModel::load_with_options(path.to_str().unwrap(), config.clone(), &options).unwrap();
+            let mut caches = [model.new_cache(64), model.new_cache(64)];
+            for cache in &mut caches {
+                model.forward(&prompt, &positions, cache.as_mut()).unwrap();
+            }
+            let stats = model.kv_pool().unwrap().stats();
+            assert_eq!(stats.allocated_blocks, stats.total_blocks);
+            assert_eq!(stats.allocated_blocks * stats.block_bytes, estimate, "{}", cache_type.name());
+            assert!(model.new_cache(64).reserve(1).is_err());
+        }
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_decode_appends_without_copying_the_prefix() {
+        // 300 single-position steps. The layer's tensors are only replaced
+        // when they run out of room, which doubling makes five times (16, 32,
+        // 64, 128, 256, 512 positions), not once a step.
+        let mut cache = LlamaCache::new(1, 512, Device::Cpu);
+        let storage = |t: &candle_core::Tensor| std::ptr::from_ref(&*t.storage_and_layout().0) as usize;
+        let (mut moves, mut last) = (0, None);
+        let mut rows = Vec::new();
+        for pos in 0..300 {
+            let row: Vec<f32> = (0..64).map(|i| ((pos * 64 + i) % 97) as f32 / 97.0).collect();
+            let k = candle_core::Tensor::from_vec(row.clone(), (1, 2, 1, 32), &Device::Cpu).unwrap();
+            let (k, _) = cache.append(0, &k, &k).unwrap();
+            assert_eq!(k.dim(2).unwrap(), pos + 1);
+            moves += usize::from(last.is_some_and(|last| last != storage(&k)));
+            last = Some(storage(&k));
+            rows.push(row);
+        }
+        assert_eq!(moves, 5);
+        assert_eq!(cache.get(0).unwrap().key.data(), rows.concat().as_slice());
+    }
+}
@@ -0,0 +1,466 @@
+use crate::core::model::{Attention, FeedForward, Model, ModelBatch, ModelConfig, ModelLayer, ModelMeta, Normalization};
+use crate::core::tensor::kernels::{self, RopeStyle};
+use crate::core::tensor::Shape;
+use crate::core::{KVCache, Result, Tensor, TensorOps, TokenId};
+use crate::infra::gguf::{split, SplitGguf};
+use rayon::prelude::*;
+
+// A llama-style decoder built from the crate's own layers and tensor ops.
+// Weights are dequantized to f32 at load, so this is the reference to check
+// other backends against rather than the fast path.
+pub struct TransformerModel {
+    config: ModelConfig,
+    meta: ModelMeta,
+    token_embd: Tensor,
+    blocks: Vec<TransformerBlock>,
+    norm: RmsNorm,
+    output: Tensor,
+}
+
+pub struct RmsNorm {
+    weight: Tensor,
+    eps: f32,
+}
+
+impl RmsNorm {
+    pub fn new(weight: Tensor, eps: f32) -> Self {
+        Self { weight, eps }
+    }
+}
+
+impl Normalization for RmsNorm {
+    fn forward(&mut self, hidden: &Tensor) -> Result<Tensor> {
+        hidden.rms_norm(&self.weight, self.eps)
+    }
+
+    fn epsilon(&self) -> f32 {
+        self.eps
+    }
+}
+
+// Grouped-query self-attention with rotary positions. Projection weights are
+// [out, in] as stored in GGUF.
+pub struct SelfAttention {
+    layer: usize,
+    wq: Tensor,
+    wk: Tensor,
+    wv: Tensor,
+    wo: Tensor,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    rope_dim: usize,
+    rope_theta: f32,
+}
+
+impl SelfAttention {
+    // Projects to [seq, heads, head_dim] and rotates by position.
+    fn project(&self, x: &Tensor, w: &Tensor, heads: usize, positions: Option<&[usize]>) -> Result<Tensor> {
+        let mut t = x.matmul_t(w)?;
+        let row_len = heads * self.head_dim;
+        if let Some(positions) = positions {
+            for (row, &pos) in t.data_mut().chunks_mut(row_len).zip(positions) {
+                kernels::rope(row, self.head_dim, self.rope_dim, pos, self.rope_theta, RopeStyle::Normal);
+            }
+        }
+        t.reshape(Shape::new(vec![x.shape().dims()[0], heads, self.head_dim]))
+    }
+}
+
+impl Attention for SelfAttention {
+    // query, key and value are the normalized hidden states, [seq, dim]; the
+    // new keys and values are appended to `cache` when one is given.
+    fn forward(
+        &mut self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        positions: &[usize],
+        cache: Option<&mut dyn KVCache>,
+    ) -> Result<Tensor> {
+        let seq = positions.len();
+        let q = self.project(query, &self.wq, self.n_head, Some(positions))?;
+        let k = self.project(key, &self.wk, self.n_kv_head, Some(positions))?;
+        let v = self.project(value, &self.wv, self.n_kv_head, None)?;
+
+        let (k, v) = match cache {
+            Some(cache) => cache.update(self.layer, &k, &v)?,
+            None => (k, v),
+        };
+        // Cached keys before this step sit at positions 0..past.
+        let total = k.shape().dims()[0];
+        let past = total.checked_sub(seq).ok_or_else(|| anyhow::anyhow!("KV cache shorter than the input"))?;
+        let key_pos = |t: usize| if t < past { t } else { positions[t - past] };
+
+        let (hd, group) = (self.head_dim, self.n_head / self.n_kv_head);
+        let scale = 1.0 / (hd as f32).sqrt();
+        let (q, k, v) = (q.data(), k.data(), v.data());
+        let mut out = vec![0.0; seq * self.n_head * hd];
+        out.par_chunks_mut(self.n_head * hd).enumerate().for_each(|(i, out_row)| {
+            let mut scores = vec![0.0; total];
+            for h in 0..self.n_head {
+                let kv = h / group;
+                let q_h = &q[(i * self.n_head + h) * hd..][..hd];
+                for (t, s) in scores.iter_mut().enumerate() {
+                    *s = if key_pos(t) > positions[i] {
+                        f32::NEG_INFINITY
+                    } else {
+                        kernels::dot(q_h, &k[(t * self.n_kv_head + kv) * hd..][..hd]) * scale
+                    };
+                }
+                kernels::softmax(&mut scores);
+                let o = &mut out_row[h * hd..][..hd];
+                for (t, &p) in scores.iter().enumerate() {
+                    if p > 0.0 {
+                        for (o, &v) in o.iter_mut().zip(&v[(t * self.n_kv_head + kv) * hd..][..hd]) {
+                            *o += p * v;
+                        }
+                    }
+                }
+            }
+        });
+
+        Tensor::new(out, Shape::new(vec![seq, self.n_head * hd])).matmul_t(&self.wo)
+    }
+
+    fn head_count(&self) -> usize {
+        self.n_head
+    }
+
+    fn head_dim(&self) -> usize {
+        self.head_dim
+    }
+}
+
+// down(silu(gate(x)) * up(x)).
+pub struct SwiGlu {
+    gate: Tensor,
+    up: Tensor,
+    down: Tensor,
+}
+
+impl FeedForward for SwiGlu {
+    fn forward(&mut self, hidden: &Tensor) -> Result<Tensor> {
+        let gate = hidden.matmul_t(&self.gate)?.silu()?;
+        let up = hidden.matmul_t(&self.up)?;
+        gate.try_mul(&up)?.matmul_t(&self.down)
+    }
+
+    fn hidden_dim(&self) -> usize {
+        self.gate.shape().dims()[0]
+    }
+}
+
+// Pre-norm attention and feed-forward, each with a residual connection.
+pub struct TransformerBlock {
+    name: String,
+    attn_norm: RmsNorm,
+    attn: SelfAttention,
+    ffn_norm: RmsNorm,
+    ffn: SwiGlu,
+}
+
+impl ModelLayer for TransformerBlock {
+    fn forward(
+        &mut self,
+        hidden: &Tensor,
+        positions: &[usize],
+        cache: Option<&mut dyn KVCache>,
+    ) -> Result<Tensor> {
+        let x = self.attn_norm.forward(hidden)?;
+        let hidden = self.attn.forward(&x, &x, &x, positions, cache)? + hidden;
+        let x = self.ffn_norm.forward(&hidden)?;
+        Ok(self.ffn.forward(&x)? + &hidden)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn param_count(&self) -> usize {
+        let a = &self.attn;
+        let f = &self.ffn;
+        [&self.attn_norm.weight, &a.wq, &a.wk, &a.wv, &a.wo, &self.ffn_norm.weight, &f.gate, &f.up, &f.down]
+            .iter()
+            .map(|t| t.numel())
+            .sum()
+    }
+}
+
+impl TransformerModel {
+    pub fn load(model_path: &str, config: ModelConfig) -> Result<Self> {
+        let model = SplitGguf::open(&split::shard_paths(model_path)?, true, false)?;
+        Self::from_gguf(&model, config)
+    }
+
+    pub fn from_gguf(model: &SplitGguf, config: ModelConfig) -> Result<Self> {
+        let token_embd = model.tensor("token_embd.weight")?;
+        let (vocab, dim) = (token_embd.shape().dims()[0], token_embd.shape().dims()[1]);
+
+        let n_head = config.num_heads.max(1);
+        let n_kv_head = if config.num_kv_heads == 0 { n_head } else { config.num_kv_heads };
+        if !n_head.is_multiple_of(n_kv_head) {
+            anyhow::bail!("{} attention heads cannot share {} KV heads", n_head, n_kv_head);
+        }
+        let head_dim = dim / n_head;
+        let rope_dim = config
+            .get::<u64>(&format!("{}.rope.dimension_count", config.architecture))
+            .map_or(head_dim, |d| d as usize);
+        let rope_theta = if config.rope_theta > 0.0 { config.rope_theta } else { 10000.0 };
+        let eps = if config.norm_eps > 0.0 { config.norm_eps } else { 1e-5 };
+
+        let blocks = (0..config.num_layers)
+            .map(|i| {
+                let t = |name: &str| model.tensor(&format!("blk.{}.{}.weight", i, name));
+                Ok(TransformerBlock {
+                    name: format!("blk.{}", i),
+                    attn_norm: RmsNorm::new(t("attn_norm")?, eps),
+                    attn: SelfAttention {
+                        layer: i,
+                        wq: t("attn_q")?,
+                        wk: t("attn_k")?,
+                        wv: t("attn_v")?,
+                        wo: t("attn_output")?,
+                        n_head,
+                        n_kv_head,
+                        head_dim,
+                        rope_dim,
+                        rope_theta,
+                    },
+                    ffn_norm: RmsNorm::new(t("ffn_norm")?, eps),
+                    ffn: SwiGlu { gate: t("ffn_gate")?, up: t("ffn_up")?, down: t("ffn_down")? },
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Tied embeddings when there is no separate output matrix.
+        let output = model.tensor("output.weight").unwrap_or_else(|_| token_embd.clone());
+        let norm = RmsNorm::new(model.tensor("output_norm.weight")?, eps);
+
+        let meta = ModelMeta {
+            name: config.architecture.clone(),
+            architecture: config.architecture.clone(),
+            parameter_count: (token_embd.numel() + output.numel() + blocks.iter().map(|b| b.param_count()).sum::<usize>()) as u64,
+            context_length: config.context_length,
+            vocab_size: vocab,
+            quantization: None,
+        };
+
+        Ok(Self { config, meta, token_embd, blocks, norm, output })
+    }
+
+    pub fn layers(&self) -> &[TransformerBlock] {
+        &self.blocks
+    }
+
+    // The embeddings of each token, [seq, dim].
+    fn embed_tokens(&self, tokens: &[TokenId]) -> Result<Tensor> {
+        let (vocab, dim) = (self.token_embd.shape().dims()[0], self.token_embd.shape().dims()[1]);
+        let mut data = Vec::with_capacity(tokens.len() * dim);
+        for t in tokens {
+            let id = usize::try_from(t.0).ok().filter(|&id| id < vocab);
+            let id = id.ok_or_else(|| anyhow::anyhow!("Token {} outside the vocabulary of {}", t.0, vocab))?;
+            data.extend_from_slice(&self.token_embd.data()[id * dim..][..dim]);
+        }
+        Ok(Tensor::new(data, Shape::new(vec![tokens.len(), dim])))
+    }
+
+    // Runs every block and returns the final hidden states, [seq, dim], along
+    // with the output of each block for inspection.
+    pub fn forward_traced(
+        &mut self,
+        tokens: &[TokenId],
+        positions: &[usize],
+        cache: &mut dyn KVCache,
+    ) -> Result<(Tensor, Vec<Tensor>)> {
+        if tokens.len() != positions.len() {
+            anyhow::bail!("{} tokens but {} positions", tokens.len(), positions.len());
+        }
+        let mut hidden = self.embed_tokens(tokens)?;
+        let mut trace = Vec::with_capacity(self.blocks.len());
+        for block in &mut self.blocks {
+            hidden = block.forward(&hidden, positions, Some(&mut *cache))?;
+            trace.push(hidden.clone());
+        }
+        Ok((hidden, trace))
+    }
+}
+
+impl Model for TransformerModel {
+    // Logits for the last input token, [vocab].
+    fn forward(
+        &mut self,
+        input: &[TokenId],
+        positions: &[usize],
+        cache: &mut dyn KVCache,
+    ) -> Result<Tensor> {
+        let (hidden, _) = self.forward_traced(input, positions, cache)?;
+        let last = hidden.rows(input.len().saturating_sub(1)..input.len())?;
+        let logits = self.logits(&last)?;
+        logits.reshape(Shape::new(vec![logits.numel()]))
+    }
+
+    // The reference path runs the sequences one after another.
+    fn forward_batch(&mut self, batch: &ModelBatch, caches: &mut [&mut dyn KVCache]) -> Result<Tensor> {
+        if caches.len() != batch.batch_size() || batch.positions.len() != batch.batch_size() {
+            anyhow::bail!("forward_batch needs one cache and position list per sequence");
+        }
+        let mut logits = Vec::new();
+        for ((tokens, positions), cache) in batch.tokens.iter().zip(&batch.positions).zip(caches.iter_mut()) {
+            logits.extend_from_slice(self.forward(tokens, positions, &mut **cache)?.data());
+        }
+        Ok(Tensor::new(logits, Shape::new(vec![batch.batch_size(), self.meta.vocab_size])))
+    }
+
+    fn config(&self) -> &ModelConfig {
+        &self.config
+    }
+
+    fn meta(&self) -> &ModelMeta {
+        &self.meta
+    }
+
+    // Mean of the token embeddings, as LlamaModel does.
+    fn embed(&self, tokens: &[TokenId]) -> Result<Tensor> {
+        let embedded = self.embed_tokens(tokens)?;
+        let dim = self.token_embd.shape().dims()[1];
+        let mut mean = vec![0.0; dim];
+        for row in embedded.data().chunks(dim) {
+            mean.iter_mut().zip(row).for_each(|(m, &x)| *m += x / tokens.len() as f32);
+        }
+        Ok(Tensor::new(mean, Shape::new(vec![dim])))
+    }
+
+    // [seq, dim] hidden states to [seq, vocab] logits.
+    fn logits(&self, hidden: &Tensor) -> Result<Tensor> {
+        hidden.rms_norm(&self.norm.weight, self.norm.eps)?.matmul_t(&self.output)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::core::cache::CausalKVCache;
+    use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+    use candle_core::Device;
+    use candle_transformers::models::quantized_llama::ModelWeights;
+
+    pub(crate) const VOCAB: usize = 32;
+    pub(crate) const DIM: usize = 16;
+    const FFN: usize = 24;
+    pub(crate) const LAYERS: usize = 2;
+
+    // Deterministic weights in [-0.5, 0.5).
+    fn weights(dims: &[usize], seed: usize) -> candle_core::Tensor {
+        let numel: usize = dims.iter().product();
+        let data: Vec<f32> = (0..numel)
+            .map(|i| ((i * 7919 + seed * 104729) % 1000) as f32 / 1000.0 - 0.5)
+            .collect();
+        candle_core::Tensor::from_vec(data, dims, &Device::Cpu).unwrap()
+    }
+
+    // Four query heads sharing two KV heads, so grouped attention is covered.
+    pub(crate) fn write_model(path: &std::path::Path) {
+        write_model_with_experts(path, 0);
+    }
+
+    // With `experts` > 1 every layer's feed-forward becomes a Mixtral-style
+    // mixture that routes each token to two of them.
+    pub(crate) fn write_model_with_experts(path: &std::path::Path, experts: usize) {
+        let ffn: Vec<String> = match experts {
+            0 | 1 => vec![String::new()],
+            n => (0..n).map(|e| format!(".{}", e)).collect(),
+        };
+        let mut tensors = vec![
+            ("token_embd.weight".to_string(), weights(&[VOCAB, DIM], 1)),
+            ("output_norm.weight".to_string(), weights(&[DIM], 2).affine(0.5, 1.0).unwrap()),
+            ("output.weight".to_string(), weights(&[VOCAB, DIM], 3)),
+        ];
+        for i in 0..LAYERS {
+            let seed = 10 * (i + 1);
+            for (name, dims) in [
+                ("attn_norm", vec![DIM]),
+                ("attn_q", vec![DIM, DIM]),
+                ("attn_k", vec![DIM / 2, DIM]),
+                ("attn_v", vec![DIM / 2, DIM]),
+                ("attn_output", vec![DIM, DIM]),
+                ("ffn_norm", vec![DIM]),
+            ] {
+                let mut w = weights(&dims, seed + tensors.len());
+                if dims.len() == 1 {
+                    w = w.affine(0.5, 1.0).unwrap();
+                }
+                tensors.push((format!("blk.{}.{}.weight", i, name), w));
+            }
+            if experts > 1 {
+                tensors.push((format!("blk.{}.ffn_gate_inp.weight", i), weights(&[experts, DIM], seed + tensors.len())));
+            }
+            for suffix in &ffn {
+                for (name, dims) in [("ffn_gate", [FFN, DIM]), ("ffn_up", [FFN, DIM]), ("ffn_down", [DIM, FFN])] {
+                    let w = weights(&dims, seed + tensors.len());
+                    tensors.push((format!("blk.{}.{}{}.weight", i, name, suffix), w));
+                }
+            }
+        }
+        let qtensors: Vec<(String, QTensor)> = tensors
+            .iter()
+            .map(|(n, t)| (n.clone(), QTensor::quantize(t, GgmlDType::F32).unwrap()))
+            .collect();
+
+        let u32 = gguf_file::Value::U32;
+        let metadata = [
+            ("general.architecture", gguf_file::Value::String("llama".into())),
+            ("llama.embedding_length", u32(DIM as u32)),
+            ("llama.feed_forward_length", u32(FFN as u32)),
+            ("llama.block_count", u32(LAYERS as u32)),
+            ("llama.attention.head_count", u32(4)),
+            ("llama.attention.head_count_kv", u32(2)),
+            ("llama.rope.dimension_count", u32((DIM / 4) as u32)),
+            ("llama.rope.freq_base", gguf_file::Value::F32(10000.0)),
+            ("llama.attention.layer_norm_rms_epsilon", gguf_file::Value::F32(1e-5)),
+            ("llama.context_length", u32(64)),
+            ("llama.expert_count", u32(experts as u32)),
+            ("llama.expert_used_count", u32(experts.min(2) as u32)),
+        ];
+        let metadata: Vec<(&str, &gguf_file::Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let refs: Vec<(&str, &QTensor)> = qtensors.iter().map(|(n, q)| (n.as_str(), q)).collect();
+        let mut file = std::fs::File::create(path).unwrap();
+        gguf_file::write(&mut file, &metadata, &refs).unwrap();
+    }
+
+    #[test]
+    fn test_logits_match_candle() {
+        let path = std::env::temp_dir().join(format!("ollama-reference-llama-{}.gguf", std::process::id()));
+        write_model(&path);
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let mut candle = ModelWeights::from_gguf(content, &mut file, &Device::Cpu).unwrap();
+
+        let split = SplitGguf::open(&[&path], false, false).unwrap();
+        let config = split.file().metadata.to_model_config();
+        let mut model = TransformerModel::from_gguf(&split, config).unwrap();
+        let mut cache = CausalKVCache::new(LAYERS, 2, DIM / 4, 64);
+
+        // A prompt, then two single-token decode steps against the cache.
+        let steps: [&[u32]; 3] = [&[1, 5, 3, 7, 30], &[12], &[4]];
+        let mut pos = 0;
+        for tokens in steps {
+            let input = candle_core::Tensor::new(tokens, &Device::Cpu).unwrap().unsqueeze(0).unwrap();
+            let expected = candle.forward(&input, pos).unwrap().squeeze(0).unwrap().to_vec1::<f32>().unwrap();
+
+            let ids: Vec<TokenId> = tokens.iter().map(|&t| TokenId(t as i32)).collect();
+            let positions: Vec<usize> = (pos..pos + tokens.len()).collect();
+            let logits = model.forward(&ids, &positions, &mut cache).unwrap();
+
+            assert_eq!(logits.shape().dims(), &[VOCAB]);
+            for (a, b) in logits.data().iter().zip(&expected) {
+                assert!((a - b).abs() < 1e-4, "position {}: {} vs {}", pos, a, b);
+            }
+            pos += tokens.len();
+        }
+        assert_eq!(cache.len(), pos);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,138 @@
+use crate::core::{Result, Tensor};
+use crate::infra::gguf::{MappedGguf, MetadataValue};
+use std::path::Path;
+use std::sync::Arc;
+
+// A GGUF LoRA adapter as written by llama.cpp's convert_lora_to_gguf:
+// `<weight>.lora_a` with dims [n_in, rank] and `<weight>.lora_b` with dims [rank, n_out].
+pub struct LoraAdapter {
+    mapping: Arc<MappedGguf>,
+    pub alpha: f32,
+    pub scale: f32,
+    pub targets: Vec<String>,
+}
+
+impl LoraAdapter {
+    pub fn load<P: AsRef<Path>>(path: P, scale: f32) -> Result<Self> {
+        let mapping = MappedGguf::open(path.as_ref(), true, false)?;
+        let metadata = &mapping.file.metadata;
+
+        let general_type = metadata.string("general.type");
+        if !general_type.is_empty() && general_type != "adapter" {
+            anyhow::bail!("{} is not an adapter (general.type = {})", path.as_ref().display(), general_type);
+        }
+        let adapter_type = metadata.string("adapter.type");
+        if !adapter_type.is_empty() && adapter_type != "lora" {
+            anyhow::bail!("Unsupported adapter type: {}", adapter_type);
+        }
+
+        let alpha = match metadata.get("adapter.lora.alpha") {
+            Some(MetadataValue::Float(f)) => *f as f32,
+            Some(MetadataValue::Uint(u)) => *u as f32,
+            Some(MetadataValue::Int(i)) => *i as f32,
+            _ => 0.0,
+        };
+
+        let mut targets = Vec::new();
+        for t in &mapping.file.tensors {
+            if let Some(base) = t.name.strip_suffix(".lora_a") {
+                if mapping.file.tensor(&format!("{}.lora_b", base)).is_none() {
+                    anyhow::bail!("LoRA tensor {} has no matching lora_b", t.name);
+                }
+                targets.push(base.to_string());
+            }
+        }
+        if targets.is_empty() {
+            anyhow::bail!("{} contains no LoRA tensors", path.as_ref().display());
+        }
+
+        Ok(Self {
+            mapping,
+            alpha,
+            scale,
+            targets,
+        })
+    }
+
+    // Returns (A, B, scale) for `weight` with A as [rank, n_in] and B as [n_out, rank],
+    // so the merged weight is W + scale * B·A.
+    pub fn factors(&self, weight: &str) -> Result<Option<(Tensor, Tensor, f32)>> {
+        if !self.targets.iter().any(|t| t == weight) {
+            return Ok(None);
+        }
+        let a = self.mapping.tensor(&format!("{}.lora_a", weight))?;
+        let b = self.mapping.tensor(&format!("{}.lora_b", weight))?;
+
+        let (a_dims, b_dims) = (a.shape().dims(), b.shape().dims());
+        if a_dims.len() != 2 || b_dims.len() != 2 || a_dims[0] != b_dims[1] {
+            anyhow::bail!("LoRA shapes for {} do not match: a {:?}, b {:?}", weight, a_dims, b_dims);
+        }
+
+        let rank = a_dims[0] as f32;
+        let scale = if self.alpha != 0.0 { self.scale * self.alpha / rank } else { self.scale };
+        Ok(Some((a, b, scale)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn write_tensor_info(buf: &mut Vec<u8>, name: &str, dims: &[u64], offset: u64) {
+        write_string(buf, name);
+        buf.extend_from_slice(&(dims.len() as u32).to_le_bytes());
+        for d in dims {
+            buf.extend_from_slice(&d.to_le_bytes());
+        }
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&offset.to_le_bytes());
+    }
+
+    #[test]
+    fn test_load_lora_factors() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(b"GGUF");
+        buf.extend_from_slice(&3u32.to_le_bytes());
+        buf.extend_from_slice(&2u64.to_le_bytes());
+        buf.extend_from_slice(&2u64.to_le_bytes());
+        write_string(&mut buf, "general.type");
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        write_string(&mut buf, "adapter");
+        write_string(&mut buf, "adapter.lora.alpha");
+        buf.extend_from_slice(&6u32.to_le_bytes());
+        buf.extend_from_slice(&2.0f32.to_le_bytes());
+        // rank 1, n_in 3, n_out 2
+        write_tensor_info(&mut buf, "blk.0.attn_q.weight.lora_a", &[3, 1], 0);
+        write_tensor_info(&mut buf, "blk.0.attn_q.weight.lora_b", &[1, 2], 32);
+        buf.resize(buf.len().next_multiple_of(32), 0);
+        let data_start = buf.len();
+        for v in [1.0f32, 2.0, 3.0] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.resize(data_start + 32, 0);
+        for v in [0.5f32, -1.0] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+
+        let path = std::env::temp_dir().join(format!("ollama-lora-{}.gguf", std::process::id()));
+        std::fs::File::create(&path).unwrap().write_all(&buf).unwrap();
+
+        let adapter = LoraAdapter::load(&path, 0.5).unwrap();
+        assert_eq!(adapter.targets, vec!["blk.0.attn_q.weight".to_string()]);
+        assert!(adapter.factors("output.weight").unwrap().is_none());
+
+        let (a, b, scale) = adapter.factors("blk.0.attn_q.weight").unwrap().unwrap();
+        assert_eq!(a.shape().dims(), &[1, 3]);
+        assert_eq!(b.shape().dims(), &[2, 1]);
+        assert_eq!(b.data(), &[0.5, -1.0]);
+        assert_eq!(scale, 1.0);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
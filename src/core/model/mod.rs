@@ -3,6 +3,7 @@ pub mod config;
 pub mod factory;
 pub mod registry;
 pub mod architectures;
+pub mod lora;
 
 pub use traits::*;
 pub use traits::ModelConfig;
@@ -122,7 +122,7 @@ mod tests {
         fn forward_batch(
             &mut self,
             _batch: &super::super::traits::ModelBatch,
-            _cache: &mut dyn crate::core::KVCache,
+            _caches: &mut [&mut dyn crate::core::KVCache],
         ) -> Result<crate::core::Tensor> {
             unimplemented!()
         }
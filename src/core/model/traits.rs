@@ -1,3 +1,4 @@
+use crate::core::cache::CausalKVCache;
 use crate::core::{Result, Tensor, KVCache, TokenId};
 use std::collections::HashMap;
 
@@ -9,14 +10,25 @@ pub trait Model: Send + Sync {
         cache: &mut dyn KVCache,
     ) -> Result<Tensor>;
     
+    // Evaluates several sequences in one pass, each against its own cache
+    // (`caches[i]` for `batch.tokens[i]`). Returns [batch, vocab] logits of
+    // each sequence's last token.
     fn forward_batch(
         &mut self,
         batch: &ModelBatch,
-        cache: &mut dyn KVCache,
+        caches: &mut [&mut dyn KVCache],
     ) -> Result<Tensor>;
     
     fn config(&self) -> &ModelConfig;
     fn meta(&self) -> &ModelMeta;
+
+    // A fresh cache for one sequence of up to `max_seq_len` positions, of the
+    // type `forward` expects.
+    fn new_cache(&self, max_seq_len: usize) -> Box<dyn KVCache> {
+        let config = self.config();
+        let kv_heads = if config.num_kv_heads == 0 { config.num_heads } else { config.num_kv_heads };
+        Box::new(CausalKVCache::new(config.num_layers, kv_heads, config.head_dim(), max_seq_len))
+    }
     
     fn embed(&self, tokens: &[TokenId]) -> Result<Tensor>;
     fn logits(&self, hidden: &Tensor) -> Result<Tensor>;
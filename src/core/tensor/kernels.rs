@@ -0,0 +1,156 @@
+// CPU kernels on row-major f32 slices. TensorOps builds on these, and model
+// code can call them directly on weights it already holds as slices.
+
+use rayon::prelude::*;
+
+// Block sizes: MC rows of A per task, KC of the shared dimension so a panel
+// of B stays in L2, NC columns of C per pass so a row of the panel stays in L1.
+const MC: usize = 32;
+const KC: usize = 256;
+const NC: usize = 512;
+
+// Below this many multiply-adds the work stays on the calling thread.
+const PARALLEL_THRESHOLD: usize = 1 << 15;
+
+// Dot product with independent accumulators so the loop vectorizes.
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let mut acc = [0.0f32; 8];
+    let (a_chunks, b_chunks) = (a.chunks_exact(8), b.chunks_exact(8));
+    let tail: f32 = a_chunks.remainder().iter().zip(b_chunks.remainder()).map(|(x, y)| x * y).sum();
+    for (x, y) in a_chunks.zip(b_chunks) {
+        for l in 0..8 {
+            acc[l] += x[l] * y[l];
+        }
+    }
+    acc.iter().sum::<f32>() + tail
+}
+
+// c[m, n] = a[m, k] * b[k, n].
+pub fn matmul(a: &[f32], b: &[f32], c: &mut [f32], m: usize, k: usize, n: usize) {
+    assert!(a.len() >= m * k && b.len() >= k * n && c.len() >= m * n, "matmul: buffer too small");
+    let c = &mut c[..m * n];
+    c.fill(0.0);
+    if n == 0 {
+        return;
+    }
+
+    let block = |(bi, c_block): (usize, &mut [f32])| {
+        let row0 = bi * MC;
+        let rows = c_block.len() / n;
+        for k0 in (0..k).step_by(KC) {
+            let k1 = (k0 + KC).min(k);
+            for j0 in (0..n).step_by(NC) {
+                let j1 = (j0 + NC).min(n);
+                for i in 0..rows {
+                    let a_row = &a[(row0 + i) * k..(row0 + i + 1) * k];
+                    let c_row = &mut c_block[i * n + j0..i * n + j1];
+                    for (l, &a_il) in a_row.iter().enumerate().take(k1).skip(k0) {
+                        let b_row = &b[l * n + j0..l * n + j1];
+                        for (c_ij, &b_lj) in c_row.iter_mut().zip(b_row) {
+                            *c_ij += a_il * b_lj;
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    if m * k * n < PARALLEL_THRESHOLD {
+        c.chunks_mut(MC * n).enumerate().for_each(block);
+    } else {
+        c.par_chunks_mut(MC * n).enumerate().for_each(block);
+    }
+}
+
+// c[m, n] = a[m, k] * bt[n, k]^T, for weights stored one output row at a time.
+pub fn matmul_t(a: &[f32], bt: &[f32], c: &mut [f32], m: usize, k: usize, n: usize) {
+    assert!(a.len() >= m * k && bt.len() >= k * n && c.len() >= m * n, "matmul_t: buffer too small");
+    let c = &mut c[..m * n];
+    if n == 0 {
+        return;
+    }
+
+    let block = |(bi, c_block): (usize, &mut [f32])| {
+        let row0 = bi * MC;
+        let rows = c_block.len() / n;
+        // A block of B rows is reused by every row of A in the block.
+        let nc = (NC * 8 / k.max(1)).clamp(1, NC);
+        for j0 in (0..n).step_by(nc) {
+            let j1 = (j0 + nc).min(n);
+            for i in 0..rows {
+                let a_row = &a[(row0 + i) * k..(row0 + i + 1) * k];
+                for j in j0..j1 {
+                    c_block[i * n + j] = dot(a_row, &bt[j * k..(j + 1) * k]);
+                }
+            }
+        }
+    };
+
+    if m * k * n < PARALLEL_THRESHOLD {
+        c.chunks_mut(MC * n).enumerate().for_each(block);
+    } else if m == 1 {
+        // Single-token decode: split the output columns instead of the rows.
+        let a = &a[..k];
+        c.par_chunks_mut(NC).enumerate().for_each(|(bj, c_cols)| {
+            let j0 = bj * NC;
+            for (j, c_j) in c_cols.iter_mut().enumerate() {
+                *c_j = dot(a, &bt[(j0 + j) * k..(j0 + j + 1) * k]);
+            }
+        });
+    } else {
+        c.par_chunks_mut(MC * n).enumerate().for_each(block);
+    }
+}
+
+// In-place softmax of one row: max, exp and sum in one pass, then scale.
+pub fn softmax(row: &mut [f32]) {
+    let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    // A fully masked row attends to nothing.
+    if max == f32::NEG_INFINITY {
+        row.fill(0.0);
+        return;
+    }
+    let mut sum = 0.0;
+    for x in row.iter_mut() {
+        *x = (*x - max).exp();
+        sum += *x;
+    }
+    let inv = 1.0 / sum;
+    row.iter_mut().for_each(|x| *x *= inv);
+}
+
+// out = x / rms(x) * weight, for one row.
+pub fn rms_norm(x: &[f32], weight: &[f32], eps: f32, out: &mut [f32]) {
+    let rms = (dot(x, x) / x.len() as f32 + eps).sqrt();
+    let scale = 1.0 / rms;
+    for ((o, &v), &w) in out.iter_mut().zip(x).zip(weight) {
+        *o = v * scale * w;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RopeStyle {
+    // Rotates adjacent pairs (x[2i], x[2i+1]); llama GGUFs are stored this way.
+    Normal,
+    // Rotates the two halves (x[i], x[i + d/2]), as in GPT-NeoX, Qwen and Phi.
+    NeoX,
+}
+
+// Rotary embedding of every head of one token at `pos`, in place. Only the
+// first `rot_dim` values of each head rotate.
+pub fn rope(x: &mut [f32], head_dim: usize, rot_dim: usize, pos: usize, theta: f32, style: RopeStyle) {
+    let half = rot_dim / 2;
+    for head in x.chunks_exact_mut(head_dim) {
+        for i in 0..half {
+            let freq = theta.powf(-2.0 * i as f32 / rot_dim as f32);
+            let (sin, cos) = (pos as f32 * freq).sin_cos();
+            let (a, b) = match style {
+                RopeStyle::Normal => (2 * i, 2 * i + 1),
+                RopeStyle::NeoX => (i, i + half),
+            };
+            let (x0, x1) = (head[a], head[b]);
+            head[a] = x0 * cos - x1 * sin;
+            head[b] = x0 * sin + x1 * cos;
+        }
+    }
+}
@@ -1,8 +1,11 @@
 pub mod ops;
+pub mod kernels;
 
 pub use ops::TensorOps;
+pub use kernels::RopeStyle;
 
 use crate::core::Result;
+use rayon::prelude::*;
 use std::ops::{Add, Mul, Sub, Div};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -81,8 +84,40 @@ impl Shape {
     pub fn dim(&self, idx: usize) -> Option<usize> {
         self.dims.get(idx).copied()
     }
+
+    // NumPy broadcasting: dims align from the right and each pair must be
+    // equal or contain a 1.
+    pub fn broadcast(&self, other: &Shape) -> Result<Shape> {
+        let len = self.len().max(other.len());
+        let dim = |s: &Shape, i: usize| (i + s.len()).checked_sub(len).map_or(1, |j| s.dims[j]);
+        let dims = (0..len)
+            .map(|i| match (dim(self, i), dim(other, i)) {
+                (a, b) if a == b || b == 1 => Ok(a),
+                (1, b) => Ok(b),
+                _ => anyhow::bail!("Cannot broadcast {:?} with {:?}", self.dims, other.dims),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Shape::new(dims))
+    }
+
+    // Row-major strides for reading this shape as `out`, which it broadcasts
+    // to: 0 along broadcast dims.
+    fn broadcast_strides(&self, out: &Shape) -> Vec<usize> {
+        let mut strides = vec![0; out.len()];
+        let mut stride = 1;
+        for (i, &d) in self.dims.iter().enumerate().rev() {
+            if d != 1 {
+                strides[out.len() - self.len() + i] = stride;
+            }
+            stride *= d;
+        }
+        strides
+    }
 }
 
+// Work below this many elements is not worth splitting across threads.
+const PARALLEL_ELEMENTS: usize = 1 << 16;
+
 #[derive(Debug, Clone)]
 pub struct Tensor {
     data: Vec<f32>,
@@ -172,6 +207,35 @@ impl Tensor {
         })
     }
     
+    // Rows `range` of the first dim.
+    pub fn rows(&self, range: std::ops::Range<usize>) -> Result<Self> {
+        let dims = self.shape.dims();
+        let rows = dims.first().copied().unwrap_or(0);
+        if range.start > range.end || range.end > rows {
+            anyhow::bail!("Rows {:?} out of range for {:?}", range, dims);
+        }
+        let row_len: usize = dims[1..].iter().product();
+        let mut new_dims = dims.to_vec();
+        new_dims[0] = range.len();
+        Ok(Self {
+            data: self.data[range.start * row_len..range.end * row_len].to_vec(),
+            shape: Shape::new(new_dims),
+            dtype: self.dtype,
+            device: self.device,
+        })
+    }
+
+    // Appends `other` along the first dim; the other dims must match.
+    pub fn append(&mut self, other: &Tensor) -> Result<()> {
+        let (dims, other_dims) = (self.shape.dims(), other.shape.dims());
+        if dims.is_empty() || dims.len() != other_dims.len() || dims[1..] != other_dims[1..] {
+            anyhow::bail!("Cannot append {:?} to {:?}", other_dims, dims);
+        }
+        self.data.extend_from_slice(&other.data);
+        self.shape.dims[0] += other_dims[0];
+        Ok(())
+    }
+    
     pub fn reshape(&self, shape: Shape) -> Result<Self> {
         if self.shape.numel() != shape.numel() {
             anyhow::bail!("Cannot reshape: element count mismatch")
@@ -202,6 +266,50 @@ impl Tensor {
             device,
         }
     }
+
+    // Applies `f` elementwise, broadcasting the two shapes against each other.
+    pub fn zip_with<F>(&self, other: &Tensor, f: F) -> Result<Tensor>
+    where
+        F: Fn(f32, f32) -> f32 + Sync,
+    {
+        let shape = self.shape.broadcast(&other.shape)?;
+        if self.shape == other.shape {
+            let data = if self.numel() < PARALLEL_ELEMENTS {
+                self.data.iter().zip(&other.data).map(|(&a, &b)| f(a, b)).collect()
+            } else {
+                self.data.par_iter().zip(&other.data).map(|(&a, &b)| f(a, b)).collect()
+            };
+            return Ok(Self { data, shape, dtype: self.dtype, device: self.device });
+        }
+
+        let a_strides = self.shape.broadcast_strides(&shape);
+        let b_strides = other.shape.broadcast_strides(&shape);
+        let dims = shape.dims();
+        let inner = dims.last().copied().unwrap_or(1).max(1);
+        let (a_last, b_last) = (a_strides.last().copied().unwrap_or(0), b_strides.last().copied().unwrap_or(0));
+
+        let mut data = vec![0.0; shape.numel()];
+        let row = |(r, out): (usize, &mut [f32])| {
+            // Offsets of this output row in each input.
+            let (mut a_off, mut b_off, mut rest) = (0, 0, r);
+            for d in (0..dims.len().saturating_sub(1)).rev() {
+                let idx = rest % dims[d];
+                rest /= dims[d];
+                a_off += idx * a_strides[d];
+                b_off += idx * b_strides[d];
+            }
+            for (j, o) in out.iter_mut().enumerate() {
+                *o = f(self.data[a_off + j * a_last], other.data[b_off + j * b_last]);
+            }
+        };
+        if data.len() < PARALLEL_ELEMENTS {
+            data.chunks_mut(inner).enumerate().for_each(row);
+        } else {
+            data.par_chunks_mut(inner).enumerate().for_each(row);
+        }
+        Ok(Self { data, shape, dtype: self.dtype, device: self.device })
+    }
+
     pub fn from_candle(t: candle_core::Tensor) -> Result<Self> {
         let shape = Shape::from_slice(t.dims());
         let data = t.flatten_all()?.to_vec1::<f32>()?;
@@ -212,98 +320,57 @@ impl Tensor {
             device: Device::Cpu, // Simplification for now
         })
     }
-}
-
-impl Add for Tensor {
-    type Output = Tensor;
     
-    fn add(self, other: Tensor) -> Self::Output {
-        let data: Vec<f32> = self.data.iter()
-            .zip(other.data.iter())
-            .map(|(&a, &b)| a + b)
-            .collect();
-        
-        Tensor {
-            data,
-            shape: self.shape,
-            dtype: self.dtype,
-            device: self.device,
+    pub fn from_gguf(info: &crate::infra::gguf::TensorInfo, data: &[u8]) -> Result<Self> {
+        if data.len() != info.size_bytes() {
+            anyhow::bail!(
+                "Tensor {}: expected {} bytes, got {}",
+                info.name,
+                info.size_bytes(),
+                data.len()
+            );
         }
-    }
-}
-
-impl Add<&Tensor> for Tensor {
-    type Output = Tensor;
-    
-    fn add(self, other: &Tensor) -> Self::Output {
-        let data: Vec<f32> = self.data.iter()
-            .zip(other.data.iter())
-            .map(|(&a, &b)| a + b)
-            .collect();
         
-        Tensor {
-            data,
-            shape: self.shape,
-            dtype: self.dtype,
-            device: self.device,
-        }
+        // GGUF stores dims innermost-first.
+        let dims: Vec<usize> = info.dims.iter().rev().copied().collect();
+        let data = crate::infra::gguf::dequantize(info.dtype, data)?;
+        Ok(Self::new(data, Shape::new(dims)))
     }
 }
 
-impl Sub for Tensor {
-    type Output = Tensor;
-    
-    fn sub(self, other: Tensor) -> Self::Output {
-        let data: Vec<f32> = self.data.iter()
-            .zip(other.data.iter())
-            .map(|(&a, &b)| a - b)
-            .collect();
-        
-        Tensor {
-            data,
-            shape: self.shape,
-            dtype: self.dtype,
-            device: self.device,
+// Each op broadcasts like NumPy. The try_ methods return an error on
+// incompatible shapes; the operators, which cannot, panic.
+macro_rules! binary_op {
+    ($trait:ident, $method:ident, $try_method:ident, $op:tt) => {
+        impl Tensor {
+            pub fn $try_method(&self, other: &Tensor) -> Result<Tensor> {
+                self.zip_with(other, |a, b| a $op b)
+            }
         }
-    }
-}
 
-impl Mul for Tensor {
-    type Output = Tensor;
-    
-    fn mul(self, other: Tensor) -> Self::Output {
-        let data: Vec<f32> = self.data.iter()
-            .zip(other.data.iter())
-            .map(|(&a, &b)| a * b)
-            .collect();
-        
-        Tensor {
-            data,
-            shape: self.shape,
-            dtype: self.dtype,
-            device: self.device,
+        impl $trait for Tensor {
+            type Output = Tensor;
+
+            fn $method(self, other: Tensor) -> Self::Output {
+                self.$method(&other)
+            }
         }
-    }
-}
 
-impl Div for Tensor {
-    type Output = Tensor;
-    
-    fn div(self, other: Tensor) -> Self::Output {
-        let data: Vec<f32> = self.data.iter()
-            .zip(other.data.iter())
-            .map(|(&a, &b)| a / b)
-            .collect();
-        
-        Tensor {
-            data,
-            shape: self.shape,
-            dtype: self.dtype,
-            device: self.device,
+        impl $trait<&Tensor> for Tensor {
+            type Output = Tensor;
+
+            fn $method(self, other: &Tensor) -> Self::Output {
+                self.$try_method(other).unwrap_or_else(|e| panic!("{}", e))
+            }
         }
-    }
+    };
 }
 
+binary_op!(Add, add, try_add, +);
+binary_op!(Sub, sub, try_sub, -);
+binary_op!(Mul, mul, try_mul, *);
+binary_op!(Div, div, try_div, /);
+
 impl Mul<f32> for Tensor {
     type Output = Tensor;
     
@@ -340,10 +407,57 @@ mod tests {
         assert_eq!(c.data(), &[5.0, 7.0, 9.0]);
     }
     
+    #[test]
+    fn test_broadcast() {
+        let a = Tensor::new((0..6).map(|x| x as f32).collect(), Shape::new(vec![2, 3]));
+        let row = Tensor::new(vec![10.0, 20.0, 30.0], Shape::new(vec![3]));
+        let col = Tensor::new(vec![1.0, 2.0], Shape::new(vec![2, 1]));
+
+        assert_eq!((a.clone() + &row).data(), &[10.0, 21.0, 32.0, 13.0, 24.0, 35.0]);
+        assert_eq!((a.clone() * col.clone()).data(), &[0.0, 1.0, 2.0, 6.0, 8.0, 10.0]);
+        assert_eq!(a.try_div(&col).unwrap().data(), &[0.0, 1.0, 2.0, 1.5, 2.0, 2.5]);
+        // [2, 1] against [3] gives [2, 3].
+        let outer = col - row;
+        assert_eq!(outer.shape().dims(), &[2, 3]);
+        assert_eq!(outer.data(), &[-9.0, -19.0, -29.0, -8.0, -18.0, -28.0]);
+        assert!(a.try_add(&Tensor::zeros(Shape::new(vec![2]))).is_err());
+
+        // Large enough for the parallel path; checked against a naive loop.
+        let big = Tensor::new((0..4 * 300 * 64).map(|x| x as f32).collect(), Shape::new(vec![4, 300, 64]));
+        let bias = Tensor::new((0..300).map(|x| x as f32).collect(), Shape::new(vec![300, 1]));
+        let sum = big.zip_with(&bias, |a, b| a + b).unwrap();
+        for (i, &v) in sum.data().iter().enumerate() {
+            assert_eq!(v, big.data()[i] + bias.data()[(i / 64) % 300]);
+        }
+    }
+
     #[test]
     fn test_tensor_scale() {
         let a = Tensor::new(vec![1.0, 2.0, 3.0], Shape::new(vec![3]));
         let b = a * 2.0;
         assert_eq!(b.data(), &[2.0, 4.0, 6.0]);
     }
+    
+    #[test]
+    fn test_tensor_from_gguf() {
+        use crate::infra::gguf::{GgmlType, TensorInfo};
+        
+        let info = TensorInfo {
+            name: "blk.0.attn_q.weight".to_string(),
+            dims: vec![32, 2],
+            dtype: GgmlType::Q8_0,
+            offset: 0,
+        };
+        let mut data = Vec::new();
+        for scale in [0.5f32, 2.0] {
+            data.extend_from_slice(&half::f16::from_f32(scale).to_le_bytes());
+            data.extend((0..32).map(|i| i as u8));
+        }
+        
+        let t = Tensor::from_gguf(&info, &data).unwrap();
+        assert_eq!(t.shape().dims(), &[2, 32]);
+        assert_eq!(t.data()[3], 1.5);
+        assert_eq!(t.data()[32 + 3], 6.0);
+        assert!(Tensor::from_gguf(&info, &data[..34]).is_err());
+    }
 }
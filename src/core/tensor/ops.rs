@@ -1,11 +1,19 @@
-use super::{Tensor, Shape};
+use super::kernels::{self, RopeStyle};
+use super::{Tensor, Shape, PARALLEL_ELEMENTS};
 use crate::core::Result;
+use rayon::prelude::*;
 
 pub trait TensorOps {
+    // [..., m, k] x [..., k, n]; the batch dims broadcast.
     fn matmul(&self, other: &Tensor) -> Result<Tensor>;
+    // [..., m, k] x [..., n, k]^T, for weights stored as [out, in].
+    fn matmul_t(&self, other: &Tensor) -> Result<Tensor>;
     fn softmax(&self, dim: usize) -> Result<Tensor>;
     fn layer_norm(&self, weight: &Tensor, bias: &Tensor, eps: f32) -> Result<Tensor>;
+    // Normalizes each row of the last dim.
     fn rms_norm(&self, weight: &Tensor, eps: f32) -> Result<Tensor>;
+    // Rotary embedding of [seq, heads, head_dim], one position per row.
+    fn rope(&self, positions: &[usize], theta: f32, style: RopeStyle) -> Result<Tensor>;
     fn silu(&self) -> Result<Tensor>;
     fn gelu(&self) -> Result<Tensor>;
     fn relu(&self) -> Result<Tensor>;
@@ -21,37 +29,65 @@ pub trait TensorOps {
     fn argmax(&self, dim: Option<usize>) -> Vec<usize>;
 }
 
+// c[m, n] = a * b for slices, in either B layout.
+type MatmulKernel = fn(&[f32], &[f32], &mut [f32], usize, usize, usize);
+
+// Shared by matmul and matmul_t: checks the shapes and runs `kernel` once per
+// broadcast batch. `b_rows_k` says whether B's rows run along k (B^T layout).
+fn batched_matmul(
+    a: &Tensor,
+    b: &Tensor,
+    b_rows_k: bool,
+    kernel: MatmulKernel,
+) -> Result<Tensor> {
+    let a_dims = a.shape.dims();
+    let b_dims = b.shape.dims();
+
+    if a_dims.len() < 2 || b_dims.len() < 2 {
+        anyhow::bail!("MatMul requires at least 2D tensors");
+    }
+
+    let (m, k) = (a_dims[a_dims.len() - 2], a_dims[a_dims.len() - 1]);
+    let (k2, n) = if b_rows_k {
+        (b_dims[b_dims.len() - 1], b_dims[b_dims.len() - 2])
+    } else {
+        (b_dims[b_dims.len() - 2], b_dims[b_dims.len() - 1])
+    };
+
+    if k != k2 {
+        anyhow::bail!("MatMul: dimension mismatch ({} != {})", k, k2);
+    }
+
+    let a_batch = Shape::from_slice(&a_dims[..a_dims.len() - 2]);
+    let b_batch = Shape::from_slice(&b_dims[..b_dims.len() - 2]);
+    let batch = a_batch.broadcast(&b_batch)?;
+    let a_strides = a_batch.broadcast_strides(&batch);
+    let b_strides = b_batch.broadcast_strides(&batch);
+
+    let mut result = vec![0.0; batch.numel() * m * n];
+    for (bi, c) in result.chunks_mut((m * n).max(1)).enumerate() {
+        let (mut a_off, mut b_off, mut rest) = (0, 0, bi);
+        for d in (0..batch.len()).rev() {
+            let idx = rest % batch.dims()[d];
+            rest /= batch.dims()[d];
+            a_off += idx * a_strides[d];
+            b_off += idx * b_strides[d];
+        }
+        kernel(&a.data[a_off * m * k..], &b.data[b_off * k * n..], c, m, k, n);
+    }
+
+    let mut dims = batch.dims().to_vec();
+    dims.extend([m, n]);
+    Ok(Tensor::new(result, Shape::new(dims)))
+}
+
 impl TensorOps for Tensor {
     fn matmul(&self, other: &Tensor) -> Result<Tensor> {
-        let a_dims = self.shape.dims();
-        let b_dims = other.shape.dims();
-        
-        if a_dims.len() < 2 || b_dims.len() < 2 {
-            anyhow::bail!("MatMul requires at least 2D tensors");
-        }
-        
-        let m = a_dims[a_dims.len() - 2];
-        let k = a_dims[a_dims.len() - 1];
-        let k2 = b_dims[b_dims.len() - 2];
-        let n = b_dims[b_dims.len() - 1];
-        
-        if k != k2 {
-            anyhow::bail!("MatMul: dimension mismatch ({} != {})", k, k2);
-        }
-        
-        let mut result = vec![0.0; m * n];
-        
-        for i in 0..m {
-            for j in 0..n {
-                let mut sum = 0.0;
-                for l in 0..k {
-                    sum += self.data[i * k + l] * other.data[l * n + j];
-                }
-                result[i * n + j] = sum;
-            }
-        }
-        
-        Ok(Tensor::new(result, Shape::new(vec![m, n])))
+        batched_matmul(self, other, false, kernels::matmul)
+    }
+
+    fn matmul_t(&self, other: &Tensor) -> Result<Tensor> {
+        batched_matmul(self, other, true, kernels::matmul_t)
     }
     
     fn softmax(&self, dim: usize) -> Result<Tensor> {
@@ -61,26 +97,29 @@ impl TensorOps for Tensor {
         }
         
         let dim_size = dims[dim];
-        let outer: usize = dims[..dim].iter().product();
         let inner: usize = dims[dim + 1..].iter().product();
         
         let mut result = self.data.clone();
-        
-        for o in 0..outer {
-            for i in 0..inner {
-                let start = o * dim_size * inner + i;
-                
-                let max = (0..dim_size)
-                    .map(|d| self.data[start + d * inner])
-                    .fold(f32::NEG_INFINITY, |a, b| a.max(b));
-                
-                let sum: f32 = (0..dim_size)
-                    .map(|d| (self.data[start + d * inner] - max).exp())
-                    .sum();
-                
-                for d in 0..dim_size {
-                    result[start + d * inner] = 
-                        (self.data[start + d * inner] - max).exp() / sum;
+
+        if inner == 1 {
+            // Contiguous rows, the attention-score case.
+            if result.len() < PARALLEL_ELEMENTS {
+                result.chunks_mut(dim_size.max(1)).for_each(kernels::softmax);
+            } else {
+                result.par_chunks_mut(dim_size.max(1)).for_each(kernels::softmax);
+            }
+        } else {
+            // Strided: gather each lane, normalize, scatter back.
+            let mut lane = vec![0.0; dim_size];
+            for outer in result.chunks_mut((dim_size * inner).max(1)) {
+                for i in 0..inner {
+                    for (d, v) in lane.iter_mut().enumerate() {
+                        *v = outer[d * inner + i];
+                    }
+                    kernels::softmax(&mut lane);
+                    for (d, v) in lane.iter().enumerate() {
+                        outer[d * inner + i] = *v;
+                    }
                 }
             }
         }
@@ -118,15 +157,18 @@ impl TensorOps for Tensor {
     }
     
     fn rms_norm(&self, weight: &Tensor, eps: f32) -> Result<Tensor> {
-        let n = self.shape.last().copied().unwrap_or(1) as f32;
-        
-        let ss: f32 = self.data.iter().map(|&x| x * x).sum();
-        let rms = (ss / n + eps).sqrt();
+        let n = self.shape.last().copied().unwrap_or(1);
+        if weight.numel() != n {
+            anyhow::bail!("RmsNorm: weight has {} elements, rows have {}", weight.numel(), n);
+        }
         
-        let data: Vec<f32> = self.data.iter()
-            .zip(weight.data.iter())
-            .map(|(&x, &w)| (x / rms) * w)
-            .collect();
+        let mut data = vec![0.0; self.data.len()];
+        let row = |(x, out): (&[f32], &mut [f32])| kernels::rms_norm(x, &weight.data, eps, out);
+        if data.len() < PARALLEL_ELEMENTS {
+            self.data.chunks(n.max(1)).zip(data.chunks_mut(n.max(1))).for_each(row);
+        } else {
+            self.data.par_chunks(n.max(1)).zip(data.par_chunks_mut(n.max(1))).for_each(row);
+        }
         
         Ok(Tensor {
             data,
@@ -135,6 +177,30 @@ impl TensorOps for Tensor {
             device: self.device,
         })
     }
+
+    fn rope(&self, positions: &[usize], theta: f32, style: RopeStyle) -> Result<Tensor> {
+        let dims = self.shape.dims();
+        if dims.len() != 3 || dims[0] != positions.len() {
+            anyhow::bail!("Rope: expected [{}, heads, head_dim], got {:?}", positions.len(), dims);
+        }
+
+        let head_dim = dims[2];
+        let mut data = self.data.clone();
+        let row = |(x, &pos): (&mut [f32], &usize)| kernels::rope(x, head_dim, head_dim, pos, theta, style);
+        let row_len = (dims[1] * head_dim).max(1);
+        if data.len() < PARALLEL_ELEMENTS {
+            data.chunks_mut(row_len).zip(positions).for_each(row);
+        } else {
+            data.par_chunks_mut(row_len).zip(positions).for_each(row);
+        }
+
+        Ok(Tensor {
+            data,
+            shape: self.shape.clone(),
+            dtype: self.dtype,
+            device: self.device,
+        })
+    }
     
     fn silu(&self) -> Result<Tensor> {
         let data: Vec<f32> = self.data.iter()
@@ -296,6 +362,15 @@ mod tests {
         
         let sum: f32 = s.data.iter().sum();
         assert!((sum - 1.0).abs() < 1e-5);
+
+        // Fully masked rows come out as zeros, along either layout.
+        let inf = f32::NEG_INFINITY;
+        let t = Tensor::new(vec![inf, inf, inf, 0.0, inf, 0.0], Shape::new(vec![2, 3]));
+        assert_eq!(t.softmax(1).unwrap().data, [0.0, 0.0, 0.0, 0.5, 0.0, 0.5]);
+        let t = Tensor::new(vec![inf, 0.0, inf, 0.0, inf, 0.0], Shape::new(vec![3, 2]));
+        let s = t.softmax(0).unwrap();
+        assert!(s.data.iter().step_by(2).all(|&v| v == 0.0));
+        assert!(s.data.iter().skip(1).step_by(2).all(|&v| (v - 1.0 / 3.0).abs() < 1e-6));
     }
     
     #[test]
@@ -311,5 +386,136 @@ mod tests {
         let w = Tensor::ones(Shape::new(vec![4]));
         let n = t.rms_norm(&w, 1e-5).unwrap();
         assert_eq!(n.data.len(), 4);
+
+        // Each row is normalized on its own; checked against the naive formula.
+        let t = tensor(&[300, 64], 8);
+        let w = tensor(&[64], 9);
+        let n = t.rms_norm(&w, 1e-6).unwrap();
+        for (x, out) in t.data().chunks(64).zip(n.data().chunks(64)) {
+            let rms = (x.iter().map(|v| v * v).sum::<f32>() / 64.0 + 1e-6).sqrt();
+            let expected: Vec<f32> = x.iter().zip(w.data()).map(|(v, w)| v / rms * w).collect();
+            assert_close(out, &expected, 1e-5);
+        }
+    }
+
+    // Deterministic values in [-1, 1).
+    fn tensor(dims: &[usize], seed: u32) -> Tensor {
+        let numel: usize = dims.iter().product();
+        let data = (0..numel as u32)
+            .map(|i| ((i.wrapping_mul(2654435761) ^ seed.wrapping_mul(40503)) % 2000) as f32 / 1000.0 - 1.0)
+            .collect();
+        Tensor::new(data, Shape::from_slice(dims))
+    }
+
+    fn transpose_2d(t: &Tensor) -> Tensor {
+        let (r, c) = (t.shape.dims()[0], t.shape.dims()[1]);
+        let data = (0..r * c).map(|i| t.data[(i % r) * c + i / r]).collect();
+        Tensor::new(data, Shape::new(vec![c, r]))
+    }
+
+    fn naive_matmul(a: &[f32], b: &[f32], m: usize, k: usize, n: usize) -> Vec<f32> {
+        let mut c = vec![0.0; m * n];
+        for i in 0..m {
+            for j in 0..n {
+                c[i * n + j] = (0..k).map(|l| a[i * k + l] * b[l * n + j]).sum();
+            }
+        }
+        c
+    }
+
+    fn assert_close(a: &[f32], b: &[f32], tol: f32) {
+        assert_eq!(a.len(), b.len());
+        for (i, (x, y)) in a.iter().zip(b).enumerate() {
+            assert!((x - y).abs() <= tol * (1.0 + y.abs()), "element {}: {} != {}", i, x, y);
+        }
+    }
+
+    #[test]
+    fn test_matmul_matches_naive() {
+        // Sizes straddle the block edges and the parallel threshold.
+        for &(m, k, n) in &[(1, 1, 1), (3, 5, 7), (33, 257, 65), (70, 300, 600), (1, 1000, 700)] {
+            let a = tensor(&[m, k], 1);
+            let b = tensor(&[k, n], 2);
+            let c = a.matmul(&b).unwrap();
+            assert_eq!(c.shape().dims(), &[m, n]);
+            assert_close(c.data(), &naive_matmul(a.data(), b.data(), m, k, n), 1e-4);
+
+            let bt = transpose_2d(&b);
+            let ct = a.matmul_t(&bt).unwrap();
+            assert_close(ct.data(), c.data(), 1e-4);
+        }
+        assert!(tensor(&[2, 3], 0).matmul(&tensor(&[4, 2], 0)).is_err());
+    }
+
+    #[test]
+    fn test_batched_matmul() {
+        // [2, 1, m, k] x [3, k, n] broadcasts to [2, 3, m, n].
+        let (m, k, n) = (4, 6, 5);
+        let a = tensor(&[2, 1, m, k], 3);
+        let b = tensor(&[3, k, n], 4);
+        let c = a.matmul(&b).unwrap();
+        assert_eq!(c.shape().dims(), &[2, 3, m, n]);
+        for i in 0..2 {
+            for j in 0..3 {
+                let expected = naive_matmul(&a.data()[i * m * k..][..m * k], &b.data()[j * k * n..][..k * n], m, k, n);
+                assert_close(&c.data()[(i * 3 + j) * m * n..][..m * n], &expected, 1e-5);
+            }
+        }
+
+        // A single [n, k] weight shared by every batch.
+        let w = tensor(&[n, k], 5);
+        let y = a.matmul_t(&w).unwrap();
+        assert_eq!(y.shape().dims(), &[2, 1, m, n]);
+        let expected = naive_matmul(&a.data()[m * k..], transpose_2d(&w).data(), m, k, n);
+        assert_close(&y.data()[m * n..], &expected, 1e-5);
+    }
+
+    #[test]
+    fn test_softmax_matches_naive() {
+        let t = tensor(&[3, 4, 5], 6);
+        for dim in 0..3 {
+            let s = t.softmax(dim).unwrap();
+            let dims = t.shape().dims();
+            let inner: usize = dims[dim + 1..].iter().product();
+            for (idx, &v) in s.data().iter().enumerate() {
+                let base = idx - (idx / inner % dims[dim]) * inner;
+                let lane: Vec<f32> = (0..dims[dim]).map(|d| t.data()[base + d * inner]).collect();
+                let sum: f32 = lane.iter().map(|x| x.exp()).sum();
+                assert!((v - t.data()[idx].exp() / sum).abs() < 1e-6);
+            }
+        }
+
+        // Masked rows stay finite where anything is unmasked.
+        let masked = Tensor::new(vec![0.0, f32::NEG_INFINITY, 1.0], Shape::new(vec![1, 3]));
+        let s = masked.softmax(1).unwrap();
+        assert_eq!(s.data()[1], 0.0);
+        assert!((s.data()[0] + s.data()[2] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_rope() {
+        let (seq, heads, dim) = (3, 2, 8);
+        let x = tensor(&[seq, heads, dim], 7);
+        let positions = [0, 1, 5];
+        for style in [RopeStyle::Normal, RopeStyle::NeoX] {
+            let y = x.rope(&positions, 10000.0, style).unwrap();
+            for (p, &pos) in positions.iter().enumerate() {
+                for h in 0..heads {
+                    let row = &x.data()[(p * heads + h) * dim..][..dim];
+                    let out = &y.data()[(p * heads + h) * dim..][..dim];
+                    for i in 0..dim / 2 {
+                        let (a, b) = match style {
+                            RopeStyle::Normal => (2 * i, 2 * i + 1),
+                            RopeStyle::NeoX => (i, i + dim / 2),
+                        };
+                        let angle = pos as f32 / 10000f32.powf(2.0 * i as f32 / dim as f32);
+                        let (sin, cos) = angle.sin_cos();
+                        assert!((out[a] - (row[a] * cos - row[b] * sin)).abs() < 1e-5);
+                        assert!((out[b] - (row[a] * sin + row[b] * cos)).abs() < 1e-5);
+                    }
+                }
+            }
+        }
+        assert!(x.rope(&[0, 1], 10000.0, RopeStyle::Normal).is_err());
     }
 }
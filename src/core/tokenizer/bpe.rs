@@ -1,138 +1,251 @@
 use super::traits::{Tokenizer, TokenizerStrategy, EncodeOptions, DecodeOptions, TokenizerKind};
-use super::Vocabulary;
+use super::{Fragment, SpecialTokens, TokenType, Vocabulary};
 use crate::core::{Result, TokenId};
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::RwLock;
+
+const GPT2_PATTERN: &str = r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+const LLAMA3_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const QWEN2_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const GPT4O_PATTERN: &str = r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const DEEPSEEK3_PATTERN: &str = r##"[!\"#$%&'()*+,\-./:;<=>?@\[\\\]^_`{|}~][A-Za-z]+|[^\r\n\p{L}\p{P}\p{S}]?[\p{L}\p{M}]+| ?[\p{P}\p{S}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+"##;
+
+// The pre-tokenizer for tokenizer.ggml.pre, as in llama.cpp. Every expression
+// splits the pieces left by the previous one, and text between matches stays
+// a piece of its own. The flag marks vocabularies whose words are looked up
+// whole before any merges are applied.
+fn pre_tokenizer(pre: &str) -> Option<(&'static [&'static str], bool)> {
+    Some(match pre {
+        "llama3" | "llama-v3" | "llama-bpe" | "falcon3" | "pixtral" => (&[LLAMA3_PATTERN], true),
+        "dbrx" | "smaug-bpe" => (&[LLAMA3_PATTERN], false),
+        "qwen2" | "deepseek-r1-qwen" | "stablelm2" | "hunyuan" => (&[QWEN2_PATTERN], false),
+        "gpt-4o" | "llama4" => (&[GPT4O_PATTERN], false),
+        "deepseek-v3" => (&[r"\p{N}{1,3}", r"[一-龥぀-ゟ゠-ヿ]+", DEEPSEEK3_PATTERN], false),
+        "deepseek-coder" => (&[r"[\r\n]", r"\s?\p{L}+", r"\s?\p{P}+", r"[一-龥ࠀ-一가-퟿]+", r"\p{N}"], false),
+        "falcon" => (&[r"[\p{P}\$\+<=>\^~\|`]+", GPT2_PATTERN, r"[0-9][0-9][0-9]"], false),
+        "starcoder" | "refact" | "command-r" | "smollm" | "codeshell" | "exaone" | "minerva-7b" => {
+            (&[r"\p{N}", GPT2_PATTERN], false)
+        }
+        // A missing or empty tokenizer.ggml.pre means llama.cpp's default.
+        "" | "default" => (&[r"[\p{P}\$\+<=>\^~\|]+", GPT2_PATTERN, r"\p{N}+", r"[0-9][0-9][0-9]"], false),
+        "gpt-2" | "phi-2" | "jina-es" | "jina-de" | "gigachat" | "jina-v1-en" | "jina-v2-es" | "jina-v2-de"
+        | "jina-v2-code" | "roberta-bpe" | "mpt" | "olmo" | "jais" => (&[GPT2_PATTERN], false),
+        _ => return None,
+    })
+}
+
+fn split_pieces<'a>(patterns: &[fancy_regex::Regex], text: &'a str) -> Vec<&'a str> {
+    let mut pieces = vec![text];
+    for pattern in patterns {
+        let mut next = Vec::with_capacity(pieces.len());
+        for piece in pieces {
+            let mut last = 0;
+            for m in pattern.find_iter(piece).flatten() {
+                if m.start() > last {
+                    next.push(&piece[last..m.start()]);
+                }
+                if !m.as_str().is_empty() {
+                    next.push(m.as_str());
+                }
+                last = m.end();
+            }
+            if last < piece.len() {
+                next.push(&piece[last..]);
+            }
+        }
+        pieces = next;
+    }
+    pieces
+}
+
+// Words kept in the cache before it is cleared; enough for the working set
+// of a long document without growing unbounded on a server.
+const WORD_CACHE_LIMIT: usize = 1 << 16;
 
 pub struct BpeTokenizer {
     vocab: Vocabulary,
-    encoder: HashMap<String, TokenId>,
-    decoder: HashMap<TokenId, String>,
-    bpe_ranks: HashMap<(String, String), usize>,
-    byte_encoder: HashMap<u8, char>,
+    // (left, right) -> (rank, merged token), rank being the position in
+    // tokenizer.ggml.merges.
+    merges: HashMap<(TokenId, TokenId), (usize, TokenId)>,
+    byte_encoder: [char; 256],
     byte_decoder: HashMap<char, u8>,
-    pattern: fancy_regex::Regex,
+    // The token of each byte's byte-level character.
+    byte_tokens: [Option<TokenId>; 256],
+    pre_tokenizer: Vec<fancy_regex::Regex>,
+    ignore_merges: bool,
+    special: SpecialTokens,
+    cache: RwLock<HashMap<String, Vec<TokenId>>>,
+}
+
+struct Symbol {
+    id: Option<TokenId>,
+    prev: Option<usize>,
+    next: Option<usize>,
 }
 
 impl BpeTokenizer {
     pub fn new(vocab: Vocabulary) -> Self {
         let byte_encoder = Self::build_byte_encoder();
         let byte_decoder: HashMap<char, u8> = byte_encoder.iter()
-            .map(|(&k, &v)| (v, k))
+            .enumerate()
+            .map(|(b, &c)| (c, b as u8))
             .collect();
-        
-        let mut encoder = HashMap::new();
-        let mut decoder = HashMap::new();
-        
-        for (i, token) in vocab.tokens.iter().enumerate() {
-            encoder.insert(token.clone(), TokenId(i as i32));
-            decoder.insert(TokenId(i as i32), token.clone());
-        }
-        
-        let mut bpe_ranks = HashMap::new();
-        for (i, merge) in vocab.merges.iter().enumerate() {
-            let parts: Vec<&str> = merge.split(' ').collect();
-            if parts.len() == 2 {
-                bpe_ranks.insert((parts[0].to_string(), parts[1].to_string()), i);
+        let byte_tokens = byte_encoder.map(|c| vocab.id(c.encode_utf8(&mut [0; 4])));
+
+        let mut merges = HashMap::new();
+        for (rank, merge) in vocab.merges.iter().enumerate() {
+            let Some((left, right)) = merge.split_once(' ') else {
+                continue;
+            };
+            if let (Some(l), Some(r), Some(merged)) = (vocab.id(left), vocab.id(right), vocab.id(&format!("{}{}", left, right))) {
+                merges.entry((l, r)).or_insert((rank, merged));
             }
         }
-        
-        let pattern = fancy_regex::Regex::new(
-            r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+"
-        ).unwrap();
-        
+
+        let (patterns, ignore_merges) = pre_tokenizer(&vocab.pre).unwrap_or_else(|| {
+            tracing::warn!("unknown pre-tokenizer type {:?}, using gpt-2", vocab.pre);
+            (&[GPT2_PATTERN], false)
+        });
+        let pre_tokenizer = patterns
+            .iter()
+            .map(|p| fancy_regex::Regex::new(p).expect("invalid pre-tokenizer pattern"))
+            .collect();
+
         Self {
+            special: vocab.special_tokens(),
             vocab,
-            encoder,
-            decoder,
-            bpe_ranks,
+            merges,
             byte_encoder,
             byte_decoder,
-            pattern,
+            byte_tokens,
+            pre_tokenizer,
+            ignore_merges,
+            cache: RwLock::new(HashMap::new()),
         }
     }
     
-    fn build_byte_encoder() -> HashMap<u8, char> {
-        let mut mapping = HashMap::new();
-        let mut add_range = |start: u8, end: u8, offset: &mut u32| {
-            for b in start..=end {
-                mapping.insert(b, char::from_u32(*offset).unwrap());
-                *offset += 1;
-            }
-        };
-        
+    // GPT-2's bytes_to_unicode: printable bytes stand for themselves, the
+    // rest are shifted past U+0100 so that every byte has a visible char.
+    fn build_byte_encoder() -> [char; 256] {
+        let mut mapping = ['\0'; 256];
+        let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
         let mut offset: u32 = 256;
-        add_range(b'!', b'~', &mut offset);
-        add_range(0xA1, 0xAC, &mut offset);
-        add_range(0xAE, 0xFF, &mut offset);
-        
         for b in 0..=255u8 {
-            if let std::collections::hash_map::Entry::Vacant(e) = mapping.entry(b) {
-                e.insert(char::from_u32(offset).unwrap());
+            mapping[b as usize] = if printable(b) {
+                b as char
+            } else {
                 offset += 1;
-            }
+                char::from_u32(offset - 1).unwrap()
+            };
         }
-        
         mapping
     }
-    
-    fn get_pairs(word: &[String]) -> Vec<(String, String)> {
-        let mut pairs = Vec::new();
-        if word.len() < 2 { return pairs; }
-        for i in 0..word.len() - 1 {
-            pairs.push((word[i].clone(), word[i + 1].clone()));
+
+    // Merges the byte-level symbols of a word, lowest rank first and leftmost
+    // on ties, keeping candidate pairs in a heap. Stale pairs are skipped when
+    // popped, so each merge costs O(log n).
+    fn bpe(&self, word: &str) -> Vec<TokenId> {
+        let len = word.len();
+        let mut symbols: Vec<Symbol> = word
+            .bytes()
+            .enumerate()
+            .map(|(i, b)| Symbol {
+                id: self.byte_tokens[b as usize],
+                prev: i.checked_sub(1),
+                next: (i + 1 < len).then_some(i + 1),
+            })
+            .collect();
+
+        let mut heap = BinaryHeap::new();
+        let pair = |symbols: &[Symbol], left: usize, right: usize| {
+            let (l, r) = (symbols[left].id?, symbols[right].id?);
+            self.merges.get(&(l, r)).map(|&(rank, _)| Reverse((rank, left, right)))
+        };
+        for i in 1..len {
+            heap.extend(pair(&symbols, i - 1, i));
         }
-        pairs
-    }
-    
-    fn bpe(&self, token: &str) -> Vec<String> {
-        let mut word: Vec<String> = token.chars().map(|c| c.to_string()).collect();
-        if word.is_empty() { return word; }
-        
-        loop {
-            let pairs = Self::get_pairs(&word);
-            if pairs.is_empty() { break; }
-            
-            let bigram = pairs.iter()
-                .filter_map(|pair| self.bpe_ranks.get(pair).map(|&rank| (pair, rank)))
-                .min_by_key(|(_, rank)| *rank)
-                .map(|(pair, _)| pair.clone());
-            
-            let bigram = match bigram {
-                Some(b) => b,
-                None => break,
+
+        while let Some(Reverse((rank, left, right))) = heap.pop() {
+            // A pair is stale once either side has merged with something else.
+            if symbols[left].next != Some(right) {
+                continue;
+            }
+            let (Some(l), Some(r)) = (symbols[left].id, symbols[right].id) else {
+                continue;
             };
-            
-            let mut new_word = Vec::new();
-            let mut i = 0;
-            
-            while i < word.len() {
-                if i < word.len() - 1 && word[i] == bigram.0 && word[i + 1] == bigram.1 {
-                    new_word.push(format!("{}{}", word[i], word[i + 1]));
-                    i += 2;
-                } else {
-                    new_word.push(word[i].clone());
-                    i += 1;
-                }
+            let merged = match self.merges.get(&(l, r)) {
+                Some(&(current, merged)) if current == rank => merged,
+                _ => continue,
+            };
+            symbols[left].id = Some(merged);
+            symbols[left].next = symbols[right].next;
+            symbols[right].id = None;
+            if let Some(next) = symbols[left].next {
+                symbols[next].prev = Some(left);
+                heap.extend(pair(&symbols, left, next));
+            }
+            if let Some(prev) = symbols[left].prev {
+                heap.extend(pair(&symbols, prev, left));
+            }
+        }
+
+        let mut ids = Vec::new();
+        let mut cursor = (len > 0).then_some(0);
+        while let Some(i) = cursor {
+            if let Some(id) = symbols[i].id.or(self.vocab.unk_token) {
+                ids.push(id);
             }
-            word = new_word;
+            cursor = symbols[i].next;
         }
-        word
+        ids
     }
     
     fn byte_encode(&self, text: &str) -> String {
-        text.bytes().map(|b| self.byte_encoder[&b]).collect()
+        text.bytes().map(|b| self.byte_encoder[b as usize]).collect()
     }
     
-    fn byte_decode(&self, tokens: &str) -> String {
-        tokens.chars()
-            .filter_map(|c| self.byte_decoder.get(&c).map(|&b| b as char))
-            .collect()
+    fn encode_word(&self, word: &str, tokens: &mut Vec<TokenId>) {
+        if let Some(ids) = self.cache.read().unwrap_or_else(|e| e.into_inner()).get(word) {
+            tokens.extend_from_slice(ids);
+            return;
+        }
+
+        let whole = if self.ignore_merges { self.vocab.id(&self.byte_encode(word)) } else { None };
+        let ids = match whole {
+            Some(id) => vec![id],
+            None => self.bpe(word),
+        };
+        tokens.extend_from_slice(&ids);
+
+        let mut cache = self.cache.write().unwrap_or_else(|e| e.into_inner());
+        if cache.len() >= WORD_CACHE_LIMIT {
+            cache.clear();
+        }
+        cache.insert(word.to_string(), ids);
+    }
+
+    fn byte_decode(&self, token: &str, bytes: &mut Vec<u8>) {
+        for c in token.chars() {
+            match self.byte_decoder.get(&c) {
+                Some(&b) => bytes.push(b),
+                None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
     }
 }
 
 impl Tokenizer for BpeTokenizer {
     fn encode(&self, text: &str) -> Result<Vec<TokenId>> {
-        self.encode_with_options(text, &EncodeOptions::default())
+        self.encode_with_options(text, &self.encode_options())
+    }
+
+    fn encode_options(&self) -> EncodeOptions {
+        EncodeOptions {
+            add_bos: self.vocab.add_bos,
+            add_eos: self.vocab.add_eos,
+            ..Default::default()
+        }
     }
     
     fn encode_with_options(&self, text: &str, options: &EncodeOptions) -> Result<Vec<TokenId>> {
@@ -142,13 +255,13 @@ impl Tokenizer for BpeTokenizer {
             tokens.push(self.vocab.bos_token);
         }
         
-        for cap in self.pattern.captures_iter(text).flatten() {
-            let match_str = cap.get(0).map(|m| m.as_str()).unwrap_or("");
-            let encoded = self.byte_encode(match_str);
-            
-            for bpe_token in self.bpe(&encoded) {
-                if let Some(&id) = self.encoder.get(&bpe_token) {
-                    tokens.push(id);
+        for fragment in self.special.split(text, options) {
+            match fragment {
+                Fragment::Token(id) => tokens.push(id),
+                Fragment::Text(text) => {
+                    for word in split_pieces(&self.pre_tokenizer, text) {
+                        self.encode_word(word, &mut tokens);
+                    }
                 }
             }
         }
@@ -168,18 +281,34 @@ impl Tokenizer for BpeTokenizer {
         self.decode_with_options(tokens, &DecodeOptions::default())
     }
     
-    fn decode_with_options(&self, tokens: &[TokenId], _options: &DecodeOptions) -> Result<String> {
-        let mut text = String::new();
-        
+    fn decode_with_options(&self, tokens: &[TokenId], options: &DecodeOptions) -> Result<String> {
+        let mut bytes = Vec::new();
+
         for &token in tokens {
-            if let Some(t) = self.decoder.get(&token) {
-                text.push_str(&self.byte_decode(t));
+            let control = self.vocab.types.get(token.0 as usize) == Some(&TokenType::Control);
+            if !(options.skip_special_tokens && control) {
+                bytes.extend(self.token_bytes(token));
             }
         }
-        
-        Ok(text)
+
+        let text = String::from_utf8_lossy(&bytes).into_owned();
+        Ok(if options.clean_up_tokenization_spaces { text.trim().to_string() } else { text })
     }
     
+    fn token_bytes(&self, id: TokenId) -> Vec<u8> {
+        let Some(t) = self.vocab.token(id) else {
+            return Vec::new();
+        };
+        let kind = self.vocab.types.get(id.0 as usize).copied().unwrap_or(TokenType::Normal);
+        let mut bytes = Vec::new();
+        match kind {
+            // Special tokens are stored as plain text, not byte-level encoded.
+            TokenType::Control | TokenType::UserDefined => bytes.extend_from_slice(t.as_bytes()),
+            _ => self.byte_decode(t, &mut bytes),
+        }
+        bytes
+    }
+
     fn vocab_size(&self) -> usize {
         self.vocab.size()
     }
@@ -193,11 +322,11 @@ impl Tokenizer for BpeTokenizer {
     }
     
     fn token_to_id(&self, token: &str) -> Option<TokenId> {
-        self.encoder.get(token).copied()
+        self.vocab.id(token)
     }
     
     fn id_to_token(&self, id: TokenId) -> Option<&str> {
-        self.decoder.get(&id).map(|s| s.as_str())
+        usize::try_from(id.0).ok().and_then(|i| self.vocab.tokens.get(i)).map(|s| s.as_str())
     }
 }
 
@@ -224,4 +353,119 @@ mod tests {
         assert_eq!(tokenizer.bos_token(), TokenId::BOS);
         assert_eq!(tokenizer.eos_token(), TokenId::EOS);
     }
+
+    fn split(pre: &str, text: &str) -> Vec<String> {
+        let patterns: Vec<_> = pre_tokenizer(pre).unwrap().0.iter().map(|p| fancy_regex::Regex::new(p).unwrap()).collect();
+        split_pieces(&patterns, text).into_iter().map(String::from).collect()
+    }
+
+    // Expected splits are those of the reference tokenizers (tiktoken and the
+    // Hugging Face pre-tokenizers) for the same input.
+    #[test]
+    fn test_pre_tokenizers() {
+        assert_eq!(
+            split("llama-bpe", "Hello world 12345  \n\n  foo"),
+            ["Hello", " world", " ", "123", "45", "  \n\n", " ", " foo"]
+        );
+        assert_eq!(split("llama-bpe", "Привет мир! 你好世界"), ["Привет", " мир", "!", " 你好世界"]);
+        assert_eq!(split("llama-bpe", "I'M here\t\tnow"), ["I", "'M", " here", "\t", "\tnow"]);
+        assert_eq!(split("qwen2", "x=12345"), ["x", "=", "1", "2", "3", "4", "5"]);
+        assert_eq!(split("gpt-2", "Hello world's 123   !"), ["Hello", " world", "'s", " 123", "  ", " !"]);
+        assert_eq!(split("default", "3333 apples!"), ["333", "3", " apples", "!"]);
+        assert_eq!(split("gpt-4o", "HelloWorld's 12345"), ["Hello", "World's", " ", "123", "45"]);
+    }
+
+    fn gpt2_vocab() -> Vocabulary {
+        let tokens = [
+            "<|endoftext|>", "H", "e", "l", "o", "Ġ", "w", "r", "d", "c", "a", "f", "Ã", "©",
+            "He", "ll", "Hell", "Hello", "Ġw", "or", "Ġwor", "ld", "Ġworld", "Ã©", "Ġc", "af", "Ġcaf", "ĠcafÃ©",
+        ];
+        let merges = [
+            "H e", "l l", "He ll", "Hell o", "Ġ w", "o r", "Ġw or", "l d", "Ġwor ld", "Ã ©", "Ġ c", "a f", "Ġc af",
+            "Ġcaf Ã©",
+        ];
+        let mut vocab = Vocabulary::new(tokens.iter().map(|t| t.to_string()).collect());
+        vocab.merges = merges.iter().map(|m| m.to_string()).collect();
+        vocab.types[0] = TokenType::Control;
+        vocab.model = "gpt2".into();
+        vocab.pre = "gpt-2".into();
+        vocab
+    }
+
+    #[test]
+    fn test_ranked_merges() {
+        let tokenizer = BpeTokenizer::new(gpt2_vocab());
+        let ids = |ids: &[i32]| ids.iter().map(|&i| TokenId(i)).collect::<Vec<_>>();
+
+        assert_eq!(tokenizer.encode("Hello world").unwrap(), ids(&[17, 22]));
+        assert_eq!(tokenizer.encode(" café").unwrap(), ids(&[27]));
+        // "cafe" has no merge for its last letter and stops at Ġcaf + e.
+        let special = EncodeOptions::new().parse_special();
+        assert_eq!(tokenizer.encode_with_options("Hello cafe<|endoftext|>", &special).unwrap(), ids(&[17, 26, 2, 0]));
+        assert!(!tokenizer.encode("<|endoftext|>").unwrap().contains(&TokenId(0)));
+
+        let tokens = tokenizer.encode("Hello world café").unwrap();
+        assert_eq!(tokenizer.decode(&tokens).unwrap(), "Hello world café");
+        let skip = DecodeOptions::new().skip_special();
+        assert_eq!(tokenizer.decode_with_options(&ids(&[17, 0]), &skip).unwrap(), "Hello");
+    }
+
+    // Collects what the tokenizer logs while `f` runs.
+    fn logs(f: impl FnOnce()) -> String {
+        #[derive(Clone, Default)]
+        struct Buffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl std::io::Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let out = buffer.0.lock().unwrap().clone();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_unknown_pre_tokenizer_warns() {
+        let mut vocab = gpt2_vocab();
+        vocab.pre = "no-such-pre".into();
+        let mut tokenizer = None;
+        let out = logs(|| tokenizer = Some(BpeTokenizer::new(vocab)));
+        assert!(out.contains("WARN") && out.contains("unknown pre-tokenizer type \"no-such-pre\""), "{}", out);
+        // It still splits like GPT-2.
+        let gpt2 = BpeTokenizer::new(gpt2_vocab());
+        assert_eq!(tokenizer.unwrap().encode("Hello world café").unwrap(), gpt2.encode("Hello world café").unwrap());
+
+        assert!(logs(|| drop(BpeTokenizer::new(gpt2_vocab()))).is_empty());
+    }
+
+    #[test]
+    fn test_missing_pre_tokenizer_is_default() {
+        let mut vocab = gpt2_vocab();
+        vocab.pre = String::new();
+        let mut tokenizer = None;
+        assert!(logs(|| tokenizer = Some(BpeTokenizer::new(vocab))).is_empty());
+        let patterns: Vec<_> = tokenizer.unwrap().pre_tokenizer.iter().map(|r| r.as_str().to_string()).collect();
+        assert_eq!(patterns, pre_tokenizer("default").unwrap().0);
+        assert_eq!(split("", "3333 apples!"), split("default", "3333 apples!"));
+    }
+
+    #[test]
+    fn test_encode_batch() {
+        let tokenizer = BpeTokenizer::new(gpt2_vocab());
+        let texts = ["Hello world", " café", "Hello cafe", "Hello world"];
+        let batch = tokenizer.encode_batch(&texts, &EncodeOptions::new()).unwrap();
+        // The second "Hello world" comes from the word cache.
+        for (text, tokens) in texts.iter().zip(&batch) {
+            assert_eq!(tokens, &tokenizer.encode(text).unwrap());
+        }
+        assert_eq!(batch[0], batch[3]);
+    }
 }
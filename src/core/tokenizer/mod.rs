@@ -2,23 +2,55 @@ pub mod traits;
 pub mod bpe;
 pub mod sentencepiece;
 pub mod wordpiece;
+pub mod unigram;
 
 pub use traits::{Tokenizer, TokenizerStrategy, TokenStream, EncodeOptions, DecodeOptions, TokenizerKind};
 pub use bpe::BpeTokenizer;
 pub use sentencepiece::SentencePieceTokenizer;
 pub use wordpiece::WordPieceTokenizer;
+pub use unigram::UnigramTokenizer;
 
 use crate::core::TokenId;
+use crate::infra::gguf::{GgufMetadata, MetadataValue};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::OnceLock;
 
 pub fn create_tokenizer(kind: TokenizerKind, vocab: Vocabulary) -> Box<dyn Tokenizer> {
     match kind {
         TokenizerKind::Bpe => Box::new(BpeTokenizer::new(vocab)),
         TokenizerKind::SentencePiece => Box::new(SentencePieceTokenizer::new(vocab)),
         TokenizerKind::WordPiece => Box::new(WordPieceTokenizer::new(vocab)),
+        TokenizerKind::Unigram => Box::new(UnigramTokenizer::new(vocab)),
         _ => Box::new(BpeTokenizer::new(vocab)), // Default to BPE for others
     }
 }
 
+// Byte ranges of each token in `text`, found by matching token bytes against
+// the text in order. Tokens absent from it, such as an added BOS or pieces
+// changed by normalization, get an empty range.
+pub fn token_offsets(tokenizer: &dyn Tokenizer, text: &str, tokens: &[TokenId]) -> Vec<Range<usize>> {
+    let text = text.as_bytes();
+    let mut pos = 0;
+    tokens
+        .iter()
+        .map(|&id| {
+            let bytes = tokenizer.token_bytes(id);
+            let rest = &text[pos..];
+            let len = if !bytes.is_empty() && rest.starts_with(&bytes) {
+                bytes.len()
+            } else if bytes.first() == Some(&b' ') && rest.starts_with(&bytes[1..]) {
+                // The SentencePiece space prefix has no counterpart in the text.
+                bytes.len() - 1
+            } else {
+                0
+            };
+            pos += len;
+            pos - len..pos
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct Vocabulary {
     pub tokens: Vec<String>,
@@ -29,6 +61,14 @@ pub struct Vocabulary {
     pub eos_token: TokenId,
     pub pad_token: Option<TokenId>,
     pub unk_token: Option<TokenId>,
+    // tokenizer.ggml.model and tokenizer.ggml.pre, e.g. "llama" / "default".
+    pub model: String,
+    pub pre: String,
+    pub add_bos: bool,
+    pub add_eos: bool,
+    pub add_space_prefix: bool,
+    // Token text to id, built on first lookup.
+    index: OnceLock<HashMap<String, TokenId>>,
 }
 
 impl Vocabulary {
@@ -43,7 +83,96 @@ impl Vocabulary {
             eos_token: TokenId::EOS,
             pad_token: None,
             unk_token: None,
+            model: String::new(),
+            pre: String::new(),
+            add_bos: false,
+            add_eos: false,
+            add_space_prefix: true,
+            index: OnceLock::new(),
+        }
+    }
+
+    // Reads the tokenizer.ggml.* keys. Missing flags take llama.cpp's defaults:
+    // SentencePiece vocabularies add BOS and a space prefix, Llama 3 adds BOS
+    // and T5's Unigram vocabularies add EOS and a space prefix.
+    pub fn from_gguf(metadata: &GgufMetadata) -> Self {
+        let strings = |key: &str| match metadata.get(key) {
+            Some(MetadataValue::Array(arr)) => arr
+                .iter()
+                .map(|v| match v {
+                    MetadataValue::String(s) => s.clone(),
+                    _ => String::new(),
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let tokens: Vec<String> = strings("tokenizer.ggml.tokens");
+        let mut vocab = Self::new(tokens);
+        vocab.merges = strings("tokenizer.ggml.merges");
+        vocab.model = metadata.string("tokenizer.ggml.model");
+        vocab.pre = metadata.string("tokenizer.ggml.pre");
+
+        if let Some(MetadataValue::Array(scores)) = metadata.get("tokenizer.ggml.scores") {
+            for (score, v) in vocab.scores.iter_mut().zip(scores) {
+                if let MetadataValue::Float(f) = v {
+                    *score = *f as f32;
+                }
+            }
         }
+        if let Some(MetadataValue::Array(types)) = metadata.get("tokenizer.ggml.token_type") {
+            for (t, v) in vocab.types.iter_mut().zip(types) {
+                *t = match v {
+                    MetadataValue::Int(n) => TokenType::from_id(*n),
+                    MetadataValue::Uint(n) => TokenType::from_id(*n as i64),
+                    _ => TokenType::Normal,
+                };
+            }
+        }
+
+        let id = |key: &str| metadata.get(key).map(|_| TokenId(metadata.uint(key) as i32));
+        if let Some(bos) = id("tokenizer.ggml.bos_token_id") {
+            vocab.bos_token = bos;
+        }
+        if let Some(eos) = id("tokenizer.ggml.eos_token_id") {
+            vocab.eos_token = eos;
+        }
+        vocab.pad_token = id("tokenizer.ggml.padding_token_id");
+        vocab.unk_token = id("tokenizer.ggml.unknown_token_id")
+            .or_else(|| vocab.types.iter().position(|t| *t == TokenType::Unknown).map(|i| TokenId(i as i32)));
+
+        let spm = vocab.kind() == TokenizerKind::SentencePiece;
+        let ugm = vocab.kind() == TokenizerKind::Unigram;
+        let llama3 = matches!(vocab.pre.as_str(), "llama3" | "llama-v3" | "llama-bpe" | "falcon3");
+        let flag = |key: &str, default: bool| match metadata.get(key) {
+            Some(MetadataValue::Bool(b)) => *b,
+            _ => default,
+        };
+        vocab.add_bos = flag("tokenizer.ggml.add_bos_token", spm || llama3);
+        vocab.add_eos = flag("tokenizer.ggml.add_eos_token", ugm);
+        vocab.add_space_prefix = flag("tokenizer.ggml.add_space_prefix", spm || ugm);
+        vocab
+    }
+
+    // The tokenizer implementation for tokenizer.ggml.model.
+    pub fn kind(&self) -> TokenizerKind {
+        match self.model.as_str() {
+            "llama" | "replit" => TokenizerKind::SentencePiece,
+            "bert" => TokenizerKind::WordPiece,
+            "t5" => TokenizerKind::Unigram,
+            _ => TokenizerKind::Bpe,
+        }
+    }
+
+    // Control, user-defined and unknown tokens, which are matched verbatim in
+    // text before the model's own segmentation runs.
+    pub fn special_tokens(&self) -> SpecialTokens {
+        let mut tokens = SpecialTokens::default();
+        for (i, (text, t)) in self.tokens.iter().zip(&self.types).enumerate() {
+            if matches!(t, TokenType::Control | TokenType::UserDefined | TokenType::Unknown) && !text.is_empty() {
+                tokens.insert(text, TokenId(i as i32), *t != TokenType::UserDefined);
+            }
+        }
+        tokens
     }
     
     pub fn size(&self) -> usize {
@@ -55,9 +184,16 @@ impl Vocabulary {
     }
     
     pub fn id(&self, token: &str) -> Option<TokenId> {
-        self.tokens.iter()
-            .position(|t| t == token)
-            .map(|i| TokenId(i as i32))
+        self.index
+            .get_or_init(|| {
+                let mut index = HashMap::with_capacity(self.tokens.len());
+                for (i, t) in self.tokens.iter().enumerate() {
+                    index.entry(t.clone()).or_insert(TokenId(i as i32));
+                }
+                index
+            })
+            .get(token)
+            .copied()
     }
 }
 
@@ -70,3 +206,125 @@ pub enum TokenType {
     Unused,
     Byte,
 }
+
+impl TokenType {
+    // Ids as stored in tokenizer.ggml.token_type.
+    pub fn from_id(id: i64) -> Self {
+        match id {
+            2 => TokenType::Unknown,
+            3 => TokenType::Control,
+            4 => TokenType::UserDefined,
+            5 => TokenType::Unused,
+            6 => TokenType::Byte,
+            _ => TokenType::Normal,
+        }
+    }
+}
+
+// A run of input text, or a special token matched inside it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fragment<'a> {
+    Text(&'a str),
+    Token(TokenId),
+}
+
+// Special token strings indexed by first byte, longest first. Control (and
+// unknown) tokens are flagged so that they are only matched on request.
+#[derive(Debug, Clone, Default)]
+pub struct SpecialTokens {
+    by_first_byte: HashMap<u8, Vec<(String, TokenId, bool)>>,
+}
+
+impl SpecialTokens {
+    pub fn insert(&mut self, text: &str, id: TokenId, control: bool) {
+        let bucket = self.by_first_byte.entry(text.as_bytes()[0]).or_default();
+        bucket.push((text.to_string(), id, control));
+        bucket.sort_by_key(|(t, _, _)| std::cmp::Reverse(t.len()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_first_byte.is_empty()
+    }
+
+    // Splits text at every special token, preferring the longest match.
+    // Control tokens are matched only with options.parse_special and never
+    // across a literal range.
+    pub fn split<'a>(&self, text: &'a str, options: &EncodeOptions) -> Vec<Fragment<'a>> {
+        let mut fragments = Vec::new();
+        let bytes = text.as_bytes();
+        let (mut start, mut i) = (0, 0);
+        while i < bytes.len() {
+            let found = self.by_first_byte.get(&bytes[i]).and_then(|bucket| {
+                bucket.iter().find(|(t, _, control)| {
+                    let end = i + t.len();
+                    bytes[i..].starts_with(t.as_bytes())
+                        && (!control
+                            || options.parse_special && !options.literal.iter().any(|r| r.start < end && i < r.end))
+                })
+            });
+            match found {
+                Some((t, id, _)) => {
+                    if start < i {
+                        fragments.push(Fragment::Text(&text[start..i]));
+                    }
+                    fragments.push(Fragment::Token(*id));
+                    i += t.len();
+                    start = i;
+                }
+                None => i += 1,
+            }
+        }
+        if start < bytes.len() {
+            fragments.push(Fragment::Text(&text[start..]));
+        }
+        fragments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::gguf::ValueType;
+
+    #[test]
+    fn test_vocabulary_from_gguf() {
+        let strings = |items: &[&str]| MetadataValue::Array(items.iter().map(|s| MetadataValue::String(s.to_string())).collect());
+        let array = |t: ValueType| ValueType::Array(Box::new(t));
+        let mut metadata = GgufMetadata::new();
+        metadata.set("tokenizer.ggml.model", MetadataValue::String("llama".into()), ValueType::String);
+        metadata.set("tokenizer.ggml.tokens", strings(&["<unk>", "<s>", "</s>", "<0x41>", "▁a", "<|im_start|>"]), array(ValueType::String));
+        metadata.set(
+            "tokenizer.ggml.scores",
+            MetadataValue::Array([0.0, 0.0, 0.0, 0.0, -1.5, 0.0].into_iter().map(MetadataValue::Float).collect()),
+            array(ValueType::F32),
+        );
+        metadata.set(
+            "tokenizer.ggml.token_type",
+            MetadataValue::Array([2, 3, 3, 6, 1, 4].into_iter().map(MetadataValue::Int).collect()),
+            array(ValueType::I32),
+        );
+        metadata.set("tokenizer.ggml.eos_token_id", MetadataValue::Uint(2), ValueType::U32);
+        metadata.set("tokenizer.ggml.add_eos_token", MetadataValue::Bool(true), ValueType::Bool);
+
+        let vocab = Vocabulary::from_gguf(&metadata);
+        assert_eq!(vocab.kind(), TokenizerKind::SentencePiece);
+        assert_eq!(vocab.scores[4], -1.5);
+        assert_eq!(vocab.types[3], TokenType::Byte);
+        assert_eq!(vocab.unk_token, Some(TokenId(0)));
+        assert!(vocab.add_bos && vocab.add_eos && vocab.add_space_prefix);
+
+        let special = vocab.special_tokens();
+        assert_eq!(
+            special.split("x<|im_start|><s>", &EncodeOptions::new().parse_special()),
+            vec![Fragment::Text("x"), Fragment::Token(TokenId(5)), Fragment::Token(TokenId(1))]
+        );
+        assert_eq!(
+            special.split("x<|im_start|><s>", &EncodeOptions::new()),
+            vec![Fragment::Text("x"), Fragment::Token(TokenId(5)), Fragment::Text("<s>")]
+        );
+        assert_eq!(
+            special.split("<s><s>", &EncodeOptions::new().parse_special().literal(3..6)),
+            vec![Fragment::Token(TokenId(1)), Fragment::Text("<s>")]
+        );
+    }
+}
@@ -1,134 +1,274 @@
 use super::traits::{Tokenizer, TokenizerStrategy, EncodeOptions, DecodeOptions, TokenizerKind};
-use super::Vocabulary;
+use super::{Fragment, SpecialTokens, TokenType, Vocabulary};
 use crate::core::{Result, TokenId};
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
 
+const SPACE: &str = "\u{2581}";
+
+// The llama.cpp SPM tokenizer: characters are merged pairwise, highest scoring
+// vocab entry first, and anything left outside the vocab falls back to
+// <0xNN> byte tokens.
 pub struct SentencePieceTokenizer {
     vocab: Vocabulary,
-    encoder: HashMap<String, TokenId>,
-    decoder: HashMap<TokenId, String>,
-    #[allow(dead_code)]
-    scores: HashMap<TokenId, f32>,
+    special: SpecialTokens,
+    byte_tokens: [Option<TokenId>; 256],
+}
+
+// A run of the input, linked to its neighbours. Merged-away symbols keep
+// len 0 so that stale bigrams can be recognised.
+struct Symbol {
+    start: usize,
+    len: usize,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+struct Bigram {
+    left: usize,
+    right: usize,
+    score: f32,
+    size: usize,
+}
+
+impl PartialEq for Bigram {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Bigram {}
+
+impl PartialOrd for Bigram {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Highest score first; ties go to the leftmost pair.
+impl Ord for Bigram {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .partial_cmp(&other.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.left.cmp(&self.left))
+    }
 }
 
 impl SentencePieceTokenizer {
     pub fn new(vocab: Vocabulary) -> Self {
-        let mut encoder = HashMap::new();
-        let mut decoder = HashMap::new();
-        let mut scores = HashMap::new();
-        
-        for (i, token) in vocab.tokens.iter().enumerate() {
-            let id = TokenId(i as i32);
-            encoder.insert(token.clone(), id);
-            decoder.insert(id, token.clone());
-            
-            if i < vocab.scores.len() {
-                scores.insert(id, vocab.scores[i]);
-            }
+        let mut byte_tokens = [None; 256];
+        for (b, slot) in byte_tokens.iter_mut().enumerate() {
+            *slot = vocab.id(&format!("<0x{:02X}>", b));
         }
-        
+
         Self {
+            special: vocab.special_tokens(),
             vocab,
-            encoder,
-            decoder,
-            scores,
+            byte_tokens,
+        }
+    }
+
+    fn score(&self, id: TokenId) -> f32 {
+        self.vocab.scores.get(id.0 as usize).copied().unwrap_or(0.0)
+    }
+
+    fn add_bigram(&self, text: &str, symbols: &[Symbol], left: usize, right: usize, heap: &mut BinaryHeap<Bigram>) {
+        let start = symbols[left].start;
+        let end = symbols[right].start + symbols[right].len;
+        if let Some(id) = self.vocab.id(&text[start..end]) {
+            heap.push(Bigram { left, right, score: self.score(id), size: end - start });
         }
     }
+
+    // Tokenizes normalized text containing no special tokens.
+    fn encode_text(&self, text: &str, tokens: &mut Vec<TokenId>) {
+        let mut symbols: Vec<Symbol> = text
+            .char_indices()
+            .enumerate()
+            .map(|(i, (start, c))| Symbol {
+                start,
+                len: c.len_utf8(),
+                prev: i.checked_sub(1),
+                next: Some(i + 1),
+            })
+            .collect();
+        let Some(last) = symbols.last_mut() else {
+            return;
+        };
+        last.next = None;
+
+        let mut heap = BinaryHeap::new();
+        for i in 1..symbols.len() {
+            self.add_bigram(text, &symbols, i - 1, i, &mut heap);
+        }
+
+        while let Some(bigram) = heap.pop() {
+            let (left, right) = (bigram.left, bigram.right);
+            let (left_len, right_len) = (symbols[left].len, symbols[right].len);
+            if left_len == 0 || right_len == 0 || left_len + right_len != bigram.size {
+                continue;
+            }
+
+            symbols[left].len += right_len;
+            symbols[right].len = 0;
+            symbols[left].next = symbols[right].next;
+            if let Some(next) = symbols[right].next {
+                symbols[next].prev = Some(left);
+            }
+
+            if let Some(prev) = symbols[left].prev {
+                self.add_bigram(text, &symbols, prev, left, &mut heap);
+            }
+            if let Some(next) = symbols[left].next {
+                self.add_bigram(text, &symbols, left, next, &mut heap);
+            }
+        }
+
+        // Every merge produced a vocab entry, so only unmerged characters can
+        // be missing from the vocab.
+        let mut cursor = Some(0);
+        while let Some(i) = cursor {
+            let piece = &text[symbols[i].start..symbols[i].start + symbols[i].len];
+            match self.vocab.id(piece) {
+                Some(id) => tokens.push(id),
+                None => self.byte_fallback(piece, tokens),
+            }
+            cursor = symbols[i].next;
+        }
+    }
+
+    fn byte_fallback(&self, piece: &str, tokens: &mut Vec<TokenId>) {
+        let bytes: Option<Vec<TokenId>> = piece.bytes().map(|b| self.byte_tokens[b as usize]).collect();
+        match (bytes, self.vocab.unk_token) {
+            (Some(ids), _) => tokens.extend(ids),
+            (None, Some(unk)) => tokens.push(unk),
+            (None, None) => {}
+        }
+    }
+
+    fn token_type(&self, id: TokenId) -> TokenType {
+        self.vocab.types.get(id.0 as usize).copied().unwrap_or(TokenType::Normal)
+    }
 }
 
 impl Tokenizer for SentencePieceTokenizer {
     fn encode(&self, text: &str) -> Result<Vec<TokenId>> {
-        self.encode_with_options(text, &EncodeOptions::default())
+        self.encode_with_options(text, &self.encode_options())
     }
-    
+
+    fn encode_options(&self) -> EncodeOptions {
+        EncodeOptions {
+            add_bos: self.vocab.add_bos,
+            add_eos: self.vocab.add_eos,
+            ..Default::default()
+        }
+    }
+
     fn encode_with_options(&self, text: &str, options: &EncodeOptions) -> Result<Vec<TokenId>> {
         let mut tokens = Vec::new();
-        
+
         if options.add_bos {
             tokens.push(self.vocab.bos_token);
         }
-        
-        let normalized = text.replace(' ', "▁");
-        let chars: Vec<char> = normalized.chars().collect();
-        
-        let mut i = 0;
-        while i < chars.len() {
-            let mut best_token: Option<TokenId> = None;
-            let mut best_len = 0;
-            
-            for len in 1..=chars.len() - i {
-                let substr: String = chars[i..i + len].iter().collect();
-                if let Some(&id) = self.encoder.get(&substr) {
-                    best_token = Some(id);
-                    best_len = len;
+
+        // Text at the start or after a special token gets the space prefix,
+        // as if it began a new sentence.
+        let mut after_special = true;
+        for fragment in self.special.split(text, options) {
+            match fragment {
+                Fragment::Token(id) => {
+                    tokens.push(id);
+                    after_special = true;
+                }
+                Fragment::Text(text) => {
+                    let mut normalized = String::with_capacity(text.len() + SPACE.len());
+                    if self.vocab.add_space_prefix && after_special {
+                        normalized.push_str(SPACE);
+                    }
+                    normalized.push_str(&text.replace(' ', SPACE));
+                    self.encode_text(&normalized, &mut tokens);
+                    after_special = false;
                 }
-            }
-            
-            if let Some(id) = best_token {
-                tokens.push(id);
-                i += best_len;
-            } else {
-                i += 1;
             }
         }
-        
+
         if options.add_eos {
             tokens.push(self.vocab.eos_token);
         }
-        
+
         if let Some(max_len) = options.truncate {
             tokens.truncate(max_len);
         }
-        
+
         Ok(tokens)
     }
-    
+
     fn decode(&self, tokens: &[TokenId]) -> Result<String> {
         self.decode_with_options(tokens, &DecodeOptions::default())
     }
-    
+
     fn decode_with_options(&self, tokens: &[TokenId], options: &DecodeOptions) -> Result<String> {
-        let mut text = String::new();
-        
+        let mut bytes = Vec::new();
+
         for &id in tokens {
-            if let Some(token) = self.decoder.get(&id) {
-                if options.skip_special_tokens && 
-                   (id == self.vocab.bos_token || id == self.vocab.eos_token) {
-                    continue;
-                }
-                text.push_str(token);
+            let special = self.token_type(id) == TokenType::Control
+                || id == self.vocab.bos_token
+                || id == self.vocab.eos_token;
+            if options.skip_special_tokens && special {
+                continue;
+            }
+            let mut piece = self.token_bytes(id);
+            // Undo the space prefix added when encoding. Only the first piece
+            // carries it; a leading <0x20> byte token is real text.
+            if bytes.is_empty() && self.vocab.add_space_prefix && self.token_type(id) != TokenType::Byte && piece.first() == Some(&b' ') {
+                piece.remove(0);
             }
+            bytes.extend(piece);
         }
-        
-        let text = text.replace('▁', " ");
-        
+
+        let text = String::from_utf8_lossy(&bytes).into_owned();
+
         let text = if options.clean_up_tokenization_spaces {
             text.trim().to_string()
         } else {
             text
         };
-        
+
         Ok(text)
     }
-    
+
+    fn token_bytes(&self, id: TokenId) -> Vec<u8> {
+        let Some(token) = self.id_to_token(id) else {
+            return Vec::new();
+        };
+        if self.token_type(id) == TokenType::Byte {
+            let hex = token.strip_prefix("<0x").and_then(|t| t.strip_suffix('>'));
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                return vec![b];
+            }
+        }
+        token.replace(SPACE, " ").into_bytes()
+    }
+
     fn vocab_size(&self) -> usize {
         self.vocab.size()
     }
-    
+
     fn bos_token(&self) -> TokenId {
         self.vocab.bos_token
     }
-    
+
     fn eos_token(&self) -> TokenId {
         self.vocab.eos_token
     }
-    
+
     fn token_to_id(&self, token: &str) -> Option<TokenId> {
-        self.encoder.get(token).copied()
+        self.vocab.id(token)
     }
-    
+
     fn id_to_token(&self, id: TokenId) -> Option<&str> {
-        self.decoder.get(&id).map(|s| s.as_str())
+        usize::try_from(id.0).ok().and_then(|i| self.vocab.tokens.get(i)).map(|s| s.as_str())
     }
 }
 
@@ -136,22 +276,117 @@ impl TokenizerStrategy for SentencePieceTokenizer {
     fn kind(&self) -> TokenizerKind {
         TokenizerKind::SentencePiece
     }
-    
+
     fn can_handle(&self, vocab_type: &str) -> bool {
-        matches!(vocab_type.to_lowercase().as_str(), "sentencepiece" | "spm" | "unigram")
+        matches!(vocab_type.to_lowercase().as_str(), "sentencepiece" | "spm" | "llama")
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+
     #[test]
     fn test_sentencepiece_tokenizer() {
         let vocab = Vocabulary::new(vec!["<s>".into(), "</s>".into(), "▁Hello".into(), "▁world".into()]);
         let tokenizer = SentencePieceTokenizer::new(vocab);
-        
+
         assert_eq!(tokenizer.vocab_size(), 4);
         assert_eq!(tokenizer.bos_token(), TokenId::BOS);
     }
+
+    // A cut-down llama vocabulary. The expected ids below are what llama.cpp's
+    // SPM tokenizer produces for the same vocab: merges follow scores, not
+    // length, so "abc" splits as ▁ a bc even though "ab" is in the vocab.
+    fn llama_vocab() -> Vocabulary {
+        let entries: &[(&str, f32, TokenType)] = &[
+            ("<unk>", 0.0, TokenType::Unknown),
+            ("<s>", 0.0, TokenType::Control),
+            ("</s>", 0.0, TokenType::Control),
+            ("<0xF0>", 0.0, TokenType::Byte),
+            ("<0x9F>", 0.0, TokenType::Byte),
+            ("<0x98>", 0.0, TokenType::Byte),
+            ("<0x80>", 0.0, TokenType::Byte),
+            ("▁", -10.0, TokenType::Normal),
+            ("h", -10.0, TokenType::Normal),
+            ("e", -10.0, TokenType::Normal),
+            ("l", -10.0, TokenType::Normal),
+            ("o", -10.0, TokenType::Normal),
+            ("w", -10.0, TokenType::Normal),
+            ("r", -10.0, TokenType::Normal),
+            ("d", -10.0, TokenType::Normal),
+            ("a", -10.0, TokenType::Normal),
+            ("b", -10.0, TokenType::Normal),
+            ("c", -10.0, TokenType::Normal),
+            ("▁h", -1.0, TokenType::Normal),
+            ("▁w", -1.5, TokenType::Normal),
+            ("ll", -2.0, TokenType::Normal),
+            ("or", -2.5, TokenType::Normal),
+            ("▁he", -3.0, TokenType::Normal),
+            ("▁hell", -4.0, TokenType::Normal),
+            ("▁hello", -5.0, TokenType::Normal),
+            ("▁wor", -6.0, TokenType::Normal),
+            ("ld", -6.5, TokenType::Normal),
+            ("▁world", -7.0, TokenType::Normal),
+            ("ab", -1.0, TokenType::Normal),
+            ("bc", -0.5, TokenType::Normal),
+            ("<|user|>", 0.0, TokenType::UserDefined),
+        ];
+        let mut vocab = Vocabulary::new(entries.iter().map(|(t, _, _)| t.to_string()).collect());
+        vocab.scores = entries.iter().map(|(_, s, _)| *s).collect();
+        vocab.types = entries.iter().map(|(_, _, t)| *t).collect();
+        vocab.unk_token = Some(TokenId(0));
+        vocab.model = "llama".into();
+        vocab.add_bos = true;
+        vocab
+    }
+
+    fn ids(ids: &[i32]) -> Vec<TokenId> {
+        ids.iter().map(|&i| TokenId(i)).collect()
+    }
+
+    #[test]
+    fn test_encode_matches_reference_ids() {
+        let tokenizer = SentencePieceTokenizer::new(llama_vocab());
+
+        assert_eq!(tokenizer.encode("hello world").unwrap(), ids(&[1, 24, 27]));
+        assert_eq!(tokenizer.encode("abc").unwrap(), ids(&[1, 7, 15, 29]));
+        assert_eq!(tokenizer.encode(" hello").unwrap(), ids(&[1, 7, 24]));
+        // 😀 is not in the vocab and falls back to its UTF-8 bytes; é has no
+        // byte tokens either and becomes <unk>.
+        assert_eq!(tokenizer.encode("he😀é").unwrap(), ids(&[1, 22, 3, 4, 5, 6, 0]));
+        // Control tokens are only matched in templates; user-defined ones always.
+        let special = tokenizer.encode_options().parse_special();
+        assert_eq!(tokenizer.encode_with_options("<|user|>hello</s>", &special).unwrap(), ids(&[1, 30, 24, 2]));
+        assert_eq!(tokenizer.encode("<|user|>hello</s>").unwrap(), ids(&[1, 30, 24, 0, 0, 0, 0]));
+        assert_eq!(tokenizer.encode("hello <|user|>").unwrap(), ids(&[1, 24, 7, 30]));
+        assert_eq!(tokenizer.encode("").unwrap(), ids(&[1]));
+    }
+
+    #[test]
+    fn test_metadata_flags() {
+        let mut vocab = llama_vocab();
+        vocab.add_bos = false;
+        vocab.add_eos = true;
+        vocab.add_space_prefix = false;
+        let tokenizer = SentencePieceTokenizer::new(vocab);
+
+        assert_eq!(tokenizer.encode("hello").unwrap(), ids(&[8, 9, 20, 11, 2]));
+        assert_eq!(tokenizer.encode(" hello").unwrap(), ids(&[24, 2]));
+    }
+
+    #[test]
+    fn test_decode() {
+        let tokenizer = SentencePieceTokenizer::new(llama_vocab());
+        let skip = DecodeOptions::new().skip_special();
+
+        let tokens = tokenizer.encode("hello world he😀").unwrap();
+        assert_eq!(tokenizer.decode_with_options(&tokens, &skip).unwrap(), "hello world he😀");
+        assert_eq!(tokenizer.decode(&ids(&[24, 27, 2])).unwrap(), "hello world</s>");
+        assert_eq!(tokenizer.decode_with_options(&ids(&[30, 24]), &skip).unwrap(), "<|user|> hello");
+
+        let text = "hello world he😀";
+        let offsets = super::super::token_offsets(&tokenizer, text, &tokens);
+        assert_eq!(offsets, [0..0, 0..5, 5..11, 11..14, 14..15, 15..16, 16..17, 17..18]);
+    }
 }
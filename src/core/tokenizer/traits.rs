@@ -1,12 +1,31 @@
 use crate::core::{Result, TokenId};
+use rayon::prelude::*;
 use std::collections::HashMap;
+use std::ops::Range;
 
 pub trait Tokenizer: Send + Sync {
     fn encode(&self, text: &str) -> Result<Vec<TokenId>>;
     fn encode_with_options(&self, text: &str, options: &EncodeOptions) -> Result<Vec<TokenId>>;
+
+    // The options `encode` uses, e.g. BOS/EOS as the vocabulary asks for them.
+    fn encode_options(&self) -> EncodeOptions {
+        EncodeOptions::default()
+    }
     
+    // Encodes independent inputs in parallel, e.g. the items of an embedding
+    // request. Output order follows the input.
+    fn encode_batch(&self, texts: &[&str], options: &EncodeOptions) -> Result<Vec<Vec<TokenId>>> {
+        texts.par_iter().map(|text| self.encode_with_options(text, options)).collect()
+    }
+
     fn decode(&self, tokens: &[TokenId]) -> Result<String>;
     fn decode_with_options(&self, tokens: &[TokenId], options: &DecodeOptions) -> Result<String>;
+
+    // The raw bytes of one token, before the cleanup `decode` applies to a
+    // whole sequence such as dropping the SentencePiece space prefix.
+    fn token_bytes(&self, id: TokenId) -> Vec<u8> {
+        self.decode(&[id]).map(String::into_bytes).unwrap_or_default()
+    }
     
     fn vocab_size(&self) -> usize;
     fn bos_token(&self) -> TokenId;
@@ -37,6 +56,12 @@ pub struct EncodeOptions {
     pub truncate: Option<usize>,
     pub return_attention_mask: bool,
     pub return_offsets: bool,
+    // Match control tokens such as <|im_start|> in the text. Only prompt
+    // templates set this; user-defined tokens are always matched.
+    pub parse_special: bool,
+    // Byte ranges never searched for control tokens, even with parse_special,
+    // e.g. user content substituted into a template.
+    pub literal: Vec<Range<usize>>,
 }
 
 impl EncodeOptions {
@@ -58,6 +83,16 @@ impl EncodeOptions {
         self.truncate = Some(max_len);
         self
     }
+
+    pub fn parse_special(mut self) -> Self {
+        self.parse_special = true;
+        self
+    }
+
+    pub fn literal(mut self, range: Range<usize>) -> Self {
+        self.literal.push(range);
+        self
+    }
 }
 
 #[derive(Debug, Clone, Default)]
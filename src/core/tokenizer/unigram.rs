@@ -0,0 +1,318 @@
+use super::traits::{Tokenizer, TokenizerStrategy, EncodeOptions, DecodeOptions, TokenizerKind};
+use super::{Fragment, SpecialTokens, TokenType, Vocabulary};
+use crate::core::{Result, TokenId};
+use unicode_normalization::UnicodeNormalization;
+
+const SPACE: &str = "\u{2581}";
+
+// Penalty below the lowest piece score for characters outside the vocab, as
+// in SentencePiece.
+const UNK_PENALTY: f32 = 10.0;
+
+// SentencePiece's Unigram model (T5, Flan, many embedding models): the input
+// is split into the sequence of vocab pieces with the highest total score.
+pub struct UnigramTokenizer {
+    vocab: Vocabulary,
+    special: SpecialTokens,
+    byte_tokens: [Option<TokenId>; 256],
+    max_piece_len: usize,
+    unk_score: f32,
+}
+
+// The best segmentation ending at a position: its score, where its last
+// piece starts, and that piece (None for an unknown character).
+#[derive(Clone, Copy)]
+struct Node {
+    score: f32,
+    start: usize,
+    token: Option<TokenId>,
+}
+
+impl UnigramTokenizer {
+    pub fn new(vocab: Vocabulary) -> Self {
+        let mut byte_tokens = [None; 256];
+        for (b, slot) in byte_tokens.iter_mut().enumerate() {
+            *slot = vocab.id(&format!("<0x{:02X}>", b));
+        }
+
+        let max_piece_len = vocab
+            .tokens
+            .iter()
+            .zip(&vocab.types)
+            .filter(|(_, t)| Self::in_lattice(**t))
+            .map(|(p, _)| p.len())
+            .max()
+            .unwrap_or(0);
+        let min_score = vocab
+            .scores
+            .iter()
+            .zip(&vocab.types)
+            .filter(|(_, t)| Self::in_lattice(**t))
+            .map(|(s, _)| *s)
+            .fold(0.0, f32::min);
+
+        Self {
+            special: vocab.special_tokens(),
+            vocab,
+            byte_tokens,
+            max_piece_len,
+            unk_score: min_score - UNK_PENALTY,
+        }
+    }
+
+    // Control, unknown, unused and byte pieces never take part in segmentation.
+    fn in_lattice(kind: TokenType) -> bool {
+        matches!(kind, TokenType::Normal | TokenType::UserDefined)
+    }
+
+    fn token_type(&self, id: TokenId) -> TokenType {
+        self.vocab.types.get(id.0 as usize).copied().unwrap_or(TokenType::Normal)
+    }
+
+    // NFKC, then whitespace runs collapse to a single ▁ and the ends are
+    // trimmed. The dummy prefix marks the start of a word.
+    fn normalize(&self, text: &str) -> String {
+        let text: String = text.nfkc().collect();
+        let mut normalized = String::with_capacity(text.len() + SPACE.len());
+        for word in text.split_whitespace() {
+            if !normalized.is_empty() || self.vocab.add_space_prefix {
+                normalized.push_str(SPACE);
+            }
+            normalized.push_str(word);
+        }
+        normalized
+    }
+
+    // The best segmentation as (start, end, piece) spans.
+    fn viterbi(&self, text: &str) -> Vec<(usize, usize, Option<TokenId>)> {
+        let mut best: Vec<Option<Node>> = vec![None; text.len() + 1];
+        best[0] = Some(Node { score: 0.0, start: 0, token: None });
+
+        for (start, c) in text.char_indices() {
+            let Some(base) = best[start] else {
+                continue;
+            };
+            let mut relax = |end: usize, score: f32, token: Option<TokenId>| {
+                let score = base.score + score;
+                if best[end].is_none_or(|n| score > n.score) {
+                    best[end] = Some(Node { score, start, token });
+                }
+            };
+
+            let mut single = false;
+            let mut end = start;
+            for next in text[start..].chars() {
+                end += next.len_utf8();
+                if end - start > self.max_piece_len {
+                    break;
+                }
+                let Some(id) = self.vocab.id(&text[start..end]) else {
+                    continue;
+                };
+                let kind = self.token_type(id);
+                if !Self::in_lattice(kind) {
+                    continue;
+                }
+                let score = if kind == TokenType::UserDefined { 0.0 } else { self.vocab.scores[id.0 as usize] };
+                single |= end == start + c.len_utf8();
+                relax(end, score, Some(id));
+            }
+            if !single {
+                relax(start + c.len_utf8(), self.unk_score, None);
+            }
+        }
+
+        let mut path = Vec::new();
+        let mut end = text.len();
+        while end > 0 {
+            let node = best[end].expect("every character has a path");
+            path.push((node.start, end, node.token));
+            end = node.start;
+        }
+        path.reverse();
+        path
+    }
+
+    fn encode_text(&self, text: &str, tokens: &mut Vec<TokenId>) {
+        let mut prev_unknown = false;
+        for (start, end, token) in self.viterbi(text) {
+            if let Some(id) = token {
+                tokens.push(id);
+                prev_unknown = false;
+                continue;
+            }
+            let piece = &text[start..end];
+            let bytes: Option<Vec<TokenId>> = piece.bytes().map(|b| self.byte_tokens[b as usize]).collect();
+            match (bytes, self.vocab.unk_token) {
+                (Some(ids), _) => tokens.extend(ids),
+                // Runs of unknown characters collapse into one <unk>.
+                (None, Some(unk)) if !prev_unknown => {
+                    tokens.push(unk);
+                    prev_unknown = true;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Tokenizer for UnigramTokenizer {
+    fn encode(&self, text: &str) -> Result<Vec<TokenId>> {
+        self.encode_with_options(text, &self.encode_options())
+    }
+
+    fn encode_options(&self) -> EncodeOptions {
+        EncodeOptions {
+            add_bos: self.vocab.add_bos,
+            add_eos: self.vocab.add_eos,
+            ..Default::default()
+        }
+    }
+
+    fn encode_with_options(&self, text: &str, options: &EncodeOptions) -> Result<Vec<TokenId>> {
+        let mut tokens = Vec::new();
+
+        if options.add_bos {
+            tokens.push(self.vocab.bos_token);
+        }
+
+        for fragment in self.special.split(text, options) {
+            match fragment {
+                Fragment::Token(id) => tokens.push(id),
+                Fragment::Text(text) => self.encode_text(&self.normalize(text), &mut tokens),
+            }
+        }
+
+        if options.add_eos {
+            tokens.push(self.vocab.eos_token);
+        }
+
+        if let Some(max_len) = options.truncate {
+            tokens.truncate(max_len);
+        }
+
+        Ok(tokens)
+    }
+
+    fn decode(&self, tokens: &[TokenId]) -> Result<String> {
+        self.decode_with_options(tokens, &DecodeOptions::default())
+    }
+
+    fn decode_with_options(&self, tokens: &[TokenId], options: &DecodeOptions) -> Result<String> {
+        let mut bytes = Vec::new();
+
+        for &id in tokens {
+            if options.skip_special_tokens && self.token_type(id) == TokenType::Control {
+                continue;
+            }
+            let mut piece = self.token_bytes(id);
+            // Only the first piece carries the space prefix added when encoding.
+            if bytes.is_empty() && self.vocab.add_space_prefix && self.token_type(id) != TokenType::Byte && piece.first() == Some(&b' ') {
+                piece.remove(0);
+            }
+            bytes.extend(piece);
+        }
+
+        let text = String::from_utf8_lossy(&bytes).into_owned();
+        Ok(if options.clean_up_tokenization_spaces { text.trim().to_string() } else { text })
+    }
+
+    fn token_bytes(&self, id: TokenId) -> Vec<u8> {
+        let Some(token) = self.id_to_token(id) else {
+            return Vec::new();
+        };
+        if self.token_type(id) == TokenType::Byte {
+            let hex = token.strip_prefix("<0x").and_then(|t| t.strip_suffix('>'));
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                return vec![b];
+            }
+        }
+        token.replace(SPACE, " ").into_bytes()
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab.size()
+    }
+
+    fn bos_token(&self) -> TokenId {
+        self.vocab.bos_token
+    }
+
+    fn eos_token(&self) -> TokenId {
+        self.vocab.eos_token
+    }
+
+    fn token_to_id(&self, token: &str) -> Option<TokenId> {
+        self.vocab.id(token)
+    }
+
+    fn id_to_token(&self, id: TokenId) -> Option<&str> {
+        usize::try_from(id.0).ok().and_then(|i| self.vocab.tokens.get(i)).map(|s| s.as_str())
+    }
+}
+
+impl TokenizerStrategy for UnigramTokenizer {
+    fn kind(&self) -> TokenizerKind {
+        TokenizerKind::Unigram
+    }
+
+    fn can_handle(&self, vocab_type: &str) -> bool {
+        matches!(vocab_type.to_lowercase().as_str(), "unigram" | "ugm" | "t5")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t5_vocab() -> Vocabulary {
+        let entries: &[(&str, f32, TokenType)] = &[
+            ("<pad>", 0.0, TokenType::Control),
+            ("</s>", 0.0, TokenType::Control),
+            ("<unk>", 0.0, TokenType::Unknown),
+            ("▁", -2.0, TokenType::Normal),
+            ("▁the", -1.0, TokenType::Normal),
+            ("▁th", -3.0, TokenType::Normal),
+            ("e", -3.0, TokenType::Normal),
+            ("▁a", -2.0, TokenType::Normal),
+            ("b", -4.0, TokenType::Normal),
+            ("c", -4.0, TokenType::Normal),
+            ("▁abc", -12.0, TokenType::Normal),
+            ("<0x78>", 0.0, TokenType::Byte),
+        ];
+        let mut vocab = Vocabulary::new(entries.iter().map(|(t, _, _)| t.to_string()).collect());
+        vocab.scores = entries.iter().map(|(_, s, _)| *s).collect();
+        vocab.types = entries.iter().map(|(_, _, t)| *t).collect();
+        vocab.model = "t5".into();
+        vocab.eos_token = TokenId(1);
+        vocab.unk_token = Some(TokenId(2));
+        vocab.add_eos = true;
+        vocab
+    }
+
+    fn ids(ids: &[i32]) -> Vec<TokenId> {
+        ids.iter().map(|&i| TokenId(i)).collect()
+    }
+
+    #[test]
+    fn test_viterbi_segmentation() {
+        let tokenizer = UnigramTokenizer::new(t5_vocab());
+
+        // ▁a b c scores -10 against -12 for the single longest piece.
+        assert_eq!(tokenizer.encode("abc").unwrap(), ids(&[7, 8, 9, 1]));
+        assert_eq!(tokenizer.encode("the").unwrap(), ids(&[4, 1]));
+        // Whitespace runs collapse and full-width letters fold under NFKC.
+        assert_eq!(tokenizer.encode("  ｔｈｅ \t\n abc  ").unwrap(), ids(&[4, 7, 8, 9, 1]));
+        // x has a byte piece; é does not, and a run of them is a single <unk>.
+        let special = tokenizer.encode_options().parse_special();
+        assert_eq!(tokenizer.encode_with_options("x ééé</s>", &special).unwrap(), ids(&[3, 11, 3, 2, 1, 1]));
+    }
+
+    #[test]
+    fn test_decode() {
+        let tokenizer = UnigramTokenizer::new(t5_vocab());
+        let tokens = tokenizer.encode("the abc x").unwrap();
+        let skip = DecodeOptions::new().skip_special();
+        assert_eq!(tokenizer.decode_with_options(&tokens, &skip).unwrap(), "the abc x");
+    }
+}
@@ -67,3 +67,64 @@ pub fn models_dir() -> std::path::PathBuf {
     
     std::path::PathBuf::from(path)
 }
+
+// The directory /api/create may import local files from (OLLAMA_IMPORT_ROOT),
+// the home directory unless set.
+pub fn import_root() -> std::path::PathBuf {
+    match env::var("OLLAMA_IMPORT_ROOT") {
+        Ok(path) if !path.is_empty() => std::path::PathBuf::from(path),
+        _ => dirs::home_dir().unwrap_or_else(|| std::path::PathBuf::from(".")),
+    }
+}
+
+// How many requests each loaded model evaluates together (OLLAMA_NUM_PARALLEL).
+pub fn num_parallel() -> usize {
+    env::var("OLLAMA_NUM_PARALLEL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n: &usize| n > 0)
+        .unwrap_or(1)
+}
+
+// Positions per paged KV cache block (OLLAMA_KV_BLOCK_SIZE).
+pub fn kv_block_size() -> usize {
+    env::var("OLLAMA_KV_BLOCK_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n: &usize| n > 0)
+        .unwrap_or(16)
+}
+
+// How K/V positions are stored (OLLAMA_KV_CACHE_TYPE): f16, the default as in
+// upstream Ollama, f32, q8_0 or q4_0.
+pub fn kv_cache_type() -> ollama::core::cache::KvCacheType {
+    let Ok(value) = env::var("OLLAMA_KV_CACHE_TYPE") else {
+        return Default::default();
+    };
+    ollama::core::cache::KvCacheType::parse(&value).unwrap_or_else(|| {
+        tracing::warn!("unknown OLLAMA_KV_CACHE_TYPE {:?}, using f16", value);
+        Default::default()
+    })
+}
+
+// Memory each loaded model may use for K/V (OLLAMA_KV_CACHE_BUDGET), in bytes
+// or with a K, M or G suffix.
+pub fn kv_cache_budget() -> Option<usize> {
+    env::var("OLLAMA_KV_CACHE_BUDGET").ok().and_then(|v| parse_size(&v))
+}
+
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (digits, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+    let scale = match unit.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(scale)
+}
@@ -1,5 +1,6 @@
 pub mod gguf {
     use std::collections::HashMap;
+    use ollama::infra::gguf::{GgmlType, GgufParser, MetadataValue};
     use std::io::{BufReader, Read};
     use std::path::Path;
 
@@ -55,6 +56,31 @@ pub mod gguf {
             }
         }
 
+        pub fn from_ggml(dtype: GgmlType) -> Self {
+            match dtype {
+                GgmlType::F32 => DataType::Float32,
+                GgmlType::F16 => DataType::Float16,
+                GgmlType::Q4_0 => DataType::Q4_0,
+                GgmlType::Q4_1 => DataType::Q4_1,
+                GgmlType::Q5_0 => DataType::Q5_0,
+                GgmlType::Q5_1 => DataType::Q5_1,
+                GgmlType::Q8_0 => DataType::Q8_0,
+                GgmlType::Q8_1 => DataType::Q8_1,
+                GgmlType::Q2K => DataType::Q2_K,
+                GgmlType::Q3K => DataType::Q3_K,
+                GgmlType::Q4K => DataType::Q4_K,
+                GgmlType::Q5K => DataType::Q5_K,
+                GgmlType::Q6K => DataType::Q6_K,
+                GgmlType::Q8K => DataType::Q8_K,
+                GgmlType::I8 => DataType::I8,
+                GgmlType::I16 => DataType::I16,
+                GgmlType::I32 => DataType::I32,
+                GgmlType::I64 => DataType::I64,
+                GgmlType::F64 => DataType::F64,
+                other => DataType::Unknown(other.id() as u8),
+            }
+        }
+
         pub fn bytes_per_element(&self) -> u32 {
             match self {
                 DataType::Float32 => 4,
@@ -97,6 +123,17 @@ pub mod gguf {
 
     #[allow(dead_code)]
     impl GGUFValue {
+        fn from_metadata(value: &MetadataValue) -> Self {
+            match value {
+                MetadataValue::String(s) => GGUFValue::String(s.clone()),
+                MetadataValue::Int(i) => GGUFValue::Int(*i),
+                MetadataValue::Uint(u) => GGUFValue::UInt(*u),
+                MetadataValue::Float(f) => GGUFValue::Float64(*f),
+                MetadataValue::Bool(b) => GGUFValue::Bool(*b),
+                MetadataValue::Array(arr) => GGUFValue::Array(arr.iter().map(Self::from_metadata).collect()),
+            }
+        }
+
         pub fn type_name(&self) -> &str {
             match self {
                 GGUFValue::String(_) => "string",
@@ -149,60 +186,29 @@ pub mod gguf {
         pub fn open(path: &Path) -> std::io::Result<Self> {
             let file = std::fs::File::open(path)?;
             let file_size = file.metadata()?.len();
-            let mut reader = BufReader::new(file);
-
-            let mut magic = [0u8; 4];
-            reader.read_exact(&mut magic)?;
-
-            if &magic != b"GGUF" {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Invalid GGUF magic number",
-                ));
-            }
-
-            let mut version_bytes = [0u8; 4];
-            reader.read_exact(&mut version_bytes)?;
-            let version = u32::from_le_bytes(version_bytes);
-
-            let mut metadata = HashMap::new();
-            let mut tensors = Vec::new();
-
-            let metadata_kv_count = read_u64(&mut reader)? as usize;
-
-            for _ in 0..metadata_kv_count {
-                let key = read_string(&mut reader)?;
-                let value_type = reader.read_u8()?;
-                let value = read_value(&mut reader, value_type)?;
-                metadata.insert(key, value);
-            }
-
-            let tensor_count = read_u64(&mut reader)? as usize;
-
-            for _ in 0..tensor_count {
-                let name = read_string(&mut reader)?;
-                let n_dims = reader.read_u8()? as usize;
-                let mut shape = Vec::with_capacity(n_dims);
-                for _ in 0..n_dims {
-                    shape.push(read_u64(&mut reader)?);
-                }
-                let dtype_byte = reader.read_u8()?;
-                let dtype = DataType::from_u8(dtype_byte);
-                let offset = read_u64(&mut reader)?;
-
-                let n_elements: u64 = shape.iter().product();
-
-                tensors.push(TensorInfo {
-                    name,
-                    shape,
-                    dtype,
-                    offset,
-                    n_elements,
-                });
-            }
+            let parsed = GgufParser::parse_reader(&mut BufReader::new(file))
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+            let metadata = parsed
+                .metadata
+                .kv
+                .iter()
+                .map(|(key, value)| (key.clone(), GGUFValue::from_metadata(value)))
+                .collect();
+            let tensors = parsed
+                .tensors
+                .iter()
+                .map(|t| TensorInfo {
+                    name: t.name.clone(),
+                    shape: t.dims.iter().map(|d| *d as u64).collect(),
+                    dtype: DataType::from_ggml(t.dtype),
+                    offset: t.offset,
+                    n_elements: t.num_elements() as u64,
+                })
+                .collect();
 
             Ok(Self {
-                version,
+                version: parsed.version,
                 tensors,
                 metadata,
                 file_size,
@@ -227,6 +233,7 @@ pub mod gguf {
         pub fn get_metadata_float(&self, key: &str) -> Option<f32> {
             self.metadata.get(key).and_then(|v| match v {
                 GGUFValue::Float(f) => Some(*f),
+                GGUFValue::Float64(f) => Some(*f as f32),
                 _ => None,
             })
         }
@@ -248,76 +255,6 @@ pub mod gguf {
         }
     }
 
-    #[allow(dead_code)]
-    fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
-        let mut buf = [0u8; 8];
-        reader.read_exact(&mut buf)?;
-        Ok(u64::from_le_bytes(buf))
-    }
-
-    #[allow(dead_code)]
-    fn read_string<R: Read>(reader: &mut R) -> std::io::Result<String> {
-        let len = match read_u64(reader)? {
-            0 => return Ok(String::new()),
-            n => n as usize,
-        };
-        
-        let mut buf = vec![0u8; len];
-        reader.read_exact(&mut buf)?;
-        
-        // Handle potential null terminator
-        if let Some(pos) = buf.iter().position(|&b| b == 0) {
-            buf.truncate(pos);
-        }
-        
-        String::from_utf8(buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
-    }
-
-    #[allow(dead_code)]
-    fn read_value<R: Read>(reader: &mut R, value_type: u8) -> std::io::Result<GGUFValue> {
-        match value_type {
-            0 => {
-                let mut buf = [0u8; 4];
-                reader.read_exact(&mut buf)?;
-                Ok(GGUFValue::Float(f32::from_le_bytes(buf)))
-            }
-            1 => {
-                let mut buf = [0u8; 8];
-                reader.read_exact(&mut buf)?;
-                Ok(GGUFValue::Int(i64::from_le_bytes(buf)))
-            }
-            2 => {
-                let mut buf = [0u8; 1];
-                reader.read_exact(&mut buf)?;
-                Ok(GGUFValue::Bool(buf[0] != 0))
-            }
-            3 => {
-                read_string(reader).map(GGUFValue::String)
-            }
-            4 => {
-                let array_type = reader.read_u8()?;
-                let count = read_u64(reader)? as usize;
-                let mut arr = Vec::with_capacity(count);
-                for _ in 0..count {
-                    arr.push(read_value(reader, array_type)?);
-                }
-                Ok(GGUFValue::Array(arr))
-            }
-            5 => {
-                read_u64(reader).map(GGUFValue::UInt)
-            }
-            6..=15 => {
-                let mut buf = [0u8; 8];
-                reader.read_exact(&mut buf)?;
-                Ok(GGUFValue::Float64(f64::from_le_bytes(buf)))
-            }
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Unknown GGUF value type: {}", value_type),
-            )),
-        }
-    }
-
     #[allow(dead_code)]
     pub trait ReadExt {
         fn read_u8(&mut self) -> std::io::Result<u8>;
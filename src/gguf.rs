@@ -1,10 +1,10 @@
-use anyhow::{bail, Result};
+use crate::core::cache::KvCacheType;
+use crate::infra::gguf::{GgufParser, MetadataValue, ValueType};
+use anyhow::Result;
 use std::fs::File;
 use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
 
-const GGUF_MAGIC: u32 = 0x46554747;
-
 pub trait GgufMetadata {
     fn string(&self, key: &str) -> String;
     fn uint(&self, key: &str) -> u64;
@@ -57,12 +57,12 @@ impl From<u32> for GgmlType {
             13 => GgmlType::Q5_K,
             14 => GgmlType::Q6_K,
             15 => GgmlType::Q8_K,
-            16 => GgmlType::I8,
-            17 => GgmlType::I16,
-            18 => GgmlType::I32,
-            19 => GgmlType::I64,
-            20 => GgmlType::F64,
-            21 => GgmlType::BF16,
+            24 => GgmlType::I8,
+            25 => GgmlType::I16,
+            26 => GgmlType::I32,
+            27 => GgmlType::I64,
+            28 => GgmlType::F64,
+            30 => GgmlType::BF16,
             _ => GgmlType::Unknown(v),
         }
     }
@@ -115,6 +115,7 @@ pub struct GgufMetadataImpl {
     pub bos_token_id: Option<u64>,
     pub vocab_tokens: Option<Vec<String>>,
     pub vocab_scores: Option<Vec<f32>>,
+    kv: std::collections::HashMap<String, Value>,
 }
 
 impl GgufMetadata for GgufMetadataImpl {
@@ -122,7 +123,7 @@ impl GgufMetadata for GgufMetadataImpl {
         match key {
             "general.architecture" => self.arch.clone().unwrap_or_default(),
             "general.name" => self.name.clone().unwrap_or_default(),
-            _ => String::new(),
+            _ => self.kv.get(key).and_then(Value::as_string).unwrap_or_default(),
         }
     }
     
@@ -136,7 +137,7 @@ impl GgufMetadata for GgufMetadataImpl {
             "llama.attention.head_count_kv" | "qwen.attention.head_count_kv" => self.head_count_kv,
             "llama.rope.dimension_count" => self.rope_dimension_count,
             "llama.vocab_size" | "qwen.vocab_size" => self.vocab_size,
-            _ => 0,
+            _ => self.kv.get(key).and_then(Value::as_u64).unwrap_or(0),
         }
     }
     
@@ -148,12 +149,15 @@ impl GgufMetadata for GgufMetadataImpl {
         match key {
             "llama.attention.layer_norm_rms_epsilon" | "qwen.attention.layer_norm_rms_epsilon" => self.layer_norm_rms_epsilon as f64,
             "llama.rope.freq_base" | "qwen.rope.freq_base" => self.rope_freq_base as f64,
-            _ => 0.0,
+            _ => self.kv.get(key).and_then(Value::as_f64).unwrap_or(0.0),
         }
     }
     
-    fn strings(&self, _key: &str) -> Vec<String> {
-        Vec::new()
+    fn strings(&self, key: &str) -> Vec<String> {
+        match self.kv.get(key) {
+            Some(Value::Array(arr)) => arr.iter().filter_map(Value::as_string).collect(),
+            _ => Vec::new(),
+        }
     }
 }
 
@@ -193,24 +197,23 @@ impl GgufFile {
         Self::read(&mut reader)
     }
 
+    // Header validation is shared with the infra parser; this only maps the
+    // result onto the legacy structures.
     fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
-        let magic = read_u32(reader)?;
-        if magic != GGUF_MAGIC {
-            bail!("Invalid GGUF magic: expected {:08x}, got {:08x}", GGUF_MAGIC, magic);
-        }
-
-        let version = read_u32(reader)?;
-        let tensor_count = read_u64(reader)?;
-        let metadata_kv_count = read_u64(reader)?;
+        let parsed = GgufParser::parse_reader(reader)?;
+        let version = parsed.version;
+        let tensor_count = parsed.tensor_count;
+        let metadata_kv_count = parsed.metadata.kv.len() as u64;
 
         let mut metadata = GgufMetadataImpl::default();
         let mut architecture = String::new();
 
-        for _ in 0..metadata_kv_count {
-            let key = read_string(reader)?;
-            let value = read_value(reader)?;
+        for key in parsed.metadata.keys() {
+            let vtype = parsed.metadata.types.get(key).unwrap_or(&ValueType::U64);
+            let value = Value::from_metadata(&parsed.metadata.kv[key], vtype);
+            metadata.kv.insert(key.to_string(), value.clone());
 
-            match key.as_str() {
+            match key {
                 "general.architecture" => {
                     architecture = value.as_string().unwrap_or_default();
                     metadata.arch = Some(architecture.clone());
@@ -280,40 +283,19 @@ impl GgufFile {
             }
         }
 
-        let mut tensors = Vec::with_capacity(tensor_count as usize);
-        for _ in 0..tensor_count {
-            let name = read_string(reader)?;
-            let n_dims = read_u32(reader)? as usize;
-            
-            if n_dims > 10 {
-                bail!("Too many dimensions: {}", n_dims);
-            }
-            
-            let mut dims = Vec::with_capacity(n_dims);
-            for _ in 0..n_dims {
-                let dim = read_u64(reader)?;
-                if dim > 100_000 {
-                    bail!("Dimension too large: {}", dim);
-                }
-                dims.push(dim);
-            }
-
-            let ggml_type = GgmlType::from(read_u32(reader)?);
-            let offset = read_u64(reader)?;
-
-            let elements: u64 = dims.iter().product();
-            let size = elements.saturating_mul(ggml_type.bytes_per_element() as u64);
-
-            tensors.push(TensorInfo {
-                name,
-                dims,
-                ggml_type,
-                offset,
-                size,
-            });
-        }
-
-        let data_offset = reader.stream_position()?;
+        let tensors = parsed
+            .tensors
+            .iter()
+            .map(|t| TensorInfo {
+                name: t.name.clone(),
+                dims: t.dims.iter().map(|d| *d as u64).collect(),
+                ggml_type: GgmlType::from(t.dtype.id()),
+                offset: t.offset,
+                size: t.size_bytes() as u64,
+            })
+            .collect();
+
+        let data_offset = parsed.data_offset;
         let model_size = reader.seek(SeekFrom::End(0))?;
 
         Ok(Self {
@@ -334,38 +316,50 @@ impl GgufFile {
         self.tensors.iter().find(|t| t.name == name)
     }
 
-    pub fn estimate_memory_usage(&self, gpu_layers: i32) -> u64 {
-        if gpu_layers <= 0 {
-            return 0;
-        }
-
-        let total_layers = self.metadata.block_count as i32;
-        let layers_on_gpu = if gpu_layers >= total_layers { total_layers } else { gpu_layers };
-        
-        let mut gpu_tensor_size = 0u64;
+    // Weights plus a K/V cache of `num_ctx` positions for each of `parallel`
+    // sequences. The first `gpu_layers` layers, and the tensors outside the
+    // layers, count as offloaded; a negative count offloads every layer.
+    pub fn estimate_memory_usage(&self, gpu_layers: i32, cache_type: KvCacheType, num_ctx: u64, parallel: usize) -> MemoryEstimate {
+        let total_layers = self.metadata.block_count;
+        let layers_on_gpu = if gpu_layers < 0 { total_layers } else { (gpu_layers as u64).min(total_layers) };
+        let layer_of = |name: &str| name.strip_prefix("blk.")?.split('.').next()?.parse::<u64>().ok();
 
+        let mut estimate = MemoryEstimate::default();
         for tensor in &self.tensors {
-            if tensor.name.contains("blk.") {
-                if let Some(layer_str) = tensor.name.split("blk.").nth(1) {
-                    if let Some(layer_num) = layer_str.split('.').next() {
-                        if let Ok(layer) = layer_num.parse::<i32>() {
-                            if layer < layers_on_gpu {
-                                gpu_tensor_size += tensor.size;
-                            }
-                        }
-                    }
-                }
-            } else {
-                gpu_tensor_size += tensor.size;
+            estimate.weights += tensor.size;
+            let offloaded = match layer_of(&tensor.name) {
+                Some(layer) => layer < layers_on_gpu,
+                None => layers_on_gpu > 0,
+            };
+            if offloaded {
+                estimate.gpu += tensor.size;
             }
         }
 
-        let kv_cache_size = self.metadata.context_length
-            * self.metadata.head_count_kv
-            * self.metadata.embedding_length
-            * 2;
+        // K and V for every layer and position, at the cache's storage size
+        // per row.
+        let head_count_kv = if self.metadata.head_count_kv == 0 { self.metadata.head_count } else { self.metadata.head_count_kv };
+        let head_dim = self.metadata.embedding_length / self.metadata.head_count.max(1);
+        let row_bytes = cache_type.row_bytes((head_count_kv * head_dim) as usize) as u64;
+        let layer_kv = 2 * num_ctx * parallel as u64 * row_bytes;
+        estimate.kv_cache = total_layers * layer_kv;
+        estimate.gpu += layers_on_gpu * layer_kv;
+        estimate
+    }
+}
+
+// Bytes a loaded model takes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    pub weights: u64,
+    pub kv_cache: u64,
+    // The part of both that is offloaded to the GPU.
+    pub gpu: u64,
+}
 
-        gpu_tensor_size + kv_cache_size
+impl MemoryEstimate {
+    pub fn total(&self) -> u64 {
+        self.weights + self.kv_cache
     }
 }
 
@@ -388,6 +382,27 @@ enum Value {
 }
 
 impl Value {
+    fn from_metadata(value: &MetadataValue, vtype: &ValueType) -> Self {
+        match (value, vtype) {
+            (MetadataValue::Uint(v), ValueType::U8) => Value::Uint8(*v as u8),
+            (MetadataValue::Uint(v), ValueType::U16) => Value::Uint16(*v as u16),
+            (MetadataValue::Uint(v), ValueType::U32) => Value::Uint32(*v as u32),
+            (MetadataValue::Uint(v), _) => Value::Uint64(*v),
+            (MetadataValue::Int(v), ValueType::I8) => Value::Int8(*v as i8),
+            (MetadataValue::Int(v), ValueType::I16) => Value::Int16(*v as i16),
+            (MetadataValue::Int(v), ValueType::I32) => Value::Int32(*v as i32),
+            (MetadataValue::Int(v), _) => Value::Int64(*v),
+            (MetadataValue::Float(v), ValueType::F32) => Value::Float32(*v as f32),
+            (MetadataValue::Float(v), _) => Value::Float64(*v),
+            (MetadataValue::Bool(v), _) => Value::Bool(*v),
+            (MetadataValue::String(v), _) => Value::String(v.clone()),
+            (MetadataValue::Array(arr), ValueType::Array(inner)) => {
+                Value::Array(arr.iter().map(|v| Value::from_metadata(v, inner)).collect())
+            }
+            (MetadataValue::Array(arr), _) => Value::Array(arr.iter().map(|v| Value::from_metadata(v, vtype)).collect()),
+        }
+    }
+
     fn as_string(&self) -> Option<String> {
         match self {
             Value::String(s) => Some(s.clone()),
@@ -417,7 +432,6 @@ impl Value {
         }
     }
 
-    #[allow(dead_code)]
     fn as_f64(&self) -> Option<f64> {
         match self {
             Value::Float32(v) => Some(*v as f64),
@@ -427,126 +441,64 @@ impl Value {
     }
 }
 
-fn read_u8<R: Read>(reader: &mut R) -> Result<u8> {
-    let mut buf = [0u8; 1];
-    reader.read_exact(&mut buf)?;
-    Ok(buf[0])
-}
-
-fn read_u16<R: Read>(reader: &mut R) -> Result<u16> {
-    let mut buf = [0u8; 2];
-    reader.read_exact(&mut buf)?;
-    Ok(u16::from_le_bytes(buf))
-}
-
-fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
-    let mut buf = [0u8; 4];
-    reader.read_exact(&mut buf)?;
-    Ok(u32::from_le_bytes(buf))
-}
-
-fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
-    let mut buf = [0u8; 8];
-    reader.read_exact(&mut buf)?;
-    Ok(u64::from_le_bytes(buf))
-}
-
-fn read_i8<R: Read>(reader: &mut R) -> Result<i8> {
-    Ok(read_u8(reader)? as i8)
-}
-
-fn read_i16<R: Read>(reader: &mut R) -> Result<i16> {
-    Ok(read_u16(reader)? as i16)
-}
-
-fn read_i32<R: Read>(reader: &mut R) -> Result<i32> {
-    Ok(read_u32(reader)? as i32)
-}
-
-fn read_i64<R: Read>(reader: &mut R) -> Result<i64> {
-    Ok(read_u64(reader)? as i64)
-}
-
-fn read_f32<R: Read>(reader: &mut R) -> Result<f32> {
-    let mut buf = [0u8; 4];
-    reader.read_exact(&mut buf)?;
-    Ok(f32::from_le_bytes(buf))
-}
-
-fn read_f64<R: Read>(reader: &mut R) -> Result<f64> {
-    let mut buf = [0u8; 8];
-    reader.read_exact(&mut buf)?;
-    Ok(f64::from_le_bytes(buf))
-}
-
-fn read_string<R: Read>(reader: &mut R) -> Result<String> {
-    let len = read_u64(reader)? as usize;
-    if len > 10_000_000 {
-        bail!("String too large: {} bytes", len);
-    }
-    let mut buf = vec![0u8; len];
-    reader.read_exact(&mut buf)?;
-    Ok(String::from_utf8_lossy(&buf).to_string())
-}
-
-fn read_value_with_type<R: Read>(reader: &mut R, value_type: u32) -> Result<Value> {
-    match value_type {
-        0 => Ok(Value::Uint8(read_u8(reader)?)),
-        1 => Ok(Value::Int8(read_i8(reader)?)),
-        2 => Ok(Value::Uint16(read_u16(reader)?)),
-        3 => Ok(Value::Int16(read_i16(reader)?)),
-        4 => Ok(Value::Uint32(read_u32(reader)?)),
-        5 => Ok(Value::Int32(read_i32(reader)?)),
-        6 => Ok(Value::Float32(read_f32(reader)?)),
-        7 => Ok(Value::Bool(read_u8(reader)? != 0)),
-        8 => Ok(Value::String(read_string(reader)?)),
-        9 => {
-            let element_type = read_u32(reader)?;
-            let len = read_u64(reader)? as usize;
-            if len > 100000 {
-                bail!("Array too large: {} elements", len);
-            }
-            let mut arr = Vec::with_capacity(len);
-            for _ in 0..len {
-                arr.push(read_value_with_type(reader, element_type)?);
-            }
-            Ok(Value::Array(arr))
-        }
-        10 => Ok(Value::Uint64(read_u64(reader)?)),
-        11 => Ok(Value::Int64(read_i64(reader)?)),
-        12 => Ok(Value::Float64(read_f64(reader)?)),
-        _ => bail!("Unknown value type in array: {}", value_type),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::gguf::{GgufMetadata as Metadata, GgufWriter, MetadataValue, ValueType};
+
+    #[test]
+    fn test_strings() {
+        let mut metadata = Metadata::new();
+        metadata.set(
+            "tokenizer.ggml.merges",
+            MetadataValue::Array(vec![MetadataValue::String("a b".into()), MetadataValue::String("ab c".into())]),
+            ValueType::Array(Box::new(ValueType::String)),
+        );
+        metadata.set("tokenizer.ggml.model", MetadataValue::String("gpt2".into()), ValueType::String);
+        let path = std::env::temp_dir().join(format!("ollama-gguf-strings-{}.gguf", std::process::id()));
+        GgufWriter::new(metadata).write_to_file(&path).unwrap();
+
+        let file = GgufFile::open(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(file.metadata.strings("tokenizer.ggml.merges"), ["a b", "ab c"]);
+        assert_eq!(file.metadata.string("tokenizer.ggml.model"), "gpt2");
+        assert!(file.metadata.strings("tokenizer.ggml.model").is_empty());
     }
-}
 
-fn read_value<R: Read>(reader: &mut R) -> Result<Value> {
-    let value_type = read_u32(reader)?;
-    
-    match value_type {
-        0 => Ok(Value::Uint8(read_u8(reader)?)),
-        1 => Ok(Value::Int8(read_i8(reader)?)),
-        2 => Ok(Value::Uint16(read_u16(reader)?)),
-        3 => Ok(Value::Int16(read_i16(reader)?)),
-        4 => Ok(Value::Uint32(read_u32(reader)?)),
-        5 => Ok(Value::Int32(read_i32(reader)?)),
-        6 => Ok(Value::Float32(read_f32(reader)?)),
-        7 => Ok(Value::Bool(read_u8(reader)? != 0)),
-        8 => Ok(Value::String(read_string(reader)?)),
-        9 => {
-            let element_type = read_u32(reader)?;
-            let len = read_u64(reader)? as usize;
-            if len > 100000 {
-                bail!("Array too large: {} elements", len);
-            }
-            let mut arr = Vec::with_capacity(len);
-            for _ in 0..len {
-                arr.push(read_value_with_type(reader, element_type)?);
-            }
-            Ok(Value::Array(arr))
+    #[test]
+    fn test_estimate_memory_usage_scales_with_cache_type() {
+        let mut metadata = Metadata::new();
+        for (key, value) in [
+            ("llama.context_length", 1024),
+            ("llama.embedding_length", 256),
+            ("llama.block_count", 4),
+            ("llama.attention.head_count", 8),
+            ("llama.attention.head_count_kv", 2),
+        ] {
+            metadata.set(key, MetadataValue::Uint(value), ValueType::U32);
         }
-        10 => Ok(Value::Uint64(read_u64(reader)?)),
-        11 => Ok(Value::Int64(read_i64(reader)?)),
-        12 => Ok(Value::Float64(read_f64(reader)?)),
-        _ => bail!("Unknown value type: {}", value_type),
+        metadata.set("general.architecture", MetadataValue::String("llama".into()), ValueType::String);
+        let path = std::env::temp_dir().join(format!("ollama-gguf-estimate-{}.gguf", std::process::id()));
+        GgufWriter::new(metadata).write_to_file(&path).unwrap();
+        let file = GgufFile::open(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        // 4 layers * K and V * 1024 positions * a 64-value row.
+        let positions = 4 * 2 * 1024;
+        let estimate = |gpu_layers, cache_type| file.estimate_memory_usage(gpu_layers, cache_type, 1024, 1);
+        assert_eq!(estimate(4, KvCacheType::F32).gpu, positions * 64 * 4);
+        assert_eq!(estimate(4, KvCacheType::F16).gpu, positions * 64 * 2);
+        assert_eq!(estimate(4, KvCacheType::Q8_0).gpu, positions * 2 * 34);
+        assert_eq!(estimate(4, KvCacheType::Q4_0).gpu, positions * 2 * 18);
+        assert_eq!(estimate(2, KvCacheType::Q4_0).gpu, positions / 2 * 2 * 18);
+        assert_eq!(estimate(-1, KvCacheType::Q4_0).gpu, positions * 2 * 18);
+
+        // On the CPU the cache still takes memory, and it grows with the
+        // context and the number of parallel sequences.
+        let cpu = estimate(0, KvCacheType::F16);
+        assert_eq!((cpu.gpu, cpu.kv_cache, cpu.total()), (0, positions * 64 * 2, positions * 64 * 2));
+        assert_eq!(estimate(2, KvCacheType::F16).total(), cpu.total());
+        assert_eq!(file.estimate_memory_usage(0, KvCacheType::F16, 256, 4).kv_cache, cpu.kv_cache);
+        assert_eq!(file.estimate_memory_usage(0, KvCacheType::F16, 1024, 3).kv_cache, 3 * cpu.kv_cache);
     }
 }
@@ -0,0 +1,251 @@
+use crate::infra::gguf::{MetadataValue, ValueType};
+use crate::infra::Result;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Llama,
+    Mistral,
+    Qwen2,
+    Gemma,
+    Phi3,
+    Bert,
+}
+
+// The subset of a Hugging Face config.json the converter reads.
+pub struct HfConfig {
+    raw: Value,
+}
+
+impl HfConfig {
+    pub fn from_json(raw: Value) -> Self {
+        Self { raw }
+    }
+
+    pub fn uint(&self, key: &str) -> Option<u64> {
+        self.raw.get(key).and_then(Value::as_u64)
+    }
+
+    pub fn float(&self, key: &str) -> Option<f64> {
+        self.raw.get(key).and_then(Value::as_f64)
+    }
+
+    pub fn bool(&self, key: &str) -> Option<bool> {
+        self.raw.get(key).and_then(Value::as_bool)
+    }
+
+    fn require(&self, key: &str) -> Result<u64> {
+        self.uint(key)
+            .ok_or_else(|| anyhow::anyhow!("config.json is missing {}", key))
+    }
+
+    pub fn architectures(&self) -> Vec<&str> {
+        self.raw
+            .get("architectures")
+            .and_then(Value::as_array)
+            .map(|a| a.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn head_count(&self) -> Result<u64> {
+        self.require("num_attention_heads")
+    }
+
+    pub fn head_count_kv(&self) -> Result<u64> {
+        match self.uint("num_key_value_heads") {
+            Some(n) => Ok(n),
+            None => self.head_count(),
+        }
+    }
+
+    pub fn head_dim(&self) -> Result<u64> {
+        match self.uint("head_dim") {
+            Some(n) => Ok(n),
+            None => Ok(self.require("hidden_size")? / self.head_count()?),
+        }
+    }
+}
+
+pub type MetadataEntry = (String, MetadataValue, ValueType);
+
+impl Architecture {
+    pub fn from_config(config: &HfConfig) -> Result<Self> {
+        for name in config.architectures() {
+            let arch = match name {
+                "LlamaForCausalLM" => Architecture::Llama,
+                "MistralForCausalLM" => Architecture::Mistral,
+                "Qwen2ForCausalLM" => Architecture::Qwen2,
+                "GemmaForCausalLM" => Architecture::Gemma,
+                "Phi3ForCausalLM" => Architecture::Phi3,
+                "BertModel" | "BertForMaskedLM" | "BertForSequenceClassification" => Architecture::Bert,
+                _ => continue,
+            };
+            return Ok(arch);
+        }
+        anyhow::bail!("unsupported architecture {:?}", config.architectures())
+    }
+
+    // Mistral checkpoints share the llama graph in GGUF.
+    pub fn gguf_name(&self) -> &'static str {
+        match self {
+            Architecture::Llama | Architecture::Mistral => "llama",
+            Architecture::Qwen2 => "qwen2",
+            Architecture::Gemma => "gemma",
+            Architecture::Phi3 => "phi3",
+            Architecture::Bert => "bert",
+        }
+    }
+
+    pub fn metadata(&self, config: &HfConfig) -> Result<Vec<MetadataEntry>> {
+        let arch = self.gguf_name();
+        let u32_entry = |key: &str, v: u64| (format!("{}.{}", arch, key), MetadataValue::Uint(v), ValueType::U32);
+        let f32_entry = |key: &str, v: f64| (format!("{}.{}", arch, key), MetadataValue::Float(v), ValueType::F32);
+
+        let mut entries = vec![
+            u32_entry("context_length", config.require("max_position_embeddings")?),
+            u32_entry("embedding_length", config.require("hidden_size")?),
+            u32_entry("block_count", config.require("num_hidden_layers")?),
+            u32_entry("feed_forward_length", config.require("intermediate_size")?),
+            u32_entry("attention.head_count", config.head_count()?),
+        ];
+
+        if *self == Architecture::Bert {
+            entries.push(f32_entry("attention.layer_norm_epsilon", config.float("layer_norm_eps").unwrap_or(1e-12)));
+            entries.push((format!("{}.attention.causal", arch), MetadataValue::Bool(false), ValueType::Bool));
+            return Ok(entries);
+        }
+
+        entries.push(u32_entry("attention.head_count_kv", config.head_count_kv()?));
+        entries.push(f32_entry("attention.layer_norm_rms_epsilon", config.float("rms_norm_eps").unwrap_or(1e-5)));
+        entries.push(f32_entry("rope.freq_base", config.float("rope_theta").unwrap_or(10000.0)));
+        entries.push(u32_entry("rope.dimension_count", config.head_dim()?));
+        if let Some(n) = config.uint("vocab_size") {
+            entries.push(u32_entry("vocab_size", n));
+        }
+        if *self == Architecture::Gemma {
+            entries.push(u32_entry("attention.key_length", config.head_dim()?));
+            entries.push(u32_entry("attention.value_length", config.head_dim()?));
+        }
+        if *self == Architecture::Phi3 {
+            if let Some(n) = config.uint("original_max_position_embeddings") {
+                entries.push(u32_entry("rope.scaling.original_context_length", n));
+            }
+        }
+        Ok(entries)
+    }
+
+    // Maps a checkpoint tensor name to its GGUF name; None means the tensor is dropped.
+    pub fn tensor_name(&self, name: &str) -> Option<String> {
+        if name.ends_with("rotary_emb.inv_freq") || name.ends_with("position_ids") {
+            return None;
+        }
+        if *self == Architecture::Bert {
+            return bert_tensor_name(name.strip_prefix("bert.").unwrap_or(name));
+        }
+
+        let (base, suffix) = split_suffix(name)?;
+        let mapped = match base {
+            "model.embed_tokens" => "token_embd".to_string(),
+            "model.norm" => "output_norm".to_string(),
+            // Gemma always ties the output head to the embeddings.
+            "lm_head" if *self == Architecture::Gemma => return None,
+            "lm_head" => "output".to_string(),
+            _ => {
+                let rest = base.strip_prefix("model.layers.")?;
+                let (layer, part) = rest.split_once('.')?;
+                let part = match part {
+                    "input_layernorm" => "attn_norm",
+                    "post_attention_layernorm" => "ffn_norm",
+                    "self_attn.q_proj" => "attn_q",
+                    "self_attn.k_proj" => "attn_k",
+                    "self_attn.v_proj" => "attn_v",
+                    "self_attn.o_proj" => "attn_output",
+                    "self_attn.qkv_proj" => "attn_qkv",
+                    "mlp.gate_proj" => "ffn_gate",
+                    "mlp.up_proj" | "mlp.gate_up_proj" => "ffn_up",
+                    "mlp.down_proj" => "ffn_down",
+                    _ => return None,
+                };
+                format!("blk.{}.{}", layer, part)
+            }
+        };
+        Some(format!("{}.{}", mapped, suffix))
+    }
+
+    // Returns the number of heads to un-interleave for llama-style rotary weights.
+    pub fn permute_heads(&self, gguf_name: &str, config: &HfConfig) -> Result<Option<u64>> {
+        if !matches!(self, Architecture::Llama | Architecture::Mistral) {
+            return Ok(None);
+        }
+        if gguf_name.ends_with("attn_q.weight") || gguf_name.ends_with("attn_q.bias") {
+            return Ok(Some(config.head_count()?));
+        }
+        if gguf_name.ends_with("attn_k.weight") || gguf_name.ends_with("attn_k.bias") {
+            return Ok(Some(config.head_count_kv()?));
+        }
+        Ok(None)
+    }
+
+    // Gemma stores RMS norm weights as an offset from one.
+    pub fn norm_offset(&self, gguf_name: &str) -> f32 {
+        if *self == Architecture::Gemma && gguf_name.ends_with("norm.weight") {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+fn split_suffix(name: &str) -> Option<(&str, &str)> {
+    let (base, suffix) = name.rsplit_once('.')?;
+    matches!(suffix, "weight" | "bias").then_some((base, suffix))
+}
+
+fn bert_tensor_name(name: &str) -> Option<String> {
+    let (base, suffix) = split_suffix(name)?;
+    let mapped = match base {
+        "embeddings.word_embeddings" => "token_embd".to_string(),
+        "embeddings.token_type_embeddings" => "token_types".to_string(),
+        "embeddings.position_embeddings" => "position_embd".to_string(),
+        "embeddings.LayerNorm" => "token_embd_norm".to_string(),
+        _ => {
+            let rest = base.strip_prefix("encoder.layer.")?;
+            let (layer, part) = rest.split_once('.')?;
+            let part = match part {
+                "attention.self.query" => "attn_q",
+                "attention.self.key" => "attn_k",
+                "attention.self.value" => "attn_v",
+                "attention.output.dense" => "attn_output",
+                "attention.output.LayerNorm" => "attn_output_norm",
+                "intermediate.dense" => "ffn_up",
+                "output.dense" => "ffn_down",
+                "output.LayerNorm" => "layer_output_norm",
+                _ => return None,
+            };
+            format!("blk.{}.{}", layer, part)
+        }
+    };
+    Some(format!("{}.{}", mapped, suffix))
+}
+
+// Undoes the HF rotary layout: rows (head, 2, half) become (head, half, 2).
+pub fn permute_rows(data: &[f32], rows: usize, n_head: usize) -> Result<Vec<f32>> {
+    if n_head == 0 || !rows.is_multiple_of(n_head * 2) {
+        anyhow::bail!("cannot permute {} rows across {} heads", rows, n_head);
+    }
+    let cols = data.len() / rows;
+    let per_head = rows / n_head;
+    let half = per_head / 2;
+
+    let mut out = vec![0f32; data.len()];
+    for h in 0..n_head {
+        for i in 0..half {
+            for j in 0..2 {
+                let dst = h * per_head + i * 2 + j;
+                let src = h * per_head + j * half + i;
+                out[dst * cols..(dst + 1) * cols].copy_from_slice(&data[src * cols..(src + 1) * cols]);
+            }
+        }
+    }
+    Ok(out)
+}
@@ -1,32 +1,307 @@
+pub mod arch;
+pub mod pickle;
 pub mod safetensors;
 pub mod torch;
 
-use crate::core::model::ModelConfig;
+use crate::infra::gguf::{dequantize, GgmlType, GgufMetadata, GgufWriter, MetadataValue, ValueType};
 use crate::infra::Result;
+use arch::{Architecture, HfConfig};
+use half::f16;
 use std::path::Path;
 
+pub use safetensors::SafetensorsModel;
+pub use torch::TorchModel;
+
+// A checkpoint tensor in Hugging Face layout: shape is outermost first.
+pub struct SourceTensor<'a> {
+    pub dtype: GgmlType,
+    pub shape: Vec<usize>,
+    pub data: &'a [u8],
+}
+
+impl SourceTensor<'_> {
+    pub fn to_f32(&self) -> Result<Vec<f32>> {
+        dequantize(self.dtype, self.data)
+    }
+}
+
+pub struct ConvertProgress<'a> {
+    pub name: &'a str,
+    pub index: usize,
+    pub total: usize,
+}
+
 pub struct ModelConverter {
-    config: ModelConfig,
+    metadata: GgufMetadata,
+    output_type: GgmlType,
 }
 
 impl ModelConverter {
-    pub fn new(config: ModelConfig) -> Self {
-        Self { config }
+    pub fn new() -> Self {
+        Self {
+            metadata: GgufMetadata::new(),
+            output_type: GgmlType::F16,
+        }
+    }
+
+    // Extra keys written ahead of the architecture keys, e.g. the tokenizer.
+    pub fn with_metadata(mut self, metadata: GgufMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_output_type(mut self, dtype: GgmlType) -> Self {
+        self.output_type = dtype;
+        self
     }
-    
-    pub fn convert_safetensors<P: AsRef<Path>>(&self, input: P, output: P) -> Result<()> {
-        safetensors::convert(input, output, &self.config)
+
+    pub fn convert_safetensors<P, Q, F>(&self, input: P, output: Q, progress: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        F: FnMut(ConvertProgress),
+    {
+        let model = SafetensorsModel::open(&input)?;
+        let config = read_config(input.as_ref())?;
+        let names: Vec<&str> = model.tensor_names().collect();
+        self.convert(input.as_ref(), &config, &names, |name| model.tensor(name), output.as_ref(), progress)
+    }
+
+    pub fn convert_pytorch<P, Q, F>(&self, input: P, output: Q, progress: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        F: FnMut(ConvertProgress),
+    {
+        let model = TorchModel::open(&input)?;
+        let dir = if input.as_ref().is_dir() {
+            input.as_ref()
+        } else {
+            input.as_ref().parent().unwrap_or(Path::new("."))
+        };
+        let config = read_config(dir)?;
+        let names: Vec<&str> = model.tensor_names().collect();
+        self.convert(dir, &config, &names, |name| model.tensor(name), output.as_ref(), progress)
+    }
+
+    fn convert<'a, L, F>(
+        &self,
+        input: &Path,
+        config: &HfConfig,
+        names: &[&str],
+        load: L,
+        output: &Path,
+        mut progress: F,
+    ) -> Result<()>
+    where
+        L: Fn(&str) -> Result<SourceTensor<'a>>,
+        F: FnMut(ConvertProgress),
+    {
+        if !matches!(self.output_type, GgmlType::F32 | GgmlType::F16) {
+            anyhow::bail!("conversion output must be F32 or F16, got {}", self.output_type.name());
+        }
+        let arch = Architecture::from_config(config)?;
+
+        let mut metadata = self.metadata.clone();
+        metadata.set("general.architecture", MetadataValue::String(arch.gguf_name().to_string()), ValueType::String);
+        if let Some(name) = input.file_name().and_then(|n| n.to_str()) {
+            metadata.set("general.name", MetadataValue::String(name.to_string()), ValueType::String);
+        }
+        let file_type = if self.output_type == GgmlType::F16 { 1 } else { 0 };
+        metadata.set("general.file_type", MetadataValue::Uint(file_type), ValueType::U32);
+        for (key, value, vtype) in arch.metadata(config)? {
+            metadata.set(&key, value, vtype);
+        }
+
+        let mut mapped: Vec<(String, &str)> = names
+            .iter()
+            .filter_map(|n| arch.tensor_name(n).map(|g| (g, *n)))
+            .collect();
+        if mapped.is_empty() {
+            anyhow::bail!("no {} tensors found in {}", arch.gguf_name(), input.display());
+        }
+        mapped.sort_by_key(|(name, _)| tensor_order(name));
+
+        let mut writer = GgufWriter::new(metadata);
+        for (index, (gguf_name, source_name)) in mapped.iter().enumerate() {
+            progress(ConvertProgress {
+                name: gguf_name,
+                index,
+                total: mapped.len(),
+            });
+
+            let tensor = load(source_name)?;
+            let mut values = tensor.to_f32()?;
+            if let Some(n_head) = arch.permute_heads(gguf_name, config)? {
+                values = arch::permute_rows(&values, tensor.shape[0], n_head as usize)?;
+            }
+            let offset = arch.norm_offset(gguf_name);
+            if offset != 0.0 {
+                values.iter_mut().for_each(|v| *v += offset);
+            }
+
+            let dims: Vec<usize> = tensor.shape.iter().rev().copied().collect();
+            let (dtype, data) = if dims.len() < 2 || self.output_type == GgmlType::F32 {
+                (GgmlType::F32, values.iter().flat_map(|v| v.to_le_bytes()).collect())
+            } else {
+                (GgmlType::F16, values.iter().flat_map(|v| f16::from_f32(*v).to_le_bytes()).collect())
+            };
+            writer.add_tensor(gguf_name, dims, dtype, data)?;
+        }
+
+        writer.write_to_file(output)?;
+        Ok(())
     }
-    
-    pub fn convert_pytorch<P: AsRef<Path>>(&self, input: P, output: P) -> Result<()> {
-        torch::convert(input, output, &self.config)
+}
+
+impl Default for ModelConverter {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub fn convert_safetensors<P: AsRef<Path>>(input: P, output: P, config: &ModelConfig) -> Result<()> {
-    safetensors::convert(input, output, config)
+pub fn read_config(dir: &Path) -> Result<HfConfig> {
+    let path = dir.join("config.json");
+    let raw = std::fs::read(&path)
+        .map_err(|e| anyhow::anyhow!("failed to read {}: {}", path.display(), e))?;
+    Ok(HfConfig::from_json(serde_json::from_slice(&raw)?))
+}
+
+// token_embd and friends first, then blocks in numeric order, then output tensors.
+fn tensor_order(name: &str) -> (u8, usize, String) {
+    if let Some(rest) = name.strip_prefix("blk.") {
+        let layer = rest.split('.').next().and_then(|l| l.parse().ok()).unwrap_or(0);
+        return (1, layer, name.to_string());
+    }
+    let group = if name.starts_with("output") { 2 } else { 0 };
+    (group, 0, name.to_string())
 }
 
-pub fn convert_pytorch<P: AsRef<Path>>(input: P, output: P, config: &ModelConfig) -> Result<()> {
-    torch::convert(input, output, config)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::gguf::{GgufParser, MappedGguf};
+    use ::safetensors::tensor::{serialize_to_file, Dtype, TensorView};
+    use std::collections::HashMap;
+
+    fn f32_bytes(v: &[f32]) -> Vec<u8> {
+        v.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    fn write_shard(path: &Path, tensors: &[(&str, Vec<usize>, Vec<f32>)]) {
+        let data: Vec<(String, Vec<usize>, Vec<u8>)> = tensors
+            .iter()
+            .map(|(n, s, v)| (n.to_string(), s.clone(), f32_bytes(v)))
+            .collect();
+        let views: Vec<(String, TensorView)> = data
+            .iter()
+            .map(|(n, s, b)| (n.clone(), TensorView::new(Dtype::F32, s.clone(), b).unwrap()))
+            .collect();
+        serialize_to_file(views, None, path).unwrap();
+    }
+
+    #[test]
+    fn test_convert_sharded_llama() {
+        let dir = std::env::temp_dir().join(format!("ollama-hf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("config.json"),
+            r#"{"architectures":["LlamaForCausalLM"],"hidden_size":4,"intermediate_size":8,
+                "num_hidden_layers":1,"num_attention_heads":1,"num_key_value_heads":1,
+                "max_position_embeddings":64,"rms_norm_eps":1e-6,"vocab_size":3}"#,
+        )
+        .unwrap();
+
+        let q: Vec<f32> = (0..16).map(|i| i as f32).collect();
+        write_shard(
+            &dir.join("model-00001-of-00002.safetensors"),
+            &[
+                ("model.embed_tokens.weight", vec![3, 4], vec![0.5; 12]),
+                ("model.layers.0.self_attn.q_proj.weight", vec![4, 4], q.clone()),
+                ("model.layers.0.self_attn.rotary_emb.inv_freq", vec![2], vec![1.0; 2]),
+            ],
+        );
+        write_shard(
+            &dir.join("model-00002-of-00002.safetensors"),
+            &[("model.layers.0.input_layernorm.weight", vec![4], vec![1.0; 4])],
+        );
+        let weight_map: HashMap<&str, &str> = [
+            ("model.embed_tokens.weight", "model-00001-of-00002.safetensors"),
+            ("model.layers.0.self_attn.q_proj.weight", "model-00001-of-00002.safetensors"),
+            ("model.layers.0.input_layernorm.weight", "model-00002-of-00002.safetensors"),
+        ]
+        .into_iter()
+        .collect();
+        std::fs::write(
+            dir.join("model.safetensors.index.json"),
+            serde_json::to_vec(&serde_json::json!({ "weight_map": weight_map })).unwrap(),
+        )
+        .unwrap();
+
+        let output = dir.join("out.gguf");
+        let mut seen = 0;
+        ModelConverter::new()
+            .with_output_type(GgmlType::F32)
+            .convert_safetensors(&dir, &output, |_| seen += 1)
+            .unwrap();
+        assert_eq!(seen, 3);
+
+        let file = GgufParser::parse(&output).unwrap();
+        assert_eq!(file.version, 3);
+        assert_eq!(file.data_offset % 32, 0);
+        assert_eq!(file.metadata.string("general.architecture"), "llama");
+        assert_eq!(file.metadata.uint("llama.block_count"), 1);
+        assert_eq!(file.metadata.value_type("llama.embedding_length"), Some(ValueType::U32));
+        let names: Vec<&str> = file.tensors.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["token_embd.weight", "blk.0.attn_norm.weight", "blk.0.attn_q.weight"]);
+        assert!(file.tensors.iter().all(|t| t.offset % 32 == 0));
+        assert_eq!(file.tensor("token_embd.weight").unwrap().dims, vec![4, 3]);
+
+        // One head of dim 4: rows (0, 1, 2, 3) interleave to (0, 2, 1, 3).
+        let mapped = MappedGguf::open(&output, false, false).unwrap();
+        let q_out = mapped.tensor("blk.0.attn_q.weight").unwrap();
+        let rows: Vec<f32> = q_out.data().chunks(4).map(|r| r[0]).collect();
+        assert_eq!(rows, vec![0.0, 8.0, 4.0, 12.0]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_rejects_overflowing_offsets_and_shapes() {
+        let dir = std::env::temp_dir().join(format!("ollama-hf-overflow-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for tensor in [
+            format!(r#"{{"dtype":"F32","shape":[1],"data_offsets":[0,{}]}}"#, usize::MAX),
+            format!(r#"{{"dtype":"F32","shape":[{},4],"data_offsets":[0,4]}}"#, usize::MAX / 2),
+        ] {
+            let header = format!(r#"{{"w":{}}}"#, tensor);
+            let mut file = (header.len() as u64).to_le_bytes().to_vec();
+            file.extend_from_slice(header.as_bytes());
+            file.extend_from_slice(&[0; 4]);
+            std::fs::write(dir.join("model.safetensors"), file).unwrap();
+            let err = SafetensorsModel::open(&dir).err().unwrap();
+            assert!(err.to_string().contains("invalid offsets"), "{}", err);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_tensor_names() {
+        let gemma = Architecture::Gemma;
+        assert_eq!(gemma.tensor_name("model.layers.3.mlp.down_proj.weight").unwrap(), "blk.3.ffn_down.weight");
+        assert_eq!(gemma.tensor_name("lm_head.weight"), None);
+        assert_eq!(gemma.norm_offset("blk.0.attn_norm.weight"), 1.0);
+
+        let phi3 = Architecture::Phi3;
+        assert_eq!(phi3.tensor_name("model.layers.0.self_attn.qkv_proj.weight").unwrap(), "blk.0.attn_qkv.weight");
+        assert_eq!(phi3.tensor_name("model.layers.0.mlp.gate_up_proj.weight").unwrap(), "blk.0.ffn_up.weight");
+
+        let qwen2 = Architecture::Qwen2;
+        assert_eq!(qwen2.tensor_name("model.layers.1.self_attn.k_proj.bias").unwrap(), "blk.1.attn_k.bias");
+
+        let bert = Architecture::Bert;
+        assert_eq!(bert.tensor_name("bert.embeddings.LayerNorm.bias").unwrap(), "token_embd_norm.bias");
+        assert_eq!(bert.tensor_name("encoder.layer.2.output.LayerNorm.weight").unwrap(), "blk.2.layer_output_norm.weight");
+        assert_eq!(bert.tensor_name("pooler.dense.weight"), None);
+    }
 }
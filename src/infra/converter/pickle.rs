@@ -0,0 +1,499 @@
+use crate::infra::Result;
+use std::collections::HashMap;
+
+// Pickle values the restricted unpickler can produce. Globals are only
+// materialized for the allowlist in `resolve_global`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    Tuple(Vec<Object>),
+    List(Vec<Object>),
+    Dict(Vec<(Object, Object)>),
+    Global(Global),
+    Storage(StorageRef),
+    Tensor(TensorRef),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Global {
+    OrderedDict,
+    RebuildTensor,
+    RebuildParameter,
+    Storage(StorageType),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageType {
+    F32,
+    F16,
+    BF16,
+    I64,
+    I32,
+}
+
+impl Object {
+    // Approximate heap footprint of a deep copy, in bytes.
+    fn footprint(&self) -> usize {
+        let nested = match self {
+            Object::String(s) => s.len(),
+            Object::Bytes(b) => b.len(),
+            Object::Tuple(items) | Object::List(items) => items.iter().map(Object::footprint).sum(),
+            Object::Dict(items) => items.iter().map(|(k, v)| k.footprint() + v.footprint()).sum(),
+            Object::Storage(s) => s.key.len(),
+            Object::Tensor(t) => t.storage.key.len() + 8 * (t.shape.len() + t.stride.len()),
+            _ => 0,
+        };
+        std::mem::size_of::<Object>() + nested
+    }
+}
+
+impl StorageType {
+    pub fn element_size(&self) -> usize {
+        match self {
+            StorageType::F32 | StorageType::I32 => 4,
+            StorageType::F16 | StorageType::BF16 => 2,
+            StorageType::I64 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageRef {
+    pub dtype: StorageType,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorRef {
+    pub storage: StorageRef,
+    pub offset: usize,
+    pub shape: Vec<usize>,
+    pub stride: Vec<usize>,
+}
+
+impl TensorRef {
+    pub fn is_contiguous(&self) -> bool {
+        let mut expected = 1;
+        for (dim, stride) in self.shape.iter().zip(&self.stride).rev() {
+            if *dim != 1 && *stride != expected {
+                return false;
+            }
+            expected *= dim;
+        }
+        true
+    }
+}
+
+fn resolve_global(module: &str, name: &str) -> Result<Global> {
+    let global = match (module, name) {
+        ("collections", "OrderedDict") => Global::OrderedDict,
+        ("torch._utils", "_rebuild_tensor_v2") => Global::RebuildTensor,
+        ("torch._utils", "_rebuild_parameter") => Global::RebuildParameter,
+        ("torch", "FloatStorage") => Global::Storage(StorageType::F32),
+        ("torch", "HalfStorage") => Global::Storage(StorageType::F16),
+        ("torch", "BFloat16Storage") => Global::Storage(StorageType::BF16),
+        ("torch", "LongStorage") => Global::Storage(StorageType::I64),
+        ("torch", "IntStorage") => Global::Storage(StorageType::I32),
+        _ => anyhow::bail!("refusing to load pickle global {}.{}", module, name),
+    };
+    Ok(global)
+}
+
+// Memo lookups and DUP deep-copy values, so a few bytes of pickle can double
+// an object tree over and over. Copies beyond this budget are refused.
+const MAX_COPIED_BYTES: usize = 256 << 20;
+
+// Unpickles a torch `data.pkl`. Only the opcodes torch.save emits are supported
+// and nothing is ever executed: REDUCE is limited to the allowlisted globals.
+pub fn unpickle(data: &[u8]) -> Result<Object> {
+    Unpickler {
+        data,
+        pos: 0,
+        stack: Vec::new(),
+        marks: Vec::new(),
+        memo: HashMap::new(),
+        copied: 0,
+    }
+    .run()
+}
+
+struct Unpickler<'a> {
+    data: &'a [u8],
+    pos: usize,
+    stack: Vec<Object>,
+    marks: Vec<usize>,
+    memo: HashMap<u32, Object>,
+    copied: usize,
+}
+
+impl<'a> Unpickler<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|e| *e <= self.data.len())
+            .ok_or_else(|| anyhow::anyhow!("truncated pickle at offset {}", self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn line(&mut self) -> Result<&'a str> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| anyhow::anyhow!("unterminated pickle line"))?;
+        let line = std::str::from_utf8(&rest[..len])?;
+        self.pos += len + 1;
+        Ok(line)
+    }
+
+    fn string(&mut self, len: usize) -> Result<Object> {
+        Ok(Object::String(std::str::from_utf8(self.take(len)?)?.to_string()))
+    }
+
+    fn pop(&mut self) -> Result<Object> {
+        self.stack.pop().ok_or_else(|| anyhow::anyhow!("pickle stack underflow"))
+    }
+
+    fn top(&mut self) -> Result<&mut Object> {
+        self.stack.last_mut().ok_or_else(|| anyhow::anyhow!("pickle stack underflow"))
+    }
+
+    fn pop_mark(&mut self) -> Result<Vec<Object>> {
+        let mark = self.marks.pop().ok_or_else(|| anyhow::anyhow!("pickle mark underflow"))?;
+        if mark > self.stack.len() {
+            anyhow::bail!("pickle mark beyond stack");
+        }
+        Ok(self.stack.split_off(mark))
+    }
+
+    fn memo_get(&mut self, index: u32) -> Result<()> {
+        let value = self
+            .memo
+            .get(&index)
+            .ok_or_else(|| anyhow::anyhow!("pickle memo {} is undefined", index))?;
+        let value = copy(&mut self.copied, value)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn memo_put(&mut self, index: u32) -> Result<()> {
+        let top = self.stack.last().ok_or_else(|| anyhow::anyhow!("pickle stack underflow"))?;
+        let value = copy(&mut self.copied, top)?;
+        self.memo.insert(index, value);
+        Ok(())
+    }
+
+    fn run(mut self) -> Result<Object> {
+        loop {
+            let op = self.u8()?;
+            match op {
+                0x80 => {
+                    self.u8()?;
+                }
+                0x95 => {
+                    self.take(8)?;
+                }
+                b'.' => return self.pop(),
+                b'(' => self.marks.push(self.stack.len()),
+                b'0' => {
+                    self.pop()?;
+                }
+                b'1' => {
+                    self.pop_mark()?;
+                }
+                b'2' => {
+                    let top = self.stack.last().ok_or_else(|| anyhow::anyhow!("pickle stack underflow"))?;
+                    let top = copy(&mut self.copied, top)?;
+                    self.stack.push(top);
+                }
+                b'N' => self.stack.push(Object::None),
+                0x88 => self.stack.push(Object::Bool(true)),
+                0x89 => self.stack.push(Object::Bool(false)),
+                b'J' => {
+                    let v = self.u32()? as i32;
+                    self.stack.push(Object::Int(v as i64));
+                }
+                b'K' => {
+                    let v = self.u8()?;
+                    self.stack.push(Object::Int(v as i64));
+                }
+                b'M' => {
+                    let v = self.u16()?;
+                    self.stack.push(Object::Int(v as i64));
+                }
+                0x8a => {
+                    let len = self.u8()? as usize;
+                    let bytes = self.take(len)?;
+                    if len > 8 {
+                        anyhow::bail!("pickle integer of {} bytes is too large", len);
+                    }
+                    let mut buf = if bytes.last().is_some_and(|b| b & 0x80 != 0) { [0xff; 8] } else { [0; 8] };
+                    buf[..len].copy_from_slice(bytes);
+                    self.stack.push(Object::Int(i64::from_le_bytes(buf)));
+                }
+                b'G' => {
+                    let v = f64::from_be_bytes(self.take(8)?.try_into().unwrap());
+                    self.stack.push(Object::Float(v));
+                }
+                b'X' => {
+                    let len = self.u32()? as usize;
+                    let s = self.string(len)?;
+                    self.stack.push(s);
+                }
+                0x8c => {
+                    let len = self.u8()? as usize;
+                    let s = self.string(len)?;
+                    self.stack.push(s);
+                }
+                b'T' | b'B' => {
+                    let len = self.u32()? as usize;
+                    let bytes = self.take(len)?.to_vec();
+                    self.stack.push(Object::Bytes(bytes));
+                }
+                b'U' | b'C' => {
+                    let len = self.u8()? as usize;
+                    let bytes = self.take(len)?.to_vec();
+                    self.stack.push(Object::Bytes(bytes));
+                }
+                b')' => self.stack.push(Object::Tuple(Vec::new())),
+                b't' => {
+                    let items = self.pop_mark()?;
+                    self.stack.push(Object::Tuple(items));
+                }
+                0x85..=0x87 => {
+                    let n = (op - 0x84) as usize;
+                    if self.stack.len() < n {
+                        anyhow::bail!("pickle stack underflow");
+                    }
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(Object::Tuple(items));
+                }
+                b']' => self.stack.push(Object::List(Vec::new())),
+                b'l' => {
+                    let items = self.pop_mark()?;
+                    self.stack.push(Object::List(items));
+                }
+                b'a' => {
+                    let item = self.pop()?;
+                    match self.top()? {
+                        Object::List(list) => list.push(item),
+                        _ => anyhow::bail!("pickle APPEND on a non-list"),
+                    }
+                }
+                b'e' => {
+                    let items = self.pop_mark()?;
+                    match self.top()? {
+                        Object::List(list) => list.extend(items),
+                        _ => anyhow::bail!("pickle APPENDS on a non-list"),
+                    }
+                }
+                b'}' => self.stack.push(Object::Dict(Vec::new())),
+                b's' => {
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    match self.top()? {
+                        Object::Dict(dict) => dict.push((key, value)),
+                        _ => anyhow::bail!("pickle SETITEM on a non-dict"),
+                    }
+                }
+                b'u' => {
+                    let items = self.pop_mark()?;
+                    if items.len() % 2 != 0 {
+                        anyhow::bail!("pickle SETITEMS with an odd number of items");
+                    }
+                    let mut items = items.into_iter();
+                    match self.top()? {
+                        Object::Dict(dict) => {
+                            while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                                dict.push((k, v));
+                            }
+                        }
+                        _ => anyhow::bail!("pickle SETITEMS on a non-dict"),
+                    }
+                }
+                b'h' => {
+                    let index = self.u8()? as u32;
+                    self.memo_get(index)?;
+                }
+                b'j' => {
+                    let index = self.u32()?;
+                    self.memo_get(index)?;
+                }
+                b'q' => {
+                    let index = self.u8()? as u32;
+                    self.memo_put(index)?;
+                }
+                b'r' => {
+                    let index = self.u32()?;
+                    self.memo_put(index)?;
+                }
+                0x94 => {
+                    let index = self.memo.len() as u32;
+                    self.memo_put(index)?;
+                }
+                b'c' => {
+                    let module = self.line()?;
+                    let name = self.line()?;
+                    self.stack.push(Object::Global(resolve_global(module, name)?));
+                }
+                0x93 => {
+                    let name = self.pop()?;
+                    let module = self.pop()?;
+                    match (module, name) {
+                        (Object::String(m), Object::String(n)) => {
+                            self.stack.push(Object::Global(resolve_global(&m, &n)?));
+                        }
+                        _ => anyhow::bail!("pickle STACK_GLOBAL expects strings"),
+                    }
+                }
+                b'Q' => {
+                    let pid = self.pop()?;
+                    self.stack.push(persistent_load(pid)?);
+                }
+                b'R' | 0x81 => {
+                    let args = self.pop()?;
+                    let callable = self.pop()?;
+                    self.stack.push(reduce(callable, args)?);
+                }
+                b'b' => {
+                    // Parameter and OrderedDict state (e.g. `_metadata`) carries no tensor data.
+                    self.pop()?;
+                }
+                _ => anyhow::bail!("unsupported pickle opcode 0x{:02x} at offset {}", op, self.pos - 1),
+            }
+        }
+    }
+}
+
+fn copy(copied: &mut usize, value: &Object) -> Result<Object> {
+    *copied = copied.saturating_add(value.footprint());
+    if *copied > MAX_COPIED_BYTES {
+        anyhow::bail!("pickle copies more than {} bytes of objects", MAX_COPIED_BYTES);
+    }
+    Ok(value.clone())
+}
+
+// torch saves storages as ('storage', storage_type, key, location, numel).
+fn persistent_load(pid: Object) -> Result<Object> {
+    let Object::Tuple(items) = pid else {
+        anyhow::bail!("unexpected persistent id {:?}", pid);
+    };
+    match items.as_slice() {
+        [Object::String(kind), Object::Global(Global::Storage(dtype)), Object::String(key), ..] if kind == "storage" => {
+            Ok(Object::Storage(StorageRef {
+                dtype: *dtype,
+                key: key.clone(),
+            }))
+        }
+        _ => anyhow::bail!("unsupported persistent id {:?}", items),
+    }
+}
+
+fn reduce(callable: Object, args: Object) -> Result<Object> {
+    let Object::Tuple(args) = args else {
+        anyhow::bail!("pickle REDUCE expects tuple arguments");
+    };
+    match callable {
+        Object::Global(Global::OrderedDict) => Ok(Object::Dict(Vec::new())),
+        Object::Global(Global::RebuildParameter) => args
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("_rebuild_parameter without data")),
+        Object::Global(Global::RebuildTensor) => {
+            let [Object::Storage(storage), Object::Int(offset), Object::Tuple(shape), Object::Tuple(stride), ..] =
+                args.as_slice()
+            else {
+                anyhow::bail!("unexpected _rebuild_tensor_v2 arguments {:?}", args);
+            };
+            Ok(Object::Tensor(TensorRef {
+                storage: storage.clone(),
+                offset: usize::try_from(*offset)?,
+                shape: dims(shape)?,
+                stride: dims(stride)?,
+            }))
+        }
+        other => anyhow::bail!("refusing to call {:?}", other),
+    }
+}
+
+fn dims(items: &[Object]) -> Result<Vec<usize>> {
+    items
+        .iter()
+        .map(|d| match d {
+            Object::Int(v) => Ok(usize::try_from(*v)?),
+            _ => anyhow::bail!("tensor dimension {:?} is not an integer", d),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refuses_arbitrary_globals() {
+        // PROTO 2, GLOBAL os.system, BINUNICODE 'echo', TUPLE1, REDUCE, STOP.
+        let evil = b"\x80\x02cos\nsystem\nX\x04\x00\x00\x00echo\x85R.";
+        let err = unpickle(evil).unwrap_err().to_string();
+        assert!(err.contains("os.system"), "{}", err);
+
+        let evil = b"\x80\x02\x8c\x08builtins\x8c\x04eval\x93.";
+        assert!(unpickle(evil).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_tensor() {
+        let mut pkl = b"\x80\x02}q\x00X\x01\x00\x00\x00wq\x01".to_vec();
+        pkl.extend_from_slice(b"ctorch._utils\n_rebuild_tensor_v2\n(");
+        pkl.extend_from_slice(b"(X\x07\x00\x00\x00storagectorch\nHalfStorage\nX\x01\x00\x00\x000X\x03\x00\x00\x00cpuK\x06tQ");
+        pkl.extend_from_slice(b"K\x00K\x02K\x03\x86K\x03K\x01\x86\x89ccollections\nOrderedDict\n)R");
+        pkl.extend_from_slice(b"tRs.");
+        let Object::Dict(dict) = unpickle(&pkl).unwrap() else {
+            panic!("expected a dict");
+        };
+        let Object::Tensor(t) = &dict[0].1 else {
+            panic!("expected a tensor");
+        };
+        assert_eq!(t.storage, StorageRef { dtype: StorageType::F16, key: "0".to_string() });
+        assert_eq!(t.shape, vec![2, 3]);
+        assert!(t.is_contiguous());
+    }
+
+    #[test]
+    fn test_refuses_exponential_copies() {
+        // Each round memoizes a list holding two copies of the previous one.
+        let mut pkl = b"\x80\x02]q\x000".to_vec();
+        for _ in 0..64 {
+            pkl.extend_from_slice(b"(h\x00h\x00lq\x000");
+        }
+        pkl.extend_from_slice(b"h\x00.");
+        let err = unpickle(&pkl).unwrap_err().to_string();
+        assert!(err.contains("copies more than"), "{}", err);
+
+        // Small copies stay well inside the budget.
+        let mut pkl = b"\x80\x02(]".to_vec();
+        pkl.extend_from_slice(&[b'2'; 64]);
+        pkl.extend_from_slice(b"l.");
+        assert!(matches!(unpickle(&pkl), Ok(Object::List(items)) if items.len() == 65));
+    }
+}
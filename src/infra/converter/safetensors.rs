@@ -1,7 +1,140 @@
-use crate::core::model::ModelConfig;
+use super::SourceTensor;
+use crate::infra::gguf::GgmlType;
 use crate::infra::Result;
-use std::path::Path;
+use memmap2::Mmap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
-pub fn convert<P: AsRef<Path>>(_input: P, _output: P, _config: &ModelConfig) -> Result<()> {
-    Ok(())
+const MAX_HEADER_SIZE: u64 = 100 * 1024 * 1024;
+
+struct Entry {
+    shard: usize,
+    dtype: GgmlType,
+    shape: Vec<usize>,
+    start: usize,
+    end: usize,
+}
+
+// A safetensors checkpoint, either a single file or shards listed in
+// model.safetensors.index.json, with every shard memory-mapped.
+pub struct SafetensorsModel {
+    shards: Vec<Mmap>,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl SafetensorsModel {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let files = shard_files(dir.as_ref())?;
+        let mut model = Self {
+            shards: Vec::with_capacity(files.len()),
+            entries: BTreeMap::new(),
+        };
+        for path in files {
+            model.add_shard(&path)?;
+        }
+        Ok(model)
+    }
+
+    fn add_shard(&mut self, path: &Path) -> Result<()> {
+        let file = std::fs::File::open(path)?;
+        // Safety: checkpoints are read-only inputs for the duration of the conversion.
+        let mmap = unsafe { Mmap::map(&file)? };
+        if mmap.len() < 8 {
+            anyhow::bail!("{} is too short to be a safetensors file", path.display());
+        }
+        let header_len = u64::from_le_bytes(mmap[..8].try_into().unwrap());
+        if header_len > MAX_HEADER_SIZE || 8 + header_len > mmap.len() as u64 {
+            anyhow::bail!("{} has an invalid header length {}", path.display(), header_len);
+        }
+        let data_start = 8 + header_len as usize;
+        let header: HashMap<String, serde_json::Value> = serde_json::from_slice(&mmap[8..data_start])?;
+
+        let shard = self.shards.len();
+        for (name, info) in header {
+            if name == "__metadata__" {
+                continue;
+            }
+            let dtype = match info["dtype"].as_str() {
+                Some("F32") => GgmlType::F32,
+                Some("F16") => GgmlType::F16,
+                Some("BF16") => GgmlType::BF16,
+                Some("F64") => GgmlType::F64,
+                Some("I64") => GgmlType::I64,
+                Some("I32") => GgmlType::I32,
+                Some("I16") => GgmlType::I16,
+                Some("I8") => GgmlType::I8,
+                other => anyhow::bail!("tensor {} has unsupported dtype {:?}", name, other),
+            };
+            let shape: Vec<usize> = serde_json::from_value(info["shape"].clone())?;
+            let offsets: [usize; 2] = serde_json::from_value(info["data_offsets"].clone())?;
+            // Offsets and shape come from the file, so their arithmetic is checked.
+            let invalid = || anyhow::anyhow!("tensor {} in {} has invalid offsets {:?}", name, path.display(), offsets);
+            let start = data_start.checked_add(offsets[0]).ok_or_else(invalid)?;
+            let end = data_start.checked_add(offsets[1]).ok_or_else(invalid)?;
+            let expected = shape
+                .iter()
+                .try_fold(1usize, |n, &d| n.checked_mul(d))
+                .and_then(|n| (n / dtype.block_size()).checked_mul(dtype.type_size()))
+                .ok_or_else(invalid)?;
+            if start > end || end > mmap.len() || end - start != expected {
+                return Err(invalid());
+            }
+            self.entries.insert(name, Entry { shard, dtype, shape, start, end });
+        }
+        self.shards.push(mmap);
+        Ok(())
+    }
+
+    pub fn tensor_names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    pub fn tensor(&self, name: &str) -> Result<SourceTensor<'_>> {
+        let entry = self
+            .entries
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("tensor {} not found", name))?;
+        Ok(SourceTensor {
+            dtype: entry.dtype,
+            shape: entry.shape.clone(),
+            data: &self.shards[entry.shard][entry.start..entry.end],
+        })
+    }
+}
+
+fn shard_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let index = dir.join("model.safetensors.index.json");
+    if index.exists() {
+        let index: serde_json::Value = serde_json::from_slice(&std::fs::read(&index)?)?;
+        let map = index["weight_map"]
+            .as_object()
+            .ok_or_else(|| anyhow::anyhow!("{} has no weight_map", dir.display()))?;
+        let mut files: Vec<&str> = map.values().filter_map(|v| v.as_str()).collect();
+        files.sort();
+        files.dedup();
+        return files
+            .into_iter()
+            .map(|f| {
+                // Shard names come from the index, so keep them inside the model directory.
+                if f.contains('/') || f.contains('\\') || f == ".." {
+                    anyhow::bail!("invalid shard name {} in index", f);
+                }
+                Ok(dir.join(f))
+            })
+            .collect();
+    }
+
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "safetensors"))
+        .collect();
+    files.sort();
+    if files.is_empty() {
+        anyhow::bail!("no safetensors files found in {}", dir.display());
+    }
+    Ok(files)
+}
+
+pub fn is_safetensors_dir(dir: &Path) -> bool {
+    dir.join("config.json").exists() && shard_files(dir).is_ok()
 }
@@ -1,7 +1,299 @@
-use crate::core::model::ModelConfig;
+use super::pickle::{self, Object, StorageType, TensorRef};
+use super::{ModelConverter, SourceTensor};
+use crate::infra::gguf::GgmlType;
 use crate::infra::Result;
-use std::path::Path;
+use memmap2::Mmap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use zip::{CompressionMethod, ZipArchive};
 
-pub fn convert<P: AsRef<Path>>(_input: P, _output: P, _config: &ModelConfig) -> Result<()> {
-    Ok(())
+// A tensor located inside a torch archive: a byte range of its storage entry.
+pub struct ArchiveTensor {
+    pub dtype: GgmlType,
+    pub shape: Vec<usize>,
+    pub start: usize,
+    pub end: usize,
+}
+
+struct Entry {
+    shard: usize,
+    tensor: ArchiveTensor,
+}
+
+// A PyTorch checkpoint (pytorch_model*.bin or *.pth zip archives), with every
+// shard memory-mapped and storages read in place.
+pub struct TorchModel {
+    shards: Vec<Mmap>,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl TorchModel {
+    pub fn open<P: AsRef<Path>>(input: P) -> Result<Self> {
+        let input = input.as_ref();
+        let files = if input.is_dir() { shard_files(input)? } else { vec![input.to_path_buf()] };
+        let mut model = Self {
+            shards: Vec::with_capacity(files.len()),
+            entries: BTreeMap::new(),
+        };
+        for path in files {
+            let file = std::fs::File::open(&path)?;
+            // Safety: checkpoints are read-only inputs for the duration of the conversion.
+            let mmap = unsafe { Mmap::map(&file)? };
+            let shard = model.shards.len();
+            for (name, tensor) in read_archive(&mmap).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))? {
+                model.entries.insert(name, Entry { shard, tensor });
+            }
+            model.shards.push(mmap);
+        }
+        Ok(model)
+    }
+
+    pub fn tensor_names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    pub fn tensor(&self, name: &str) -> Result<SourceTensor<'_>> {
+        let entry = self
+            .entries
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("tensor {} not found", name))?;
+        Ok(SourceTensor {
+            dtype: entry.tensor.dtype,
+            shape: entry.tensor.shape.clone(),
+            data: &self.shards[entry.shard][entry.tensor.start..entry.tensor.end],
+        })
+    }
+}
+
+// Reads the state dict of a zip-based torch.save archive. Tensor byte ranges
+// index into `data`; storages must be stored uncompressed, as torch writes them.
+pub fn read_archive(data: &[u8]) -> Result<Vec<(String, ArchiveTensor)>> {
+    if !data.starts_with(b"PK\x03\x04") {
+        anyhow::bail!("not a zip-based torch archive (legacy torch.save files are not supported)");
+    }
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+
+    let mut pickle_index = None;
+    let mut byteorder_index = None;
+    let mut storages: HashMap<String, (usize, usize)> = HashMap::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        let name = file.name().to_string();
+        if name.ends_with("/data.pkl") || name == "data.pkl" {
+            pickle_index = Some(i);
+        } else if name.ends_with("/byteorder") {
+            byteorder_index = Some(i);
+        } else if let Some((_, key)) = name.rsplit_once("/data/") {
+            if file.compression() != CompressionMethod::Stored {
+                anyhow::bail!("storage {} is compressed", name);
+            }
+            let start = file.data_start().ok_or_else(|| anyhow::anyhow!("storage {} has no data offset", name))?;
+            let start = usize::try_from(start)?;
+            let end = start
+                .checked_add(usize::try_from(file.compressed_size())?)
+                .filter(|e| *e <= data.len())
+                .ok_or_else(|| anyhow::anyhow!("storage {} extends past the archive", name))?;
+            storages.insert(key.to_string(), (start, end));
+        }
+    }
+
+    if let Some(i) = byteorder_index {
+        let mut order = String::new();
+        std::io::Read::read_to_string(&mut archive.by_index(i)?, &mut order)?;
+        if order.trim() != "little" {
+            anyhow::bail!("big-endian torch archives are not supported");
+        }
+    }
+
+    let index = pickle_index.ok_or_else(|| anyhow::anyhow!("archive has no data.pkl"))?;
+    let mut pkl = Vec::new();
+    std::io::Read::read_to_end(&mut archive.by_index(index)?, &mut pkl)?;
+
+    let mut root = pickle::unpickle(&pkl)?;
+    // Training checkpoints nest the weights under "state_dict" or "model".
+    if let Object::Dict(items) = &root {
+        let nested = items.iter().find_map(|(k, v)| match (k, v) {
+            (Object::String(k), Object::Dict(_)) if k == "state_dict" || k == "model" => Some(v.clone()),
+            _ => None,
+        });
+        if let Some(nested) = nested {
+            root = nested;
+        }
+    }
+    let Object::Dict(items) = root else {
+        anyhow::bail!("checkpoint is not a state dict");
+    };
+
+    let mut tensors = Vec::new();
+    for (key, value) in items {
+        let (Object::String(name), Object::Tensor(tensor)) = (key, value) else {
+            continue;
+        };
+        let located = locate(&name, &tensor, &storages)?;
+        tensors.push((name, located));
+    }
+    Ok(tensors)
+}
+
+fn locate(name: &str, tensor: &TensorRef, storages: &HashMap<String, (usize, usize)>) -> Result<ArchiveTensor> {
+    if !tensor.is_contiguous() {
+        anyhow::bail!("tensor {} is not contiguous", name);
+    }
+    let (storage_start, storage_end) = storages
+        .get(&tensor.storage.key)
+        .copied()
+        .ok_or_else(|| anyhow::anyhow!("tensor {} references missing storage {}", name, tensor.storage.key))?;
+
+    let element_size = tensor.storage.dtype.element_size();
+    let elements = tensor
+        .shape
+        .iter()
+        .try_fold(1usize, |acc, d| acc.checked_mul(*d))
+        .ok_or_else(|| anyhow::anyhow!("tensor {} is too large", name))?;
+    let start = tensor
+        .offset
+        .checked_mul(element_size)
+        .and_then(|o| o.checked_add(storage_start));
+    let end = elements
+        .checked_mul(element_size)
+        .and_then(|len| start.and_then(|s| s.checked_add(len)));
+    let (Some(start), Some(end)) = (start, end) else {
+        anyhow::bail!("tensor {} has an invalid extent", name);
+    };
+    if end > storage_end {
+        anyhow::bail!("tensor {} extends past its storage", name);
+    }
+
+    let dtype = match tensor.storage.dtype {
+        StorageType::F32 => GgmlType::F32,
+        StorageType::F16 => GgmlType::F16,
+        StorageType::BF16 => GgmlType::BF16,
+        StorageType::I64 => GgmlType::I64,
+        StorageType::I32 => GgmlType::I32,
+    };
+    Ok(ArchiveTensor {
+        dtype,
+        shape: tensor.shape.clone(),
+        start,
+        end,
+    })
+}
+
+fn shard_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let index = dir.join("pytorch_model.bin.index.json");
+    if index.exists() {
+        let index: serde_json::Value = serde_json::from_slice(&std::fs::read(&index)?)?;
+        let map = index["weight_map"]
+            .as_object()
+            .ok_or_else(|| anyhow::anyhow!("{} has no weight_map", dir.display()))?;
+        let mut files: Vec<&str> = map.values().filter_map(|v| v.as_str()).collect();
+        files.sort();
+        files.dedup();
+        return files
+            .into_iter()
+            .map(|f| {
+                if f.contains('/') || f.contains('\\') || f == ".." {
+                    anyhow::bail!("invalid shard name {} in index", f);
+                }
+                Ok(dir.join(f))
+            })
+            .collect();
+    }
+
+    // training_args.bin and friends are pickles too, so only take weight files.
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            let name = p.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            (name.starts_with("pytorch_model") && name.ends_with(".bin"))
+                || name.ends_with(".pth")
+                || name.ends_with(".pt")
+        })
+        .collect();
+    files.sort();
+    if files.is_empty() {
+        anyhow::bail!("no PyTorch checkpoint files found in {}", dir.display());
+    }
+    Ok(files)
+}
+
+pub fn is_pytorch_dir(dir: &Path) -> bool {
+    dir.join("config.json").exists() && shard_files(dir).is_ok()
+}
+
+pub fn convert<P: AsRef<Path>>(input: P, output: P) -> Result<()> {
+    ModelConverter::new().convert_pytorch(input, output, |_| {})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{SimpleFileOptions, ZipWriter};
+
+    // A torch.save archive with one fp16 tensor of shape (2, 3) whose
+    // storage holds 8 elements, viewed from offset 1.
+    fn archive(name: &str) -> Vec<u8> {
+        let mut pkl = b"\x80\x02}q\x00X".to_vec();
+        pkl.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        pkl.extend_from_slice(name.as_bytes());
+        pkl.extend_from_slice(b"q\x01ctorch._utils\n_rebuild_tensor_v2\n(");
+        pkl.extend_from_slice(b"(X\x07\x00\x00\x00storagectorch\nHalfStorage\nX\x01\x00\x00\x000X\x03\x00\x00\x00cpuK\x08tQ");
+        pkl.extend_from_slice(b"K\x01K\x02K\x03\x86K\x03K\x01\x86\x89ccollections\nOrderedDict\n)RtRs.");
+
+        let storage: Vec<u8> = (0..8).flat_map(|i| half::f16::from_f32(i as f32).to_le_bytes()).collect();
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        for (name, data) in [
+            ("archive/data.pkl", pkl.as_slice()),
+            ("archive/byteorder", b"little".as_slice()),
+            ("archive/data/0", storage.as_slice()),
+        ] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_read_archive() {
+        let data = archive("w");
+        let tensors = read_archive(&data).unwrap();
+        assert_eq!(tensors.len(), 1);
+        let (name, t) = &tensors[0];
+        assert_eq!(name, "w");
+        assert_eq!(t.shape, vec![2, 3]);
+        let source = SourceTensor {
+            dtype: t.dtype,
+            shape: t.shape.clone(),
+            data: &data[t.start..t.end],
+        };
+        assert_eq!(source.to_f32().unwrap(), vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn test_convert_pytorch_dir() {
+        let dir = std::env::temp_dir().join(format!("ollama-torch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("config.json"),
+            r#"{"architectures":["Qwen2ForCausalLM"],"hidden_size":3,"intermediate_size":4,
+                "num_hidden_layers":1,"num_attention_heads":1,"max_position_embeddings":16}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("pytorch_model.bin"), archive("model.embed_tokens.weight")).unwrap();
+        // Not a weight file, and its pickle would be refused if it were read.
+        std::fs::write(dir.join("training_args.bin"), b"\x80\x02cos\nsystem\n.").unwrap();
+        assert!(is_pytorch_dir(&dir));
+
+        let output = dir.join("out.gguf");
+        convert(&dir, &output).unwrap();
+        let file = crate::infra::gguf::GgufParser::parse(&output).unwrap();
+        assert_eq!(file.metadata.string("general.architecture"), "qwen2");
+        let embd = file.tensor("token_embd.weight").unwrap();
+        assert_eq!(embd.dims, vec![3, 2]);
+        assert_eq!(embd.dtype, GgmlType::F16);
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
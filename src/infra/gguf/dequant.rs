@@ -0,0 +1,592 @@
+use super::iq_grids::*;
+use super::GgmlType;
+use crate::infra::Result;
+use half::{bf16, f16};
+use rayon::prelude::*;
+
+const PARALLEL_THRESHOLD: usize = 1 << 16;
+const TASK_ELEMENTS: usize = 1 << 14;
+
+const KMASK_IQ2XS: [u8; 8] = [1, 2, 4, 8, 16, 32, 64, 128];
+const KVALUES_IQ4NL: [i8; 16] = [-127, -104, -83, -65, -49, -35, -22, -10, 1, 13, 25, 38, 53, 69, 89, 113];
+const IQ1S_DELTA: f32 = 0.125;
+
+type BlockFn = fn(&[u8], &mut [f32]);
+
+pub fn dequantize(dtype: GgmlType, data: &[u8]) -> Result<Vec<f32>> {
+    let mut out = vec![0f32; data.len() / dtype.type_size() * dtype.block_size()];
+    dequantize_into(dtype, data, &mut out)?;
+    Ok(out)
+}
+
+// Dequantizes `data` into the front of `out`, which callers reuse across
+// calls to avoid an allocation per read.
+pub fn dequantize_into(dtype: GgmlType, data: &[u8], out: &mut [f32]) -> Result<()> {
+    let block_size = dtype.block_size();
+    let type_size = dtype.type_size();
+    if !data.len().is_multiple_of(type_size) {
+        anyhow::bail!(
+            "{} data length {} is not a multiple of the block size {}",
+            dtype.name(),
+            data.len(),
+            type_size
+        );
+    }
+    let len = data.len() / type_size * block_size;
+    if out.len() < len {
+        anyhow::bail!("{} output of {} values is too small for {}", dtype.name(), out.len(), len);
+    }
+
+    let f = block_fn(dtype);
+    let out = &mut out[..len];
+    if len >= PARALLEL_THRESHOLD {
+        let blocks_per_task = (TASK_ELEMENTS / block_size).max(1);
+        out.par_chunks_mut(blocks_per_task * block_size)
+            .zip(data.par_chunks(blocks_per_task * type_size))
+            .for_each(|(y, x)| dequantize_blocks(f, x, y, type_size, block_size));
+    } else {
+        dequantize_blocks(f, data, out, type_size, block_size);
+    }
+
+    Ok(())
+}
+
+fn dequantize_blocks(f: BlockFn, x: &[u8], y: &mut [f32], type_size: usize, block_size: usize) {
+    for (xb, yb) in x.chunks_exact(type_size).zip(y.chunks_exact_mut(block_size)) {
+        f(xb, yb);
+    }
+}
+
+fn block_fn(dtype: GgmlType) -> BlockFn {
+    match dtype {
+        GgmlType::F32 => |x, y| y[0] = f32::from_le_bytes([x[0], x[1], x[2], x[3]]),
+        GgmlType::F16 => |x, y| y[0] = read_f16(x, 0),
+        GgmlType::BF16 => |x, y| y[0] = bf16::from_le_bytes([x[0], x[1]]).to_f32(),
+        GgmlType::F64 => |x, y| y[0] = f64::from_le_bytes(x[..8].try_into().unwrap()) as f32,
+        GgmlType::I8 => |x, y| y[0] = x[0] as i8 as f32,
+        GgmlType::I16 => |x, y| y[0] = i16::from_le_bytes([x[0], x[1]]) as f32,
+        GgmlType::I32 => |x, y| y[0] = i32::from_le_bytes([x[0], x[1], x[2], x[3]]) as f32,
+        GgmlType::I64 => |x, y| y[0] = i64::from_le_bytes(x[..8].try_into().unwrap()) as f32,
+        GgmlType::Q4_0 => dequantize_q4_0,
+        GgmlType::Q4_1 => dequantize_q4_1,
+        GgmlType::Q5_0 => dequantize_q5_0,
+        GgmlType::Q5_1 => dequantize_q5_1,
+        GgmlType::Q8_0 => dequantize_q8_0,
+        GgmlType::Q8_1 => dequantize_q8_1,
+        GgmlType::Q2K => dequantize_q2_k,
+        GgmlType::Q3K => dequantize_q3_k,
+        GgmlType::Q4K => dequantize_q4_k,
+        GgmlType::Q5K => dequantize_q5_k,
+        GgmlType::Q6K => dequantize_q6_k,
+        GgmlType::Q8K => dequantize_q8_k,
+        GgmlType::IQ2XXS => dequantize_iq2_xxs,
+        GgmlType::IQ2XS => dequantize_iq2_xs,
+        GgmlType::IQ2S => dequantize_iq2_s,
+        GgmlType::IQ3XXS => dequantize_iq3_xxs,
+        GgmlType::IQ3S => dequantize_iq3_s,
+        GgmlType::IQ1S => dequantize_iq1_s,
+        GgmlType::IQ1M => dequantize_iq1_m,
+        GgmlType::IQ4NL => dequantize_iq4_nl,
+        GgmlType::IQ4XS => dequantize_iq4_xs,
+    }
+}
+
+fn read_f16(x: &[u8], at: usize) -> f32 {
+    f16::from_le_bytes([x[at], x[at + 1]]).to_f32()
+}
+
+fn read_u16(x: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([x[at], x[at + 1]])
+}
+
+fn read_u32(x: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([x[at], x[at + 1], x[at + 2], x[at + 3]])
+}
+
+fn sign(signs: u8, j: usize) -> f32 {
+    if signs & KMASK_IQ2XS[j] != 0 { -1.0 } else { 1.0 }
+}
+
+fn dequantize_q4_0(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let qs = &x[2..18];
+    for j in 0..16 {
+        y[j] = ((qs[j] & 0x0F) as i32 - 8) as f32 * d;
+        y[j + 16] = ((qs[j] >> 4) as i32 - 8) as f32 * d;
+    }
+}
+
+fn dequantize_q4_1(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let m = read_f16(x, 2);
+    let qs = &x[4..20];
+    for j in 0..16 {
+        y[j] = (qs[j] & 0x0F) as f32 * d + m;
+        y[j + 16] = (qs[j] >> 4) as f32 * d + m;
+    }
+}
+
+fn dequantize_q5_0(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let qh = read_u32(x, 2);
+    let qs = &x[6..22];
+    for j in 0..16 {
+        let xh_0 = ((qh >> j) << 4) & 0x10;
+        let xh_1 = (qh >> (j + 12)) & 0x10;
+        y[j] = (((qs[j] & 0x0F) as u32 | xh_0) as i32 - 16) as f32 * d;
+        y[j + 16] = (((qs[j] >> 4) as u32 | xh_1) as i32 - 16) as f32 * d;
+    }
+}
+
+fn dequantize_q5_1(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let m = read_f16(x, 2);
+    let qh = read_u32(x, 4);
+    let qs = &x[8..24];
+    for j in 0..16 {
+        let xh_0 = ((qh >> j) << 4) & 0x10;
+        let xh_1 = (qh >> (j + 12)) & 0x10;
+        y[j] = ((qs[j] & 0x0F) as u32 | xh_0) as f32 * d + m;
+        y[j + 16] = ((qs[j] >> 4) as u32 | xh_1) as f32 * d + m;
+    }
+}
+
+fn dequantize_q8_0(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    for (y, &q) in y.iter_mut().zip(&x[2..34]) {
+        *y = q as i8 as f32 * d;
+    }
+}
+
+fn dequantize_q8_1(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    for (y, &q) in y.iter_mut().zip(&x[4..36]) {
+        *y = q as i8 as f32 * d;
+    }
+}
+
+fn dequantize_q2_k(x: &[u8], y: &mut [f32]) {
+    let scales = &x[0..16];
+    let d = read_f16(x, 80);
+    let min = read_f16(x, 82);
+
+    let mut is = 0;
+    let mut out = 0;
+    for half in 0..2 {
+        let q = &x[16 + 32 * half..16 + 32 * (half + 1)];
+        for shift in (0..8).step_by(2) {
+            for offset in [0, 16] {
+                let sc = scales[is];
+                is += 1;
+                let dl = d * (sc & 0x0F) as f32;
+                let ml = min * (sc >> 4) as f32;
+                for l in 0..16 {
+                    y[out] = dl * ((q[l + offset] >> shift) & 3) as f32 - ml;
+                    out += 1;
+                }
+            }
+        }
+    }
+}
+
+fn dequantize_q3_k(x: &[u8], y: &mut [f32]) {
+    const KMASK1: u32 = 0x03030303;
+    const KMASK2: u32 = 0x0f0f0f0f;
+
+    let hm = &x[0..32];
+    let d_all = read_f16(x, 108);
+
+    let mut aux = [read_u32(x, 96), read_u32(x, 100), read_u32(x, 104), 0];
+    let tmp = aux[2];
+    aux[2] = ((aux[0] >> 4) & KMASK2) | (((tmp >> 4) & KMASK1) << 4);
+    aux[3] = ((aux[1] >> 4) & KMASK2) | (((tmp >> 6) & KMASK1) << 4);
+    aux[0] = (aux[0] & KMASK2) | ((tmp & KMASK1) << 4);
+    aux[1] = (aux[1] & KMASK2) | (((tmp >> 2) & KMASK1) << 4);
+    let scales: Vec<i8> = aux.iter().flat_map(|a| a.to_le_bytes()).map(|b| b as i8).collect();
+
+    let mut is = 0;
+    let mut out = 0;
+    let mut m = 1u8;
+    for half in 0..2 {
+        let q = &x[32 + 32 * half..32 + 32 * (half + 1)];
+        for shift in (0..8).step_by(2) {
+            for offset in [0, 16] {
+                let dl = d_all * (scales[is] as i32 - 32) as f32;
+                is += 1;
+                for l in 0..16 {
+                    let h = if hm[l + offset] & m != 0 { 0 } else { 4 };
+                    y[out] = dl * (((q[l + offset] >> shift) & 3) as i32 - h) as f32;
+                    out += 1;
+                }
+            }
+            m <<= 1;
+        }
+    }
+}
+
+fn get_scale_min_k4(j: usize, q: &[u8]) -> (u8, u8) {
+    if j < 4 {
+        (q[j] & 63, q[j + 4] & 63)
+    } else {
+        let d = (q[j + 4] & 0x0F) | ((q[j - 4] >> 6) << 4);
+        let m = (q[j + 4] >> 4) | ((q[j] >> 6) << 4);
+        (d, m)
+    }
+}
+
+fn dequantize_q4_k(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let min = read_f16(x, 2);
+    let scales = &x[4..16];
+
+    for (chunk, (q, y)) in x[16..144].chunks_exact(32).zip(y.chunks_exact_mut(64)).enumerate() {
+        let (sc1, m1) = get_scale_min_k4(2 * chunk, scales);
+        let (sc2, m2) = get_scale_min_k4(2 * chunk + 1, scales);
+        let (d1, m1) = (d * sc1 as f32, min * m1 as f32);
+        let (d2, m2) = (d * sc2 as f32, min * m2 as f32);
+        for l in 0..32 {
+            y[l] = d1 * (q[l] & 0x0F) as f32 - m1;
+            y[l + 32] = d2 * (q[l] >> 4) as f32 - m2;
+        }
+    }
+}
+
+fn dequantize_q5_k(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let min = read_f16(x, 2);
+    let scales = &x[4..16];
+    let qh = &x[16..48];
+
+    for (chunk, (ql, y)) in x[48..176].chunks_exact(32).zip(y.chunks_exact_mut(64)).enumerate() {
+        let (sc1, m1) = get_scale_min_k4(2 * chunk, scales);
+        let (sc2, m2) = get_scale_min_k4(2 * chunk + 1, scales);
+        let (d1, m1) = (d * sc1 as f32, min * m1 as f32);
+        let (d2, m2) = (d * sc2 as f32, min * m2 as f32);
+        let u1 = 1u8 << (2 * chunk);
+        let u2 = 2u8 << (2 * chunk);
+        for l in 0..32 {
+            let h1 = if qh[l] & u1 != 0 { 16 } else { 0 };
+            let h2 = if qh[l] & u2 != 0 { 16 } else { 0 };
+            y[l] = d1 * ((ql[l] & 0x0F) + h1) as f32 - m1;
+            y[l + 32] = d2 * ((ql[l] >> 4) + h2) as f32 - m2;
+        }
+    }
+}
+
+fn dequantize_q6_k(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 208);
+
+    for (n, y) in y.chunks_exact_mut(128).enumerate() {
+        let ql = &x[64 * n..64 * (n + 1)];
+        let qh = &x[128 + 32 * n..128 + 32 * (n + 1)];
+        let sc = &x[192 + 8 * n..192 + 8 * (n + 1)];
+        for l in 0..32 {
+            let is = l / 16;
+            let q1 = ((ql[l] & 0x0F) | ((qh[l] & 3) << 4)) as i32 - 32;
+            let q2 = ((ql[l + 32] & 0x0F) | (((qh[l] >> 2) & 3) << 4)) as i32 - 32;
+            let q3 = ((ql[l] >> 4) | (((qh[l] >> 4) & 3) << 4)) as i32 - 32;
+            let q4 = ((ql[l + 32] >> 4) | (((qh[l] >> 6) & 3) << 4)) as i32 - 32;
+            y[l] = d * (sc[is] as i8) as f32 * q1 as f32;
+            y[l + 32] = d * (sc[is + 2] as i8) as f32 * q2 as f32;
+            y[l + 64] = d * (sc[is + 4] as i8) as f32 * q3 as f32;
+            y[l + 96] = d * (sc[is + 6] as i8) as f32 * q4 as f32;
+        }
+    }
+}
+
+fn dequantize_q8_k(x: &[u8], y: &mut [f32]) {
+    let d = f32::from_le_bytes([x[0], x[1], x[2], x[3]]);
+    for (y, &q) in y.iter_mut().zip(&x[4..260]) {
+        *y = q as i8 as f32 * d;
+    }
+}
+
+fn dequantize_iq2_xxs(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+
+    for (ib32, y) in y.chunks_exact_mut(32).enumerate() {
+        let aux8 = &x[2 + 8 * ib32..2 + 8 * (ib32 + 1)];
+        let aux1 = read_u32(aux8, 4);
+        let db = d * (0.5 + (aux1 >> 28) as f32) * 0.25;
+        for (l, y) in y.chunks_exact_mut(8).enumerate() {
+            let grid = IQ2XXS_GRID[aux8[l] as usize].to_le_bytes();
+            let signs = KSIGNS_IQ2XS[((aux1 >> (7 * l)) & 127) as usize];
+            for j in 0..8 {
+                y[j] = db * grid[j] as f32 * sign(signs, j);
+            }
+        }
+    }
+}
+
+fn dequantize_iq2_xs(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let scales = &x[66..74];
+
+    for (ib32, y) in y.chunks_exact_mut(32).enumerate() {
+        let db = [
+            d * (0.5 + (scales[ib32] & 0x0F) as f32) * 0.25,
+            d * (0.5 + (scales[ib32] >> 4) as f32) * 0.25,
+        ];
+        for (l, y) in y.chunks_exact_mut(8).enumerate() {
+            let q = read_u16(x, 2 + 2 * (4 * ib32 + l));
+            let grid = IQ2XS_GRID[(q & 511) as usize].to_le_bytes();
+            let signs = KSIGNS_IQ2XS[(q >> 9) as usize];
+            for j in 0..8 {
+                y[j] = db[l / 2] * grid[j] as f32 * sign(signs, j);
+            }
+        }
+    }
+}
+
+fn dequantize_iq2_s(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let qs = &x[2..34];
+    let signs = &x[34..66];
+    let qh = &x[66..74];
+    let scales = &x[74..82];
+
+    for (ib32, y) in y.chunks_exact_mut(32).enumerate() {
+        let db = [
+            d * (0.5 + (scales[ib32] & 0x0F) as f32) * 0.25,
+            d * (0.5 + (scales[ib32] >> 4) as f32) * 0.25,
+        ];
+        for (l, y) in y.chunks_exact_mut(8).enumerate() {
+            let idx = qs[4 * ib32 + l] as usize | (((qh[ib32] as usize) << (8 - 2 * l)) & 0x300);
+            let grid = IQ2S_GRID[idx].to_le_bytes();
+            let s = signs[4 * ib32 + l];
+            for j in 0..8 {
+                y[j] = db[l / 2] * grid[j] as f32 * sign(s, j);
+            }
+        }
+    }
+}
+
+fn dequantize_iq3_xxs(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let qs = &x[2..66];
+
+    for (ib32, y) in y.chunks_exact_mut(32).enumerate() {
+        let aux32 = read_u32(x, 66 + 4 * ib32);
+        let db = d * (0.5 + (aux32 >> 28) as f32) * 0.5;
+        for (l, y) in y.chunks_exact_mut(8).enumerate() {
+            let signs = KSIGNS_IQ2XS[((aux32 >> (7 * l)) & 127) as usize];
+            let grid1 = IQ3XXS_GRID[qs[8 * ib32 + 2 * l] as usize].to_le_bytes();
+            let grid2 = IQ3XXS_GRID[qs[8 * ib32 + 2 * l + 1] as usize].to_le_bytes();
+            for j in 0..4 {
+                y[j] = db * grid1[j] as f32 * sign(signs, j);
+                y[j + 4] = db * grid2[j] as f32 * sign(signs, j + 4);
+            }
+        }
+    }
+}
+
+fn dequantize_iq3_s(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let qs = &x[2..66];
+    let qh = &x[66..74];
+    let signs = &x[74..106];
+    let scales = &x[106..110];
+
+    for (ib32, y) in y.chunks_exact_mut(32).enumerate() {
+        let sc = if ib32 % 2 == 0 { scales[ib32 / 2] & 0x0F } else { scales[ib32 / 2] >> 4 };
+        let db = d * (1 + 2 * sc as i32) as f32;
+        let qs = &qs[8 * ib32..8 * (ib32 + 1)];
+        let qh = qh[ib32] as usize;
+        for (l, y) in y.chunks_exact_mut(8).enumerate() {
+            let grid1 = IQ3S_GRID[qs[2 * l] as usize | ((qh << (8 - 2 * l)) & 256)].to_le_bytes();
+            let grid2 = IQ3S_GRID[qs[2 * l + 1] as usize | ((qh << (7 - 2 * l)) & 256)].to_le_bytes();
+            let s = signs[4 * ib32 + l];
+            for j in 0..4 {
+                y[j] = db * grid1[j] as f32 * sign(s, j);
+                y[j + 4] = db * grid2[j] as f32 * sign(s, j + 4);
+            }
+        }
+    }
+}
+
+fn dequantize_iq1_s(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let qs = &x[2..34];
+
+    for (ib, y) in y.chunks_exact_mut(32).enumerate() {
+        let qh = read_u16(x, 34 + 2 * ib);
+        let dl = d * (2 * ((qh >> 12) & 7) + 1) as f32;
+        let delta = if qh & 0x8000 != 0 { -IQ1S_DELTA } else { IQ1S_DELTA };
+        for (l, y) in y.chunks_exact_mut(8).enumerate() {
+            let idx = qs[4 * ib + l] as usize | ((((qh >> (3 * l)) & 7) as usize) << 8);
+            let grid = IQ1S_GRID[idx].to_le_bytes();
+            for j in 0..8 {
+                y[j] = dl * (grid[j] as i8 as f32 + delta);
+            }
+        }
+    }
+}
+
+fn dequantize_iq1_m(x: &[u8], y: &mut [f32]) {
+    let qs = &x[0..32];
+    let qh = &x[32..48];
+    let sc = [read_u16(x, 48), read_u16(x, 50), read_u16(x, 52), read_u16(x, 54)];
+    let scale = (sc[0] >> 12) | ((sc[1] >> 8) & 0x00F0) | ((sc[2] >> 4) & 0x0F00) | (sc[3] & 0xF000);
+    let d = f16::from_bits(scale).to_f32();
+
+    for (ib, y) in y.chunks_exact_mut(32).enumerate() {
+        let s = sc[ib / 2] >> (6 * (ib % 2));
+        let dl1 = d * (2 * (s & 7) + 1) as f32;
+        let dl2 = d * (2 * ((s >> 3) & 7) + 1) as f32;
+        let qs = &qs[4 * ib..4 * (ib + 1)];
+        let qh = [qh[2 * ib] as usize, qh[2 * ib + 1] as usize];
+        let idx = [
+            qs[0] as usize | ((qh[0] << 8) & 0x700),
+            qs[1] as usize | ((qh[0] << 4) & 0x700),
+            qs[2] as usize | ((qh[1] << 8) & 0x700),
+            qs[3] as usize | ((qh[1] << 4) & 0x700),
+        ];
+        let delta = [
+            if qh[0] & 0x08 != 0 { -IQ1S_DELTA } else { IQ1S_DELTA },
+            if qh[0] & 0x80 != 0 { -IQ1S_DELTA } else { IQ1S_DELTA },
+            if qh[1] & 0x08 != 0 { -IQ1S_DELTA } else { IQ1S_DELTA },
+            if qh[1] & 0x80 != 0 { -IQ1S_DELTA } else { IQ1S_DELTA },
+        ];
+        for (l, y) in y.chunks_exact_mut(8).enumerate() {
+            let dl = if l < 2 { dl1 } else { dl2 };
+            let grid = IQ1S_GRID[idx[l]].to_le_bytes();
+            for j in 0..8 {
+                y[j] = dl * (grid[j] as i8 as f32 + delta[l]);
+            }
+        }
+    }
+}
+
+fn dequantize_iq4_nl(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let qs = &x[2..18];
+    for j in 0..16 {
+        y[j] = d * KVALUES_IQ4NL[(qs[j] & 0x0F) as usize] as f32;
+        y[j + 16] = d * KVALUES_IQ4NL[(qs[j] >> 4) as usize] as f32;
+    }
+}
+
+fn dequantize_iq4_xs(x: &[u8], y: &mut [f32]) {
+    let d = read_f16(x, 0);
+    let scales_h = read_u16(x, 2);
+    let scales_l = &x[4..8];
+
+    for (ib, y) in y.chunks_exact_mut(32).enumerate() {
+        let ls = ((scales_l[ib / 2] >> (4 * (ib % 2))) & 0x0F) as i32
+            | ((((scales_h >> (2 * ib)) & 3) as i32) << 4);
+        let dl = d * (ls - 32) as f32;
+        let qs = &x[8 + 16 * ib..8 + 16 * (ib + 1)];
+        for j in 0..16 {
+            y[j] = dl * KVALUES_IQ4NL[(qs[j] & 0x0F) as usize] as f32;
+            y[j + 16] = dl * KVALUES_IQ4NL[(qs[j] >> 4) as usize] as f32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::{GgmlDType, QTensor};
+    use candle_core::{Device, Tensor};
+
+    fn sample(n: usize) -> Vec<f32> {
+        (0..n).map(|i| ((i as f32 * 0.37).sin() * 3.0) + (i % 7) as f32 * 0.01).collect()
+    }
+
+    fn check_against_candle(dtype: GgmlType, candle_dtype: GgmlDType, n: usize) {
+        let src = Tensor::from_vec(sample(n), n, &Device::Cpu).unwrap();
+        let q = QTensor::quantize(&src, candle_dtype).unwrap();
+        let expected: Vec<f32> = q.dequantize(&Device::Cpu).unwrap().to_vec1().unwrap();
+        let got = dequantize(dtype, &q.data().unwrap()).unwrap();
+        assert_eq!(got.len(), expected.len(), "{}", dtype.name());
+        for (i, (a, b)) in got.iter().zip(&expected).enumerate() {
+            assert!((a - b).abs() <= 1e-6 * b.abs().max(1.0), "{} [{}]: {} != {}", dtype.name(), i, a, b);
+        }
+    }
+
+    #[test]
+    fn test_matches_candle_reference() {
+        let cases = [
+            (GgmlType::F16, GgmlDType::F16),
+            (GgmlType::BF16, GgmlDType::BF16),
+            (GgmlType::Q4_0, GgmlDType::Q4_0),
+            (GgmlType::Q4_1, GgmlDType::Q4_1),
+            (GgmlType::Q5_0, GgmlDType::Q5_0),
+            (GgmlType::Q5_1, GgmlDType::Q5_1),
+            (GgmlType::Q8_0, GgmlDType::Q8_0),
+            (GgmlType::Q2K, GgmlDType::Q2K),
+            (GgmlType::Q3K, GgmlDType::Q3K),
+            (GgmlType::Q4K, GgmlDType::Q4K),
+            (GgmlType::Q5K, GgmlDType::Q5K),
+            (GgmlType::Q6K, GgmlDType::Q6K),
+            (GgmlType::Q8K, GgmlDType::Q8K),
+        ];
+        for (dtype, candle_dtype) in cases {
+            check_against_candle(dtype, candle_dtype, 1024);
+        }
+    }
+
+    #[test]
+    fn test_parallel_path_matches_serial() {
+        let n = PARALLEL_THRESHOLD * 2;
+        check_against_candle(GgmlType::Q4K, GgmlDType::Q4K, n);
+        check_against_candle(GgmlType::Q8_0, GgmlDType::Q8_0, n);
+    }
+
+    #[test]
+    fn test_rejects_partial_block() {
+        assert!(dequantize(GgmlType::Q4_0, &[0u8; 17]).is_err());
+        assert!(dequantize(GgmlType::F32, &[0u8; 6]).is_err());
+    }
+
+    #[test]
+    fn test_plain_types() {
+        let data: Vec<u8> = [1.5f32, -2.0].iter().flat_map(|v| v.to_le_bytes()).collect();
+        assert_eq!(dequantize(GgmlType::F32, &data).unwrap(), vec![1.5, -2.0]);
+        assert_eq!(dequantize(GgmlType::I8, &[0xFF, 3]).unwrap(), vec![-1.0, 3.0]);
+        let data: Vec<u8> = [-7i32, 9].iter().flat_map(|v| v.to_le_bytes()).collect();
+        assert_eq!(dequantize(GgmlType::I32, &data).unwrap(), vec![-7.0, 9.0]);
+    }
+
+    // Two blocks of each IQ type followed by their expected values as f32,
+    // computed by a separate transcription of ggml's reference dequantize_row_*
+    // routines, so they do not share code with this module.
+    #[test]
+    fn test_iq_types_match_ggml_reference() {
+        macro_rules! fixture {
+            ($name:literal) => {
+                include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/dequant/", $name, ".bin"))
+            };
+        }
+        let cases: [(GgmlType, &[u8]); 9] = [
+            (GgmlType::IQ2XXS, fixture!("iq2_xxs")),
+            (GgmlType::IQ2XS, fixture!("iq2_xs")),
+            (GgmlType::IQ2S, fixture!("iq2_s")),
+            (GgmlType::IQ3XXS, fixture!("iq3_xxs")),
+            (GgmlType::IQ3S, fixture!("iq3_s")),
+            (GgmlType::IQ1S, fixture!("iq1_s")),
+            (GgmlType::IQ1M, fixture!("iq1_m")),
+            (GgmlType::IQ4NL, fixture!("iq4_nl")),
+            (GgmlType::IQ4XS, fixture!("iq4_xs")),
+        ];
+        for (dtype, fixture) in cases {
+            let (blocks, expected) = fixture.split_at(dtype.type_size() * 2);
+            let expected: Vec<f32> = expected.chunks_exact(4).map(|c| f32::from_le_bytes(c.try_into().unwrap())).collect();
+            let got = dequantize(dtype, blocks).unwrap();
+            assert_eq!(got.len(), expected.len(), "{}", dtype.name());
+            for (i, (a, b)) in got.iter().zip(&expected).enumerate() {
+                assert!((a - b).abs() <= 1e-6 * b.abs().max(1.0), "{} [{}]: {} != {}", dtype.name(), i, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_types_dequantize_zero_blocks() {
+        for id in [0, 1, 2, 3, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30] {
+            let dtype = super::super::GgufParser::dtype_from_id(id).unwrap();
+            let y = dequantize(dtype, &vec![0u8; dtype.type_size() * 2]).unwrap();
+            assert_eq!(y.len(), dtype.block_size() * 2);
+            assert!(y.iter().all(|v| v.is_finite()));
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use super::{GgufMetadata, GgufParser, GgufWriter, MetadataValue, ValueType};
+use crate::infra::Result;
+use std::path::Path;
+
+// A metadata change. Set values are kept as text until they are applied, so
+// an untyped value can take the type the key already has in the file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    Set { key: String, vtype: Option<ValueType>, value: String },
+    Unset(String),
+}
+
+impl Edit {
+    // Parses `key=value` or `key:type=value`, e.g. `llama.rope.freq_base:f32=1e6`.
+    pub fn parse_set(arg: &str) -> Result<Self> {
+        let (key, value) = arg
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected key=value, got {}", arg))?;
+        let (key, vtype) = split_type(key)?;
+        Ok(Edit::Set { key, vtype, value: value.to_string() })
+    }
+}
+
+// Splits an optional `:type` suffix off a key.
+pub fn split_type(key: &str) -> Result<(String, Option<ValueType>)> {
+    let (key, vtype) = match key.rsplit_once(':') {
+        Some((key, name)) => (key, Some(parse_type(name)?)),
+        None => (key, None),
+    };
+    if key.is_empty() {
+        anyhow::bail!("metadata key must not be empty");
+    }
+    Ok((key.to_string(), vtype))
+}
+
+fn parse_type(name: &str) -> Result<ValueType> {
+    if let Some(element) = name.strip_prefix("array[").and_then(|n| n.strip_suffix(']')) {
+        return Ok(ValueType::Array(Box::new(parse_type(element)?)));
+    }
+    Ok(match name {
+        "u8" => ValueType::U8,
+        "i8" => ValueType::I8,
+        "u16" => ValueType::U16,
+        "i16" => ValueType::I16,
+        "u32" => ValueType::U32,
+        "i32" => ValueType::I32,
+        "u64" => ValueType::U64,
+        "i64" => ValueType::I64,
+        "f32" => ValueType::F32,
+        "f64" => ValueType::F64,
+        "bool" => ValueType::Bool,
+        "string" | "str" => ValueType::String,
+        _ => anyhow::bail!("unknown metadata type {}", name),
+    })
+}
+
+pub fn parse_value(text: &str, vtype: &ValueType) -> Result<MetadataValue> {
+    let invalid = |e: &dyn std::fmt::Display| anyhow::anyhow!("invalid {:?} value {:?}: {}", vtype, text, e);
+    let uint = |max: u64| -> Result<MetadataValue> {
+        let n: u64 = text.trim().parse().map_err(|e| invalid(&e))?;
+        if n > max {
+            return Err(invalid(&"out of range"));
+        }
+        Ok(MetadataValue::Uint(n))
+    };
+    let int = |min: i64, max: i64| -> Result<MetadataValue> {
+        let n: i64 = text.trim().parse().map_err(|e| invalid(&e))?;
+        if n < min || n > max {
+            return Err(invalid(&"out of range"));
+        }
+        Ok(MetadataValue::Int(n))
+    };
+    Ok(match vtype {
+        ValueType::U8 => uint(u8::MAX as u64)?,
+        ValueType::U16 => uint(u16::MAX as u64)?,
+        ValueType::U32 => uint(u32::MAX as u64)?,
+        ValueType::U64 => uint(u64::MAX)?,
+        ValueType::I8 => int(i8::MIN as i64, i8::MAX as i64)?,
+        ValueType::I16 => int(i16::MIN as i64, i16::MAX as i64)?,
+        ValueType::I32 => int(i32::MIN as i64, i32::MAX as i64)?,
+        ValueType::I64 => int(i64::MIN, i64::MAX)?,
+        ValueType::F32 | ValueType::F64 => MetadataValue::Float(text.trim().parse().map_err(|e| invalid(&e))?),
+        ValueType::Bool => match text.trim() {
+            "true" | "1" => MetadataValue::Bool(true),
+            "false" | "0" => MetadataValue::Bool(false),
+            _ => return Err(invalid(&"expected true or false")),
+        },
+        ValueType::String => MetadataValue::String(text.to_string()),
+        // Arrays are written as JSON lists: ["a", "b"] or [1, 2].
+        ValueType::Array(element) => {
+            let items: Vec<serde_json::Value> = serde_json::from_str(text).map_err(|e| invalid(&e))?;
+            let items = items
+                .iter()
+                .map(|item| match item {
+                    serde_json::Value::String(s) => parse_value(s, element),
+                    other => parse_value(&other.to_string(), element),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            MetadataValue::Array(items)
+        }
+    })
+}
+
+// The narrowest type that holds a value given without one.
+fn infer_type(text: &str) -> ValueType {
+    let text = text.trim();
+    if text == "true" || text == "false" {
+        ValueType::Bool
+    } else if let Ok(n) = text.parse::<u64>() {
+        if n <= u32::MAX as u64 { ValueType::U32 } else { ValueType::U64 }
+    } else if let Ok(n) = text.parse::<i64>() {
+        if n >= i32::MIN as i64 { ValueType::I32 } else { ValueType::I64 }
+    } else if text.parse::<f64>().is_ok_and(f64::is_finite) {
+        ValueType::F32
+    } else {
+        ValueType::String
+    }
+}
+
+pub fn apply_edits(metadata: &mut GgufMetadata, edits: &[Edit]) -> Result<()> {
+    for edit in edits {
+        match edit {
+            Edit::Set { key, vtype, value } => {
+                let vtype = vtype
+                    .clone()
+                    .or_else(|| metadata.value_type(key))
+                    .unwrap_or_else(|| infer_type(value));
+                let parsed = parse_value(value, &vtype).map_err(|e| anyhow::anyhow!("{}: {}", key, e))?;
+                metadata.set(key, parsed, vtype);
+            }
+            Edit::Unset(key) => {
+                if metadata.remove(key).is_none() {
+                    anyhow::bail!("metadata key {} not found", key);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Writes `input` to `output` with the edited header. Tensor data is streamed
+// from the input unchanged and re-aligned to the resulting general.alignment.
+pub fn rewrite<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q, edits: &[Edit]) -> Result<u64> {
+    let input = input.as_ref();
+    let file = GgufParser::parse(input)?;
+    let mut metadata = file.metadata;
+    apply_edits(&mut metadata, edits)?;
+
+    let mut writer = GgufWriter::new(metadata);
+    for t in &file.tensors {
+        writer.add_tensor_from_file(&t.name, t.dims.clone(), t.dtype, input, file.data_offset + t.offset)?;
+    }
+    writer.write_to_file(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::gguf::GgmlType;
+
+    #[test]
+    fn test_rewrite_keeps_types_and_tensors() {
+        let mut metadata = GgufMetadata::new();
+        metadata.set("general.name", MetadataValue::String("old".into()), ValueType::String);
+        metadata.set("llama.rope.freq_base", MetadataValue::Float(10000.0), ValueType::F32);
+        metadata.set("tokenizer.chat_template", MetadataValue::String("{{ broken".into()), ValueType::String);
+        let mut writer = GgufWriter::new(metadata);
+        let data: Vec<u8> = (0..34 * 2).map(|i| i as u8).collect();
+        writer.add_tensor("token_embd.weight", vec![32, 2], GgmlType::Q8_0, data.clone()).unwrap();
+        writer.add_tensor("output_norm.weight", vec![4], GgmlType::F32, vec![7; 16]).unwrap();
+
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("ollama-edit-in-{}.gguf", std::process::id()));
+        let output = dir.join(format!("ollama-edit-out-{}.gguf", std::process::id()));
+        writer.write_to_file(&input).unwrap();
+
+        let edits = vec![
+            Edit::parse_set("general.name=new name").unwrap(),
+            Edit::parse_set("llama.rope.freq_base=1000000").unwrap(),
+            Edit::parse_set("general.alignment:u32=64").unwrap(),
+            Edit::parse_set("tokenizer.ggml.eos_token_ids:array[u32]=[1, 2]").unwrap(),
+            Edit::Unset("tokenizer.chat_template".into()),
+        ];
+        rewrite(&input, &output, &edits).unwrap();
+
+        let file = GgufParser::parse(&output).unwrap();
+        let bytes = std::fs::read(&output).unwrap();
+        std::fs::remove_file(&input).ok();
+        std::fs::remove_file(&output).ok();
+
+        assert_eq!(file.metadata.string("general.name"), "new name");
+        assert_eq!(file.metadata.value_type("llama.rope.freq_base"), Some(ValueType::F32));
+        assert_eq!(file.metadata.float("llama.rope.freq_base"), 1e6);
+        assert!(file.metadata.get("tokenizer.chat_template").is_none());
+        assert_eq!(
+            file.metadata.get("tokenizer.ggml.eos_token_ids"),
+            Some(&MetadataValue::Array(vec![MetadataValue::Uint(1), MetadataValue::Uint(2)]))
+        );
+        assert_eq!(file.alignment, 64);
+        for t in &file.tensors {
+            assert_eq!((file.data_offset + t.offset) % 64, 0);
+        }
+        let embd = file.tensor("token_embd.weight").unwrap();
+        let start = (file.data_offset + embd.offset) as usize;
+        assert_eq!(&bytes[start..start + data.len()], &data[..]);
+    }
+
+    #[test]
+    fn test_rejects_bad_values() {
+        let mut metadata = GgufMetadata::new();
+        metadata.set("llama.block_count", MetadataValue::Uint(32), ValueType::U32);
+        assert!(apply_edits(&mut metadata, &[Edit::parse_set("llama.block_count=-1").unwrap()]).is_err());
+        assert!(apply_edits(&mut metadata, &[Edit::Unset("missing".into())]).is_err());
+        assert!(Edit::parse_set("key:u128=1").is_err());
+        assert!(Edit::parse_set("no value").is_err());
+        assert_eq!(metadata.uint("llama.block_count"), 32);
+    }
+}
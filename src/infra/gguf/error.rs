@@ -0,0 +1,47 @@
+use thiserror::Error;
+
+// Upper bounds applied while parsing, independent of the file size checks.
+pub const MAX_STRING_LEN: u64 = 16 * 1024 * 1024;
+pub const MAX_ARRAY_LEN: u64 = 64 * 1024 * 1024;
+pub const MAX_ARRAY_DEPTH: usize = 8;
+pub const MAX_DIMS: u32 = 4;
+
+#[derive(Debug, Error)]
+pub enum GgufError {
+    #[error("invalid GGUF magic: expected 0x46554747, got 0x{0:08X}")]
+    InvalidMagic(u32),
+    #[error("unsupported GGUF version {0}")]
+    UnsupportedVersion(u32),
+    #[error("GGUF file is truncated at offset {0}")]
+    Truncated(u64),
+    #[error("{what} count {count} is larger than the file can hold")]
+    CountTooLarge { what: &'static str, count: u64 },
+    #[error("string of {0} bytes exceeds the limit")]
+    StringTooLong(u64),
+    #[error("array of {0} elements exceeds the limit")]
+    ArrayTooLong(u64),
+    #[error("metadata arrays nested deeper than {MAX_ARRAY_DEPTH} levels")]
+    NestingTooDeep,
+    #[error("unknown metadata value type {0}")]
+    InvalidValueType(u32),
+    #[error("duplicate metadata key {0}")]
+    DuplicateKey(String),
+    #[error("invalid general.alignment {0}")]
+    InvalidAlignment(u64),
+    #[error("unknown tensor type {0}")]
+    InvalidTensorType(u32),
+    #[error("tensor {name} has {dims} dimensions, at most {MAX_DIMS} are supported")]
+    TooManyDims { name: String, dims: u32 },
+    #[error("tensor {0} has an invalid shape")]
+    InvalidShape(String),
+    #[error("duplicate tensor {0}")]
+    DuplicateTensor(String),
+    #[error("tensor {0} offset is not aligned")]
+    MisalignedTensor(String),
+    #[error("tensor {0} extends past the end of the file")]
+    TensorOutOfBounds(String),
+    #[error("tensors {0} and {1} overlap")]
+    OverlappingTensors(String, String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,177 @@
+use super::{GgufFile, GgufParser, TensorInfo};
+use crate::core::Tensor;
+use crate::infra::Result;
+use memmap2::Mmap;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+// Blob path and whether it is mapped rather than read onto the heap.
+type MappingKey = (PathBuf, bool);
+
+// One mapping per blob, shared by every runner that loads it. A heap copy is
+// shared only with other runners that asked for one.
+static MAPPINGS: Lazy<Mutex<HashMap<MappingKey, Weak<MappedGguf>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+enum Backing {
+    Mmap(Mmap),
+    Heap(Vec<u8>),
+}
+
+pub struct MappedGguf {
+    path: PathBuf,
+    backing: Backing,
+    pub file: GgufFile,
+}
+
+impl MappedGguf {
+    pub fn open<P: AsRef<Path>>(path: P, use_mmap: bool, use_mlock: bool) -> Result<Arc<Self>> {
+        let path = path.as_ref().canonicalize()?;
+        let mut mappings = MAPPINGS.lock().unwrap();
+        mappings.retain(|_, m| m.strong_count() > 0);
+
+        let key = (path, use_mmap);
+        if let Some(existing) = mappings.get(&key).and_then(Weak::upgrade) {
+            if use_mlock {
+                existing.lock();
+            }
+            return Ok(existing);
+        }
+
+        let mapped = Arc::new(Self::open_unshared(&key.0, use_mmap)?);
+        if use_mlock {
+            mapped.lock();
+        }
+        mappings.insert(key, Arc::downgrade(&mapped));
+        Ok(mapped)
+    }
+
+    fn open_unshared(path: &Path, use_mmap: bool) -> Result<Self> {
+        let mut f = std::fs::File::open(path)?;
+        let backing = if use_mmap {
+            // Safety: model blobs are content-addressed and never modified in place.
+            Backing::Mmap(unsafe { Mmap::map(&f)? })
+        } else {
+            let mut data = Vec::new();
+            f.read_to_end(&mut data)?;
+            Backing::Heap(data)
+        };
+
+        let bytes = match &backing {
+            Backing::Mmap(m) => &m[..],
+            Backing::Heap(v) => &v[..],
+        };
+        let file = GgufParser::parse_reader(&mut std::io::Cursor::new(bytes))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            backing,
+            file,
+        })
+    }
+
+    fn lock(&self) {
+        if let Backing::Mmap(m) = &self.backing {
+            if let Err(e) = m.lock() {
+                tracing::warn!("failed to mlock {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_mmap(&self) -> bool {
+        matches!(self.backing, Backing::Mmap(_))
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match &self.backing {
+            Backing::Mmap(m) => m,
+            Backing::Heap(v) => v,
+        }
+    }
+
+    pub fn tensor_info(&self, name: &str) -> Result<&TensorInfo> {
+        self.file
+            .tensor(name)
+            .ok_or_else(|| anyhow::anyhow!("Tensor {} not found in {}", name, self.path.display()))
+    }
+
+    pub fn tensor_data(&self, name: &str) -> Result<&[u8]> {
+        let info = self.tensor_info(name)?;
+        let start = self.file.data_offset as usize + info.offset as usize;
+        let end = start + info.size_bytes();
+        self.bytes()
+            .get(start..end)
+            .ok_or_else(|| anyhow::anyhow!("Tensor {} extends past end of file", name))
+    }
+
+    pub fn tensor(&self, name: &str) -> Result<Tensor> {
+        Tensor::from_gguf(self.tensor_info(name)?, self.tensor_data(name)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn write_test_gguf(path: &Path) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(b"GGUF");
+        buf.extend_from_slice(&3u32.to_le_bytes());
+        buf.extend_from_slice(&1u64.to_le_bytes());
+        buf.extend_from_slice(&1u64.to_le_bytes());
+        write_string(&mut buf, "general.architecture");
+        buf.extend_from_slice(&8u32.to_le_bytes());
+        write_string(&mut buf, "llama");
+        write_string(&mut buf, "weight");
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        buf.extend_from_slice(&2u64.to_le_bytes());
+        buf.extend_from_slice(&2u64.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.resize(buf.len().next_multiple_of(32), 0);
+        for v in [1.0f32, 2.0, 3.0, 4.0] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        std::fs::File::create(path).unwrap().write_all(&buf).unwrap();
+    }
+
+    #[test]
+    fn test_mapping_is_shared_and_lazy() {
+        let path = std::env::temp_dir().join(format!("ollama-mapped-{}.gguf", std::process::id()));
+        write_test_gguf(&path);
+
+        let a = MappedGguf::open(&path, true, false).unwrap();
+        let b = MappedGguf::open(&path, true, false).unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(a.is_mmap());
+
+        let data = a.tensor_data("weight").unwrap();
+        assert_eq!(data.as_ptr(), a.bytes()[a.file.data_offset as usize..].as_ptr());
+        let t = a.tensor("weight").unwrap();
+        assert_eq!(t.shape().dims(), &[2, 2]);
+        assert_eq!(t.data(), &[1.0, 2.0, 3.0, 4.0]);
+
+        // Asking for a heap copy while the mapping is alive gets its own.
+        let heap = MappedGguf::open(&path, false, false).unwrap();
+        assert!(!heap.is_mmap());
+        assert!(!Arc::ptr_eq(&a, &heap));
+        assert!(Arc::ptr_eq(&heap, &MappedGguf::open(&path, false, false).unwrap()));
+        drop((a, b));
+        assert_eq!(heap.tensor("weight").unwrap().data(), &[1.0, 2.0, 3.0, 4.0]);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,13 +1,32 @@
 use crate::core::model::{ModelConfig, ConfigValue};
 use crate::infra::Result;
 use std::collections::HashMap;
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 
+pub mod dequant;
+pub mod edit;
+pub mod error;
+pub mod mapped;
+pub mod split;
+pub mod writer;
+mod iq_grids;
+
+pub use dequant::{dequantize, dequantize_into};
+pub use error::GgufError;
+pub use mapped::MappedGguf;
+pub use split::SplitGguf;
+pub use writer::GgufWriter;
+
+use error::{MAX_ARRAY_DEPTH, MAX_ARRAY_LEN, MAX_DIMS, MAX_STRING_LEN};
+
+pub type ParseResult<T> = std::result::Result<T, GgufError>;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GgmlType {
     F32,
     F16,
+    BF16,
     Q4_0,
     Q4_1,
     Q5_0,
@@ -20,31 +39,32 @@ pub enum GgmlType {
     Q5K,
     Q6K,
     Q8K,
+    IQ2XXS,
+    IQ2XS,
+    IQ2S,
+    IQ3XXS,
+    IQ3S,
+    IQ1S,
+    IQ1M,
+    IQ4NL,
+    IQ4XS,
     I8,
     I16,
     I32,
+    I64,
+    F64,
 }
 
 impl GgmlType {
     pub fn bytes_per_element(&self) -> usize {
         match self {
             GgmlType::F32 => 4,
-            GgmlType::F16 => 2,
-            GgmlType::Q4_0 => 1,
-            GgmlType::Q4_1 => 1,
-            GgmlType::Q5_0 => 1,
-            GgmlType::Q5_1 => 1,
-            GgmlType::Q8_0 => 1,
-            GgmlType::Q8_1 => 1,
-            GgmlType::Q2K => 1,
-            GgmlType::Q3K => 1,
-            GgmlType::Q4K => 1,
-            GgmlType::Q5K => 1,
-            GgmlType::Q6K => 1,
-            GgmlType::Q8K => 1,
+            GgmlType::F16 | GgmlType::BF16 => 2,
             GgmlType::I8 => 1,
             GgmlType::I16 => 2,
             GgmlType::I32 => 4,
+            GgmlType::I64 | GgmlType::F64 => 8,
+            _ => 1,
         }
     }
     
@@ -53,11 +73,125 @@ impl GgmlType {
             GgmlType::Q4_0 | GgmlType::Q4_1 => 32,
             GgmlType::Q5_0 | GgmlType::Q5_1 => 32,
             GgmlType::Q8_0 | GgmlType::Q8_1 => 32,
+            GgmlType::IQ4NL => 32,
             GgmlType::Q2K | GgmlType::Q3K | GgmlType::Q4K | 
             GgmlType::Q5K | GgmlType::Q6K | GgmlType::Q8K => 256,
+            GgmlType::IQ2XXS | GgmlType::IQ2XS | GgmlType::IQ2S |
+            GgmlType::IQ3XXS | GgmlType::IQ3S | GgmlType::IQ1S |
+            GgmlType::IQ1M | GgmlType::IQ4XS => 256,
             _ => 1,
         }
     }
+    
+    // Size in bytes of one block of `block_size()` elements.
+    pub fn type_size(&self) -> usize {
+        match self {
+            GgmlType::Q4_0 => 18,
+            GgmlType::Q4_1 => 20,
+            GgmlType::Q5_0 => 22,
+            GgmlType::Q5_1 => 24,
+            GgmlType::Q8_0 => 34,
+            GgmlType::Q8_1 => 36,
+            GgmlType::Q2K => 84,
+            GgmlType::Q3K => 110,
+            GgmlType::Q4K => 144,
+            GgmlType::Q5K => 176,
+            GgmlType::Q6K => 210,
+            GgmlType::Q8K => 292,
+            GgmlType::IQ2XXS => 66,
+            GgmlType::IQ2XS => 74,
+            GgmlType::IQ2S => 82,
+            GgmlType::IQ3XXS => 98,
+            GgmlType::IQ3S => 110,
+            GgmlType::IQ1S => 50,
+            GgmlType::IQ1M => 56,
+            GgmlType::IQ4NL => 18,
+            GgmlType::IQ4XS => 136,
+            _ => self.bytes_per_element(),
+        }
+    }
+    
+    pub fn row_size(&self, elements: usize) -> usize {
+        elements / self.block_size() * self.type_size()
+    }
+    
+    pub fn is_quantized(&self) -> bool {
+        self.block_size() > 1
+    }
+    
+    pub fn id(&self) -> u32 {
+        match self {
+            GgmlType::F32 => 0,
+            GgmlType::F16 => 1,
+            GgmlType::Q4_0 => 2,
+            GgmlType::Q4_1 => 3,
+            GgmlType::Q5_0 => 6,
+            GgmlType::Q5_1 => 7,
+            GgmlType::Q8_0 => 8,
+            GgmlType::Q8_1 => 9,
+            GgmlType::Q2K => 10,
+            GgmlType::Q3K => 11,
+            GgmlType::Q4K => 12,
+            GgmlType::Q5K => 13,
+            GgmlType::Q6K => 14,
+            GgmlType::Q8K => 15,
+            GgmlType::IQ2XXS => 16,
+            GgmlType::IQ2XS => 17,
+            GgmlType::IQ3XXS => 18,
+            GgmlType::IQ1S => 19,
+            GgmlType::IQ4NL => 20,
+            GgmlType::IQ3S => 21,
+            GgmlType::IQ2S => 22,
+            GgmlType::IQ4XS => 23,
+            GgmlType::I8 => 24,
+            GgmlType::I16 => 25,
+            GgmlType::I32 => 26,
+            GgmlType::I64 => 27,
+            GgmlType::F64 => 28,
+            GgmlType::IQ1M => 29,
+            GgmlType::BF16 => 30,
+        }
+    }
+    
+    pub fn name(&self) -> &'static str {
+        match self {
+            GgmlType::F32 => "F32",
+            GgmlType::F16 => "F16",
+            GgmlType::BF16 => "BF16",
+            GgmlType::Q4_0 => "Q4_0",
+            GgmlType::Q4_1 => "Q4_1",
+            GgmlType::Q5_0 => "Q5_0",
+            GgmlType::Q5_1 => "Q5_1",
+            GgmlType::Q8_0 => "Q8_0",
+            GgmlType::Q8_1 => "Q8_1",
+            GgmlType::Q2K => "Q2_K",
+            GgmlType::Q3K => "Q3_K",
+            GgmlType::Q4K => "Q4_K",
+            GgmlType::Q5K => "Q5_K",
+            GgmlType::Q6K => "Q6_K",
+            GgmlType::Q8K => "Q8_K",
+            GgmlType::IQ2XXS => "IQ2_XXS",
+            GgmlType::IQ2XS => "IQ2_XS",
+            GgmlType::IQ2S => "IQ2_S",
+            GgmlType::IQ3XXS => "IQ3_XXS",
+            GgmlType::IQ3S => "IQ3_S",
+            GgmlType::IQ1S => "IQ1_S",
+            GgmlType::IQ1M => "IQ1_M",
+            GgmlType::IQ4NL => "IQ4_NL",
+            GgmlType::IQ4XS => "IQ4_XS",
+            GgmlType::I8 => "I8",
+            GgmlType::I16 => "I16",
+            GgmlType::I32 => "I32",
+            GgmlType::I64 => "I64",
+            GgmlType::F64 => "F64",
+        }
+    }
+    
+    pub fn from_name(name: &str) -> Option<Self> {
+        (0..=30)
+            .filter_map(|id| GgufParser::dtype_from_id(id).ok())
+            .find(|t| t.name().eq_ignore_ascii_case(name))
+    }
 }
 
 pub struct GgufFile {
@@ -65,16 +199,36 @@ pub struct GgufFile {
     pub tensor_count: u64,
     pub metadata: GgufMetadata,
     pub tensors: Vec<TensorInfo>,
+    pub alignment: u64,
+    pub data_offset: u64,
 }
 
+impl GgufFile {
+    pub fn tensor(&self, name: &str) -> Option<&TensorInfo> {
+        self.tensors.iter().find(|t| t.name == name)
+    }
+    
+    pub fn read_tensor_data<R: Read + Seek>(&self, reader: &mut R, info: &TensorInfo) -> Result<Vec<u8>> {
+        reader.seek(SeekFrom::Start(self.data_offset + info.offset))?;
+        let mut data = vec![0u8; info.size_bytes()];
+        reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+#[derive(Clone)]
 pub struct GgufMetadata {
     pub kv: HashMap<String, MetadataValue>,
+    pub types: HashMap<String, ValueType>,
+    order: Vec<String>,
 }
 
 impl GgufMetadata {
     pub fn new() -> Self {
         Self {
             kv: HashMap::new(),
+            types: HashMap::new(),
+            order: Vec::new(),
         }
     }
     
@@ -82,6 +236,35 @@ impl GgufMetadata {
         self.kv.get(key)
     }
     
+    pub fn set(&mut self, key: &str, value: MetadataValue, vtype: ValueType) {
+        if self.kv.insert(key.to_string(), value).is_none() {
+            self.order.push(key.to_string());
+        }
+        self.types.insert(key.to_string(), vtype);
+    }
+    
+    pub fn remove(&mut self, key: &str) -> Option<MetadataValue> {
+        self.types.remove(key);
+        self.order.retain(|k| k != key);
+        self.kv.remove(key)
+    }
+    
+    // Keys in file order, followed by any inserted directly into `kv`.
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.order.iter().filter(|k| self.kv.contains_key(*k)).map(|k| k.as_str()).collect();
+        let mut extra: Vec<&str> = self.kv.keys().filter(|k| !self.order.contains(k)).map(|k| k.as_str()).collect();
+        extra.sort();
+        keys.extend(extra);
+        keys
+    }
+    
+    pub fn value_type(&self, key: &str) -> Option<ValueType> {
+        self.types
+            .get(key)
+            .cloned()
+            .or_else(|| self.kv.get(key).map(ValueType::infer))
+    }
+    
     pub fn string(&self, key: &str) -> String {
         self.kv.get(key)
             .and_then(|v| match v {
@@ -132,7 +315,10 @@ impl GgufMetadata {
             .num_layers(self.uint(&format!("{}.block_count", arch)) as usize)
             .num_heads(self.uint(&format!("{}.attention.head_count", arch)) as usize)
             .num_kv_heads(self.uint(&format!("{}.attention.head_count_kv", arch)) as usize)
-            .vocab_size(self.uint("tokenizer.ggml.model") as usize)
+            .vocab_size(match self.get("tokenizer.ggml.tokens") {
+                Some(MetadataValue::Array(tokens)) => tokens.len(),
+                _ => 0,
+            })
             .context_length(self.uint(&format!("{}.context_length", arch)) as usize)
             .rope_theta(self.float(&format!("{}.rope.freq_base", arch)) as f32)
             .norm_eps(self.float(&format!("{}.attention.layer_norm_rms_epsilon", arch)) as f32);
@@ -167,7 +353,7 @@ impl Default for GgufMetadata {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MetadataValue {
     Uint(u64),
     Int(i64),
@@ -177,6 +363,80 @@ pub enum MetadataValue {
     Array(Vec<MetadataValue>),
 }
 
+// The on-disk type of a metadata value; MetadataValue widens integers and floats,
+// so this is what lets a file be written back with the types llama.cpp expects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    Bool,
+    String,
+    Array(Box<ValueType>),
+    U64,
+    I64,
+    F64,
+}
+
+impl ValueType {
+    pub fn id(&self) -> u32 {
+        match self {
+            ValueType::U8 => 0,
+            ValueType::I8 => 1,
+            ValueType::U16 => 2,
+            ValueType::I16 => 3,
+            ValueType::U32 => 4,
+            ValueType::I32 => 5,
+            ValueType::F32 => 6,
+            ValueType::Bool => 7,
+            ValueType::String => 8,
+            ValueType::Array(_) => 9,
+            ValueType::U64 => 10,
+            ValueType::I64 => 11,
+            ValueType::F64 => 12,
+        }
+    }
+    
+    // Arrays get a placeholder element type, since the id alone does not carry one.
+    pub fn from_id(id: u32) -> Result<Self> {
+        Ok(match id {
+            0 => ValueType::U8,
+            1 => ValueType::I8,
+            2 => ValueType::U16,
+            3 => ValueType::I16,
+            4 => ValueType::U32,
+            5 => ValueType::I32,
+            6 => ValueType::F32,
+            7 => ValueType::Bool,
+            8 => ValueType::String,
+            9 => ValueType::Array(Box::new(ValueType::U8)),
+            10 => ValueType::U64,
+            11 => ValueType::I64,
+            12 => ValueType::F64,
+            _ => anyhow::bail!("Unknown metadata value type: {}", id),
+        })
+    }
+    
+    pub fn infer(value: &MetadataValue) -> Self {
+        match value {
+            MetadataValue::Uint(n) if *n <= u32::MAX as u64 => ValueType::U32,
+            MetadataValue::Uint(_) => ValueType::U64,
+            MetadataValue::Int(n) if i32::try_from(*n).is_ok() => ValueType::I32,
+            MetadataValue::Int(_) => ValueType::I64,
+            MetadataValue::Float(_) => ValueType::F32,
+            MetadataValue::String(_) => ValueType::String,
+            MetadataValue::Bool(_) => ValueType::Bool,
+            MetadataValue::Array(arr) => ValueType::Array(Box::new(
+                arr.first().map(ValueType::infer).unwrap_or(ValueType::U32),
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TensorInfo {
     pub name: String,
@@ -185,54 +445,251 @@ pub struct TensorInfo {
     pub offset: u64,
 }
 
+impl TensorInfo {
+    pub fn num_elements(&self) -> usize {
+        self.dims.iter().product()
+    }
+    
+    pub fn size_bytes(&self) -> usize {
+        self.dtype.row_size(self.num_elements())
+    }
+}
+
 pub struct GgufParser;
 
+// Smallest encodings of a metadata pair and a tensor info, used to reject
+// counts that could not possibly fit in the remaining bytes.
+const MIN_KV_SIZE: u64 = 8 + 4 + 1;
+const MIN_TENSOR_INFO_SIZE: u64 = 8 + 4 + 4 + 8;
+
+// Reads the header while tracking how many bytes of the file remain, so no
+// length or count from the file is trusted before it is checked.
+struct HeaderReader<'a, R> {
+    inner: &'a mut R,
+    pos: u64,
+    len: u64,
+    depth: usize,
+}
+
+impl<R: Read> HeaderReader<'_, R> {
+    fn remaining(&self) -> u64 {
+        self.len.saturating_sub(self.pos)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> ParseResult<()> {
+        if buf.len() as u64 > self.remaining() {
+            return Err(GgufError::Truncated(self.pos));
+        }
+        self.inner.read_exact(buf).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => GgufError::Truncated(self.pos),
+            _ => GgufError::Io(e),
+        })?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    fn bytes<const N: usize>(&mut self) -> ParseResult<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> ParseResult<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> ParseResult<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn check_count(&self, what: &'static str, count: u64, min_size: u64) -> ParseResult<()> {
+        if count.checked_mul(min_size).is_none_or(|n| n > self.remaining()) {
+            return Err(GgufError::CountTooLarge { what, count });
+        }
+        Ok(())
+    }
+
+    // Room to reserve for `count` items of at least `min_size` bytes each: no
+    // more than the remaining bytes could hold, whatever the file claims.
+    fn capacity(&self, count: u64, min_size: u64) -> usize {
+        count.min(self.remaining() / min_size.max(1)) as usize
+    }
+
+    fn string(&mut self) -> ParseResult<String> {
+        let len = self.u64()?;
+        if len > MAX_STRING_LEN {
+            return Err(GgufError::StringTooLong(len));
+        }
+        if len > self.remaining() {
+            return Err(GgufError::Truncated(self.pos));
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.read_exact(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn value(&mut self, vtype: u32) -> ParseResult<(MetadataValue, ValueType)> {
+        let value = match vtype {
+            0 => (MetadataValue::Uint(u8::from_le_bytes(self.bytes()?) as u64), ValueType::U8),
+            1 => (MetadataValue::Int(i8::from_le_bytes(self.bytes()?) as i64), ValueType::I8),
+            2 => (MetadataValue::Uint(u16::from_le_bytes(self.bytes()?) as u64), ValueType::U16),
+            3 => (MetadataValue::Int(i16::from_le_bytes(self.bytes()?) as i64), ValueType::I16),
+            4 => (MetadataValue::Uint(u32::from_le_bytes(self.bytes()?) as u64), ValueType::U32),
+            5 => (MetadataValue::Int(i32::from_le_bytes(self.bytes()?) as i64), ValueType::I32),
+            6 => (MetadataValue::Float(f32::from_le_bytes(self.bytes()?) as f64), ValueType::F32),
+            7 => (MetadataValue::Bool(self.bytes::<1>()?[0] != 0), ValueType::Bool),
+            8 => (MetadataValue::String(self.string()?), ValueType::String),
+            9 => {
+                if self.depth >= MAX_ARRAY_DEPTH {
+                    return Err(GgufError::NestingTooDeep);
+                }
+                let element_type = self.u32()?;
+                let min_size = min_value_size(element_type)?;
+                let len = self.u64()?;
+                if len > MAX_ARRAY_LEN {
+                    return Err(GgufError::ArrayTooLong(len));
+                }
+                self.check_count("array element", len, min_size)?;
+
+                self.depth += 1;
+                let mut arr = Vec::with_capacity(self.capacity(len, min_size));
+                // Nested arrays carry their own element type; keep the first one seen.
+                let mut inner = None;
+                for _ in 0..len {
+                    let (v, t) = self.value(element_type)?;
+                    inner.get_or_insert(t);
+                    arr.push(v);
+                }
+                self.depth -= 1;
+                let inner = match inner {
+                    Some(t) => t,
+                    None => ValueType::from_id(element_type).map_err(|_| GgufError::InvalidValueType(element_type))?,
+                };
+                (MetadataValue::Array(arr), ValueType::Array(Box::new(inner)))
+            }
+            10 => (MetadataValue::Uint(self.u64()?), ValueType::U64),
+            11 => (MetadataValue::Int(i64::from_le_bytes(self.bytes()?)), ValueType::I64),
+            12 => (MetadataValue::Float(f64::from_le_bytes(self.bytes()?)), ValueType::F64),
+            _ => return Err(GgufError::InvalidValueType(vtype)),
+        };
+        Ok(value)
+    }
+
+    fn tensor_info(&mut self) -> ParseResult<TensorInfo> {
+        let name = self.string()?;
+        let n_dims = self.u32()?;
+        if n_dims > MAX_DIMS {
+            return Err(GgufError::TooManyDims { name, dims: n_dims });
+        }
+        let mut dims = Vec::with_capacity(n_dims as usize);
+        for _ in 0..n_dims {
+            dims.push(self.u64()?);
+        }
+        let dtype_id = self.u32()?;
+        let dtype = GgufParser::dtype_from_id(dtype_id)?;
+        let offset = self.u64()?;
+
+        // The element count, byte size and row blocking must all be representable.
+        let elements = dims.iter().try_fold(1u64, |acc, d| acc.checked_mul(*d));
+        let row_ok = dims.first().is_none_or(|d| d % dtype.block_size() as u64 == 0);
+        let size = elements.and_then(|n| (n / dtype.block_size() as u64).checked_mul(dtype.type_size() as u64));
+        let (Some(_), Some(size), true) = (elements, size, row_ok) else {
+            return Err(GgufError::InvalidShape(name));
+        };
+        let (Ok(_), Ok(dims)) = (
+            usize::try_from(size),
+            dims.into_iter().map(usize::try_from).collect::<std::result::Result<Vec<_>, _>>(),
+        ) else {
+            return Err(GgufError::InvalidShape(name));
+        };
+        Ok(TensorInfo { name, dims, dtype, offset })
+    }
+}
+
+fn min_value_size(vtype: u32) -> ParseResult<u64> {
+    Ok(match vtype {
+        0 | 1 | 7 => 1,
+        2 | 3 => 2,
+        4..=6 => 4,
+        8 | 10..=12 => 8,
+        9 => 12,
+        _ => return Err(GgufError::InvalidValueType(vtype)),
+    })
+}
+
 impl GgufParser {
     const GGUF_MAGIC: u32 = 0x46554747;
+    const DEFAULT_ALIGNMENT: u64 = 32;
     
-    pub fn parse<P: AsRef<Path>>(path: P) -> Result<GgufFile> {
-        let mut file = std::fs::File::open(path)?;
+    pub fn parse<P: AsRef<Path>>(path: P) -> ParseResult<GgufFile> {
+        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
         Self::parse_reader(&mut file)
     }
     
-    pub fn parse_reader<R: Read + Seek>(reader: &mut R) -> Result<GgufFile> {
-        let magic = Self::read_u32(reader)?;
+    // Parses and validates a GGUF header: every length and count is bounded by
+    // the file size, and every tensor must lie aligned and inside the data section.
+    pub fn parse_reader<R: Read + Seek>(reader: &mut R) -> ParseResult<GgufFile> {
+        let start = reader.stream_position()?;
+        let len = reader.seek(SeekFrom::End(0))?.saturating_sub(start);
+        reader.seek(SeekFrom::Start(start))?;
+        let mut r = HeaderReader { inner: reader, pos: 0, len, depth: 0 };
+
+        let magic = r.u32()?;
         if magic != Self::GGUF_MAGIC {
-            anyhow::bail!("Invalid GGUF magic: expected 0x{:08X}, got 0x{:08X}", Self::GGUF_MAGIC, magic);
+            return Err(GgufError::InvalidMagic(magic));
         }
-        
-        let version = Self::read_u32(reader)?;
-        let tensor_count = Self::read_u64(reader)?;
-        let metadata_kv_count = Self::read_u64(reader)?;
+        let version = r.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(GgufError::UnsupportedVersion(version));
+        }
+        let tensor_count = r.u64()?;
+        let metadata_kv_count = r.u64()?;
+        r.check_count("metadata", metadata_kv_count, MIN_KV_SIZE)?;
+        r.check_count("tensor", tensor_count, MIN_TENSOR_INFO_SIZE)?;
         
         let mut metadata = GgufMetadata::new();
-        
         for _ in 0..metadata_kv_count {
-            let key = Self::read_string(reader)?;
-            let value = Self::read_metadata_value(reader)?;
-            metadata.kv.insert(key, value);
+            let key = r.string()?;
+            if metadata.kv.contains_key(&key) {
+                return Err(GgufError::DuplicateKey(key));
+            }
+            let vtype = r.u32()?;
+            let (value, vtype) = r.value(vtype)?;
+            metadata.set(&key, value, vtype);
         }
+
+        let alignment = match metadata.get("general.alignment") {
+            None => Self::DEFAULT_ALIGNMENT,
+            Some(MetadataValue::Uint(n)) if n.is_power_of_two() && *n <= u32::MAX as u64 => *n,
+            Some(_) => return Err(GgufError::InvalidAlignment(metadata.uint("general.alignment"))),
+        };
         
-        let mut tensors = Vec::with_capacity(tensor_count as usize);
-        
+        let mut tensors = Vec::with_capacity(r.capacity(tensor_count, MIN_TENSOR_INFO_SIZE));
+        let mut names = std::collections::HashSet::new();
         for _ in 0..tensor_count {
-            let name = Self::read_string(reader)?;
-            let n_dims = Self::read_u32(reader)? as usize;
-            
-            let mut dims = Vec::with_capacity(n_dims);
-            for _ in 0..n_dims {
-                dims.push(Self::read_u64(reader)? as usize);
+            let info = r.tensor_info()?;
+            if !names.insert(info.name.clone()) {
+                return Err(GgufError::DuplicateTensor(info.name));
+            }
+            if info.offset % alignment != 0 {
+                return Err(GgufError::MisalignedTensor(info.name));
+            }
+            tensors.push(info);
+        }
+
+        let data_start = r.pos.next_multiple_of(alignment);
+        let data_len = len.saturating_sub(data_start);
+        for info in &tensors {
+            if info.offset.checked_add(info.size_bytes() as u64).is_none_or(|end| end > data_len) {
+                return Err(GgufError::TensorOutOfBounds(info.name.clone()));
+            }
+        }
+        let mut by_offset: Vec<&TensorInfo> = tensors.iter().filter(|t| t.size_bytes() > 0).collect();
+        by_offset.sort_by_key(|t| t.offset);
+        for pair in by_offset.windows(2) {
+            if pair[0].offset + pair[0].size_bytes() as u64 > pair[1].offset {
+                return Err(GgufError::OverlappingTensors(pair[0].name.clone(), pair[1].name.clone()));
             }
-            
-            let dtype_id = Self::read_u32(reader)?;
-            let dtype = Self::dtype_from_id(dtype_id)?;
-            
-            tensors.push(TensorInfo {
-                name,
-                dims,
-                dtype,
-                offset: 0,
-            });
         }
         
         Ok(GgufFile {
@@ -240,63 +697,12 @@ impl GgufParser {
             tensor_count,
             metadata,
             tensors,
+            alignment,
+            data_offset: start + data_start,
         })
     }
     
-    fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
-        let mut buf = [0u8; 4];
-        reader.read_exact(&mut buf)?;
-        Ok(u32::from_le_bytes(buf))
-    }
-    
-    fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
-        let mut buf = [0u8; 8];
-        reader.read_exact(&mut buf)?;
-        Ok(u64::from_le_bytes(buf))
-    }
-    
-    fn read_i64<R: Read>(reader: &mut R) -> Result<i64> {
-        let mut buf = [0u8; 8];
-        reader.read_exact(&mut buf)?;
-        Ok(i64::from_le_bytes(buf))
-    }
-    
-    fn read_f64<R: Read>(reader: &mut R) -> Result<f64> {
-        let mut buf = [0u8; 8];
-        reader.read_exact(&mut buf)?;
-        Ok(f64::from_le_bytes(buf))
-    }
-    
-    fn read_string<R: Read>(reader: &mut R) -> Result<String> {
-        let len = Self::read_u64(reader)? as usize;
-        let mut buf = vec![0u8; len];
-        reader.read_exact(&mut buf)?;
-        Ok(String::from_utf8_lossy(&buf).into_owned())
-    }
-    
-    fn read_metadata_value<R: Read>(reader: &mut R) -> Result<MetadataValue> {
-        let vtype = Self::read_u32(reader)?;
-        
-        match vtype {
-            0 => Ok(MetadataValue::Uint(Self::read_u64(reader)?)),
-            1 => Ok(MetadataValue::Int(Self::read_i64(reader)?)),
-            2 => Ok(MetadataValue::Float(Self::read_f64(reader)?)),
-            3 => Ok(MetadataValue::String(Self::read_string(reader)?)),
-            4 => Ok(MetadataValue::Bool(false)),
-            5 => Ok(MetadataValue::Bool(true)),
-            6 => {
-                let len = Self::read_u64(reader)? as usize;
-                let mut arr = Vec::with_capacity(len);
-                for _ in 0..len {
-                    arr.push(Self::read_metadata_value(reader)?);
-                }
-                Ok(MetadataValue::Array(arr))
-            }
-            _ => anyhow::bail!("Unknown metadata value type: {}", vtype),
-        }
-    }
-    
-    fn dtype_from_id(id: u32) -> Result<GgmlType> {
+    pub fn dtype_from_id(id: u32) -> ParseResult<GgmlType> {
         match id {
             0 => Ok(GgmlType::F32),
             1 => Ok(GgmlType::F16),
@@ -312,10 +718,37 @@ impl GgufParser {
             13 => Ok(GgmlType::Q5K),
             14 => Ok(GgmlType::Q6K),
             15 => Ok(GgmlType::Q8K),
-            16 => Ok(GgmlType::I8),
-            17 => Ok(GgmlType::I16),
-            18 => Ok(GgmlType::I32),
-            _ => anyhow::bail!("Unknown GGML type: {}", id),
+            16 => Ok(GgmlType::IQ2XXS),
+            17 => Ok(GgmlType::IQ2XS),
+            18 => Ok(GgmlType::IQ3XXS),
+            19 => Ok(GgmlType::IQ1S),
+            20 => Ok(GgmlType::IQ4NL),
+            21 => Ok(GgmlType::IQ3S),
+            22 => Ok(GgmlType::IQ2S),
+            23 => Ok(GgmlType::IQ4XS),
+            24 => Ok(GgmlType::I8),
+            25 => Ok(GgmlType::I16),
+            26 => Ok(GgmlType::I32),
+            27 => Ok(GgmlType::I64),
+            28 => Ok(GgmlType::F64),
+            29 => Ok(GgmlType::IQ1M),
+            30 => Ok(GgmlType::BF16),
+            _ => Err(GgufError::InvalidTensorType(id)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capacity_is_bounded_by_remaining_bytes() {
+        let mut data = std::io::Cursor::new(vec![0u8; 100]);
+        let mut r = HeaderReader { inner: &mut data, pos: 0, len: 100, depth: 0 };
+        r.u32().unwrap();
+        assert_eq!(r.capacity(u64::MAX, MIN_TENSOR_INFO_SIZE), 96 / 24);
+        assert_eq!(r.capacity(u64::MAX, min_value_size(0).unwrap()), 96);
+        assert_eq!(r.capacity(3, 8), 3);
+    }
+}
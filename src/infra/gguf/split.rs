@@ -0,0 +1,171 @@
+use super::{GgufFile, GgufParser, MappedGguf, MetadataValue, TensorInfo};
+use crate::core::Tensor;
+use crate::infra::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const KEY_SPLIT_NO: &str = "split.no";
+pub const KEY_SPLIT_COUNT: &str = "split.count";
+pub const KEY_SPLIT_TENSORS_COUNT: &str = "split.tensors.count";
+
+// Returns (split.no, split.count) for a gguf-split shard, None for a single file.
+pub fn split_info(file: &GgufFile) -> Option<(u64, u64)> {
+    match file.metadata.get(KEY_SPLIT_COUNT) {
+        Some(MetadataValue::Uint(count)) if *count > 1 => Some((file.metadata.uint(KEY_SPLIT_NO), *count)),
+        _ => None,
+    }
+}
+
+// The shard files of the model starting at `path`, in split order. Shards are
+// located by the gguf-split naming scheme, `<prefix>-00001-of-00003.gguf`.
+pub fn shard_paths<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let file = GgufParser::parse(path)?;
+    let Some((no, count)) = split_info(&file) else {
+        return Ok(vec![path.to_path_buf()]);
+    };
+
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let prefix = name
+        .strip_suffix(&format!("-{:05}-of-{:05}.gguf", no + 1, count))
+        .ok_or_else(|| anyhow::anyhow!("cannot locate the other {} shards of {}", count - 1, path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok((1..=count).map(|i| dir.join(format!("{}-{:05}-of-{:05}.gguf", prefix, i, count))).collect())
+}
+
+// A model stored as one or more GGUF shards. Metadata comes from the first
+// shard; tensors are looked up in whichever shard holds them.
+pub struct SplitGguf {
+    shards: Vec<Arc<MappedGguf>>,
+    index: HashMap<String, usize>,
+}
+
+impl SplitGguf {
+    pub fn open<P: AsRef<Path>>(paths: &[P], use_mmap: bool, use_mlock: bool) -> Result<Self> {
+        if paths.is_empty() {
+            anyhow::bail!("no model shards given");
+        }
+        let shards = paths
+            .iter()
+            .map(|p| MappedGguf::open(p, use_mmap, use_mlock))
+            .collect::<Result<Vec<_>>>()?;
+
+        let count = shards.len() as u64;
+        for (i, shard) in shards.iter().enumerate() {
+            match split_info(&shard.file) {
+                Some((no, n)) if no == i as u64 && n == count => {}
+                None if count == 1 => {}
+                Some((no, n)) => anyhow::bail!(
+                    "{} is shard {} of {}, expected shard {} of {}",
+                    shard.path().display(),
+                    no + 1,
+                    n,
+                    i + 1,
+                    count
+                ),
+                None => anyhow::bail!("{} is not a split GGUF shard", shard.path().display()),
+            }
+        }
+
+        let mut index = HashMap::new();
+        for (i, shard) in shards.iter().enumerate() {
+            for t in &shard.file.tensors {
+                if index.insert(t.name.clone(), i).is_some() {
+                    anyhow::bail!("tensor {} appears in more than one shard", t.name);
+                }
+            }
+        }
+        let metadata = &shards[0].file.metadata;
+        if metadata.get(KEY_SPLIT_TENSORS_COUNT).is_some() {
+            let expected = metadata.uint(KEY_SPLIT_TENSORS_COUNT);
+            if expected != index.len() as u64 {
+                anyhow::bail!("split model declares {} tensors, shards hold {}", expected, index.len());
+            }
+        }
+
+        Ok(Self { shards, index })
+    }
+
+    pub fn shards(&self) -> &[Arc<MappedGguf>] {
+        &self.shards
+    }
+
+    // The header carrying the model metadata.
+    pub fn file(&self) -> &GgufFile {
+        &self.shards[0].file
+    }
+
+    pub fn tensors(&self) -> impl Iterator<Item = &TensorInfo> {
+        self.shards.iter().flat_map(|s| s.file.tensors.iter())
+    }
+
+    fn shard(&self, name: &str) -> Result<&MappedGguf> {
+        self.index
+            .get(name)
+            .map(|i| &*self.shards[*i])
+            .ok_or_else(|| anyhow::anyhow!("Tensor {} not found in {}", name, self.shards[0].path().display()))
+    }
+
+    pub fn tensor_info(&self, name: &str) -> Result<&TensorInfo> {
+        self.shard(name)?.tensor_info(name)
+    }
+
+    pub fn tensor_data(&self, name: &str) -> Result<&[u8]> {
+        self.shard(name)?.tensor_data(name)
+    }
+
+    pub fn tensor(&self, name: &str) -> Result<Tensor> {
+        self.shard(name)?.tensor(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::gguf::{GgmlType, GgufMetadata, GgufWriter, ValueType};
+
+    fn write_shards(dir: &Path, tensors: &[(&str, &[f32])]) -> Vec<PathBuf> {
+        let count = tensors.len();
+        tensors
+            .iter()
+            .enumerate()
+            .map(|(i, (name, values))| {
+                let mut metadata = GgufMetadata::new();
+                if i == 0 {
+                    metadata.set("general.architecture", MetadataValue::String("llama".into()), ValueType::String);
+                    metadata.set(KEY_SPLIT_TENSORS_COUNT, MetadataValue::Int(count as i64), ValueType::I32);
+                }
+                metadata.set(KEY_SPLIT_NO, MetadataValue::Uint(i as u64), ValueType::U16);
+                metadata.set(KEY_SPLIT_COUNT, MetadataValue::Uint(count as u64), ValueType::U16);
+                let mut writer = GgufWriter::new(metadata);
+                let data = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                writer.add_tensor(name, vec![values.len()], GgmlType::F32, data).unwrap();
+                let path = dir.join(format!("model-{:05}-of-{:05}.gguf", i + 1, count));
+                writer.write_to_file(&path).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_open_split_model() {
+        let dir = std::env::temp_dir().join(format!("ollama-split-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let written = write_shards(&dir, &[("a", &[1.0, 2.0]), ("b", &[3.0]), ("c", &[4.0, 5.0, 6.0])]);
+
+        let paths = shard_paths(&written[0]).unwrap();
+        assert_eq!(paths, written);
+        let model = SplitGguf::open(&paths, true, false).unwrap();
+        assert_eq!(model.file().metadata.string("general.architecture"), "llama");
+        assert_eq!(model.tensors().count(), 3);
+        assert_eq!(model.tensor("c").unwrap().data(), &[4.0, 5.0, 6.0]);
+        assert!(model.tensor("d").is_err());
+
+        let reordered = [paths[1].clone(), paths[0].clone(), paths[2].clone()];
+        assert!(SplitGguf::open(&reordered, true, false).is_err());
+        assert!(SplitGguf::open(&paths[..2], true, false).is_err());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
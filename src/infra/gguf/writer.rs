@@ -0,0 +1,410 @@
+use super::{GgmlType, GgufMetadata, MetadataValue, TensorInfo, ValueType};
+use crate::infra::Result;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const GGUF_VERSION: u32 = 3;
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+// Where a tensor's bytes come from: held in memory, or streamed from a file
+// region at write time so large models never have to be resident.
+enum TensorSource {
+    Memory(Vec<u8>),
+    File { path: PathBuf, offset: u64 },
+}
+
+pub struct GgufWriter {
+    metadata: GgufMetadata,
+    tensors: Vec<(TensorInfo, TensorSource)>,
+}
+
+impl GgufWriter {
+    pub fn new(metadata: GgufMetadata) -> Self {
+        Self {
+            metadata,
+            tensors: Vec::new(),
+        }
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut GgufMetadata {
+        &mut self.metadata
+    }
+
+    pub fn add_tensor(&mut self, name: &str, dims: Vec<usize>, dtype: GgmlType, data: Vec<u8>) -> Result<()> {
+        let info = Self::tensor_info(name, dims, dtype)?;
+        if data.len() != info.size_bytes() {
+            anyhow::bail!("Tensor {} has {} bytes, expected {}", name, data.len(), info.size_bytes());
+        }
+        self.tensors.push((info, TensorSource::Memory(data)));
+        Ok(())
+    }
+
+    // Adds a tensor whose bytes are copied from `path` starting at `offset` when the file is written.
+    pub fn add_tensor_from_file<P: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        dims: Vec<usize>,
+        dtype: GgmlType,
+        path: P,
+        offset: u64,
+    ) -> Result<()> {
+        let info = Self::tensor_info(name, dims, dtype)?;
+        let len = std::fs::metadata(path.as_ref())?.len();
+        if offset.checked_add(info.size_bytes() as u64).is_none_or(|end| end > len) {
+            anyhow::bail!("Tensor {} extends past the end of {}", name, path.as_ref().display());
+        }
+        let source = TensorSource::File {
+            path: path.as_ref().to_path_buf(),
+            offset,
+        };
+        self.tensors.push((info, source));
+        Ok(())
+    }
+
+    fn tensor_info(name: &str, dims: Vec<usize>, dtype: GgmlType) -> Result<TensorInfo> {
+        if dims.first().is_some_and(|&n| n % dtype.block_size() != 0) {
+            anyhow::bail!("Tensor {} row of {} is not a multiple of the {} block size", name, dims[0], dtype.name());
+        }
+        Ok(TensorInfo {
+            name: name.to_string(),
+            dims,
+            dtype,
+            offset: 0,
+        })
+    }
+
+    // general.alignment must be a power of two stored as a u32; 32 when unset.
+    fn alignment(&self) -> Result<u64> {
+        if self.metadata.get("general.alignment").is_none() {
+            return Ok(DEFAULT_ALIGNMENT);
+        }
+        let alignment = self.metadata.uint("general.alignment");
+        if !alignment.is_power_of_two() || alignment > u32::MAX as u64 {
+            anyhow::bail!("general.alignment must be a power of two, got {}", alignment);
+        }
+        if self.metadata.value_type("general.alignment") != Some(ValueType::U32) {
+            anyhow::bail!("general.alignment must be stored as a u32");
+        }
+        Ok(alignment)
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<u64> {
+        let alignment = self.alignment()?;
+        let keys = self.metadata.keys();
+
+        let mut header = Vec::new();
+        header.extend_from_slice(GGUF_MAGIC);
+        header.extend_from_slice(&GGUF_VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.tensors.len() as u64).to_le_bytes());
+        header.extend_from_slice(&(keys.len() as u64).to_le_bytes());
+
+        for key in keys {
+            let value = &self.metadata.kv[key];
+            let vtype = self.metadata.value_type(key).unwrap_or_else(|| ValueType::infer(value));
+            write_string(&mut header, key);
+            header.extend_from_slice(&vtype.id().to_le_bytes());
+            write_value(&mut header, key, value, &vtype)?;
+        }
+
+        let mut offset = 0u64;
+        for (info, _) in &self.tensors {
+            write_string(&mut header, &info.name);
+            header.extend_from_slice(&(info.dims.len() as u32).to_le_bytes());
+            for d in &info.dims {
+                header.extend_from_slice(&(*d as u64).to_le_bytes());
+            }
+            header.extend_from_slice(&info.dtype.id().to_le_bytes());
+            header.extend_from_slice(&offset.to_le_bytes());
+            offset = (offset + info.size_bytes() as u64).next_multiple_of(alignment);
+        }
+
+        header.resize((header.len() as u64).next_multiple_of(alignment) as usize, 0);
+        w.write_all(&header)?;
+
+        let mut written = header.len() as u64;
+        for (info, source) in &self.tensors {
+            let size = info.size_bytes() as u64;
+            match source {
+                TensorSource::Memory(data) => w.write_all(data)?,
+                TensorSource::File { path, offset } => {
+                    let mut file = std::fs::File::open(path)?;
+                    file.seek(SeekFrom::Start(*offset))?;
+                    let copied = std::io::copy(&mut file.take(size), w)?;
+                    if copied != size {
+                        anyhow::bail!("{} was truncated while writing tensor {}", path.display(), info.name);
+                    }
+                }
+            }
+            let padded = size.next_multiple_of(alignment);
+            w.write_all(&vec![0u8; (padded - size) as usize])?;
+            written += padded;
+        }
+        Ok(written)
+    }
+
+    pub fn write_to_file<P: AsRef<std::path::Path>>(&self, path: P) -> Result<u64> {
+        let mut w = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let n = self.write(&mut w)?;
+        w.flush()?;
+        Ok(n)
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_value(buf: &mut Vec<u8>, key: &str, value: &MetadataValue, vtype: &ValueType) -> Result<()> {
+    // Integer targets need an exact integer source; anything that would truncate is an error.
+    let (int, float) = match value {
+        MetadataValue::Uint(n) => (Some(*n as i128), *n as f64),
+        MetadataValue::Int(n) => (Some(*n as i128), *n as f64),
+        MetadataValue::Float(f) => ((f.fract() == 0.0).then_some(*f as i128), *f),
+        MetadataValue::Bool(b) => (Some(*b as i128), *b as u8 as f64),
+        _ => (None, 0.0),
+    };
+    let numeric = matches!(value, MetadataValue::Uint(_) | MetadataValue::Int(_) | MetadataValue::Float(_) | MetadataValue::Bool(_));
+    let out_of_range = || anyhow::anyhow!("Metadata {} value {:?} is out of range for {:?}", key, value, vtype);
+    macro_rules! put_int {
+        ($t:ty) => {{
+            let n = int.and_then(|n| <$t>::try_from(n).ok()).ok_or_else(out_of_range)?;
+            buf.extend_from_slice(&n.to_le_bytes())
+        }};
+    }
+
+    match (vtype, value) {
+        (ValueType::String, MetadataValue::String(s)) => write_string(buf, s),
+        (ValueType::Array(element), MetadataValue::Array(arr)) => {
+            buf.extend_from_slice(&element.id().to_le_bytes());
+            buf.extend_from_slice(&(arr.len() as u64).to_le_bytes());
+            for v in arr {
+                write_value(buf, key, v, element)?;
+            }
+        }
+        (ValueType::U8, _) if numeric => put_int!(u8),
+        (ValueType::I8, _) if numeric => put_int!(i8),
+        (ValueType::U16, _) if numeric => put_int!(u16),
+        (ValueType::I16, _) if numeric => put_int!(i16),
+        (ValueType::U32, _) if numeric => put_int!(u32),
+        (ValueType::I32, _) if numeric => put_int!(i32),
+        (ValueType::U64, _) if numeric => put_int!(u64),
+        (ValueType::I64, _) if numeric => put_int!(i64),
+        (ValueType::F32, _) if numeric => {
+            if float.is_finite() && (float as f32).is_infinite() {
+                return Err(out_of_range());
+            }
+            buf.extend_from_slice(&(float as f32).to_le_bytes())
+        }
+        (ValueType::F64, _) if numeric => buf.extend_from_slice(&float.to_le_bytes()),
+        (ValueType::Bool, _) if numeric => match int {
+            Some(n @ (0 | 1)) => buf.push(n as u8),
+            _ => return Err(out_of_range()),
+        },
+        _ => anyhow::bail!("Metadata {} cannot be written as {:?}", key, vtype),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::gguf::GgufParser;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_type(rng: &mut StdRng, depth: usize) -> ValueType {
+        let max = if depth < 2 { 13 } else { 12 };
+        match rng.gen_range(0..max) {
+            0 => ValueType::U8,
+            1 => ValueType::I8,
+            2 => ValueType::U16,
+            3 => ValueType::I16,
+            4 => ValueType::U32,
+            5 => ValueType::I32,
+            6 => ValueType::F32,
+            7 => ValueType::Bool,
+            8 => ValueType::String,
+            9 => ValueType::U64,
+            10 => ValueType::I64,
+            11 => ValueType::F64,
+            _ => ValueType::Array(Box::new(random_type(rng, depth + 1))),
+        }
+    }
+
+    fn random_value(rng: &mut StdRng, vtype: &ValueType) -> MetadataValue {
+        match vtype {
+            ValueType::U8 => MetadataValue::Uint(rng.gen::<u8>() as u64),
+            ValueType::I8 => MetadataValue::Int(rng.gen::<i8>() as i64),
+            ValueType::U16 => MetadataValue::Uint(rng.gen::<u16>() as u64),
+            ValueType::I16 => MetadataValue::Int(rng.gen::<i16>() as i64),
+            ValueType::U32 => MetadataValue::Uint(rng.gen::<u32>() as u64),
+            ValueType::I32 => MetadataValue::Int(rng.gen::<i32>() as i64),
+            ValueType::U64 => MetadataValue::Uint(rng.gen()),
+            ValueType::I64 => MetadataValue::Int(rng.gen()),
+            ValueType::F32 => MetadataValue::Float(rng.gen_range(-1e6f32..1e6) as f64),
+            ValueType::F64 => MetadataValue::Float(rng.gen_range(-1e300..1e300)),
+            ValueType::Bool => MetadataValue::Bool(rng.gen()),
+            ValueType::String => {
+                let len = rng.gen_range(0..12);
+                MetadataValue::String((0..len).map(|_| rng.gen::<char>()).collect())
+            }
+            ValueType::Array(element) => {
+                // An empty array of arrays cannot record its inner element type on disk.
+                let min = if matches!(**element, ValueType::Array(_)) { 1 } else { 0 };
+                let len = rng.gen_range(min..6);
+                MetadataValue::Array((0..len).map(|_| random_value(rng, element)).collect())
+            }
+        }
+    }
+
+    fn random_dtype(rng: &mut StdRng) -> GgmlType {
+        let types = [GgmlType::F32, GgmlType::F16, GgmlType::BF16, GgmlType::Q4_0, GgmlType::Q8_0, GgmlType::Q4K, GgmlType::Q6K, GgmlType::I8];
+        types[rng.gen_range(0..types.len())]
+    }
+
+    #[test]
+    fn test_round_trip_property() {
+        for seed in 0..200 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut metadata = GgufMetadata::new();
+            if rng.gen_bool(0.5) {
+                let alignment = 1u64 << rng.gen_range(0..9);
+                metadata.set("general.alignment", MetadataValue::Uint(alignment), ValueType::U32);
+            }
+            for i in 0..rng.gen_range(0..12) {
+                let vtype = random_type(&mut rng, 0);
+                let value = random_value(&mut rng, &vtype);
+                metadata.set(&format!("test.key{}.{}", i, rng.gen::<u16>()), value, vtype);
+            }
+
+            let mut writer = GgufWriter::new(metadata.clone());
+            let mut expected = Vec::new();
+            for i in 0..rng.gen_range(0..5) {
+                let dtype = random_dtype(&mut rng);
+                let dims: Vec<usize> = std::iter::once(dtype.block_size() * rng.gen_range(1..3))
+                    .chain((0..rng.gen_range(0..3)).map(|_| rng.gen_range(1..4)))
+                    .collect();
+                let data: Vec<u8> = (0..dtype.row_size(dims.iter().product())).map(|_| rng.gen()).collect();
+                let name = format!("blk.{}.weight", i);
+                writer.add_tensor(&name, dims.clone(), dtype, data.clone()).unwrap();
+                expected.push((name, dims, dtype, data));
+            }
+
+            let mut buf = Vec::new();
+            let written = writer.write(&mut buf).unwrap();
+            assert_eq!(written, buf.len() as u64, "seed {}", seed);
+            let file = GgufParser::parse_reader(&mut std::io::Cursor::new(&buf)).unwrap();
+
+            assert_eq!(file.metadata.keys(), metadata.keys(), "seed {}", seed);
+            for key in metadata.keys() {
+                assert_eq!(file.metadata.get(key), metadata.get(key), "seed {} key {}", seed, key);
+                assert_eq!(file.metadata.value_type(key), metadata.value_type(key), "seed {} key {}", seed, key);
+            }
+
+            assert_eq!(file.data_offset % file.alignment, 0, "seed {}", seed);
+            assert_eq!(file.tensors.len(), expected.len());
+            for (info, (name, dims, dtype, data)) in file.tensors.iter().zip(&expected) {
+                assert_eq!((&info.name, &info.dims, info.dtype), (name, dims, *dtype), "seed {}", seed);
+                assert_eq!(info.offset % file.alignment, 0, "seed {}", seed);
+                let bytes = file.read_tensor_data(&mut std::io::Cursor::new(&buf), info).unwrap();
+                assert_eq!(&bytes, data, "seed {} tensor {}", seed, name);
+            }
+
+            // Writing the parsed file back yields the same bytes.
+            let mut rewriter = GgufWriter::new(file.metadata.clone());
+            for (name, dims, dtype, data) in &expected {
+                rewriter.add_tensor(name, dims.clone(), *dtype, data.clone()).unwrap();
+            }
+            let mut again = Vec::new();
+            rewriter.write(&mut again).unwrap();
+            assert!(again == buf, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_stream_tensor_from_file() {
+        let path = std::env::temp_dir().join(format!("ollama-writer-{}.bin", std::process::id()));
+        let blob: Vec<u8> = (0..100u8).collect();
+        std::fs::write(&path, &blob).unwrap();
+
+        let mut streamed = GgufWriter::new(GgufMetadata::new());
+        streamed.add_tensor_from_file("a", vec![4], GgmlType::F32, &path, 10).unwrap();
+        streamed.add_tensor("b", vec![2], GgmlType::F16, vec![1, 2, 3, 4]).unwrap();
+        assert!(streamed.add_tensor_from_file("c", vec![32], GgmlType::F32, &path, 0).is_err());
+
+        let mut in_memory = GgufWriter::new(GgufMetadata::new());
+        in_memory.add_tensor("a", vec![4], GgmlType::F32, blob[10..26].to_vec()).unwrap();
+        in_memory.add_tensor("b", vec![2], GgmlType::F16, vec![1, 2, 3, 4]).unwrap();
+
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        streamed.write(&mut a).unwrap();
+        in_memory.write(&mut b).unwrap();
+        assert_eq!(a, b);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_rejects_bad_alignment() {
+        let mut metadata = GgufMetadata::new();
+        metadata.set("general.alignment", MetadataValue::Uint(24), ValueType::U32);
+        assert!(GgufWriter::new(metadata.clone()).write(&mut Vec::new()).is_err());
+        metadata.set("general.alignment", MetadataValue::Uint(64), ValueType::U64);
+        assert!(GgufWriter::new(metadata).write(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_write_preserves_types() {
+        let mut metadata = GgufMetadata::new();
+        metadata.set("general.architecture", MetadataValue::String("llama".into()), ValueType::String);
+        metadata.set("llama.block_count", MetadataValue::Uint(2), ValueType::U32);
+        metadata.set("llama.rope.freq_base", MetadataValue::Float(10000.0), ValueType::F32);
+        metadata.set(
+            "tokenizer.ggml.token_type",
+            MetadataValue::Array(vec![MetadataValue::Int(1), MetadataValue::Int(3)]),
+            ValueType::Array(Box::new(ValueType::I32)),
+        );
+
+        let mut writer = GgufWriter::new(metadata);
+        let data: Vec<u8> = [1.0f32, 2.0].iter().flat_map(|v| v.to_le_bytes()).collect();
+        writer.add_tensor("a", vec![2], GgmlType::F32, data.clone()).unwrap();
+        writer.add_tensor("b", vec![2], GgmlType::F32, data).unwrap();
+        assert!(writer.add_tensor("c", vec![3], GgmlType::F32, vec![0; 4]).is_err());
+
+        let mut buf = Vec::new();
+        writer.write(&mut buf).unwrap();
+        let file = GgufParser::parse_reader(&mut std::io::Cursor::new(&buf)).unwrap();
+
+        assert_eq!(file.metadata.keys()[0], "general.architecture");
+        assert_eq!(file.metadata.value_type("llama.block_count"), Some(ValueType::U32));
+        assert_eq!(file.metadata.value_type("llama.rope.freq_base"), Some(ValueType::F32));
+        assert_eq!(
+            file.metadata.value_type("tokenizer.ggml.token_type"),
+            Some(ValueType::Array(Box::new(ValueType::I32)))
+        );
+        assert_eq!(file.metadata.uint("llama.block_count"), 2);
+        assert_eq!(file.tensors[1].offset, 32);
+        assert_eq!(file.data_offset % 32, 0);
+        let b = file.tensor("b").unwrap();
+        let bytes = file.read_tensor_data(&mut std::io::Cursor::new(&buf), b).unwrap();
+        assert_eq!(f32::from_le_bytes(bytes[4..8].try_into().unwrap()), 2.0);
+    }
+
+    #[test]
+    fn test_write_rejects_out_of_range_values() {
+        let write = |value: MetadataValue, vtype: ValueType| write_value(&mut Vec::new(), "k", &value, &vtype);
+        assert!(write(MetadataValue::Uint(300), ValueType::U8).is_err());
+        assert!(write(MetadataValue::Int(-1), ValueType::U32).is_err());
+        assert!(write(MetadataValue::Int(i64::MIN), ValueType::I32).is_err());
+        assert!(write(MetadataValue::Float(1.5), ValueType::U16).is_err());
+        assert!(write(MetadataValue::Float(1e40), ValueType::F32).is_err());
+        assert!(write(MetadataValue::Uint(2), ValueType::Bool).is_err());
+        let err = write(MetadataValue::Array(vec![MetadataValue::Int(128)]), ValueType::Array(Box::new(ValueType::I8)));
+        assert!(err.unwrap_err().to_string().contains("out of range"));
+
+        assert!(write(MetadataValue::Uint(255), ValueType::U8).is_ok());
+        assert!(write(MetadataValue::Float(4.0), ValueType::I64).is_ok());
+        assert!(write(MetadataValue::Bool(true), ValueType::U8).is_ok());
+    }
+}
@@ -13,7 +13,7 @@ pub mod model {
     pub use crate::core::model::*;
 }
 
-pub use gguf::{GgufFile, GgufMetadata, GgufMetadataImpl, GgmlType};
+pub use gguf::{GgufFile, GgufMetadata, GgufMetadataImpl, GgmlType, MemoryEstimate};
 pub use rng::SeededRng;
 
 pub use core::{
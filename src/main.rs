@@ -1,5 +1,6 @@
 mod api;
 mod cmd;
+mod convert;
 mod envconfig;
 mod format;
 mod parser;
@@ -47,10 +48,21 @@ enum Commands {
         model: String,
         #[arg(short, long)]
         file: Option<String>,
+        #[arg(short, long)]
+        quantize: Option<String>,
     },
     Show {
         model: String,
     },
+    Inspect {
+        model: String,
+        #[arg(long)]
+        json: bool,
+    },
+    Gguf {
+        #[command(subcommand)]
+        command: GgufCommand,
+    },
     #[command(alias = "ls")]
     List,
     Ps,
@@ -83,6 +95,26 @@ enum Commands {
     Version,
 }
 
+#[derive(Subcommand)]
+enum GgufCommand {
+    #[command(about = "Set metadata: key=value, key:type=value, or key=@file to read the value from a file")]
+    Set {
+        file: String,
+        #[arg(required = true)]
+        values: Vec<String>,
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    #[command(about = "Remove metadata keys")]
+    Unset {
+        file: String,
+        #[arg(required = true)]
+        keys: Vec<String>,
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+}
+
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
@@ -90,8 +122,13 @@ async fn main() {
     let result = match cli.command {
         Commands::Run { model, args } => cmd::run(&model, args).await,
         Commands::Serve => cmd::serve().await,
-        Commands::Create { model, file } => cmd::create(&model, file).await,
+        Commands::Create { model, file, quantize } => cmd::create(&model, file, quantize).await,
         Commands::Show { model } => cmd::show(&model).await,
+        Commands::Inspect { model, json } => cmd::inspect(&model, json).await,
+        Commands::Gguf { command } => match command {
+            GgufCommand::Set { file, values, output } => cmd::gguf_set(&file, &values, output.as_deref()).await,
+            GgufCommand::Unset { file, keys, output } => cmd::gguf_unset(&file, &keys, output.as_deref()).await,
+        },
         Commands::List => cmd::list().await,
         Commands::Ps => cmd::ps().await,
         Commands::Pull { model, insecure } => cmd::pull(&model, insecure).await,
@@ -5,6 +5,7 @@ use indicatif::{ProgressBar, ProgressStyle, MultiProgress};
 use parking_lot::Mutex;
 use reqwest::header::ACCEPT;
 use serde::{Deserialize, Serialize};
+use sha2::Digest;
 use std::fs;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
@@ -73,6 +74,9 @@ pub mod registry {
     }
 }
 
+pub const MODEL_MEDIA_TYPE: &str = "application/vnd.ollama.image.model";
+pub const ADAPTER_MEDIA_TYPE: &str = "application/vnd.ollama.image.adapter";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Manifest {
     #[serde(rename = "schemaVersion")]
@@ -189,6 +193,11 @@ impl ModelManager {
     }
     
     pub fn get_model_weights_path(&self, name: &str) -> Option<PathBuf> {
+        self.get_model_weights_paths(name).and_then(|paths| paths.into_iter().next())
+    }
+
+    // Every weights blob of a model: one per shard for split GGUFs, in split order.
+    pub fn get_model_weights_paths(&self, name: &str) -> Option<Vec<PathBuf>> {
         let (full_name, tag) = registry::Registry::resolve_name(name);
         let model_dir = self.get_model_dir(&full_name);
         let manifest_path = model_dir.join(format!("{}.json", tag));
@@ -196,22 +205,24 @@ impl ModelManager {
         if manifest_path.exists() {
             if let Ok(content) = fs::read_to_string(&manifest_path) {
                 if let Ok(manifest) = serde_json::from_str::<Manifest>(&content) {
-                    for layer in &manifest.layers {
-                        if layer.media_type.as_deref() == Some("application/vnd.ollama.image.model") {
-                            let blob_path = self.get_blob_path(&layer.digest);
-                            if blob_path.exists() {
-                                return Some(blob_path);
-                            }
-                        }
+                    let shards: Vec<PathBuf> = manifest.layers.iter()
+                        .filter(|l| l.media_type.as_deref() == Some(MODEL_MEDIA_TYPE))
+                        .map(|l| self.get_blob_path(&l.digest))
+                        .collect();
+                    if !shards.is_empty() && shards.iter().all(|p| p.exists()) {
+                        return Some(shards);
                     }
                     
                     for layer in &manifest.layers {
+                        if layer.media_type.as_deref() == Some(ADAPTER_MEDIA_TYPE) {
+                            continue;
+                        }
                         let blob_path = self.get_blob_path(&layer.digest);
                         if blob_path.exists() {
                             if let Ok(mut file) = std::fs::File::open(&blob_path) {
                                 let mut magic = [0u8; 4];
                                 if file.read_exact(&mut magic).is_ok() && &magic == b"GGUF" {
-                                    return Some(blob_path);
+                                    return Some(vec![blob_path]);
                                 }
                             }
                         }
@@ -228,7 +239,7 @@ impl ModelManager {
                         if let Ok(mut file) = std::fs::File::open(&path) {
                             let mut magic = [0u8; 4];
                             if file.read_exact(&mut magic).is_ok() && &magic == b"GGUF" {
-                                return Some(path);
+                                return Some(vec![path]);
                             }
                         }
                     }
@@ -239,6 +250,25 @@ impl ModelManager {
         None
     }
     
+    pub fn get_model_adapter_paths(&self, name: &str) -> Vec<PathBuf> {
+        let (full_name, tag) = registry::Registry::resolve_name(name);
+        let manifest_path = self.get_manifest_path(&full_name, &tag);
+        
+        let manifest = match fs::read_to_string(&manifest_path)
+            .ok()
+            .and_then(|c| serde_json::from_str::<Manifest>(&c).ok())
+        {
+            Some(m) => m,
+            None => return Vec::new(),
+        };
+        
+        manifest.layers.iter()
+            .filter(|l| l.media_type.as_deref() == Some(ADAPTER_MEDIA_TYPE))
+            .map(|l| self.get_blob_path(&l.digest))
+            .filter(|p| p.exists())
+            .collect()
+    }
+    
     pub fn stat_blob(&self, digest: &str) -> Option<u64> {
         let path = self.get_blob_path(digest);
         fs::metadata(&path).ok().map(|m| m.len())
@@ -251,6 +281,20 @@ impl ModelManager {
         Ok(())
     }
     
+    pub fn partial_blob_path(&self) -> PathBuf {
+        self.blobs_dir.join(format!("sha256-{}-partial", uuid::Uuid::new_v4()))
+    }
+    
+    // Hashes a file written under the blobs directory and moves it to its digest path.
+    pub fn commit_blob(&self, partial: &Path) -> Result<(String, u64)> {
+        let mut file = std::fs::File::open(partial)?;
+        let mut hasher = sha2::Sha256::new();
+        let size = std::io::copy(&mut file, &mut hasher)?;
+        let digest = format!("sha256:{:x}", hasher.finalize());
+        fs::rename(partial, self.get_blob_path(&digest))?;
+        Ok((digest, size))
+    }
+    
     pub fn list_local_models(&self) -> Result<Vec<LocalModel>> {
         let mut models = Vec::new();
         
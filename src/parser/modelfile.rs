@@ -1,7 +1,8 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use std::io::BufRead;
 use std::path::Path;
 use serde::{Deserialize, Serialize};
+use sha2::Digest;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Command {
@@ -35,6 +36,25 @@ pub fn parse<R: BufRead>(reader: R) -> Result<Modelfile> {
     Ok(modelfile)
 }
 
+// Makes relative ADAPTER and local FROM paths absolute so the server can read them,
+// leaving every other line untouched.
+pub fn resolve_relative_paths(modelfile: &str, base: &Path) -> String {
+    modelfile.lines()
+        .map(|line| {
+            let (name, args) = split_command(line.trim());
+            let relative = !args.is_empty() && Path::new(args).is_relative();
+            // FROM may name a model instead of a path, so only rewrite it when it looks local.
+            let local_from = name.eq_ignore_ascii_case("from")
+                && (args.starts_with('.') || base.join(args).exists());
+            if relative && (name.eq_ignore_ascii_case("adapter") || local_from) {
+                format!("{} {}\n", name, base.join(args).display())
+            } else {
+                format!("{}\n", line)
+            }
+        })
+        .collect()
+}
+
 fn split_command(line: &str) -> (&str, &str) {
     let mut parts = line.splitn(2, |c: char| c.is_whitespace());
     let name = parts.next().unwrap_or("");
@@ -62,8 +82,8 @@ impl Modelfile {
                 "system" => req.system = Some(cmd.args.clone()),
                 "license" => req.license = Some(vec![cmd.args.clone()]), // simplified
                 "adapter" => {
-                    // In a real impl, this would resolve the path
-                    req.adapters.insert(cmd.args.clone(), "".to_string());
+                    let data = std::fs::read(&cmd.args).with_context(|| format!("reading adapter {}", cmd.args))?;
+                    req.adapters.insert(cmd.args.clone(), format!("sha256:{:x}", sha2::Sha256::digest(&data)));
                 },
                 "parameter" => {
                     let (key, val) = split_command(&cmd.args);
@@ -95,3 +115,22 @@ impl Modelfile {
         Ok(req)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adapter_digest() {
+        let path = std::env::temp_dir().join(format!("ollama-modelfile-adapter-{}.gguf", std::process::id()));
+        std::fs::write(&path, b"adapter").unwrap();
+        let modelfile = |adapter: &Path| parse(format!("FROM base\nADAPTER {}\n", adapter.display()).as_bytes()).unwrap();
+
+        let req = modelfile(&path).to_api_request().unwrap();
+        let digest = &req.adapters[path.to_str().unwrap()];
+        assert_eq!(digest, &format!("sha256:{:x}", sha2::Sha256::digest(b"adapter")));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(modelfile(&path).to_api_request().is_err());
+    }
+}
@@ -4,10 +4,16 @@
 pub mod runner {
     use anyhow::Result;
     use std::collections::HashMap;
+    use std::ops::Range;
     use serde::{Deserialize, Serialize};
     use anyhow::bail;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
     use chrono::Utc;
     use candle_transformers::generation::LogitsProcessor;
+    use super::batch::{Batcher, SequenceEvent, SequenceRequest, SharedModel};
+    use ollama::core::cache::{BlockPool, PagedCacheConfig, PoolStats};
+    use ollama::TokenId;
 
     #[derive(Debug, Clone, Default)]
     #[allow(dead_code)]
@@ -31,6 +37,9 @@ pub mod runner {
         pub yarn_beta_fast: f32,
         pub yarn_beta_slow: f32,
         pub raw: bool,
+        pub use_mmap: Option<bool>,
+        pub use_mlock: bool,
+        pub lora_scale: Option<f32>,
     }
 
     impl RunnerOptions {
@@ -87,6 +96,17 @@ pub mod runner {
                     opts.seed = n as i32;
                 }
             }
+            if let Some(v) = m.get("use_mmap") {
+                opts.use_mmap = v.as_bool();
+            }
+            if let Some(v) = m.get("use_mlock") {
+                if let Some(b) = v.as_bool() {
+                    opts.use_mlock = b;
+                }
+            }
+            if let Some(v) = m.get("lora_scale") {
+                opts.lora_scale = v.as_f64().map(|n| n as f32);
+            }
             
             opts
         }
@@ -122,6 +142,8 @@ pub mod runner {
         pub total_duration: i64,
         pub load_duration: i64,
         pub prompt_eval_count: i32,
+        // Prompt tokens whose K/V were reused from the previous request.
+        pub prompt_cache_count: i32,
         pub prompt_eval_duration: i64,
         pub eval_count: i32,
         pub eval_duration: i64,
@@ -140,17 +162,31 @@ pub mod runner {
     #[derive(Debug)]
     pub struct EmbedResult {
         pub embeddings: Vec<Vec<f32>>,
+        pub prompt_eval_count: usize,
         pub total_duration: i64,
+        pub load_duration: i64,
     }
 
+    // Totals across all runners, reported by /api/metrics.
+    pub static PROMPT_EVAL_TOKENS: AtomicU64 = AtomicU64::new(0);
+    pub static PROMPT_CACHE_HIT_TOKENS: AtomicU64 = AtomicU64::new(0);
+
     #[allow(dead_code)]
     pub struct Runner {
         model_name: String,
         model_path: String,
         options: RunnerOptions,
         tool_executor: crate::tools::ToolExecutor,
-        model: Option<Box<dyn ollama::Model>>,
+        model: Option<SharedModel>,
         tokenizer: Option<Box<dyn ollama::Tokenizer>>,
+        // Runs the generate requests of the loaded model, several at a time.
+        batcher: Option<Batcher>,
+        kv_pool: Option<Arc<BlockPool>>,
+        adapters: Vec<std::path::PathBuf>,
+        // Every shard of a split model, starting with model_path.
+        shards: Vec<std::path::PathBuf>,
+        // Reported by the first request served after a load.
+        pending_load_duration: Mutex<Option<std::time::Duration>>,
     }
 
     #[allow(dead_code)]
@@ -163,126 +199,176 @@ pub mod runner {
                 tool_executor: crate::tools::ToolExecutor::new(),
                 model: None,
                 tokenizer: None,
+                batcher: None,
+                kv_pool: None,
+                adapters: Vec::new(),
+                shards: vec![std::path::PathBuf::from(model_path)],
+                pending_load_duration: Mutex::new(None),
             })
         }
 
+        pub fn with_shards(mut self, shards: Vec<std::path::PathBuf>) -> Self {
+            self.shards = shards;
+            self
+        }
+
+        pub fn with_adapters(mut self, adapters: Vec<std::path::PathBuf>) -> Self {
+            self.adapters = adapters;
+            self
+        }
+
         pub fn with_options(mut self, options: RunnerOptions) -> Self {
             self.options = options;
             self
         }
 
+        // Whether `options` ask for adapters merged at a different scale than
+        // the loaded weights were. Merged weights cannot be rescaled in place.
+        pub fn needs_reload(&self, options: &RunnerOptions) -> bool {
+            self.is_loaded()
+                && !self.adapters.is_empty()
+                && options.lora_scale.is_some_and(|scale| scale != self.options.lora_scale.unwrap_or(1.0))
+        }
+
+        pub fn set_load_options(&mut self, options: &RunnerOptions) {
+            self.options.use_mmap = options.use_mmap;
+            self.options.use_mlock = options.use_mlock;
+            self.options.lora_scale = options.lora_scale;
+        }
+
+        fn take_load_duration(&self) -> i64 {
+            self.pending_load_duration.lock().unwrap().take().map(|d| d.as_nanos() as i64).unwrap_or(0)
+        }
+
         pub fn load(&mut self) -> Result<()> {
             println!("Loading model from {} with {} GPU layers", self.model_path, self.options.gpu_layers);
+            let start = std::time::Instant::now();
+            let use_mmap = self.options.use_mmap.unwrap_or(true);
             
-            // Load GGUF metadata to get config
-            let gguf = ollama::infra::GgufParser::parse(&self.model_path)?;
+            // Map the blobs once; the model below reuses the same mappings
+            let split = ollama::infra::gguf::SplitGguf::open(&self.shards, use_mmap, self.options.use_mlock)?;
+            let gguf = split.file();
             let config = gguf.metadata.to_model_config();
             
+            // The parallel sequences draw K/V blocks from one pool. Unless
+            // capped, it has room for each of them to fill its context.
+            let parallel = crate::envconfig::num_parallel();
+            let context = match self.options.context_size {
+                0 => config.context_length,
+                n => n,
+            };
+            let kv_heads = if config.num_kv_heads == 0 { config.num_heads } else { config.num_kv_heads };
+            let cache_type = crate::envconfig::kv_cache_type();
+            let position_bytes = config.num_layers * 2 * cache_type.row_bytes(kv_heads * config.head_dim());
+            let kv_cache = PagedCacheConfig {
+                block_size: crate::envconfig::kv_block_size(),
+                memory_budget: crate::envconfig::kv_cache_budget().unwrap_or(parallel * context.max(1) * position_bytes),
+                cache_type,
+            };
+
             // Load model weights using Llama architecture (assuming llama for now as per current codebase)
-            let model = ollama::core::model::architectures::llama::LlamaModel::load(&self.model_path, config.clone())?;
-            self.model = Some(Box::new(model));
+            let load_options = ollama::core::model::architectures::llama::LoadOptions {
+                use_mmap,
+                use_mlock: self.options.use_mlock,
+                adapters: self.adapters.clone(),
+                lora_scale: self.options.lora_scale.unwrap_or(1.0),
+                kv_cache: Some(kv_cache),
+            };
+            let model = ollama::core::model::architectures::llama::LlamaModel::load_shards(
+                &self.shards,
+                config.clone(),
+                &load_options,
+            )?;
+            self.kv_pool = model.kv_pool().cloned();
+            let model: SharedModel = Arc::new(Mutex::new(Box::new(model)));
             
             // Load tokenizer from GGUF metadata
-            let vocab = self.extract_vocab_from_gguf(&gguf);
-            let kind = if config.architecture.contains("llama") {
-                ollama::core::tokenizer::TokenizerKind::Bpe
-            } else {
-                ollama::core::tokenizer::TokenizerKind::WordPiece
-            };
-            self.tokenizer = Some(ollama::core::tokenizer::create_tokenizer(kind, vocab));
+            let vocab = ollama::core::tokenizer::Vocabulary::from_gguf(&gguf.metadata);
+            let tokenizer = ollama::core::tokenizer::create_tokenizer(vocab.kind(), vocab);
+            self.batcher = Some(Batcher::spawn(model.clone(), tokenizer.eos_token(), parallel));
+            self.model = Some(model);
+            self.tokenizer = Some(tokenizer);
+            *self.pending_load_duration.lock().unwrap() = Some(start.elapsed());
             
             Ok(())
         }
 
-        fn extract_vocab_from_gguf(&self, gguf: &ollama::infra::gguf::GgufFile) -> ollama::core::tokenizer::Vocabulary {
-            let tokens = if let Some(ollama::infra::gguf::MetadataValue::Array(arr)) = gguf.metadata.get("tokenizer.ggml.tokens") {
-                arr.iter().filter_map(|v| match v {
-                    ollama::infra::gguf::MetadataValue::String(s) => Some(s.clone()),
-                    _ => None,
-                }).collect()
-            } else {
-                vec![]
-            };
-
-            let scores = if let Some(ollama::infra::gguf::MetadataValue::Array(arr)) = gguf.metadata.get("tokenizer.ggml.scores") {
-                arr.iter().filter_map(|v| match v {
-                    ollama::infra::gguf::MetadataValue::Float(f) => Some(*f as f32),
-                    _ => None,
-                }).collect()
-            } else {
-                vec![0.0; tokens.len()]
-            };
+        // Generates from user text; control tokens in it are not parsed.
+        pub fn generate<F>(&self, prompt: &str, callback: F) -> Result<GenerateResult>
+        where F: FnMut(String, bool)
+        {
+            self.generate_with(prompt, false, &[], callback)
+        }
 
-            let mut vocab = ollama::core::tokenizer::Vocabulary::new(tokens);
-            vocab.scores = scores;
-            
-            // Try to find special tokens
-            if let Some(ollama::infra::gguf::MetadataValue::Uint(id)) = gguf.metadata.get("tokenizer.ggml.bos_token_id") {
-                vocab.bos_token = ollama::core::TokenId(*id as i32);
-            }
-            if let Some(ollama::infra::gguf::MetadataValue::Uint(id)) = gguf.metadata.get("tokenizer.ggml.eos_token_id") {
-                vocab.eos_token = ollama::core::TokenId(*id as i32);
-            }
-            
-            vocab
+        // Generates from a rendered template. Control tokens are parsed except
+        // in the `content` byte ranges, which hold text from the request.
+        pub fn generate_templated<F>(&self, prompt: &str, content: &[Range<usize>], callback: F) -> Result<GenerateResult>
+        where F: FnMut(String, bool)
+        {
+            self.generate_with(prompt, true, content, callback)
         }
 
-        pub fn generate<F>(&mut self, prompt: &str, mut callback: F) -> Result<GenerateResult>
+        // Queues the prompt on the batch worker, which evaluates it alongside
+        // the other in-flight requests, and streams the sampled tokens.
+        fn generate_with<F>(&self, prompt: &str, parse_special: bool, content: &[Range<usize>], mut callback: F) -> Result<GenerateResult>
         where F: FnMut(String, bool)
         {
-            let model = self.model.as_mut().ok_or_else(|| anyhow::anyhow!("Model not loaded"))?;
+            let load_duration = self.take_load_duration();
+            let model = self.model.as_ref().ok_or_else(|| anyhow::anyhow!("Model not loaded"))?;
+            let batcher = self.batcher.as_ref().ok_or_else(|| anyhow::anyhow!("Model not loaded"))?;
             let tokenizer = self.tokenizer.as_ref().ok_or_else(|| anyhow::anyhow!("Tokenizer not loaded"))?;
             
-            let tokens = tokenizer.encode(prompt)?;
+            let mut options = tokenizer.encode_options();
+            options.parse_special = parse_special;
+            options.literal = content.to_vec();
+            let tokens = tokenizer.encode_with_options(prompt, &options)?;
             let mut current_tokens = tokens.clone();
             let mut generated = String::new();
             
-            // Note: We use the cache from the trait if provided, otherwise create a local one.
-            // For now, LlamaModel handles its own internal state if start_pos is provided,
-            // but we pass a stub cache to satisfy the trait.
-            let mut stub_cache = ollama::core::cache::CausalKVCache::new(0, 0, 0, 0);
-
+            // Sized by num_ctx or else the model's trained context.
+            let context = match self.options.context_size {
+                0 => model.lock().unwrap().config().context_length,
+                n => n,
+            };
+            let max_to_generate = if self.options.num_predict > 0 { self.options.num_predict } else { 128 };
             let start_time = std::time::Instant::now();
-            let mut eval_count = 0;
 
-            // Initialize LogitsProcessor for real sampling
-            let mut logits_processor = LogitsProcessor::new(
-                self.options.seed as u64,
-                Some(self.options.temperature as f64),
-                Some(self.options.top_p as f64),
-            );
+            let (events, rx) = std::sync::mpsc::channel();
+            batcher.submit(SequenceRequest {
+                tokens: tokens.clone(),
+                num_predict: max_to_generate as usize,
+                capacity: context.max(tokens.len()),
+                sampler: LogitsProcessor::new(
+                    self.options.seed as u64,
+                    Some(self.options.temperature as f64),
+                    Some(self.options.top_p as f64),
+                ),
+                events,
+            })?;
 
-            // Generation loop
-            let max_to_generate = if self.options.num_predict > 0 { self.options.num_predict } else { 128 };
-            
-            for i in 0..max_to_generate {
-                // If it's the first token, we process the whole prompt
-                // If not, we only process the last generated token
-                let (input_tokens, pos) = if i == 0 {
-                    (current_tokens.clone(), (0..current_tokens.len()).collect::<Vec<_>>())
-                } else {
-                    let last = current_tokens.last().cloned().unwrap();
-                    (vec![last], vec![current_tokens.len() - 1])
-                };
-
-                let logits = model.forward(&input_tokens, &pos, &mut stub_cache)?;
-                
-                // Real Probabilistic Sampling
-                let logits_vec = logits.data();
-                let candle_logits = candle_core::Tensor::new(logits_vec, &candle_core::Device::Cpu)?;
-                let next_token_u32 = logits_processor.sample(&candle_logits)?;
-                let next_token = ollama::TokenId(next_token_u32 as i32);
-                
-                if next_token == tokenizer.eos_token() {
-                    break;
+            let mut eval_count = 0;
+            let mut detokenizer = Detokenizer::default();
+            let (prompt_eval_count, prompt_cache_count) = loop {
+                match rx.recv().map_err(|_| anyhow::anyhow!("Batch worker exited"))? {
+                    SequenceEvent::Token(token) => {
+                        let token_text = detokenizer.push(tokenizer.as_ref(), token)?;
+                        if !token_text.is_empty() {
+                            generated.push_str(&token_text);
+                            callback(token_text, false);
+                        }
+                        current_tokens.push(token);
+                        eval_count += 1;
+                    }
+                    SequenceEvent::Done { prompt_eval_count, prompt_cache_count } => {
+                        break (prompt_eval_count, prompt_cache_count);
+                    }
+                    SequenceEvent::Error(e) => bail!(e),
                 }
-
-                let token_text = tokenizer.decode(&[next_token])?;
-                generated.push_str(&token_text);
-                callback(token_text, false);
-                
-                current_tokens.push(next_token);
-                eval_count += 1;
+            };
+            let rest = detokenizer.finish(tokenizer.as_ref())?;
+            if !rest.is_empty() {
+                generated.push_str(&rest);
+                callback(rest, false);
             }
 
             callback(String::new(), true);
@@ -291,26 +377,34 @@ pub mod runner {
                 response: generated,
                 done: true,
                 context: current_tokens.iter().map(|t| t.0).collect(),
-                total_duration: start_time.elapsed().as_nanos() as i64,
-                load_duration: 0,
-                prompt_eval_count: tokens.len() as i32,
+                total_duration: start_time.elapsed().as_nanos() as i64 + load_duration,
+                load_duration,
+                prompt_eval_count: prompt_eval_count as i32,
+                prompt_cache_count: prompt_cache_count as i32,
                 prompt_eval_duration: 0,
-                eval_count: eval_count as i32,
+                eval_count,
                 eval_duration: 0,
             })
         }
 
-        pub fn chat<F>(&mut self, messages: &[Message], _tools: Option<&str>, mut callback: F) -> Result<ChatResult> 
+        pub fn chat<F>(&self, messages: &[Message], _tools: Option<&str>, mut callback: F) -> Result<ChatResult> 
         where F: FnMut(String, bool)
         {
-            // Simplified chat for now: combine messages into a prompt
+            // Simplified chat for now: combine messages into a prompt. Roles and
+            // content come from the request and are kept literal.
             let mut prompt = String::new();
+            let mut content = Vec::new();
             for msg in messages {
-                prompt.push_str(&format!("{}: {}\n", msg.role, msg.content));
+                for (text, suffix) in [(&msg.role, ": "), (&msg.content, "\n")] {
+                    let start = prompt.len();
+                    prompt.push_str(text);
+                    content.push(start..prompt.len());
+                    prompt.push_str(suffix);
+                }
             }
             prompt.push_str("assistant: ");
             
-            let res = self.generate(&prompt, callback)?;
+            let res = self.generate_templated(&prompt, &content, callback)?;
             
             Ok(ChatResult {
                 message: Message {
@@ -325,28 +419,104 @@ pub mod runner {
             })
         }
 
-        pub fn embed(&mut self, input: &str, _dimensions: Option<usize>) -> Result<EmbedResult> {
-            let model = self.model.as_mut().ok_or_else(|| anyhow::anyhow!("Model not loaded"))?;
+        // One embedding per input; the inputs are tokenized in parallel.
+        pub fn embed(&self, inputs: &[&str], _dimensions: Option<usize>) -> Result<EmbedResult> {
+            let load_duration = self.take_load_duration();
+            let start_time = std::time::Instant::now();
+            let model = self.model.as_ref().ok_or_else(|| anyhow::anyhow!("Model not loaded"))?;
             let tokenizer = self.tokenizer.as_ref().ok_or_else(|| anyhow::anyhow!("Tokenizer not loaded"))?;
             
-            let tokens = tokenizer.encode(input)?;
-            let embedding = model.embed(&tokens)?;
+            let batch = tokenizer.encode_batch(inputs, &tokenizer.encode_options())?;
+            let mut embeddings = Vec::with_capacity(batch.len());
+            let model = model.lock().unwrap();
+            for tokens in &batch {
+                embeddings.push(model.embed(tokens)?.data().to_vec());
+            }
             
             Ok(EmbedResult {
-                embeddings: vec![embedding.data().to_vec()],
-                total_duration: 0,
+                embeddings,
+                prompt_eval_count: batch.iter().map(Vec::len).sum(),
+                total_duration: start_time.elapsed().as_nanos() as i64 + load_duration,
+                load_duration,
             })
         }
 
+        pub fn kv_cache_stats(&self) -> Option<PoolStats> {
+            self.kv_pool.as_ref().map(|pool| pool.stats())
+        }
+
+        // Bytes the model takes once loaded: the weights of every shard and
+        // the K/V pool load() sizes for it.
+        pub fn memory_estimate(&self) -> Result<u64> {
+            let first = ollama::GgufFile::open(&self.shards[0])?;
+            let context = match self.options.context_size {
+                0 => first.metadata.context_length,
+                n => n as u64,
+            };
+            let estimate = first.estimate_memory_usage(
+                self.options.gpu_layers,
+                crate::envconfig::kv_cache_type(),
+                context.max(1),
+                crate::envconfig::num_parallel(),
+            );
+            let mut weights = estimate.weights;
+            for shard in &self.shards[1..] {
+                weights += ollama::GgufFile::open(shard)?.tensors.iter().map(|t| t.size).sum::<u64>();
+            }
+            Ok(weights + crate::envconfig::kv_cache_budget().map_or(estimate.kv_cache, |budget| budget as u64))
+        }
+
         pub fn is_loaded(&self) -> bool {
             self.model.is_some() && self.tokenizer.is_some()
         }
 
         pub fn unload(&mut self) {
+            // Waits for the worker to finish the requests it has admitted.
+            self.batcher = None;
+            self.kv_pool = None;
+            self.model = None;
+            self.tokenizer = None;
             println!("Model unloaded");
         }
     }
 
+    // Streams generated tokens as text. Decoding tokens one at a time would drop
+    // the space prefix of every SentencePiece piece and turn each byte of a
+    // multi-byte character into U+FFFD, so each delta is the difference
+    // between decoding a short window with and without the new tokens. Text
+    // that ends in an incomplete character is held back until it completes.
+    #[derive(Default)]
+    struct Detokenizer {
+        tokens: Vec<TokenId>,
+        // Start of the window and end of the tokens already emitted.
+        prefix: usize,
+        read: usize,
+    }
+
+    impl Detokenizer {
+        fn push(&mut self, tokenizer: &dyn ollama::Tokenizer, token: TokenId) -> Result<String> {
+            self.tokens.push(token);
+            let emitted = tokenizer.decode(&self.tokens[self.prefix..self.read])?;
+            let text = tokenizer.decode(&self.tokens[self.prefix..])?;
+            if text.len() <= emitted.len() || text.ends_with(char::REPLACEMENT_CHARACTER) {
+                return Ok(String::new());
+            }
+            self.prefix = self.read;
+            self.read = self.tokens.len();
+            Ok(text.get(emitted.len()..).unwrap_or_default().to_string())
+        }
+
+        // Whatever is still held back, e.g. bytes of a character the model
+        // never finished.
+        fn finish(&mut self, tokenizer: &dyn ollama::Tokenizer) -> Result<String> {
+            let emitted = tokenizer.decode(&self.tokens[self.prefix..self.read])?;
+            let text = tokenizer.decode(&self.tokens[self.prefix..])?;
+            self.prefix = self.tokens.len();
+            self.read = self.tokens.len();
+            Ok(text.get(emitted.len()..).unwrap_or_default().to_string())
+        }
+    }
+
     #[allow(dead_code)]
     fn detect_tool_call(response: &str) -> Option<ToolCall> {
         // Very simple regex or JSON-like parsing for demonstration
@@ -370,11 +540,794 @@ pub mod runner {
         }
         hash
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ollama::core::tokenizer::{create_tokenizer, TokenType, Vocabulary};
+
+        // Laid out like llama's vocabulary: control tokens, the 256 byte
+        // fallback tokens, then pieces scored so longer merges win.
+        fn llama_tokenizer() -> Box<dyn ollama::Tokenizer> {
+            let mut tokens: Vec<String> = vec!["<unk>".into(), "<s>".into(), "</s>".into()];
+            tokens.extend((0..=255u8).map(|b| format!("<0x{:02X}>", b)));
+            let pieces = ["▁", "H", "e", "l", "o", "w", "r", "d", "c", "a", "f", "!", "ll", "▁H", "▁He", "▁Hell", "▁Hello", "▁w", "or", "ld", "▁wor", "▁world", "▁c", "▁ca", "▁caf"];
+            let bytes = tokens.len();
+            tokens.extend(pieces.iter().map(|p| p.to_string()));
+            let mut vocab = Vocabulary::new(tokens);
+            for (i, kind) in vocab.types.iter_mut().enumerate() {
+                *kind = match i {
+                    0 => TokenType::Unknown,
+                    1 | 2 => TokenType::Control,
+                    i if i < bytes => TokenType::Byte,
+                    _ => TokenType::Normal,
+                };
+            }
+            for (i, score) in vocab.scores.iter_mut().enumerate().skip(bytes) {
+                *score = (i - bytes) as f32;
+            }
+            vocab.unk_token = Some(TokenId(0));
+            vocab.model = "llama".into();
+            vocab.add_bos = true;
+            create_tokenizer(vocab.kind(), vocab)
+        }
+
+        #[test]
+        fn test_detokenizer_streams_whole_text() {
+            let tokenizer = llama_tokenizer();
+            let text = "Hello world 😀 café!";
+            let tokens = tokenizer.encode(text).unwrap()[1..].to_vec();
+            // The emoji and é are not in the vocabulary and fall back to bytes.
+            assert!(tokens.len() > 8);
+            assert_eq!(tokenizer.decode(&tokens).unwrap(), text);
+
+            let mut detokenizer = Detokenizer::default();
+            let mut deltas = Vec::new();
+            for &token in &tokens {
+                deltas.push(detokenizer.push(tokenizer.as_ref(), token).unwrap());
+            }
+            deltas.push(detokenizer.finish(tokenizer.as_ref()).unwrap());
+            assert!(deltas.iter().all(|d| !d.contains(char::REPLACEMENT_CHARACTER)), "{:?}", deltas);
+            assert_eq!(deltas[..2], ["Hello", " world"]);
+            assert_eq!(deltas.concat(), text);
+
+            // A character the model never finishes is flushed at the end.
+            let mut detokenizer = Detokenizer::default();
+            assert_eq!(detokenizer.push(tokenizer.as_ref(), tokens[0]).unwrap(), "Hello");
+            assert_eq!(detokenizer.push(tokenizer.as_ref(), TokenId(3 + 0xF0)).unwrap(), "");
+            assert_eq!(detokenizer.finish(tokenizer.as_ref()).unwrap(), "\u{FFFD}");
+        }
+    }
+}
+
+pub mod batch {
+    // Continuous batching: one worker thread per loaded model evaluates every
+    // active sequence in a single forward_batch per step, and admits queued
+    // requests between steps instead of waiting for the batch to drain.
+    use anyhow::Result;
+    use std::collections::VecDeque;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+    use candle_transformers::generation::LogitsProcessor;
+    use ollama::core::model::ModelBatch;
+    use ollama::{KVCache, Model, TokenId};
+
+    use super::runner::{PROMPT_CACHE_HIT_TOKENS, PROMPT_EVAL_TOKENS};
+
+    pub type SharedModel = Arc<Mutex<Box<dyn Model>>>;
+
+    pub struct SequenceRequest {
+        pub tokens: Vec<TokenId>,
+        pub num_predict: usize,
+        // Positions the sequence's cache must hold.
+        pub capacity: usize,
+        pub sampler: LogitsProcessor,
+        pub events: Sender<SequenceEvent>,
+    }
+
+    pub enum SequenceEvent {
+        Token(TokenId),
+        Done { prompt_eval_count: usize, prompt_cache_count: usize },
+        Error(String),
+    }
+
+    pub struct Batcher {
+        requests: Option<Sender<SequenceRequest>>,
+        worker: Option<JoinHandle<()>>,
+    }
+
+    impl Batcher {
+        pub fn spawn(model: SharedModel, eos: TokenId, parallel: usize) -> Self {
+            let (requests, rx) = mpsc::channel();
+            let worker = std::thread::spawn(move || Worker::new(model, eos, parallel).run(rx));
+            Self { requests: Some(requests), worker: Some(worker) }
+        }
+
+        pub fn submit(&self, request: SequenceRequest) -> Result<()> {
+            let requests = self.requests.as_ref().ok_or_else(|| anyhow::anyhow!("Batcher stopped"))?;
+            requests.send(request).map_err(|_| anyhow::anyhow!("Batch worker exited"))
+        }
+    }
+
+    impl Drop for Batcher {
+        // Lets the worker finish what it has admitted, then waits for it.
+        fn drop(&mut self) {
+            self.requests = None;
+            if let Some(worker) = self.worker.take() {
+                let _ = worker.join();
+            }
+        }
+    }
+
+    // How many leading tokens `a` and `b` have in common.
+    fn common_prefix(a: &[TokenId], b: &[TokenId]) -> usize {
+        a.iter().zip(b).take_while(|(x, y)| x == y).count()
+    }
+
+    // A cache the worker keeps between requests. A new request takes the
+    // idle slot sharing the longest prefix with its prompt.
+    #[derive(Default)]
+    struct Slot {
+        tokens: Vec<TokenId>,
+        cache: Option<Box<dyn KVCache>>,
+        busy: bool,
+    }
+
+    struct Sequence {
+        slot: usize,
+        // Tokens whose K/V are in `cache`.
+        tokens: Vec<TokenId>,
+        cache: Box<dyn KVCache>,
+        // Evaluated on the next step: the uncached prompt, then each sample.
+        pending: Vec<TokenId>,
+        remaining: usize,
+        sampler: LogitsProcessor,
+        events: Sender<SequenceEvent>,
+        prompt_eval_count: usize,
+        prompt_cache_count: usize,
+    }
+
+    struct Worker {
+        model: SharedModel,
+        eos: TokenId,
+        parallel: usize,
+        slots: Vec<Slot>,
+        active: Vec<Sequence>,
+        queue: VecDeque<SequenceRequest>,
+    }
+
+    impl Worker {
+        fn new(model: SharedModel, eos: TokenId, parallel: usize) -> Self {
+            let parallel = parallel.max(1);
+            Self {
+                model,
+                eos,
+                parallel,
+                slots: (0..parallel).map(|_| Slot::default()).collect(),
+                active: Vec::new(),
+                queue: VecDeque::new(),
+            }
+        }
+
+        fn run(mut self, requests: Receiver<SequenceRequest>) {
+            loop {
+                // Block only when there is nothing to step.
+                if self.active.is_empty() && self.queue.is_empty() {
+                    match requests.recv() {
+                        Ok(request) => self.queue.push_back(request),
+                        Err(_) => return,
+                    }
+                }
+                self.queue.extend(requests.try_iter());
+                while self.active.len() < self.parallel {
+                    let Some(request) = self.queue.pop_front() else { break };
+                    // A prompt whose blocks don't fit waits for sequences in
+                    // flight to finish.
+                    if let Some(request) = self.admit(request) {
+                        self.queue.push_front(request);
+                        break;
+                    }
+                }
+                if !self.active.is_empty() {
+                    self.step();
+                }
+            }
+        }
+
+        // Starts a request, or hands it back when its prompt's K/V don't fit
+        // in memory until sequences in flight finish.
+        fn admit(&mut self, request: SequenceRequest) -> Option<SequenceRequest> {
+            let (tokens, capacity) = (&request.tokens, request.capacity);
+            if tokens.is_empty() {
+                let _ = request.events.send(SequenceEvent::Error("Prompt is empty".to_string()));
+                return None;
+            }
+            let (index, shared) = self
+                .slots
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| !s.busy)
+                .map(|(i, s)| (i, common_prefix(&s.tokens, tokens)))
+                .max_by_key(|&(i, shared)| (shared, std::cmp::Reverse(i)))
+                .expect("a slot is free while fewer than `parallel` sequences are active");
+            let slot = &mut self.slots[index];
+            slot.busy = true;
+            let mut cache = slot.cache.take().filter(|cache| cache.capacity() >= capacity);
+            let mut cached_tokens = std::mem::take(&mut slot.tokens);
+            let mut shared = if cache.is_some() { shared } else { 0 };
+
+            // A sequence in flight with a longer common prefix, such as the
+            // same system prompt, is forked instead. With a paged cache the
+            // fork shares its blocks.
+            let donor = self
+                .active
+                .iter()
+                .filter(|s| s.cache.capacity() >= capacity)
+                .map(|s| (common_prefix(&s.tokens, tokens), s))
+                .max_by_key(|&(prefix, _)| prefix);
+            if let Some((prefix, donor)) = donor.filter(|&(prefix, _)| prefix > shared) {
+                cache = Some(donor.cache.clone_cache());
+                cached_tokens = tokens[..prefix].to_vec();
+                shared = prefix;
+            }
+
+            // At least one prompt token is evaluated, to get logits.
+            let mut cached = shared.min(tokens.len() - 1);
+            let reused = cache.and_then(|mut cache| cache.truncate(cached).is_ok().then_some(cache));
+            let mut cache = reused.unwrap_or_else(|| {
+                cached = 0;
+                self.model.lock().unwrap().new_cache(capacity)
+            });
+            cached_tokens.truncate(cached);
+
+            let mut reserved = cache.reserve(tokens.len());
+            if reserved.is_err() && self.release_idle() {
+                reserved = cache.reserve(tokens.len());
+            }
+            if let Err(e) = reserved {
+                if self.active.is_empty() {
+                    let _ = request.events.send(SequenceEvent::Error(e.to_string()));
+                    self.slots[index] = Slot::default();
+                    return None;
+                }
+                self.slots[index] = Slot { tokens: cached_tokens, cache: Some(cache), busy: false };
+                return Some(request);
+            }
+
+            let SequenceRequest { tokens, num_predict, sampler, events, .. } = request;
+            PROMPT_EVAL_TOKENS.fetch_add((tokens.len() - cached) as u64, Ordering::Relaxed);
+            PROMPT_CACHE_HIT_TOKENS.fetch_add(cached as u64, Ordering::Relaxed);
+            self.active.push(Sequence {
+                slot: index,
+                tokens: cached_tokens,
+                cache,
+                pending: tokens[cached..].to_vec(),
+                remaining: num_predict,
+                sampler,
+                events,
+                prompt_eval_count: tokens.len() - cached,
+                prompt_cache_count: cached,
+            });
+            None
+        }
+
+        // Drops the caches idle slots keep for prefix reuse, to free their
+        // memory. Returns whether there were any.
+        fn release_idle(&mut self) -> bool {
+            let mut released = false;
+            for slot in self.slots.iter_mut().filter(|s| !s.busy && s.cache.is_some()) {
+                *slot = Slot::default();
+                released = true;
+            }
+            released
+        }
+
+        // One forward over every active sequence, then a sample for each.
+        fn step(&mut self) {
+            // Each sequence takes the memory for this step's positions first,
+            // so running out fails only a sequence that needed more.
+            let mut i = 0;
+            while i < self.active.len() {
+                let len = self.active[i].cache.len() + self.active[i].pending.len();
+                let mut reserved = self.active[i].cache.reserve(len);
+                if reserved.is_err() && self.release_idle() {
+                    reserved = self.active[i].cache.reserve(len);
+                }
+                match reserved {
+                    Ok(()) => i += 1,
+                    Err(e) => {
+                        let seq = self.active.swap_remove(i);
+                        let _ = seq.events.send(SequenceEvent::Error(e.to_string()));
+                        self.slots[seq.slot] = Slot::default();
+                    }
+                }
+            }
+            if self.active.is_empty() {
+                return;
+            }
+
+            let batch = ModelBatch::new(
+                self.active.iter().map(|s| s.pending.clone()).collect(),
+                self.active.iter().map(|s| (s.cache.len()..s.cache.len() + s.pending.len()).collect()).collect(),
+            );
+            let logits = match self.forward(&batch) {
+                Ok(logits) => logits,
+                Err(e) => {
+                    // The caches may be partly updated, so none are kept.
+                    for seq in std::mem::take(&mut self.active) {
+                        let _ = seq.events.send(SequenceEvent::Error(e.to_string()));
+                        self.slots[seq.slot] = Slot::default();
+                    }
+                    return;
+                }
+            };
+
+            let vocab = logits.shape().dims().last().copied().unwrap_or(0);
+            let mut finished = Vec::new();
+            for (i, seq) in self.active.iter_mut().enumerate() {
+                seq.tokens.append(&mut seq.pending);
+                let row = &logits.data()[i * vocab..(i + 1) * vocab];
+                let next = candle_core::Tensor::new(row, &candle_core::Device::Cpu)
+                    .and_then(|row| seq.sampler.sample(&row))
+                    .map(|t| TokenId(t as i32));
+                let done = match next {
+                    Ok(next) if next != self.eos => {
+                        seq.remaining = seq.remaining.saturating_sub(1);
+                        seq.pending.push(next);
+                        // A closed channel means the client went away.
+                        seq.events.send(SequenceEvent::Token(next)).is_err()
+                            || seq.remaining == 0
+                            || seq.cache.len() >= seq.cache.capacity()
+                    }
+                    Ok(_) => true,
+                    Err(e) => {
+                        let _ = seq.events.send(SequenceEvent::Error(e.to_string()));
+                        true
+                    }
+                };
+                if done {
+                    finished.push(i);
+                }
+            }
+            for i in finished.into_iter().rev() {
+                self.finish(i);
+            }
+        }
+
+        fn forward(&mut self, batch: &ModelBatch) -> ollama::Result<ollama::Tensor> {
+            let mut caches: Vec<&mut dyn KVCache> = self.active.iter_mut().map(|s| &mut *s.cache as _).collect();
+            self.model.lock().unwrap().forward_batch(batch, &mut caches)
+        }
+
+        fn finish(&mut self, index: usize) {
+            let seq = self.active.swap_remove(index);
+            let _ = seq.events.send(SequenceEvent::Done {
+                prompt_eval_count: seq.prompt_eval_count,
+                prompt_cache_count: seq.prompt_cache_count,
+            });
+            self.slots[seq.slot] = Slot { tokens: seq.tokens, cache: Some(seq.cache), busy: false };
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ollama::core::model::{ModelConfig, ModelMeta};
+        use ollama::core::tensor::Shape;
+        use ollama::Tensor;
+        use std::sync::atomic::AtomicUsize;
+
+        #[test]
+        fn test_common_prefix() {
+            let ids = |v: &[i32]| v.iter().map(|&t| TokenId(t)).collect::<Vec<_>>();
+            assert_eq!(common_prefix(&ids(&[1, 2, 3, 9]), &ids(&[1, 2, 3, 4, 5])), 3);
+            assert_eq!(common_prefix(&ids(&[1, 2]), &ids(&[1, 2, 3])), 2);
+            assert_eq!(common_prefix(&ids(&[7]), &ids(&[1, 2])), 0);
+            assert_eq!(common_prefix(&[], &ids(&[1])), 0);
+        }
+
+        const VOCAB: usize = 16;
+
+        // Always predicts the last token plus one, and records the widest
+        // batch it was given. With a gate, each step waits for a permit.
+        struct Counter {
+            config: ModelConfig,
+            meta: ModelMeta,
+            widest: Arc<AtomicUsize>,
+            gate: Option<Mutex<Receiver<()>>>,
+        }
+
+        fn counter(gate: Option<Receiver<()>>) -> (SharedModel, Arc<AtomicUsize>) {
+            let config = ModelConfig { num_layers: 1, num_heads: 1, num_kv_heads: 1, hidden_size: 1, ..Default::default() };
+            let widest = Arc::new(AtomicUsize::new(0));
+            let model: SharedModel = Arc::new(Mutex::new(Box::new(Counter {
+                config,
+                meta: ModelMeta::default(),
+                widest: widest.clone(),
+                gate: gate.map(Mutex::new),
+            })));
+            (model, widest)
+        }
+
+        impl Model for Counter {
+            fn forward(&mut self, input: &[TokenId], positions: &[usize], cache: &mut dyn KVCache) -> ollama::Result<Tensor> {
+                let batch = ModelBatch::new(vec![input.to_vec()], vec![positions.to_vec()]);
+                self.forward_batch(&batch, &mut [cache])
+            }
+
+            fn forward_batch(&mut self, batch: &ModelBatch, caches: &mut [&mut dyn KVCache]) -> ollama::Result<Tensor> {
+                if let Some(gate) = &self.gate {
+                    gate.lock().unwrap().recv()?;
+                }
+                self.widest.fetch_max(batch.batch_size(), Ordering::SeqCst);
+                let mut logits = vec![0.0; batch.batch_size() * VOCAB];
+                for (i, (tokens, cache)) in batch.tokens.iter().zip(caches.iter_mut()).enumerate() {
+                    let kv = Tensor::zeros(Shape::new(vec![tokens.len(), 1, 1]));
+                    cache.update(0, &kv, &kv)?;
+                    let next = (tokens.last().unwrap().0 as usize + 1) % VOCAB;
+                    logits[i * VOCAB + next] = 10.0;
+                }
+                Ok(Tensor::new(logits, Shape::new(vec![batch.batch_size(), VOCAB])))
+            }
+
+            fn config(&self) -> &ModelConfig {
+                &self.config
+            }
+
+            fn meta(&self) -> &ModelMeta {
+                &self.meta
+            }
+
+            fn embed(&self, _tokens: &[TokenId]) -> ollama::Result<Tensor> {
+                Err(anyhow::anyhow!("Counter has no embeddings"))
+            }
+
+            fn logits(&self, _hidden: &Tensor) -> ollama::Result<Tensor> {
+                Err(anyhow::anyhow!("Counter only produces logits through forward"))
+            }
+        }
+
+        fn request(prompt: &[i32], num_predict: usize) -> (SequenceRequest, Receiver<SequenceEvent>) {
+            let (events, rx) = mpsc::channel();
+            let request = SequenceRequest {
+                tokens: prompt.iter().map(|&t| TokenId(t)).collect(),
+                num_predict,
+                capacity: 32,
+                sampler: LogitsProcessor::new(0, None, None),
+                events,
+            };
+            (request, rx)
+        }
+
+        // The prompt counters behind /metrics are process-wide, so tests that
+        // admit sequences take turns.
+        fn serial() -> std::sync::MutexGuard<'static, ()> {
+            static SERIAL: Mutex<()> = Mutex::new(());
+            SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+        }
+
+        fn collect(rx: Receiver<SequenceEvent>) -> (Vec<i32>, usize, usize) {
+            let mut tokens = Vec::new();
+            for event in rx {
+                match event {
+                    SequenceEvent::Token(t) => tokens.push(t.0),
+                    SequenceEvent::Done { prompt_eval_count, prompt_cache_count } => {
+                        return (tokens, prompt_eval_count, prompt_cache_count)
+                    }
+                    SequenceEvent::Error(e) => panic!("{}", e),
+                }
+            }
+            panic!("worker exited without finishing the sequence");
+        }
+
+        #[test]
+        fn test_batcher_interleaves_and_reuses_prefixes() {
+            let _serial = serial();
+            let (model, widest) = counter(None);
+            let batcher = Batcher::spawn(model.clone(), TokenId(15), 2);
+
+            // Holding the model stalls the worker, so the second request is
+            // queued while the first is in flight and joins it.
+            let guard = model.lock().unwrap();
+            let (first, first_rx) = request(&[1, 2], 4);
+            let (second, second_rx) = request(&[12], 10);
+            batcher.submit(first).unwrap();
+            batcher.submit(second).unwrap();
+            drop(guard);
+
+            assert_eq!(collect(first_rx), (vec![3, 4, 5, 6], 2, 0));
+            // Stops at the end-of-sequence token.
+            assert_eq!(collect(second_rx), (vec![13, 14], 1, 0));
+            assert_eq!(widest.load(Ordering::SeqCst), 2);
+
+            // Continuing the first conversation evaluates only the new suffix.
+            let (next, next_rx) = request(&[1, 2, 3, 4, 5, 9], 1);
+            batcher.submit(next).unwrap();
+            assert_eq!(collect(next_rx), (vec![10], 1, 5));
+
+            drop(batcher);
+        }
+
+        #[test]
+        fn test_batcher_forks_a_sequence_in_flight() {
+            let _serial = serial();
+            let (permits, gate) = mpsc::channel();
+            let (model, _) = counter(Some(gate));
+            let batcher = Batcher::spawn(model, TokenId(15), 2);
+
+            let (long, long_rx) = request(&[4, 4, 4, 4], 8);
+            batcher.submit(long).unwrap();
+            permits.send(()).unwrap();
+            assert!(matches!(long_rx.recv().unwrap(), SequenceEvent::Token(TokenId(5))));
+
+            // The first sequence cannot finish without permits, so this one
+            // can only get its prefix by forking it.
+            let (fork, fork_rx) = request(&[4, 4, 4, 4, 9], 1);
+            batcher.submit(fork).unwrap();
+            for _ in 0..16 {
+                permits.send(()).unwrap();
+            }
+            assert_eq!(collect(fork_rx), (vec![10], 1, 4));
+            assert_eq!(collect(long_rx).0, vec![6, 7, 8, 9, 10, 11, 12]);
+
+            drop(batcher);
+        }
+
+        // Passes everything to a real model and keeps the logits it returns.
+        struct Recorder {
+            inner: Box<dyn Model>,
+            logits: Arc<Mutex<Vec<Vec<f32>>>>,
+        }
+
+        impl Model for Recorder {
+            fn forward(&mut self, input: &[TokenId], positions: &[usize], cache: &mut dyn KVCache) -> ollama::Result<Tensor> {
+                self.inner.forward(input, positions, cache)
+            }
+
+            fn forward_batch(&mut self, batch: &ModelBatch, caches: &mut [&mut dyn KVCache]) -> ollama::Result<Tensor> {
+                let logits = self.inner.forward_batch(batch, caches)?;
+                let vocab = logits.shape().dims().last().copied().unwrap_or(0);
+                self.logits.lock().unwrap().extend(logits.data().chunks(vocab).map(<[f32]>::to_vec));
+                Ok(logits)
+            }
+
+            fn config(&self) -> &ModelConfig {
+                self.inner.config()
+            }
+
+            fn meta(&self) -> &ModelMeta {
+                self.inner.meta()
+            }
+
+            fn new_cache(&self, max_seq_len: usize) -> Box<dyn KVCache> {
+                self.inner.new_cache(max_seq_len)
+            }
+
+            fn embed(&self, tokens: &[TokenId]) -> ollama::Result<Tensor> {
+                self.inner.embed(tokens)
+            }
+
+            fn logits(&self, hidden: &Tensor) -> ollama::Result<Tensor> {
+                self.inner.logits(hidden)
+            }
+        }
+
+        // A two-layer llama with deterministic f32 weights and a vocabulary of VOCAB.
+        fn write_llama(path: &std::path::Path) {
+            use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+
+            let (dim, ffn) = (8, 12);
+            let mut tensors = vec![
+                ("token_embd.weight".to_string(), vec![VOCAB, dim]),
+                ("output_norm.weight".to_string(), vec![dim]),
+                ("output.weight".to_string(), vec![VOCAB, dim]),
+            ];
+            for i in 0..2 {
+                for (name, dims) in [
+                    ("attn_norm", vec![dim]),
+                    ("attn_q", vec![dim, dim]),
+                    ("attn_k", vec![dim / 2, dim]),
+                    ("attn_v", vec![dim / 2, dim]),
+                    ("attn_output", vec![dim, dim]),
+                    ("ffn_norm", vec![dim]),
+                    ("ffn_gate", vec![ffn, dim]),
+                    ("ffn_up", vec![ffn, dim]),
+                    ("ffn_down", vec![dim, ffn]),
+                ] {
+                    tensors.push((format!("blk.{}.{}.weight", i, name), dims));
+                }
+            }
+            let qtensors: Vec<(String, QTensor)> = tensors
+                .iter()
+                .enumerate()
+                .map(|(seed, (name, dims))| {
+                    let numel: usize = dims.iter().product();
+                    let data: Vec<f32> = (0..numel).map(|i| ((i * 7919 + seed * 104729) % 1000) as f32 / 500.0 - 1.0).collect();
+                    let t = candle_core::Tensor::from_vec(data, dims.as_slice(), &candle_core::Device::Cpu).unwrap();
+                    (name.clone(), QTensor::quantize(&t, GgmlDType::F32).unwrap())
+                })
+                .collect();
+
+            let u32 = gguf_file::Value::U32;
+            let metadata = [
+                ("general.architecture", gguf_file::Value::String("llama".into())),
+                ("llama.embedding_length", u32(dim as u32)),
+                ("llama.feed_forward_length", u32(ffn as u32)),
+                ("llama.block_count", u32(2)),
+                ("llama.attention.head_count", u32(2)),
+                ("llama.attention.head_count_kv", u32(1)),
+                ("llama.attention.layer_norm_rms_epsilon", gguf_file::Value::F32(1e-5)),
+                ("llama.context_length", u32(32)),
+            ];
+            let metadata: Vec<(&str, &gguf_file::Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+            let refs: Vec<(&str, &QTensor)> = qtensors.iter().map(|(n, q)| (n.as_str(), q)).collect();
+            gguf_file::write(&mut std::fs::File::create(path).unwrap(), &metadata, &refs).unwrap();
+        }
+
+        // A block of this model holds 2 layers of K and V for 4 positions of
+        // 4 f32 values: 256 bytes.
+        fn recorded_llama(path: &std::path::Path, memory_budget: usize) -> (SharedModel, Arc<Mutex<Vec<Vec<f32>>>>) {
+            use ollama::core::cache::{PagedCacheConfig, KvCacheType};
+            use ollama::core::model::architectures::llama::{LlamaModel, LoadOptions};
+
+            let config = ollama::infra::gguf::SplitGguf::open(&[path], true, false).unwrap().file().metadata.to_model_config();
+            // Blocks of 4 positions, so reused prefixes end inside a block.
+            let options = LoadOptions {
+                use_mmap: true,
+                use_mlock: false,
+                adapters: Vec::new(),
+                lora_scale: 1.0,
+                kv_cache: Some(PagedCacheConfig { block_size: 4, memory_budget, cache_type: KvCacheType::F32 }),
+            };
+            let inner = LlamaModel::load_shards(&[path], config, &options).unwrap();
+            let logits = Arc::new(Mutex::new(Vec::new()));
+            let model: SharedModel = Arc::new(Mutex::new(Box::new(Recorder { inner: Box::new(inner), logits: logits.clone() })));
+            (model, logits)
+        }
+
+        // (ollama_prompt_eval_tokens_total, ollama_prompt_cache_hit_tokens_total) from /metrics.
+        fn prompt_metrics() -> (u64, u64) {
+            let text = crate::server::metrics_text();
+            let value = |name: &str| {
+                text.lines().find_map(|l| l.strip_prefix(name)?.trim().parse().ok()).unwrap()
+            };
+            (value("ollama_prompt_eval_tokens_total "), value("ollama_prompt_cache_hit_tokens_total "))
+        }
+
+        #[test]
+        fn test_reused_prefix_matches_fresh_cache() {
+            let _serial = serial();
+            let path = std::env::temp_dir().join(format!("ollama-batch-llama-{}.gguf", std::process::id()));
+            write_llama(&path);
+            // No token is the end of sequence, so every request runs to num_predict.
+            let eos = TokenId(VOCAB as i32);
+
+            let (model, reused_logits) = recorded_llama(&path, 1 << 20);
+            let batcher = Batcher::spawn(model, eos, 1);
+            let (first, first_rx) = request(&[1, 5, 3, 7, 2], 3);
+            batcher.submit(first).unwrap();
+            let (generated, _, _) = collect(first_rx);
+            assert_eq!(generated.len(), 3);
+
+            // The conversation continues: the prompt, what was generated and a
+            // new turn. The last sampled token was never evaluated, so it and
+            // the new turn are all that is left to evaluate.
+            let mut prompt = vec![1, 5, 3, 7, 2];
+            prompt.extend(&generated);
+            prompt.extend([9, 4, 11]);
+            reused_logits.lock().unwrap().clear();
+            let before = prompt_metrics();
+            let (next, next_rx) = request(&prompt, 4);
+            batcher.submit(next).unwrap();
+            let (reused, eval_count, cache_count) = collect(next_rx);
+            assert_eq!((eval_count, cache_count), (4, 7));
+            let after = prompt_metrics();
+            assert_eq!((after.0 - before.0, after.1 - before.1), (4, 7));
+            drop(batcher);
+
+            let (model, fresh_logits) = recorded_llama(&path, 1 << 20);
+            let batcher = Batcher::spawn(model, eos, 1);
+            let (fresh, fresh_rx) = request(&prompt, 4);
+            batcher.submit(fresh).unwrap();
+            let (tokens, eval_count, cache_count) = collect(fresh_rx);
+            assert_eq!((eval_count, cache_count), (prompt.len(), 0));
+            drop(batcher);
+
+            assert_eq!(reused, tokens);
+            let (reused_logits, fresh_logits) = (reused_logits.lock().unwrap(), fresh_logits.lock().unwrap());
+            assert_eq!(reused_logits.len(), 4);
+            assert_eq!(reused_logits.len(), fresh_logits.len());
+            for (a, b) in reused_logits.iter().zip(fresh_logits.iter()) {
+                assert!(a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-4), "{:?} != {:?}", a, b);
+            }
+
+            std::fs::remove_file(&path).ok();
+        }
+
+        fn outcome(rx: Receiver<SequenceEvent>) -> std::result::Result<Vec<i32>, String> {
+            let mut tokens = Vec::new();
+            for event in rx {
+                match event {
+                    SequenceEvent::Token(t) => tokens.push(t.0),
+                    SequenceEvent::Done { .. } => return Ok(tokens),
+                    SequenceEvent::Error(e) => return Err(e),
+                }
+            }
+            panic!("worker exited without finishing the sequence");
+        }
+
+        // Tokens for `prompt` with the cache to itself.
+        fn alone(path: &std::path::Path, prompt: &[i32], num_predict: usize) -> Vec<i32> {
+            let batcher = Batcher::spawn(recorded_llama(path, 1 << 20).0, TokenId(VOCAB as i32), 1);
+            let (req, rx) = request(prompt, num_predict);
+            batcher.submit(req).unwrap();
+            outcome(rx).unwrap()
+        }
+
+        #[test]
+        fn test_small_pool_fails_only_the_sequence_that_runs_out() {
+            let _serial = serial();
+            let path = std::env::temp_dir().join(format!("ollama-batch-small-pool-{}.gguf", std::process::id()));
+            write_llama(&path);
+
+            // Four blocks; each sequence evaluates 3 + 8 - 1 positions, three
+            // blocks' worth. Stalling the worker admits both before either
+            // grows, and the first to need a third block finds none free.
+            let (model, _) = recorded_llama(&path, 4 * 256);
+            let batcher = Batcher::spawn(model.clone(), TokenId(VOCAB as i32), 2);
+            let guard = model.lock().unwrap();
+            let (a, a_rx) = request(&[1, 5, 3], 8);
+            let (b, b_rx) = request(&[2, 6, 4], 8);
+            batcher.submit(a).unwrap();
+            batcher.submit(b).unwrap();
+            drop(guard);
+            let (a, b) = (outcome(a_rx), outcome(b_rx));
+            drop(batcher);
+
+            let error = a.unwrap_err();
+            assert!(error.contains("exhausted"), "{}", error);
+            assert_eq!(b.unwrap(), alone(&path, &[2, 6, 4], 8));
+            std::fs::remove_file(&path).ok();
+        }
+
+        #[test]
+        fn test_prompt_waits_for_blocks() {
+            let _serial = serial();
+            let path = std::env::temp_dir().join(format!("ollama-batch-wait-{}.gguf", std::process::id()));
+            write_llama(&path);
+
+            // Three blocks. The second prompt needs all of them, so it waits
+            // for the first sequence, then takes the blocks its idle slot kept.
+            let (model, _) = recorded_llama(&path, 3 * 256);
+            let batcher = Batcher::spawn(model.clone(), TokenId(VOCAB as i32), 2);
+            let guard = model.lock().unwrap();
+            let long = [2, 6, 4, 8, 1, 9, 7, 3, 5];
+            let (a, a_rx) = request(&[1, 5, 3], 6);
+            let (b, b_rx) = request(&long, 2);
+            batcher.submit(a).unwrap();
+            batcher.submit(b).unwrap();
+            drop(guard);
+            let (a, b) = (outcome(a_rx).unwrap(), outcome(b_rx).unwrap());
+
+            // A prompt larger than the whole pool fails instead of waiting.
+            let (c, c_rx) = request(&[1; 13], 1);
+            batcher.submit(c).unwrap();
+            assert!(outcome(c_rx).unwrap_err().contains("exhausted"));
+            drop(batcher);
+
+            assert_eq!(a, alone(&path, &[1, 5, 3], 6));
+            assert_eq!(b, alone(&path, &long, 2));
+            std::fs::remove_file(&path).ok();
+        }
+    }
 }
 
 pub mod scheduler {
     use anyhow::Result;
     use std::collections::HashMap;
+    use std::path::PathBuf;
     use std::sync::Arc;
     use tokio::sync::RwLock;
     use std::time::{Duration, Instant};
@@ -398,33 +1351,58 @@ pub mod scheduler {
         runners: HashMap<String, ScheduledRunner>,
         max_models: usize,
         default_keep_alive: Duration,
+        // Bytes the loaded models may take together.
+        memory: u64,
     }
 
     #[allow(dead_code)]
     impl Scheduler {
         pub fn new(max_models: usize) -> Self {
+            let memory = match crate::discover::get_cpu_info().total_memory {
+                0 => u64::MAX,
+                n => n,
+            };
             Self {
                 runners: HashMap::new(),
                 max_models,
                 default_keep_alive: Duration::from_secs(300), // 5 minutes
+                memory,
             }
         }
 
-        pub async fn get_runner(&mut self, model_name: &str, model_path: &str) -> Result<Arc<RwLock<Runner>>> {
+        pub fn with_memory(mut self, memory: u64) -> Self {
+            self.memory = memory;
+            self
+        }
+
+        pub async fn get_runner(&mut self, model_name: &str, model_paths: &[PathBuf], adapters: Vec<PathBuf>) -> Result<Arc<RwLock<Runner>>> {
             // Check if runner already exists - use get_mut for mutable access
             if let Some(scheduled) = self.runners.get_mut(model_name) {
                 scheduled.last_used = Instant::now();
                 return Ok(scheduled.runner.clone());
             }
 
-            // Evict old runners if at capacity
-            while self.runners.len() >= self.max_models {
-                self.evict_oldest().await?;
+            // Create new runner
+            let first = model_paths.first().ok_or_else(|| anyhow::anyhow!("model {} has no weights", model_name))?;
+            let runner = Runner::new(&first.to_string_lossy())?
+                .with_shards(model_paths.to_vec())
+                .with_adapters(adapters);
+            let size = runner.memory_estimate()?;
+            if size > self.memory {
+                anyhow::bail!(
+                    "model requires more system memory ({}) than is available ({})",
+                    crate::format::human_bytes(size),
+                    crate::format::human_bytes(self.memory)
+                );
             }
 
-            // Create new runner
-            let runner = Runner::new(model_path)?;
-            let size = std::fs::metadata(model_path).map(|m| m.len()).unwrap_or(0);
+            // Evict the least recently used runners until the new one fits,
+            // both in count and in memory.
+            while !self.runners.is_empty()
+                && (self.runners.len() >= self.max_models || self.loaded_size() + size > self.memory)
+            {
+                self.evict_oldest().await?;
+            }
 
             let scheduled = ScheduledRunner {
                 runner: Arc::new(RwLock::new(runner)),
@@ -439,6 +1417,10 @@ pub mod scheduler {
             Ok(self.runners.get(model_name).unwrap().runner.clone())
         }
 
+        fn loaded_size(&self) -> u64 {
+            self.runners.values().map(|s| s.size).sum()
+        }
+
         async fn evict_oldest(&mut self) -> Result<()> {
             if let Some((name, _)) = self.runners.iter()
                 .min_by_key(|(_, v)| v.last_used)
@@ -490,6 +1472,19 @@ pub mod scheduler {
                     "Never".to_string()
                 };
 
+                // A runner being loaded is locked; it has no cache yet anyway.
+                let kv_cache = s.runner.try_read().ok().and_then(|r| r.kv_cache_stats()).map(|stats| {
+                    crate::api::KvCacheUsage {
+                        cache_type: stats.cache_type.name().to_string(),
+                        block_size: stats.block_size,
+                        blocks_used: stats.used_blocks,
+                        blocks_total: stats.total_blocks,
+                        bytes_used: (stats.used_blocks * stats.block_bytes) as u64,
+                        bytes_total: (stats.total_blocks * stats.block_bytes) as u64,
+                        utilization: stats.utilization(),
+                    }
+                });
+
                 crate::api::RunningModel {
                     name: name.clone(),
                     model: name.clone(),
@@ -499,6 +1494,7 @@ pub mod scheduler {
                     digest: String::new(),
                     expires_at,
                     context_length: 0,
+                    kv_cache,
                 }
             }).collect()
         }
@@ -510,4 +1506,141 @@ pub mod scheduler {
             Ok(())
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ollama::infra::gguf::{GgufMetadata, GgufWriter, MetadataValue, ValueType};
+
+        // A llama header with no weights, so its estimate is all K/V.
+        fn write_header(path: &std::path::Path) {
+            let mut metadata = GgufMetadata::new();
+            metadata.set("general.architecture", MetadataValue::String("llama".into()), ValueType::String);
+            for (key, value) in [
+                ("llama.context_length", 1024),
+                ("llama.embedding_length", 256),
+                ("llama.block_count", 4),
+                ("llama.attention.head_count", 8),
+                ("llama.attention.head_count_kv", 2),
+            ] {
+                metadata.set(key, MetadataValue::Uint(value), ValueType::U32);
+            }
+            GgufWriter::new(metadata).write_to_file(path).unwrap();
+        }
+
+        // Runner::new builds a blocking HTTP client, which debug builds refuse
+        // to do inside a tokio runtime, so this runs on the futures executor.
+        #[test]
+        fn test_evicts_until_the_model_fits_in_memory() {
+            futures::executor::block_on(evicts_until_the_model_fits_in_memory());
+        }
+
+        async fn evicts_until_the_model_fits_in_memory() {
+            let dir = std::env::temp_dir().join(format!("ollama-scheduler-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let (a, b) = ([dir.join("a.gguf")], [dir.join("b.gguf")]);
+            write_header(&a[0]);
+            write_header(&b[0]);
+            let size = Runner::new(&a[0].to_string_lossy()).unwrap().memory_estimate().unwrap();
+            assert!(size > 0);
+
+            // Room for three models by count but one by memory.
+            let mut scheduler = Scheduler::new(3).with_memory(size * 3 / 2);
+            scheduler.get_runner("a", &a, Vec::new()).await.unwrap();
+            scheduler.get_runner("b", &b, Vec::new()).await.unwrap();
+            let running: Vec<_> = scheduler.list_running().into_iter().map(|m| (m.name, m.size)).collect();
+            assert_eq!(running, [("b".to_string(), size)]);
+
+            let mut small = Scheduler::new(3).with_memory(size - 1);
+            let error = small.get_runner("a", &a, Vec::new()).await.err().unwrap();
+            assert!(error.to_string().contains("requires more system memory"), "{}", error);
+            assert_eq!(small.runner_count(), 0);
+
+            std::fs::remove_dir_all(&dir).ok();
+        }
+    }
+}
+
+pub mod tokenizers {
+    use anyhow::Result;
+    use ollama::core::tokenizer::{create_tokenizer, Tokenizer, Vocabulary};
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    // Tokenizers for /api/tokenize and /api/detokenize. They are built from the
+    // GGUF header alone, so no weights are loaded and no runner is scheduled.
+    pub struct TokenizerCache {
+        entries: HashMap<PathBuf, (Arc<dyn Tokenizer>, Instant)>,
+        capacity: usize,
+    }
+
+    impl TokenizerCache {
+        pub fn new(capacity: usize) -> Self {
+            Self {
+                entries: HashMap::new(),
+                capacity: capacity.max(1),
+            }
+        }
+
+        // `path` is the model's first shard, which carries the tokenizer metadata.
+        pub fn get(&mut self, path: &Path) -> Result<Arc<dyn Tokenizer>> {
+            if let Some((tokenizer, last_used)) = self.entries.get_mut(path) {
+                *last_used = Instant::now();
+                return Ok(tokenizer.clone());
+            }
+
+            let header = ollama::infra::gguf::GgufParser::parse(path)?;
+            let vocab = Vocabulary::from_gguf(&header.metadata);
+            if vocab.tokens.is_empty() {
+                anyhow::bail!("{} has no tokenizer vocabulary", path.display());
+            }
+            let tokenizer: Arc<dyn Tokenizer> = Arc::from(create_tokenizer(vocab.kind(), vocab));
+
+            if self.entries.len() >= self.capacity {
+                let oldest = self.entries.iter().min_by_key(|(_, (_, used))| *used).map(|(p, _)| p.clone());
+                if let Some(oldest) = oldest {
+                    self.entries.remove(&oldest);
+                }
+            }
+            self.entries.insert(path.to_path_buf(), (tokenizer.clone(), Instant::now()));
+            Ok(tokenizer)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ollama::infra::gguf::{GgufMetadata, GgufWriter, MetadataValue, ValueType};
+
+        fn write_vocab(path: &Path, tokens: &[&str]) {
+            let mut metadata = GgufMetadata::new();
+            metadata.set("tokenizer.ggml.model", MetadataValue::String("llama".into()), ValueType::String);
+            metadata.set(
+                "tokenizer.ggml.tokens",
+                MetadataValue::Array(tokens.iter().map(|t| MetadataValue::String(t.to_string())).collect()),
+                ValueType::Array(Box::new(ValueType::String)),
+            );
+            GgufWriter::new(metadata).write_to_file(path).unwrap();
+        }
+
+        #[test]
+        fn test_tokenizer_cache() {
+            let dir = std::env::temp_dir().join(format!("ollama-tokenizers-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let (a, b) = (dir.join("a.gguf"), dir.join("b.gguf"));
+            write_vocab(&a, &["<unk>", "<s>", "</s>", "▁h", "▁hi"]);
+            write_vocab(&b, &["<unk>", "<s>", "</s>", "▁h", "▁hi", "▁there"]);
+
+            let mut cache = TokenizerCache::new(1);
+            let first = cache.get(&a).unwrap();
+            assert!(Arc::ptr_eq(&first, &cache.get(&a).unwrap()));
+            assert_eq!(first.encode("hi").unwrap(), vec![ollama::TokenId(1), ollama::TokenId(4)]);
+            assert_eq!(cache.get(&b).unwrap().vocab_size(), 6);
+            assert!(!Arc::ptr_eq(&first, &cache.get(&a).unwrap()));
+
+            std::fs::remove_dir_all(&dir).ok();
+        }
+    }
 }
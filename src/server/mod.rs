@@ -14,22 +14,24 @@ use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::io::Read;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, OwnedRwLockReadGuard, RwLock};
 use tokio_stream::wrappers::ReceiverStream;
 use base64::{engine::general_purpose, Engine as _};
 use chrono::Utc;
 use std::fs;
 use sha2::{Sha256, Digest};
 
-use crate::models::{ModelManager, LocalModel, PullProgress, PushProgress, ModelDetails};
+use crate::models::{ModelManager, LocalModel, PullProgress, PushProgress, ModelDetails, MODEL_MEDIA_TYPE};
 
 #[derive(Clone)]
 pub struct AppState {
     pub models_dir: PathBuf,
     pub model_manager: Arc<ModelManager>,
     pub scheduler: Arc<RwLock<crate::runner::scheduler::Scheduler>>,
+    pub tokenizers: Arc<std::sync::Mutex<crate::runner::tokenizers::TokenizerCache>>,
 }
 
 // Reuse the cache from native runner if available, or stub
@@ -170,6 +172,7 @@ pub async fn serve() -> Result<()> {
         models_dir: models_dir.clone(),
         model_manager,
         scheduler: Arc::new(RwLock::new(crate::runner::scheduler::Scheduler::new(1))),
+        tokenizers: Arc::new(std::sync::Mutex::new(crate::runner::tokenizers::TokenizerCache::new(8))),
     };
 
     let app = Router::new()
@@ -183,6 +186,9 @@ pub async fn serve() -> Result<()> {
         .route("/api/create", post(create_model))
         .route("/api/delete", axum_delete(delete_model))
         .route("/api/copy", post(copy_model))
+        .route("/api/edit", post(edit_model))
+        .route("/api/tokenize", post(tokenize))
+        .route("/api/detokenize", post(detokenize))
         .route("/api/embed", post(embed))
         .route("/api/embeddings", post(embeddings))
         .route("/api/blobs/:digest", head(head_blob))
@@ -218,10 +224,11 @@ async fn generate(
     let (tx, rx) = mpsc::channel::<Result<Bytes, Infallible>>(100);
     
     let name = req.model.clone();
-    let model_path = match state.model_manager.get_model_weights_path(&name) {
+    let model_paths = match state.model_manager.get_model_weights_paths(&name) {
         Some(p) => p,
         None => return (StatusCode::NOT_FOUND, format!("Model '{}' not found", name)).into_response(),
     };
+    let adapters = state.model_manager.get_model_adapter_paths(&name);
 
     let scheduler = Arc::clone(&state.scheduler);
     
@@ -237,12 +244,15 @@ async fn generate(
     }
 
     let prompt = req.prompt.unwrap_or_default();
+    // A raw prompt is already formatted by the client, control tokens included.
+    let raw = req.raw.unwrap_or(false);
+    let options = req.options.as_ref().map(crate::runner::runner::RunnerOptions::from_map).unwrap_or_default();
     
     tokio::spawn(async move {
         // Use a block to ensure sched lock is dropped after getting runner
         let runner_arc = {
             let mut sched = scheduler.write().await;
-            match sched.get_runner(&name, &model_path.to_string_lossy()).await {
+            match sched.get_runner(&name, &model_paths, adapters).await {
                 Ok(r) => r,
                 Err(e) => {
                     let _ = tx.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
@@ -251,19 +261,22 @@ async fn generate(
             }
         };
 
-        let mut runner = runner_arc.write().await;
-        if !runner.is_loaded() {
-            if let Err(e) = runner.load() {
+        let runner = match loaded_runner(runner_arc, Some(&options)).await {
+            Ok(runner) => runner,
+            Err(e) => {
                 let _ = tx.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
                 return;
             }
-        }
+        };
 
         let name_clone = name.clone();
         let tx_clone = tx.clone();
         
-        // Generate with callback for streaming
-        let res = runner.generate(&prompt, move |text, done| {
+        // Generate with callback for streaming; the final chunk carries the stats
+        let on_token = move |text: String, done: bool| {
+            if done {
+                return;
+            }
             let resp = GenerateResponse {
                 model: name_clone.clone(),
                 created_at: Utc::now().to_rfc3339(),
@@ -280,10 +293,37 @@ async fn generate(
             };
             let line = serde_json::to_string(&resp).unwrap() + "\n";
             let _ = tx_clone.try_send(Ok(Bytes::from(line)));
-        });
-
-        if let Err(e) = res {
-            let _ = tx.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
+        };
+        let res = run_blocking(move || {
+            if raw {
+                runner.generate_templated(&prompt, &[], on_token)
+            } else {
+                runner.generate(&prompt, on_token)
+            }
+        })
+        .await;
+
+        match res {
+            Ok(res) => {
+                let resp = GenerateResponse {
+                    model: name,
+                    created_at: Utc::now().to_rfc3339(),
+                    response: String::new(),
+                    done: true,
+                    context: Some(res.context),
+                    total_duration: Some(res.total_duration),
+                    load_duration: Some(res.load_duration),
+                    prompt_eval_count: Some(res.prompt_eval_count),
+                    prompt_eval_duration: Some(res.prompt_eval_duration),
+                    eval_count: Some(res.eval_count),
+                    eval_duration: Some(res.eval_duration),
+                    tokens: None,
+                };
+                let _ = tx.send(Ok(Bytes::from(serde_json::to_string(&resp).unwrap() + "\n"))).await;
+            }
+            Err(e) => {
+                let _ = tx.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
+            }
         }
     });
 
@@ -293,6 +333,44 @@ async fn generate(
         .unwrap()
 }
 
+// The runner for a request, loading it first if needed. It is returned
+// shared, so requests to one model reach its batch worker together.
+async fn loaded_runner(
+    runner: Arc<RwLock<crate::runner::runner::Runner>>,
+    options: Option<&crate::runner::runner::RunnerOptions>,
+) -> Result<OwnedRwLockReadGuard<crate::runner::runner::Runner>> {
+    let shared = runner.clone().read_owned().await;
+    if shared.is_loaded() && !options.is_some_and(|o| shared.needs_reload(o)) {
+        return Ok(shared);
+    }
+    drop(shared);
+
+    let mut exclusive = runner.write_owned().await;
+    reload_for_scale(&mut exclusive, options);
+    if !exclusive.is_loaded() {
+        if let Some(options) = options {
+            exclusive.set_load_options(options);
+        }
+        exclusive.load()?;
+    }
+    Ok(exclusive.downgrade())
+}
+
+// A runner is cached by model name, so a request asking for another LoRA
+// scale unloads it to be loaded again with that scale.
+fn reload_for_scale(runner: &mut crate::runner::runner::Runner, options: Option<&crate::runner::runner::RunnerOptions>) {
+    if let Some(options) = options.filter(|o| runner.needs_reload(o)) {
+        println!("Reloading model for lora_scale {:?}", options.lora_scale);
+        runner.unload();
+    }
+}
+
+// Generation blocks until the batch worker is done with the request, so it
+// runs off the async workers.
+async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f).await?
+}
+
 async fn chat(
     AxumState(state): AxumState<AppState>,
     Json(req): Json<ChatRequest>,
@@ -300,12 +378,14 @@ async fn chat(
     let (tx, rx) = mpsc::channel::<Result<Bytes, Infallible>>(100);
     
     let name = req.model.clone();
-    let model_path = match state.model_manager.get_model_weights_path(&name) {
+    let model_paths = match state.model_manager.get_model_weights_paths(&name) {
         Some(p) => p,
         None => return (StatusCode::NOT_FOUND, format!("Model '{}' not found", name)).into_response(),
     };
+    let adapters = state.model_manager.get_model_adapter_paths(&name);
 
     let scheduler = Arc::clone(&state.scheduler);
+    let options = req.options.as_ref().map(crate::runner::runner::RunnerOptions::from_map).unwrap_or_default();
     let messages: Vec<crate::runner::runner::Message> = req.messages.iter().map(|m| crate::runner::runner::Message {
         role: m.role.clone(),
         content: m.content.clone(),
@@ -315,7 +395,7 @@ async fn chat(
     tokio::spawn(async move {
         let runner_arc = {
             let mut sched = scheduler.write().await;
-            match sched.get_runner(&name, &model_path.to_string_lossy()).await {
+            match sched.get_runner(&name, &model_paths, adapters).await {
                 Ok(r) => r,
                 Err(e) => {
                     let _ = tx.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
@@ -324,18 +404,18 @@ async fn chat(
             }
         };
 
-        let mut runner = runner_arc.write().await;
-        if !runner.is_loaded() {
-            if let Err(e) = runner.load() {
+        let runner = match loaded_runner(runner_arc, Some(&options)).await {
+            Ok(runner) => runner,
+            Err(e) => {
                 let _ = tx.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
                 return;
             }
-        }
+        };
 
         let name_clone = name.clone();
         let tx_clone = tx.clone();
 
-        match runner.chat(&messages, None, move |text, done| {
+        let res = run_blocking(move || runner.chat(&messages, None, move |text, done| {
             let resp = ChatResponse {
                 model: name_clone.clone(),
                 created_at: Utc::now().to_rfc3339(),
@@ -352,11 +432,10 @@ async fn chat(
             };
             let line = serde_json::to_string(&resp).unwrap() + "\n";
             let _ = tx_clone.try_send(Ok(Bytes::from(line)));
-        }) {
-            Ok(_) => {}
-            Err(e) => {
-                let _ = tx.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
-            }
+        }))
+        .await;
+        if let Err(e) = res {
+            let _ = tx.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
         }
     });
 
@@ -424,43 +503,57 @@ async fn embed(
     Json(req): Json<EmbedRequest>,
 ) -> impl IntoResponse {
     let name = req.model.clone();
-    let model_path = match state.model_manager.get_model_weights_path(&name) {
+    let model_paths = match state.model_manager.get_model_weights_paths(&name) {
         Some(p) => p,
         None => return (StatusCode::NOT_FOUND, format!("Model '{}' not found", name)).into_response(),
     };
+    let adapters = state.model_manager.get_model_adapter_paths(&name);
 
     let scheduler = Arc::clone(&state.scheduler);
-    let input = if let Some(s) = req.input.as_str() {
-        s.to_string()
-    } else {
-        return (StatusCode::BAD_REQUEST, "Input must be a string").into_response();
+    let Some(input) = embed_inputs(&req.input) else {
+        return (StatusCode::BAD_REQUEST, "Input must be a string or an array of strings").into_response();
     };
     
     let mut sched = scheduler.write().await;
-    let runner_arc = match sched.get_runner(&name, &model_path.to_string_lossy()).await {
+    let runner_arc = match sched.get_runner(&name, &model_paths, adapters).await {
         Ok(r) => r,
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     };
 
     let mut runner = runner_arc.write().await;
+    let options = req.options.as_ref().map(crate::runner::runner::RunnerOptions::from_map);
+    reload_for_scale(&mut runner, options.as_ref());
     if !runner.is_loaded() {
+        if let Some(ref options) = options {
+            runner.set_load_options(options);
+        }
         if let Err(e) = runner.load() {
             return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
         }
     }
 
+    let input: Vec<&str> = input.iter().map(String::as_str).collect();
     match runner.embed(&input, req.dimensions) {
         Ok(result) => Json(EmbedResponse {
             model: name,
             embeddings: result.embeddings,
             total_duration: Some(result.total_duration),
-            load_duration: Some(0),
-            prompt_eval_count: Some(0),
+            load_duration: Some(result.load_duration),
+            prompt_eval_count: Some(result.prompt_eval_count as i32),
         }).into_response(),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     }
 }
 
+// Embedding input is either one string or an array of them.
+fn embed_inputs(input: &Value) -> Option<Vec<String>> {
+    match input {
+        Value::String(s) => Some(vec![s.clone()]),
+        Value::Array(items) => items.iter().map(|v| v.as_str().map(String::from)).collect(),
+        _ => None,
+    }
+}
+
 async fn embeddings(
     AxumState(state): AxumState<AppState>,
     Json(req): Json<HashMap<String, Value>>,
@@ -481,6 +574,123 @@ async fn embeddings(
     embed(AxumState(state), Json(embed_req)).await
 }
 
+#[derive(Debug, Deserialize)]
+pub struct TokenizeRequest {
+    pub model: String,
+    pub content: String,
+    // Add BOS/EOS as the model's vocabulary asks for them.
+    #[serde(default)]
+    pub add_special: bool,
+    // Match control tokens such as <|im_start|> in the content.
+    #[serde(default)]
+    pub parse_special: bool,
+    #[serde(default)]
+    pub with_pieces: bool,
+    #[serde(default)]
+    pub with_offsets: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenizeResponse {
+    pub model: String,
+    pub tokens: Vec<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pieces: Option<Vec<String>>,
+    // Byte offsets [start, end) into the content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offsets: Option<Vec<[usize; 2]>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DetokenizeRequest {
+    pub model: String,
+    pub tokens: Vec<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DetokenizeResponse {
+    pub model: String,
+    pub content: String,
+}
+
+// Tokenizers come from their own cache, so counting tokens never loads weights
+// or evicts a model from the scheduler.
+fn model_tokenizer(state: &AppState, name: &str) -> Result<Arc<dyn ollama::core::tokenizer::Tokenizer>, (StatusCode, String)> {
+    let paths = state
+        .model_manager
+        .get_model_weights_paths(name)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Model '{}' not found", name)))?;
+    let mut cache = state.tokenizers.lock().unwrap_or_else(|e| e.into_inner());
+    cache
+        .get(&paths[0])
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+async fn tokenize(
+    AxumState(state): AxumState<AppState>,
+    Json(req): Json<TokenizeRequest>,
+) -> impl IntoResponse {
+    let res = tokio::task::spawn_blocking(move || {
+        let tokenizer = model_tokenizer(&state, &req.model)?;
+        let mut options = if req.add_special {
+            tokenizer.encode_options()
+        } else {
+            ollama::core::tokenizer::EncodeOptions::new()
+        };
+        options.parse_special = req.parse_special;
+        let tokens = tokenizer
+            .encode_with_options(&req.content, &options)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+        let pieces = req.with_pieces.then(|| {
+            tokens.iter().map(|&t| tokenizer.id_to_token(t).unwrap_or_default().to_string()).collect()
+        });
+        let offsets = req.with_offsets.then(|| {
+            ollama::core::tokenizer::token_offsets(tokenizer.as_ref(), &req.content, &tokens)
+                .into_iter()
+                .map(|r| [r.start, r.end])
+                .collect()
+        });
+        Ok::<_, (StatusCode, String)>(TokenizeResponse {
+            model: req.model,
+            tokens: tokens.iter().map(|t| t.0).collect(),
+            pieces,
+            offsets,
+        })
+    })
+    .await;
+
+    match res {
+        Ok(Ok(resp)) => Json(resp).into_response(),
+        Ok(Err(e)) => e.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn detokenize(
+    AxumState(state): AxumState<AppState>,
+    Json(req): Json<DetokenizeRequest>,
+) -> impl IntoResponse {
+    let res = tokio::task::spawn_blocking(move || {
+        let tokenizer = model_tokenizer(&state, &req.model)?;
+        if let Some(bad) = req.tokens.iter().find(|&&t| t < 0 || t as usize >= tokenizer.vocab_size()) {
+            return Err((StatusCode::BAD_REQUEST, format!("token {} is out of range", bad)));
+        }
+        let tokens: Vec<ollama::TokenId> = req.tokens.iter().map(|&t| ollama::TokenId(t)).collect();
+        let content = tokenizer
+            .decode(&tokens)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        Ok(DetokenizeResponse { model: req.model, content })
+    })
+    .await;
+
+    match res {
+        Ok(Ok(resp)) => Json(resp).into_response(),
+        Ok(Err(e)) => e.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
 async fn head_blob(
     AxumState(state): AxumState<AppState>,
     Path(digest): Path<String>,
@@ -585,6 +795,14 @@ async fn create_model(
         Some(m) => m.to_string(),
         None => return (StatusCode::BAD_REQUEST, "Missing modelfile").into_response(),
     };
+    
+    let quantize = match req.get("quantize").and_then(|v| v.as_str()).filter(|q| !q.is_empty()) {
+        Some(q) => match q.parse::<crate::convert::quants::QuantizationType>() {
+            Ok(q) => Some(q),
+            Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        },
+        None => None,
+    };
 
     let (tx, rx) = mpsc::channel::<Result<Bytes, Infallible>>(100);
     let tx_for_blocking = tx.clone();
@@ -615,9 +833,11 @@ async fn create_model(
             
             let models_dir = state.models_dir.clone();
             
-            // Resolve base model
+            // Resolve base model; the CLI sends local FROM paths as absolute paths.
+            let local_path = PathBuf::from(&base_model);
+            let is_local = local_path.is_absolute() && local_path.exists();
             let base_path = state.model_manager.get_model_dir(&base_model);
-            if !base_path.exists() {
+            if !is_local && !base_path.exists() {
                 return Err(anyhow::anyhow!("Base model {} not found locally. Please pull it first.", base_model));
             }
                 
@@ -647,15 +867,75 @@ async fn create_model(
             let _ = tx_inner.blocking_send(Ok(Bytes::from(serde_json::to_string(&json!({"status": format!("processing layers for {}", name)})).unwrap() + "\n")));
 
             let mm = &state.model_manager;
-            let (base_full, base_tag) = crate::models::registry::Registry::resolve_name(&base_model);
-            let base_manifest_path = mm.get_manifest_path(&base_full, &base_tag);
-            
-            if !base_manifest_path.exists() {
-                return Err(anyhow::anyhow!("Base model manifest not found: {}", base_model));
+            let import_root = crate::envconfig::import_root();
+            let mut new_manifest = if is_local {
+                import_local_model(mm, &import_root, &local_path, |status| {
+                    let _ = tx_inner.blocking_send(Ok(Bytes::from(serde_json::to_string(&json!({"status": status})).unwrap() + "\n")));
+                })?
+            } else {
+                let (base_full, base_tag) = crate::models::registry::Registry::resolve_name(&base_model);
+                let base_manifest_path = mm.get_manifest_path(&base_full, &base_tag);
+                
+                if !base_manifest_path.exists() {
+                    return Err(anyhow::anyhow!("Base model manifest not found: {}", base_model));
+                }
+                
+                let manifest_content = fs::read_to_string(&base_manifest_path)?;
+                serde_json::from_str(&manifest_content)?
+            };
+
+            for cmd in mf.commands.iter().filter(|c| c.name == "adapter") {
+                let adapter_path = import_path(&import_root, std::path::Path::new(&cmd.args))?;
+                ollama::core::model::lora::LoraAdapter::load(&adapter_path, 1.0)
+                    .map_err(|e| anyhow::anyhow!("invalid adapter {}: {}", cmd.args, e))?;
+                
+                let data = fs::read(&adapter_path)?;
+                let mut hasher = Sha256::new();
+                hasher.update(&data);
+                let digest = format!("sha256:{:x}", hasher.finalize());
+                if mm.stat_blob(&digest).is_none() {
+                    mm.create_blob(&digest, &data)?;
+                }
+                
+                let _ = tx_inner.blocking_send(Ok(Bytes::from(serde_json::to_string(&json!({"status": format!("adding adapter {}", &digest[7..19])})).unwrap() + "\n")));
+                new_manifest.layers.retain(|l| l.digest != digest);
+                new_manifest.layers.push(crate::models::Layer {
+                    media_type: Some(crate::models::ADAPTER_MEDIA_TYPE.to_string()),
+                    digest,
+                    size: data.len() as u64,
+                });
             }
 
-            let manifest_content = fs::read_to_string(&base_manifest_path)?;
-            let mut new_manifest: crate::models::Manifest = serde_json::from_str(&manifest_content)?;
+            if let Some(qtype) = quantize {
+                let shards = new_manifest.layers.iter().filter(|l| l.media_type.as_deref() == Some(MODEL_MEDIA_TYPE)).count();
+                if shards > 1 {
+                    bail!("quantizing split models is not supported; merge the {} shards first", shards);
+                }
+                let weights = new_manifest.layers.iter()
+                    .find(|l| l.media_type.as_deref() == Some(MODEL_MEDIA_TYPE))
+                    .map(|l| mm.get_blob_path(&l.digest))
+                    .filter(|p| p.exists())
+                    .ok_or_else(|| anyhow::anyhow!("no model weights found for {}", base_model))?;
+                let partial = mm.partial_blob_path();
+                let tx_progress = tx_inner.clone();
+                let quantized = crate::convert::quants::quantize_gguf(&weights, &partial, qtype, |p| {
+                    let status = format!("quantizing {} tensor {}/{} to {}", p.name, p.index + 1, p.total, p.dtype.name());
+                    let _ = tx_progress.blocking_send(Ok(Bytes::from(serde_json::to_string(&json!({"status": status})).unwrap() + "\n")));
+                });
+                if let Err(e) = quantized {
+                    let _ = fs::remove_file(&partial);
+                    return Err(e);
+                }
+                let (digest, size) = mm.commit_blob(&partial)?;
+                
+                let _ = tx_inner.blocking_send(Ok(Bytes::from(serde_json::to_string(&json!({"status": format!("quantized model to {}", qtype.name())})).unwrap() + "\n")));
+                new_manifest.layers.retain(|l| l.media_type.as_deref() != Some(MODEL_MEDIA_TYPE));
+                new_manifest.layers.insert(0, crate::models::Layer {
+                    media_type: Some(MODEL_MEDIA_TYPE.to_string()),
+                    digest,
+                    size,
+                });
+            }
 
             // Add system layer if present
             if !system.is_empty() {
@@ -694,6 +974,93 @@ async fn create_model(
         .into_response()
 }
 
+// Imports a GGUF file or a Hugging Face checkpoint directory as new model
+// blobs. Split GGUFs become one model layer per shard, in split order.
+// Files named in a Modelfile are read by the server, so they must resolve,
+// symlinks and all, to somewhere under the import root.
+fn import_path(root: &std::path::Path, path: &std::path::Path) -> Result<PathBuf> {
+    let root = root
+        .canonicalize()
+        .map_err(|e| anyhow::anyhow!("import root {} is not accessible: {}", root.display(), e))?;
+    let resolved = path.canonicalize().map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    if !resolved.starts_with(&root) {
+        bail!("{} is outside the import root {} (set OLLAMA_IMPORT_ROOT)", path.display(), root.display());
+    }
+    Ok(resolved)
+}
+
+fn import_local_model<F: Fn(String)>(
+    mm: &crate::models::ModelManager,
+    root: &std::path::Path,
+    path: &std::path::Path,
+    progress: F,
+) -> Result<crate::models::Manifest> {
+    let path = import_path(root, path)?;
+    let sources = if path.is_dir() {
+        vec![path]
+    } else {
+        let mut magic = [0u8; 4];
+        std::fs::File::open(&path)?.read_exact(&mut magic)?;
+        if &magic != b"GGUF" {
+            bail!("{} is not a GGUF file", path.display());
+        }
+        ollama::infra::gguf::split::shard_paths(&path)?
+    };
+
+    let mut layers = Vec::with_capacity(sources.len());
+    for source in &sources {
+        let partial = mm.partial_blob_path();
+        let written = if source.is_dir() {
+            if !ollama::infra::converter::safetensors::is_safetensors_dir(source) {
+                bail!("{} is not a safetensors model directory", source.display());
+            }
+            progress(format!("converting model {}", source.display()));
+            crate::convert::convert_model_dir(source, &partial, |p| {
+                progress(format!("converting tensor {}/{} {}", p.index + 1, p.total, p.name));
+            })
+        } else {
+            progress(format!("copying model {}", source.display()));
+            fs::copy(source, &partial).map(|_| ()).map_err(Into::into)
+        };
+        if let Err(e) = written {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        let (digest, size) = mm.commit_blob(&partial)?;
+        layers.push(crate::models::Layer {
+            media_type: Some(MODEL_MEDIA_TYPE.to_string()),
+            digest,
+            size,
+        });
+    }
+
+    // Check the shards belong together before writing a manifest for them.
+    let blobs: Vec<PathBuf> = layers.iter().map(|l| mm.get_blob_path(&l.digest)).collect();
+    let split = ollama::infra::gguf::SplitGguf::open(&blobs, true, false)?;
+    let arch = split.file().metadata.string("general.architecture");
+    let config = crate::models::ModelConfig {
+        model_format: Some("gguf".to_string()),
+        model_family: Some(arch.clone()),
+        model_families: Some(vec![arch]),
+        parameter_size: None,
+        quantization_level: None,
+    };
+    let config_data = serde_json::to_vec(&config)?;
+    let config_digest = format!("sha256:{:x}", Sha256::digest(&config_data));
+    mm.create_blob(&config_digest, &config_data)?;
+
+    Ok(crate::models::Manifest {
+        schema_version: 2,
+        media_type: Some("application/vnd.docker.distribution.manifest.v2+json".to_string()),
+        config: crate::models::Layer {
+            media_type: Some("application/vnd.docker.container.image.v1+json".to_string()),
+            digest: config_digest,
+            size: config_data.len() as u64,
+        },
+        layers,
+    })
+}
+
 async fn delete_model(
     AxumState(state): AxumState<AppState>,
     Json(req): Json<HashMap<String, String>>,
@@ -725,6 +1092,78 @@ async fn copy_model(
     }
 }
 
+#[derive(Deserialize)]
+pub struct EditRequest {
+    pub model: String,
+    pub destination: String,
+    // Keys may carry a type suffix, e.g. "llama.rope.freq_base:f32".
+    #[serde(default)]
+    pub set: serde_json::Map<String, Value>,
+    #[serde(default)]
+    pub unset: Vec<String>,
+}
+
+async fn edit_model(
+    AxumState(state): AxumState<AppState>,
+    Json(req): Json<EditRequest>,
+) -> impl IntoResponse {
+    let res = tokio::task::spawn_blocking(move || edit_model_metadata(&state.model_manager, &req)).await;
+    match res {
+        Ok(Ok(digest)) => Json(json!({"status": "success", "digest": digest})).into_response(),
+        Ok(Err(e)) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+// Writes the edited weights as a new blob and saves a manifest pointing at it
+// under the destination name; the source model's blobs and manifest are untouched.
+fn edit_model_metadata(mm: &ModelManager, req: &EditRequest) -> Result<String> {
+    use ollama::infra::gguf::edit::{self, Edit};
+
+    let mut edits = Vec::new();
+    for (key, value) in &req.set {
+        let (key, vtype) = edit::split_type(key)?;
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        edits.push(Edit::Set { key, vtype, value });
+    }
+    edits.extend(req.unset.iter().cloned().map(Edit::Unset));
+    if edits.is_empty() {
+        bail!("no metadata changes given");
+    }
+
+    let (src_full, src_tag) = crate::models::registry::Registry::resolve_name(&req.model);
+    let (dst_full, dst_tag) = crate::models::registry::Registry::resolve_name(&req.destination);
+    let src_path = mm.get_manifest_path(&src_full, &src_tag);
+    let dst_path = mm.get_manifest_path(&dst_full, &dst_tag);
+    if src_path == dst_path {
+        bail!("destination must differ from {}", req.model);
+    }
+    let content = fs::read_to_string(&src_path).map_err(|_| anyhow::anyhow!("model '{}' not found", req.model))?;
+    let mut manifest: crate::models::Manifest = serde_json::from_str(&content)?;
+
+    // Model metadata lives in the first weights layer, also for split models.
+    let layer = manifest
+        .layers
+        .iter_mut()
+        .find(|l| l.media_type.as_deref() == Some(MODEL_MEDIA_TYPE))
+        .ok_or_else(|| anyhow::anyhow!("no model weights found for {}", req.model))?;
+    let partial = mm.partial_blob_path();
+    if let Err(e) = edit::rewrite(mm.get_blob_path(&layer.digest), &partial, &edits) {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    let (digest, size) = mm.commit_blob(&partial)?;
+    layer.digest = digest.clone();
+    layer.size = size;
+
+    fs::create_dir_all(dst_path.parent().unwrap())?;
+    fs::write(&dst_path, serde_json::to_string(&manifest)?)?;
+    Ok(digest)
+}
+
 async fn version() -> impl IntoResponse {
     Json(json!({"version": "0.5.0-rust"})).into_response()
 }
@@ -734,7 +1173,22 @@ async fn health() -> impl IntoResponse {
 }
 
 async fn metrics() -> impl IntoResponse {
-    (StatusCode::OK, "# Ollama metrics").into_response()
+    (StatusCode::OK, metrics_text()).into_response()
+}
+
+pub(crate) fn metrics_text() -> String {
+    use crate::runner::runner::{PROMPT_CACHE_HIT_TOKENS, PROMPT_EVAL_TOKENS};
+    use std::sync::atomic::Ordering;
+
+    format!(
+        "# Ollama metrics\n\
+         # TYPE ollama_prompt_eval_tokens_total counter\n\
+         ollama_prompt_eval_tokens_total {}\n\
+         # TYPE ollama_prompt_cache_hit_tokens_total counter\n\
+         ollama_prompt_cache_hit_tokens_total {}\n",
+        PROMPT_EVAL_TOKENS.load(Ordering::Relaxed),
+        PROMPT_CACHE_HIT_TOKENS.load(Ordering::Relaxed),
+    )
 }
 
 async fn auth_me() -> impl IntoResponse {
@@ -761,10 +1215,11 @@ async fn openai_chat_completions(
     let (tx, rx) = mpsc::channel::<Result<Bytes, Infallible>>(100);
     
     let name = req.model.clone();
-    let model_path = match state.model_manager.get_model_weights_path(&name) {
+    let model_paths = match state.model_manager.get_model_weights_paths(&name) {
         Some(p) => p,
         None => return (StatusCode::NOT_FOUND, format!("Model '{}' not found", name)).into_response(),
     };
+    let adapters = state.model_manager.get_model_adapter_paths(&name);
 
     let scheduler = Arc::clone(&state.scheduler);
     let messages: Vec<crate::runner::runner::Message> = req.messages.iter().map(|m| {
@@ -782,7 +1237,7 @@ async fn openai_chat_completions(
     tokio::spawn(async move {
         let runner_arc = {
             let mut sched = scheduler.write().await;
-            match sched.get_runner(&name_clone, &model_path.to_string_lossy()).await {
+            match sched.get_runner(&name_clone, &model_paths, adapters).await {
                 Ok(r) => r,
                 Err(e) => {
                     let _ = tx_clone.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
@@ -791,19 +1246,19 @@ async fn openai_chat_completions(
             }
         };
 
-        let mut runner = runner_arc.write().await;
-        if !runner.is_loaded() {
-            if let Err(e) = runner.load() {
+        let runner = match loaded_runner(runner_arc, None).await {
+            Ok(runner) => runner,
+            Err(e) => {
                 let _ = tx_clone.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
                 return;
             }
-        }
+        };
 
         let model_id = format!("chatcmpl-{}", uuid::Uuid::new_v4());
         let name_inner = name_clone.clone();
 
         let tx_for_closure = tx_clone.clone();
-        match runner.chat(&messages, None, move |text, done| {
+        let res = run_blocking(move || runner.chat(&messages, None, move |text, done| {
             if is_stream {
                 let chunk = crate::openai::ChatCompletionChunk {
                     id: model_id.clone(),
@@ -828,11 +1283,10 @@ async fn openai_chat_completions(
                 let resp = crate::openai::ChatCompletionResponse::new(name_inner.clone(), text, 0, 0);
                 let _ = tx_for_closure.try_send(Ok(Bytes::from(serde_json::to_string(&resp).unwrap())));
             }
-        }) {
-            Ok(_) => {}
-            Err(e) => {
-                let _ = tx_clone.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
-            }
+        }))
+        .await;
+        if let Err(e) = res {
+            let _ = tx_clone.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
         }
     });
 
@@ -850,10 +1304,11 @@ async fn openai_completions(
     let (tx, rx) = mpsc::channel::<Result<Bytes, Infallible>>(100);
     
     let name = req.model.clone();
-    let model_path = match state.model_manager.get_model_weights_path(&name) {
+    let model_paths = match state.model_manager.get_model_weights_paths(&name) {
         Some(p) => p,
         None => return (StatusCode::NOT_FOUND, format!("Model '{}' not found", name)).into_response(),
     };
+    let adapters = state.model_manager.get_model_adapter_paths(&name);
 
     let scheduler = Arc::clone(&state.scheduler);
     let prompt = req.prompt.clone();
@@ -864,7 +1319,7 @@ async fn openai_completions(
     tokio::spawn(async move {
         let runner_arc = {
             let mut sched = scheduler.write().await;
-            match sched.get_runner(&name_clone, &model_path.to_string_lossy()).await {
+            match sched.get_runner(&name_clone, &model_paths, adapters).await {
                 Ok(r) => r,
                 Err(e) => {
                     let _ = tx_clone.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
@@ -873,18 +1328,18 @@ async fn openai_completions(
             }
         };
 
-        let mut runner = runner_arc.write().await;
-        if !runner.is_loaded() {
-            if let Err(e) = runner.load() {
+        let runner = match loaded_runner(runner_arc, None).await {
+            Ok(runner) => runner,
+            Err(e) => {
                 let _ = tx_clone.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
                 return;
             }
-        }
+        };
 
         let model_id = format!("cmpl-{}", uuid::Uuid::new_v4());
 
         let tx_for_closure = tx_clone.clone();
-        match runner.generate(&prompt, move |text, done| {
+        let res = run_blocking(move || runner.generate(&prompt, move |text, done| {
             if is_stream {
                 let chunk = crate::openai::CompletionResponse::new_chunk(&model_id, &name_clone, text, if done { Some("stop".to_string()) } else { None });
                 let line = format!("data: {}\n\n", serde_json::to_string(&chunk).unwrap());
@@ -896,11 +1351,10 @@ async fn openai_completions(
                 let resp = crate::openai::CompletionResponse::new_final(&model_id, &name_clone, text, 0, 0);
                 let _ = tx_for_closure.try_send(Ok(Bytes::from(serde_json::to_string(&resp).unwrap())));
             }
-        }) {
-            Ok(_) => {}
-            Err(e) => {
-                let _ = tx_clone.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
-            }
+        }))
+        .await;
+        if let Err(e) = res {
+            let _ = tx_clone.send(Ok(Bytes::from(json!({"error": e.to_string()}).to_string() + "\n"))).await;
         }
     });
 
@@ -939,19 +1393,19 @@ async fn openai_embeddings(
     Json(req): Json<crate::openai::EmbeddingRequest>,
 ) -> impl IntoResponse {
     let name = req.model.clone();
-    let model_path = match state.model_manager.get_model_weights_path(&name) {
+    let model_paths = match state.model_manager.get_model_weights_paths(&name) {
         Some(p) => p,
         None => return (StatusCode::NOT_FOUND, format!("Model '{}' not found", name)).into_response(),
     };
+    let adapters = state.model_manager.get_model_adapter_paths(&name);
 
     let scheduler = Arc::clone(&state.scheduler);
-    let input = match req.input.as_str() {
-        Some(s) => s.to_string(),
-        None => return (StatusCode::BAD_REQUEST, "Input must be a string").into_response(),
+    let Some(input) = embed_inputs(&req.input) else {
+        return (StatusCode::BAD_REQUEST, "Input must be a string or an array of strings").into_response();
     };
     
     let mut sched = scheduler.write().await;
-    let runner_arc = match sched.get_runner(&name, &model_path.to_string_lossy()).await {
+    let runner_arc = match sched.get_runner(&name, &model_paths, adapters).await {
         Ok(r) => r,
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     };
@@ -963,6 +1417,7 @@ async fn openai_embeddings(
         }
     }
 
+    let input: Vec<&str> = input.iter().map(String::as_str).collect();
     match runner.embed(&input, None) {
         Ok(res) => {
             let resp = crate::openai::EmbeddingResponse {
@@ -976,8 +1431,8 @@ async fn openai_embeddings(
                 }).collect(),
                 model: name,
                 usage: crate::openai::EmbeddingUsage {
-                    prompt_tokens: 0,
-                    total_tokens: 0,
+                    prompt_tokens: res.prompt_eval_count,
+                    total_tokens: res.prompt_eval_count,
                 },
             };
             Json(resp).into_response()
@@ -985,3 +1440,117 @@ async fn openai_embeddings(
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Layer, Manifest};
+    use ollama::infra::gguf::{GgufMetadata, GgufWriter, MetadataValue, ValueType};
+
+    // A models directory holding one model, "tiny", with a small llama vocabulary.
+    fn state(dir: &std::path::Path) -> AppState {
+        let model_manager = ModelManager::new(dir).unwrap();
+        let mut metadata = GgufMetadata::new();
+        metadata.set("tokenizer.ggml.model", MetadataValue::String("llama".into()), ValueType::String);
+        let tokens = ["<unk>", "<s>", "</s>", "▁h", "▁hi", "▁there"];
+        metadata.set(
+            "tokenizer.ggml.tokens",
+            MetadataValue::Array(tokens.iter().map(|t| MetadataValue::String(t.to_string())).collect()),
+            ValueType::Array(Box::new(ValueType::String)),
+        );
+        let digest = "sha256:0123";
+        GgufWriter::new(metadata).write_to_file(model_manager.get_blob_path(digest)).unwrap();
+        let layer = |media_type: &str| Layer { media_type: Some(media_type.to_string()), digest: digest.to_string(), size: 0 };
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: None,
+            config: layer("application/vnd.docker.container.image.v1+json"),
+            layers: vec![layer(MODEL_MEDIA_TYPE)],
+        };
+        let manifest_path = model_manager.get_model_dir("library/tiny").join("latest.json");
+        fs::create_dir_all(manifest_path.parent().unwrap()).unwrap();
+        fs::write(manifest_path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+
+        AppState {
+            models_dir: dir.to_path_buf(),
+            model_manager: Arc::new(model_manager),
+            scheduler: Arc::new(RwLock::new(crate::runner::scheduler::Scheduler::new(1))),
+            tokenizers: Arc::new(std::sync::Mutex::new(crate::runner::tokenizers::TokenizerCache::new(1))),
+        }
+    }
+
+    async fn call(response: impl IntoResponse) -> (StatusCode, Value) {
+        let response = response.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into())))
+    }
+
+    async fn tokenize_json(state: &AppState, req: Value) -> (StatusCode, Value) {
+        call(tokenize(AxumState(state.clone()), Json(serde_json::from_value(req).unwrap())).await).await
+    }
+
+    async fn detokenize_json(state: &AppState, req: Value) -> (StatusCode, Value) {
+        call(detokenize(AxumState(state.clone()), Json(serde_json::from_value(req).unwrap())).await).await
+    }
+
+    #[test]
+    fn test_import_rejects_paths_outside_the_root() {
+        let dir = std::env::temp_dir().join(format!("ollama-import-root-{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        let mm = ModelManager::new(&dir.join("models")).unwrap();
+        let outside = dir.join("outside.gguf");
+        GgufWriter::new(GgufMetadata::new()).write_to_file(&outside).unwrap();
+
+        for path in [outside.clone(), root.join("../outside.gguf"), dir.clone(), PathBuf::from("/etc/passwd")] {
+            let err = import_local_model(&mm, &root, &path, |_| {}).err().unwrap();
+            assert!(err.to_string().contains("outside the import root"), "{}: {}", path.display(), err);
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, root.join("link.gguf")).unwrap();
+            let err = import_local_model(&mm, &root, &root.join("link.gguf"), |_| {}).err().unwrap();
+            assert!(err.to_string().contains("outside the import root"), "{}", err);
+        }
+
+        // A file inside the root gets as far as reading it as a model.
+        fs::copy(&outside, root.join("inside.gguf")).unwrap();
+        let err = import_local_model(&mm, &root, &root.join("inside.gguf"), |_| {}).err().map(|e| e.to_string());
+        assert!(!err.unwrap_or_default().contains("outside the import root"));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_tokenize_and_detokenize() {
+        let dir = std::env::temp_dir().join(format!("ollama-tokenize-{}", std::process::id()));
+        let state = state(&dir);
+
+        let (status, body) = tokenize_json(&state, json!({"model": "tiny", "content": "hi h", "with_pieces": true})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["tokens"], json!([4, 3]));
+        assert_eq!(body["pieces"], json!(["▁hi", "▁h"]));
+        let (status, body) = detokenize_json(&state, json!({"model": "tiny", "tokens": body["tokens"]})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["content"], "hi h");
+
+        // Empty input, with and without the vocabulary's BOS.
+        let (status, body) = tokenize_json(&state, json!({"model": "tiny", "content": ""})).await;
+        assert_eq!((status, &body["tokens"]), (StatusCode::OK, &json!([])));
+        let (_, body) = tokenize_json(&state, json!({"model": "tiny", "content": "", "add_special": true})).await;
+        assert_eq!(body["tokens"], json!([1]));
+        let (status, body) = detokenize_json(&state, json!({"model": "tiny", "tokens": []})).await;
+        assert_eq!((status, &body["content"]), (StatusCode::OK, &json!("")));
+
+        let (status, body) = tokenize_json(&state, json!({"model": "missing", "content": "hi"})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.as_str().unwrap().contains("'missing' not found"));
+        let (status, _) = detokenize_json(&state, json!({"model": "missing", "tokens": [4]})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, body) = detokenize_json(&state, json!({"model": "tiny", "tokens": [4, 6]})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.as_str().unwrap().contains("token 6 is out of range"));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -8,6 +8,67 @@ mod test_gguf {
         assert!(GgmlType::I32.bytes_per_element() == 4);
         assert!(GgmlType::I8.bytes_per_element() == 1);
     }
+
+    #[test]
+    fn test_malformed_gguf_fixtures() {
+        use ollama::infra::gguf::{GgufError, GgufParser};
+
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/gguf");
+        let valid = GgufParser::parse(dir.join("valid.gguf")).unwrap();
+        assert_eq!(valid.tensors.len(), 1);
+
+        type Check = fn(&GgufError) -> bool;
+        let cases: &[(&str, Check)] = &[
+            ("bad_magic", |e| matches!(e, GgufError::InvalidMagic(_))),
+            ("bad_version", |e| matches!(e, GgufError::UnsupportedVersion(99))),
+            ("truncated_header", |e| matches!(e, GgufError::Truncated(_))),
+            ("truncated_metadata", |e| matches!(e, GgufError::Truncated(_) | GgufError::CountTooLarge { .. })),
+            ("truncated_tensor_info", |e| matches!(e, GgufError::Truncated(_) | GgufError::CountTooLarge { .. })),
+            ("huge_string", |e| matches!(e, GgufError::StringTooLong(_))),
+            ("huge_array", |e| matches!(e, GgufError::ArrayTooLong(_))),
+            ("huge_kv_count", |e| matches!(e, GgufError::CountTooLarge { what: "metadata", .. })),
+            ("huge_tensor_count", |e| matches!(e, GgufError::CountTooLarge { what: "tensor", .. })),
+            ("bad_value_type", |e| matches!(e, GgufError::InvalidValueType(42))),
+            ("deep_nesting", |e| matches!(e, GgufError::NestingTooDeep)),
+            ("duplicate_key", |e| matches!(e, GgufError::DuplicateKey(_))),
+            ("bad_alignment", |e| matches!(e, GgufError::InvalidAlignment(33))),
+            ("too_many_dims", |e| matches!(e, GgufError::TooManyDims { dims: 9, .. })),
+            ("dim_overflow", |e| matches!(e, GgufError::InvalidShape(_))),
+            ("bad_tensor_type", |e| matches!(e, GgufError::InvalidTensorType(99))),
+            ("unaligned_row", |e| matches!(e, GgufError::InvalidShape(_))),
+            ("duplicate_tensor", |e| matches!(e, GgufError::DuplicateTensor(_))),
+            ("misaligned_offset", |e| matches!(e, GgufError::MisalignedTensor(_))),
+            ("offset_out_of_bounds", |e| matches!(e, GgufError::TensorOutOfBounds(_))),
+            ("offset_overflow", |e| matches!(e, GgufError::TensorOutOfBounds(_))),
+            ("tensor_past_eof", |e| matches!(e, GgufError::TensorOutOfBounds(_))),
+            ("overlapping_tensors", |e| matches!(e, GgufError::OverlappingTensors(_, _))),
+        ];
+        for (name, check) in cases {
+            let Err(err) = GgufParser::parse(dir.join(format!("{}.gguf", name))) else {
+                panic!("{}: parsed successfully", name);
+            };
+            assert!(check(&err), "{}: unexpected error {:?}", name, err);
+        }
+    }
+
+    #[test]
+    fn test_mutated_gguf_never_panics() {
+        use ollama::infra::gguf::GgufParser;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/gguf");
+        let valid = std::fs::read(dir.join("valid.gguf")).unwrap();
+        let mut rng = StdRng::seed_from_u64(35);
+        for _ in 0..2000 {
+            let mut data = valid.clone();
+            for _ in 0..rng.gen_range(1..8) {
+                let i = rng.gen_range(0..data.len());
+                data[i] = rng.gen();
+            }
+            data.truncate(rng.gen_range(0..=data.len()));
+            let _ = GgufParser::parse_reader(&mut std::io::Cursor::new(data));
+        }
+    }
 }
 
 // Add more pure Rust integration tests here as needed